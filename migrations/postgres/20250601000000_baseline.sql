@@ -1,4 +1,7 @@
-CREATE TABLE deploy_config (
+-- Baseline schema, previously applied by hand from database.sql. IF NOT EXISTS
+-- lets databases created that way adopt the migration history.
+
+CREATE TABLE IF NOT EXISTS deploy_config (
     id BIGSERIAL PRIMARY KEY,
     name TEXT NOT NULL UNIQUE,
     client TEXT NOT NULL,
@@ -7,7 +10,7 @@ CREATE TABLE deploy_config (
     CONSTRAINT unique_deployment UNIQUE (client, environment, solution)
 );
 
-CREATE TABLE compose_stack (
+CREATE TABLE IF NOT EXISTS compose_stack (
     id BIGSERIAL PRIMARY KEY,
     deployment_id BIGINT NOT NULL REFERENCES deploy_config (id) ON DELETE CASCADE,
     stack_name TEXT NOT NULL,
@@ -16,7 +19,7 @@ CREATE TABLE compose_stack (
     CONSTRAINT unique_stack UNIQUE (deployment_id, stack_name, filename, home_directory)
 );
 
-CREATE TABLE container (
+CREATE TABLE IF NOT EXISTS container (
     id BIGSERIAL PRIMARY KEY,
     stack_id BIGINT NOT NULL REFERENCES compose_stack (id) ON DELETE CASCADE,
     service_name TEXT NOT NULL,
@@ -38,6 +41,6 @@ CREATE TABLE container (
     CONSTRAINT unique_container UNIQUE (stack_id,service_name,container_name)
 );
 
-CREATE INDEX idx_compose_stack_deployment_id ON compose_stack (deployment_id);
+CREATE INDEX IF NOT EXISTS idx_compose_stack_deployment_id ON compose_stack (deployment_id);
 
-CREATE INDEX idx_container_stack_id ON container (stack_id);
+CREATE INDEX IF NOT EXISTS idx_container_stack_id ON container (stack_id);
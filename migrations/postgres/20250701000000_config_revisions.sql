@@ -0,0 +1,12 @@
+ALTER TABLE deploy_config ADD COLUMN IF NOT EXISTS revision BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS config_revision (
+    id BIGSERIAL PRIMARY KEY,
+    deployment_id BIGINT NOT NULL REFERENCES deploy_config (id) ON DELETE CASCADE,
+    revision BIGINT NOT NULL,
+    author TEXT NOT NULL,
+    message TEXT NOT NULL,
+    snapshot JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CONSTRAINT unique_revision UNIQUE (deployment_id, revision)
+);
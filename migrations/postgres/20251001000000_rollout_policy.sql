@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS rollout_policy (
+    deployment_id BIGINT PRIMARY KEY REFERENCES deploy_config (id) ON DELETE CASCADE,
+    canary_nodes BIGINT CHECK (canary_nodes > 0),
+    canary_percent BIGINT CHECK (canary_percent BETWEEN 1 AND 100),
+    health_timeout_secs BIGINT NOT NULL DEFAULT 300 CHECK (health_timeout_secs > 0),
+    on_failure TEXT NOT NULL DEFAULT 'pause' CHECK (on_failure IN ('pause', 'abort'))
+);
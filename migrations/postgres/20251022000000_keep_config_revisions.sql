@@ -0,0 +1,17 @@
+-- Revision history outlives its deployment: rows keep the name the deployment
+-- had and lose their link to it once it is deleted.
+ALTER TABLE config_revision ADD COLUMN IF NOT EXISTS deployment_name TEXT;
+UPDATE config_revision
+SET deployment_name = deploy_config.name
+FROM deploy_config
+WHERE deploy_config.id = config_revision.deployment_id;
+ALTER TABLE config_revision ALTER COLUMN deployment_name SET NOT NULL;
+
+ALTER TABLE config_revision ALTER COLUMN deployment_id DROP NOT NULL;
+ALTER TABLE config_revision DROP CONSTRAINT IF EXISTS config_revision_deployment_id_fkey;
+ALTER TABLE config_revision
+    ADD CONSTRAINT config_revision_deployment_id_fkey
+    FOREIGN KEY (deployment_id) REFERENCES deploy_config (id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS config_revision_deployment_name
+    ON config_revision (deployment_name, revision);
@@ -0,0 +1,12 @@
+ALTER TABLE deploy_config ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS config_revision (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    deployment_id INTEGER NOT NULL REFERENCES deploy_config (id) ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    author TEXT NOT NULL,
+    message TEXT NOT NULL,
+    snapshot TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    CONSTRAINT unique_revision UNIQUE (deployment_id, revision)
+);
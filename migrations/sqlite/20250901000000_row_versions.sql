@@ -0,0 +1,5 @@
+-- Row versions for optimistic concurrency, bumped on every update and matched
+-- against the If-Match header of PUT and PATCH requests.
+ALTER TABLE deploy_config ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE compose_stack ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE container ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS maintenance_window (
+    deployment_id INTEGER PRIMARY KEY REFERENCES deploy_config (id) ON DELETE CASCADE,
+    schedule TEXT NOT NULL CHECK (schedule <> ''),
+    duration_minutes INTEGER NOT NULL CHECK (duration_minutes > 0),
+    timezone TEXT NOT NULL DEFAULT 'UTC'
+);
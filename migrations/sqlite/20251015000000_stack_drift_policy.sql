@@ -0,0 +1,5 @@
+-- What nodes do when a stack no longer runs as configured: ignore it, report
+-- the drift or apply the configuration again.
+ALTER TABLE compose_stack
+    ADD COLUMN drift_policy TEXT NOT NULL DEFAULT 'report'
+    CHECK (drift_policy IN ('ignore', 'report', 'heal'));
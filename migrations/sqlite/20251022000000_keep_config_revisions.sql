@@ -0,0 +1,31 @@
+-- Revision history outlives its deployment: rows keep the name the deployment
+-- had and lose their link to it once it is deleted. SQLite cannot change a
+-- foreign key in place, so the table is rebuilt.
+CREATE TABLE config_revision_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    deployment_id INTEGER REFERENCES deploy_config (id) ON DELETE SET NULL,
+    deployment_name TEXT NOT NULL,
+    revision INTEGER NOT NULL,
+    author TEXT NOT NULL,
+    message TEXT NOT NULL,
+    snapshot TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    CONSTRAINT unique_revision UNIQUE (deployment_id, revision)
+);
+INSERT INTO config_revision_new
+    (id, deployment_id, deployment_name, revision, author, message, snapshot, created_at)
+SELECT config_revision.id,
+    config_revision.deployment_id,
+    deploy_config.name,
+    config_revision.revision,
+    config_revision.author,
+    config_revision.message,
+    config_revision.snapshot,
+    config_revision.created_at
+FROM config_revision
+JOIN deploy_config ON deploy_config.id = config_revision.deployment_id;
+DROP TABLE config_revision;
+ALTER TABLE config_revision_new RENAME TO config_revision;
+
+CREATE INDEX IF NOT EXISTS config_revision_deployment_name
+    ON config_revision (deployment_name, revision);
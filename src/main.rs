@@ -5,24 +5,35 @@ mod utils;
 
 use clap::Parser;
 use log::{error, info};
-use mode::{daemon::daemon_mode, server::server_mode};
+use mode::{
+    daemon::daemon_mode,
+    server::{migrate_mode, server_mode},
+};
 use utils::{
-    cli::{HikariCli, HikariCommands},
+    cli::{HikariCli, HikariCommands, ServerCommands},
     config::{load_config, load_hikari_config},
     crypto::{decrypt_json, encrypt_json},
     docker_utils::dry_run_generate_compose,
+    drift::DriftSchedule,
     error::ConfigError,
+    file_utils::CacheValidators,
+    logging::{init_logging, set_process},
+    promote::{PromoteOptions, promote},
+    runtime::{init_runtime, watch_events},
     secrets::load_secrets,
 };
 
-use crate::mode::agent::agent_mode;
+use crate::mode::{
+    agent::{agent_mode, node_name},
+    status::serve_status,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), ConfigError> {
-    let _ = log4rs::init_file("log4rs.yaml", Default::default());
+    let cli = HikariCli::parse();
+    init_logging(&cli.log_config);
     info!("Hikari Booting Up!");
     let (main_config, update_options) = load_config()?;
-    let cli = HikariCli::parse();
 
     match &cli.command {
         HikariCommands::Encrypt {
@@ -54,17 +65,69 @@ async fn main() -> Result<(), ConfigError> {
                 error!("Error loading configuration: {e}");
             }
         },
-        HikariCommands::Daemon => loop {
-            let keys = load_secrets("daemon")?;
-            if let Err(err) = daemon_mode(&main_config, &update_options, &keys[1]).await {
-                error!("{err}");
-                break;
+        HikariCommands::Daemon => {
+            set_process("daemon", Some(node_name(&main_config)));
+            init_runtime(&main_config.runtime);
+            watch_events();
+            if let Some(address) = update_options.status_address.clone() {
+                tokio::spawn(serve_status(address));
             }
-        },
-        HikariCommands::Server => {
-            server_mode().await?;
+            let mut validators = CacheValidators::default();
+            let mut drift = DriftSchedule::new(&update_options);
+            loop {
+                let keys = load_secrets("daemon")?;
+                if let Err(err) = daemon_mode(
+                    &main_config,
+                    &update_options,
+                    &keys[1],
+                    &mut validators,
+                    &mut drift,
+                )
+                .await
+                {
+                    error!("{err}");
+                    break;
+                }
+            }
+        }
+        HikariCommands::Server { command } => {
+            set_process("server", None);
+            match command {
+                Some(ServerCommands::Migrate) => migrate_mode().await?,
+                None => server_mode().await?,
+            }
+        }
+        HikariCommands::Agent => {
+            set_process("agent", Some(node_name(&main_config)));
+            init_runtime(&main_config.runtime);
+            watch_events();
+            if let Some(address) = update_options.status_address.clone() {
+                tokio::spawn(serve_status(address));
+            }
+            agent_mode(&main_config, &update_options).await?
+        }
+        HikariCommands::Promote {
+            source,
+            target,
+            rules_file,
+            server,
+            prune,
+            yes,
+            message,
+            urgent,
+        } => {
+            promote(PromoteOptions {
+                server,
+                source,
+                target,
+                rules_file: rules_file.as_deref(),
+                prune: *prune,
+                yes: *yes,
+                message: message.as_deref(),
+                urgent: *urgent,
+            })
+            .await?
         }
-        HikariCommands::Agent => agent_mode(&main_config, &update_options).await?,
     }
 
     Ok(())
@@ -1,60 +1,343 @@
-use futures::StreamExt;
-use log::{error, info};
-use tokio::time::{Duration, sleep};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use std::{collections::HashMap, future::pending};
+
+use chrono::Utc;
+use futures::{Sink, SinkExt, StreamExt};
+use log::{error, info, warn};
+use tokio::{
+    sync::mpsc::{UnboundedSender, unbounded_channel},
+    task::JoinHandle,
+    time::{Duration, Instant, interval_at, sleep, sleep_until},
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{Error as WsError, Message},
+};
 
 use crate::{
-    objects::structs::{HikariConfig, NodeConfig, NodeUpdateOptions},
+    mode::status::{StackState, TELEMETRY},
+    objects::structs::{
+        ChangeNotification, HikariConfig, NodeConfig, NodeStatus, NodeUpdateOptions, PendingChange,
+        StackStatus,
+    },
     utils::{
         config::load_hikari_config,
+        drift::{check_drift, drift_period},
         error::ConfigError,
-        file_utils::{load_config_from_url, write_file},
+        file_utils::{load_config_from_url, load_revisions_from_url, write_file},
+        logging::LogContext,
         manage::manage_node,
+        runtime::{blocking, runtime},
         secrets::load_secrets,
     },
 };
 
+/// How many times a stack still starting is looked at before it counts as
+/// unhealthy.
+const HEALTH_ATTEMPTS: u32 = 12;
+const HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Name this node goes by on the server: `node_name` from node.toml, else the
+/// hostname.
+pub fn node_name(node_config: &NodeConfig) -> String {
+    node_config
+        .node_name
+        .clone()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|hostname| hostname.trim().to_string())
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Fetches the node's configuration and applies it. `urgent` names a
+/// deployment whose changes skip its maintenance window. Returns the changes
+/// left waiting for a window.
 pub async fn configuration_init(
     node_config: &NodeConfig,
     node_update_config: &NodeUpdateOptions,
     host: String,
-) -> Result<(), ConfigError> {
-    let mut incoming_config: HikariConfig = HikariConfig::default();
-    match load_config_from_url(
+    urgent: Option<&str>,
+) -> Result<Vec<PendingChange>, ConfigError> {
+    // failing here leaves the node as it is, applying an empty config would stop
+    // every stack
+    let mut incoming_config: HikariConfig = load_config_from_url(
         format!(
-            "https://{}/api/v1/hikari/metadata?client={}&environment={}&solution={}",
-            host, node_config.client, node_config.environment, node_config.solution
+            "https://{}/api/v1/hikari/metadata?client={}&environment={}&solution={}&node={}",
+            host,
+            node_config.client,
+            node_config.environment,
+            node_config.solution,
+            node_name(node_config)
         )
         .as_str(),
     )
     .await
+    .inspect_err(|_| TELEMETRY.download_errors.inc())?;
+    if let Some(deploy_config) =
+        urgent.and_then(|name| incoming_config.deploy_configs.get_mut(name))
     {
-        Ok(reference) => incoming_config = reference,
-        Err(e) => {
-            error!("Error loading initial configuration: {e}");
-        }
+        deploy_config.urgent = true;
     }
 
-    match load_hikari_config(&node_update_config.reference_file_path) {
-        Ok(reference) => {
-            manage_node(
-                &reference,
-                &incoming_config,
-                &node_config.client,
-                &node_config.environment,
-                &node_config.solution,
+    let reference = load_hikari_config(&node_update_config.reference_file_path)?;
+    let owned_node_config = node_config.clone();
+    let changes =
+        blocking(move || manage_node(&reference, &incoming_config, &owned_node_config)).await;
+    TELEMETRY.record_applied(&changes, node_config);
+    let serialized =
+        serde_json::to_string(&changes.applied).map_err(ConfigError::JsonParseError)?;
+    write_file(&serialized, &node_update_config.reference_file_path)
+        .await
+        .map_err(ConfigError::FileError)?;
+    Ok(changes.pending)
+}
+
+/// Returns whether `notification` carries a revision this node has not applied
+/// yet, logging any revisions that were missed in between.
+fn needs_update(notification: &ChangeNotification, reference_file_path: &str) -> bool {
+    let applied = load_hikari_config(reference_file_path)
+        .ok()
+        .and_then(|reference| {
+            reference
+                .deploy_configs
+                .get(&notification.deployment)
+                .and_then(|deploy_config| deploy_config.revision)
+        });
+    match applied {
+        Some(applied) if notification.revision <= applied => {
+            info!(
+                "Revision {} of '{}' is already applied, skipping",
+                notification.revision, notification.deployment
             );
-            let serialized =
-                serde_json::to_string(&incoming_config).map_err(ConfigError::JsonParseError)?;
-            write_file(&serialized, &node_update_config.reference_file_path)
-                .await
-                .map_err(ConfigError::FileError)?;
+            false
+        }
+        Some(applied) => {
+            if notification.revision > applied + 1 {
+                warn!(
+                    "Missed revisions {}..{} of '{}'",
+                    applied + 1,
+                    notification.revision - 1,
+                    notification.deployment
+                );
+            }
+            true
         }
+        None => true,
+    }
+}
+
+/// Compares the revisions applied on this node with the ones held by the
+/// server and re-applies the configuration when they differ, catching up on
+/// notifications missed while disconnected. Returns the changes left waiting
+/// for a maintenance window.
+pub async fn resync(
+    node_config: &NodeConfig,
+    node_update_config: &NodeUpdateOptions,
+    host: String,
+) -> Result<Vec<PendingChange>, ConfigError> {
+    let remote = match load_revisions_from_url(
+        format!(
+            "https://{}/api/v1/hikari/revisions?client={}&environment={}&solution={}&node={}",
+            host,
+            node_config.client,
+            node_config.environment,
+            node_config.solution,
+            node_name(node_config)
+        )
+        .as_str(),
+    )
+    .await
+    {
+        Ok(remote) => remote,
         Err(e) => {
-            error!("Error loading reference configuration: {e}");
+            TELEMETRY.download_errors.inc();
+            warn!("Unable to fetch revisions, re-applying configuration: {e}");
+            return configuration_init(node_config, node_update_config, host, None).await;
         }
+    };
+    let applied: HashMap<String, i64> = load_hikari_config(&node_update_config.reference_file_path)
+        .map(|reference| {
+            reference
+                .deploy_configs
+                .into_iter()
+                .map(|(name, deploy_config)| (name, deploy_config.revision.unwrap_or(-1)))
+                .collect()
+        })
+        .unwrap_or_default();
+    if applied == remote {
+        info!("Applied revisions are up to date");
+        return Ok(Vec::new());
     }
-    Ok(())
+    info!("Applied revisions {applied:?} differ from server revisions {remote:?}, resyncing");
+    configuration_init(node_config, node_update_config, host, None).await
+}
+
+/// Sends the status reports of one connection. Reports wait for stacks that
+/// are still starting, so they run beside the receive loop, each new report
+/// replacing one still waiting.
+struct Reporter {
+    tx: UnboundedSender<Message>,
+    writer: JoinHandle<()>,
+    running: Option<JoinHandle<()>>,
+}
+
+impl Reporter {
+    fn new(mut ws_tx: impl Sink<Message, Error = WsError> + Unpin + Send + 'static) -> Self {
+        let (tx, mut rx) = unbounded_channel::<Message>();
+        let writer = tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if let Err(e) = ws_tx.send(message).await {
+                    warn!("Unable to report status: {e}");
+                    break;
+                }
+            }
+        });
+        Self {
+            tx,
+            writer,
+            running: None,
+        }
+    }
+
+    fn report(
+        &mut self,
+        node_config: &NodeConfig,
+        node_update_config: &NodeUpdateOptions,
+        pending: &[PendingChange],
+    ) {
+        if let Some(running) = self.running.take() {
+            running.abort();
+        }
+        self.running = Some(tokio::spawn(report_status(
+            node_config.clone(),
+            node_update_config.clone(),
+            pending.to_vec(),
+            self.tx.clone(),
+        )));
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.abort();
+        }
+        self.writer.abort();
+    }
+}
+
+/// Tells the server which revision of each deployment this node runs and
+/// whether its stacks came up healthy, waiting on stacks that are still
+/// starting. Changes held for a maintenance window are reported along.
+async fn report_status(
+    node_config: NodeConfig,
+    node_update_config: NodeUpdateOptions,
+    pending: Vec<PendingChange>,
+    tx: UnboundedSender<Message>,
+) {
+    let Ok(reference) = load_hikari_config(&node_update_config.reference_file_path) else {
+        return;
+    };
+    let mut stack_states = Vec::new();
+    for (name, deploy_config) in reference.deploy_configs {
+        if deploy_config.client != node_config.client
+            || deploy_config.environment != node_config.environment
+            || deploy_config.solution != node_config.solution
+        {
+            continue;
+        }
+        let mut stacks = Vec::new();
+        for stack in &deploy_config.deploy_stacks {
+            let mut healthy = None;
+            for attempt in 1..=HEALTH_ATTEMPTS {
+                let stack = stack.clone();
+                healthy = blocking(move || runtime().health(&stack)).await;
+                if healthy.is_some() || attempt == HEALTH_ATTEMPTS {
+                    break;
+                }
+                sleep(HEALTH_INTERVAL).await;
+            }
+            stack_states.push(StackState::new(&name, &stack.stack_name, healthy));
+            stacks.push(StackStatus {
+                stack_name: stack.stack_name.clone(),
+                healthy: healthy.unwrap_or(false),
+                last_operation: TELEMETRY.last_operation(&name, &stack.stack_name),
+                drift: TELEMETRY.drift(&name, &stack.stack_name),
+            });
+        }
+        let status = NodeStatus {
+            deployment: name.clone(),
+            revision: deploy_config.revision.unwrap_or(-1),
+            stacks,
+            pending: pending
+                .iter()
+                .find(|change| change.deployment == name)
+                .cloned(),
+        };
+        let message = match serde_json::to_string(&status) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Unable to serialize node status: {e}");
+                continue;
+            }
+        };
+        // the connection is gone, the next one reports again
+        if tx.send(Message::Text(message.into())).is_err() {
+            break;
+        }
+    }
+    TELEMETRY.record_stacks(stack_states);
+}
+
+/// Checks the stacks of the reference configuration for drift, healing those
+/// whose policy asks for it. Returns whether the server should hear about it.
+async fn detect_drift(node_config: &NodeConfig, node_update_config: &NodeUpdateOptions) -> bool {
+    let reference = match load_hikari_config(&node_update_config.reference_file_path) {
+        Ok(reference) => reference,
+        Err(e) => {
+            error!("Unable to load the reference configuration: {e}");
+            return false;
+        }
+    };
+    let node_config = node_config.clone();
+    let check = LogContext::reconcile()
+        .operation("drift_check")
+        .scope(blocking(move || check_drift(&reference, &node_config)))
+        .await;
+    TELEMETRY.record_drift(&check) || !check.outcomes.is_empty()
+}
+
+/// Runs one pass bringing the node to its configuration, keeping the changes
+/// it leaves queued and recording its outcome for the status API.
+async fn reconcile(
+    pass: impl Future<Output = Result<Vec<PendingChange>, ConfigError>>,
+    queued: &mut Vec<PendingChange>,
+) {
+    LogContext::reconcile()
+        .scope(async {
+            let started = Instant::now();
+            let result = pass.await;
+            TELEMETRY.record_reconcile(
+                started.elapsed(),
+                result.as_ref().err().map(|e| e.to_string()),
+            );
+            match result {
+                Ok(pending_changes) => *queued = pending_changes,
+                Err(e) => error!("Error updating configuration: {e}"),
+            }
+        })
+        .await
+}
+
+/// When the earliest maintenance window `queued` changes wait for opens.
+fn window_opening(queued: &[PendingChange]) -> Option<Instant> {
+    let opens_at = queued.iter().filter_map(|change| change.opens_at).min()?;
+    let wait = (opens_at - Utc::now()).to_std().unwrap_or_default();
+    // a little past the opening so the window reads as open
+    Some(Instant::now() + wait + Duration::from_secs(1))
 }
 
 pub async fn agent_mode(
@@ -64,20 +347,36 @@ pub async fn agent_mode(
     let secrets = load_secrets("agent")?;
     let host = secrets[0].clone();
 
-    if let Err(e) = configuration_init(node_config, node_update_config, host.clone()).await {
-        error!("Error updating configuration: {e}");
-    }
+    // changes waiting for a maintenance window, retried when the first one opens
+    let mut queued = Vec::new();
+    reconcile(
+        configuration_init(node_config, node_update_config, host.clone(), None),
+        &mut queued,
+    )
+    .await;
+    let resync_period = match &node_update_config.resync_interval {
+        Some(val) => match val.parse::<u64>() {
+            Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+            _ => {
+                error!("Invalid resync_interval value");
+                None
+            }
+        },
+        None => None,
+    };
+    let drift_period = drift_period(node_update_config);
     const MAX_BACKOFF: u64 = 64;
     let mut backoff: u64 = 1;
 
     loop {
         match connect_async(
             format!(
-                "ws://{}/ws?client={}&environment={}&solution={}",
+                "ws://{}/ws?client={}&environment={}&solution={}&node={}",
                 host.clone(),
                 node_config.client,
                 node_config.environment,
-                node_config.solution
+                node_config.solution,
+                node_name(node_config)
             )
             .as_str(),
         )
@@ -86,23 +385,101 @@ pub async fn agent_mode(
             Ok((ws_stream, _)) => {
                 info!("Connected to {}", host.clone());
                 backoff = 1;
-                let (mut _ws_tx, mut ws_rx) = ws_stream.split();
+                let (ws_tx, mut ws_rx) = ws_stream.split();
+                let mut reporter = Reporter::new(ws_tx);
+                // anything broadcast while we were away is lost, catch up now
+                reconcile(
+                    resync(node_config, node_update_config, host.clone()),
+                    &mut queued,
+                )
+                .await;
+                reporter.report(node_config, node_update_config, &queued);
+                let mut safety_poll =
+                    resync_period.map(|period| interval_at(Instant::now() + period, period));
+                let mut drift_poll =
+                    drift_period.map(|period| interval_at(Instant::now() + period, period));
 
-                while let Some(msg_res) = ws_rx.next().await {
+                loop {
+                    let msg_res = tokio::select! {
+                        msg_res = ws_rx.next() => match msg_res {
+                            Some(msg_res) => msg_res,
+                            None => break,
+                        },
+                        _ = async {
+                            match safety_poll.as_mut() {
+                                Some(safety_poll) => {
+                                    safety_poll.tick().await;
+                                }
+                                None => pending::<()>().await,
+                            }
+                        } => {
+                            reconcile(resync(node_config, node_update_config, host.clone()), &mut queued).await;
+                            reporter.report(node_config, node_update_config, &queued);
+                            continue;
+                        }
+                        _ = async {
+                            match drift_poll.as_mut() {
+                                Some(drift_poll) => {
+                                    drift_poll.tick().await;
+                                }
+                                None => pending::<()>().await,
+                            }
+                        } => {
+                            if detect_drift(node_config, node_update_config).await {
+                                reporter.report(node_config, node_update_config, &queued);
+                            }
+                            continue;
+                        }
+                        _ = async {
+                            match window_opening(&queued) {
+                                Some(opening) => sleep_until(opening).await,
+                                None => pending::<()>().await,
+                            }
+                        } => {
+                            info!("Maintenance window opened, applying queued changes");
+                            reconcile(configuration_init(
+                                node_config,
+                                node_update_config,
+                                host.clone(),
+                                None,
+                            ), &mut queued).await;
+                            reporter.report(node_config, node_update_config, &queued);
+                            continue;
+                        }
+                    };
                     match msg_res {
                         Ok(message) => match message {
                             Message::Text(txt_bytes) => {
-                                let text = txt_bytes.as_str();
-                                if text == "DEPLOYMENT UPDATED" {
-                                    info!("{text}");
-                                    if let Err(e) = configuration_init(
-                                        node_config,
-                                        node_update_config,
-                                        host.clone(),
-                                    )
-                                    .await
-                                    {
-                                        error!("Error updating configuration: {e}");
+                                match serde_json::from_str::<ChangeNotification>(txt_bytes.as_str())
+                                {
+                                    Ok(notification) => {
+                                        info!(
+                                            "Deployment '{}' updated to revision {}",
+                                            notification.deployment, notification.revision
+                                        );
+                                        if !needs_update(
+                                            &notification,
+                                            &node_update_config.reference_file_path,
+                                        ) {
+                                            continue;
+                                        }
+                                        let urgent = notification
+                                            .urgent
+                                            .then_some(notification.deployment.as_str());
+                                        reconcile(
+                                            configuration_init(
+                                                node_config,
+                                                node_update_config,
+                                                host.clone(),
+                                                urgent,
+                                            ),
+                                            &mut queued,
+                                        )
+                                        .await;
+                                        reporter.report(node_config, node_update_config, &queued);
+                                    }
+                                    Err(e) => {
+                                        error!("Unable to parse change notification: {e}");
                                     }
                                 }
                             }
@@ -126,6 +503,7 @@ pub async fn agent_mode(
             }
         }
 
+        TELEMETRY.websocket_reconnects.inc();
         info!("Reconnecting in {backoff} seconds");
         sleep(Duration::from_secs(backoff)).await;
         backoff = (backoff * 2).min(MAX_BACKOFF);
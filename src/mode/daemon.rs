@@ -1,23 +1,115 @@
-use std::{process::exit, time::Duration};
+use std::{
+    process::exit,
+    time::{Duration, Instant},
+};
 
-use log::error;
+use log::{error, info};
 use tokio::time::sleep;
 
 use crate::{
-    objects::structs::{NodeConfig, NodeUpdateOptions},
+    mode::status::{StackState, TELEMETRY},
+    objects::structs::{HikariConfig, NodeConfig, NodeUpdateOptions},
     utils::{
         config::load_hikari_config,
         crypto::decrypt_json,
+        drift::{DriftSchedule, check_drift},
         error::ConfigError,
-        file_utils::{copy_file, download_file},
+        file_utils::{CacheValidators, DownloadOutcome, download_file, write_file},
+        logging::LogContext,
         manage::manage_node,
+        runtime::{blocking, runtime},
     },
 };
 
+/// Applies `config` on top of the reference configuration and stores what the
+/// node now runs as the new reference.
+async fn apply_config(
+    node_config: &NodeConfig,
+    node_update_config: &NodeUpdateOptions,
+    config: &HikariConfig,
+) -> Result<(), ConfigError> {
+    let started = Instant::now();
+    let result = LogContext::reconcile()
+        .scope(reconcile(node_config, node_update_config, config))
+        .await;
+    TELEMETRY.record_reconcile(
+        started.elapsed(),
+        result.as_ref().err().map(|e| e.to_string()),
+    );
+    result
+}
+
+async fn reconcile(
+    node_config: &NodeConfig,
+    node_update_config: &NodeUpdateOptions,
+    config: &HikariConfig,
+) -> Result<(), ConfigError> {
+    let reference = load_hikari_config(&node_update_config.reference_file_path)?;
+    let (config, owned_node_config) = (config.clone(), node_config.clone());
+    let changes = blocking(move || {
+        let changes = manage_node(&reference, &config, &owned_node_config);
+        record_stacks(&changes.applied, &owned_node_config);
+        changes
+    })
+    .await;
+    for change in &changes.pending {
+        info!("Changes to '{}' are pending", change.deployment);
+    }
+    TELEMETRY.record_applied(&changes, node_config);
+    let applied = changes.applied;
+    let serialized = serde_json::to_string(&applied).map_err(ConfigError::JsonParseError)?;
+    write_file(&serialized, &node_update_config.reference_file_path)
+        .await
+        .map_err(ConfigError::FileError)
+}
+
+/// Records the health of the stacks `config` runs on this node.
+fn record_stacks(config: &HikariConfig, node_config: &NodeConfig) {
+    TELEMETRY.record_stacks(
+        config
+            .deploy_configs
+            .iter()
+            .filter(|(_, deploy_config)| {
+                deploy_config.client == node_config.client
+                    && deploy_config.environment == node_config.environment
+                    && deploy_config.solution == node_config.solution
+            })
+            .flat_map(|(name, deploy_config)| {
+                deploy_config.deploy_stacks.iter().map(move |stack| {
+                    StackState::new(name, &stack.stack_name, runtime().health(stack))
+                })
+            })
+            .collect(),
+    );
+}
+
+/// Checks the stacks of the reference configuration for drift, healing those
+/// whose policy asks for it.
+async fn detect_drift(node_config: &NodeConfig, node_update_config: &NodeUpdateOptions) {
+    match load_hikari_config(&node_update_config.reference_file_path) {
+        Ok(reference) => {
+            let node_config = node_config.clone();
+            LogContext::reconcile()
+                .operation("drift_check")
+                .scope(blocking(move || {
+                    let check = check_drift(&reference, &node_config);
+                    TELEMETRY.record_drift(&check);
+                    if !check.outcomes.is_empty() {
+                        record_stacks(&reference, &node_config);
+                    }
+                }))
+                .await
+        }
+        Err(e) => error!("Unable to load the reference configuration: {e}"),
+    }
+}
+
 pub async fn daemon_mode(
     node_config: &NodeConfig,
     node_update_config: &NodeUpdateOptions,
     private_key_path: &str,
+    validators: &mut CacheValidators,
+    drift: &mut DriftSchedule,
 ) -> Result<(), ConfigError> {
     let remote_url = if let Some(val) = &node_update_config.remote_url {
         val
@@ -43,29 +135,16 @@ pub async fn daemon_mode(
         return Err(ConfigError::MissingField("poll_interval".into()));
     };
 
-    match download_file(remote_url, encrypted_file_path).await {
-        Ok(true) => {
+    match download_file(remote_url, encrypted_file_path, validators).await {
+        Ok(DownloadOutcome::Downloaded(fetched)) => {
             match decrypt_json(encrypted_file_path, decrypted_file_path, private_key_path) {
                 Ok(()) => match load_hikari_config(decrypted_file_path) {
                     Ok(config) => {
                         if config.version.trim() == node_config.version {
-                            match load_hikari_config(&node_update_config.reference_file_path) {
-                                Ok(reference) => {
-                                    manage_node(
-                                        &reference,
-                                        &config,
-                                        &node_config.client,
-                                        &node_config.environment,
-                                        &node_config.solution,
-                                    );
-                                    copy_file(
-                                        decrypted_file_path,
-                                        &node_update_config.reference_file_path,
-                                    )
-                                    .await
-                                }
+                            match apply_config(node_config, node_update_config, &config).await {
+                                Ok(()) => *validators = fetched,
                                 Err(e) => {
-                                    error!("Error loading reference configuration: {e}");
+                                    error!("Error applying configuration: {e}");
                                 }
                             }
                         } else {
@@ -87,10 +166,31 @@ pub async fn daemon_mode(
                 }
             }
         }
-        Ok(false) => {
+        Ok(DownloadOutcome::NotModified) => {
+            // changes queued for a maintenance window still differ from the reference
+            match (
+                load_hikari_config(decrypted_file_path),
+                load_hikari_config(&node_update_config.reference_file_path),
+            ) {
+                (Ok(config), Ok(reference)) if config != reference => {
+                    if let Err(e) = apply_config(node_config, node_update_config, &config).await {
+                        error!("Error applying configuration: {e}");
+                    }
+                }
+                _ => info!("Remote configuration is unchanged, skipping"),
+            }
+        }
+        Ok(DownloadOutcome::Failed) => {
+            TELEMETRY.download_errors.inc();
             error!("Unable to Download the file");
         }
-        _ => {}
+        Err(e) => {
+            TELEMETRY.download_errors.inc();
+            error!("Unable to Download the file: {e}");
+        }
+    }
+    if drift.due() {
+        detect_drift(node_config, node_update_config).await;
     }
     if let Ok(poll_secs) = poll_interval.parse::<u64>() {
         sleep(Duration::from_secs(poll_secs)).await;
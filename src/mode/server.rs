@@ -1,84 +1,152 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use axum::{
-    Extension, Router,
-    routing::{any, delete, get, post, put},
-};
-use sqlx::{PgPool, postgres::PgPoolOptions};
-use tokio::{
-    net::TcpListener,
-    sync::{RwLock, broadcast::Sender},
+    Extension, Router, middleware,
+    routing::{any, delete, get, patch, post, put},
 };
+use tokio::{net::TcpListener, sync::RwLock};
 
 use crate::{
     server::{
         api::{
-            compose_stack::{delete_stack, get_all_stacks, get_stack, post_stack, update_stack},
+            apply::apply_hikari,
+            audit::get_audit_log,
+            compose_stack::{
+                clone_stack, delete_stack, get_all_stacks, get_stack, move_stack, patch_stack,
+                post_stack, update_stack,
+            },
             container::{
-                delete_container, get_all_containers, get_container, post_container,
-                update_container,
+                delete_container, get_all_containers, get_container, patch_container,
+                post_container, update_container,
             },
             deployments::{
-                delete_deployment, get_all_deployments, get_deployment, post_deployment,
-                update_deployment,
+                delete_deployment, get_all_deployments, get_deployment, patch_deployment,
+                post_deployment, update_deployment,
+            },
+            health::{healthz, metrics, readyz},
+            hikari::{
+                export_hikari, get_hikari_by_metadata, get_hikari_by_name, get_hikari_revisions,
+                put_hikari,
+            },
+            maintenance_windows::{
+                delete_maintenance_window, get_maintenance_window, put_maintenance_window,
+            },
+            nodes::get_nodes,
+            promote::promote_deployment,
+            revisions::{get_revision, get_revision_diff, get_revisions, rollback_deployment},
+            rollouts::{
+                abort_deployment_rollout, delete_rollout_policy, get_rollout_policy, get_rollouts,
+                put_rollout_policy, resume_deployment_rollout,
             },
-            hikari::{get_hikari_by_metadata, get_hikari_by_name},
         },
-        ws::websocket::websocket_handler,
+        metrics::{ServerMetrics, track_requests},
+        migrate::run_migrations,
+        request_id::request_id,
+        rollout::Rollout,
+        storage::Storage,
+        ws::websocket::{NodeConnection, websocket_handler},
     },
-    utils::{error::ConfigError, secrets::load_secrets},
+    utils::error::ConfigError,
 };
 
 #[derive(Clone, Debug)]
 pub struct AppState {
-    pub pool: PgPool,
-    pub channel_map: Arc<RwLock<HashMap<String, Sender<String>>>>,
+    pub storage: Storage,
+    pub connections: Arc<RwLock<HashMap<u64, NodeConnection>>>,
+    pub rollouts: Arc<RwLock<HashMap<String, Rollout>>>,
+    pub metrics: ServerMetrics,
+}
+
+pub async fn migrate_mode() -> Result<(), ConfigError> {
+    let storage = Storage::connect().await?;
+    run_migrations(&storage).await
 }
 
 pub async fn server_mode() -> Result<(), ConfigError> {
-    let secrets = load_secrets("server")?;
-    let pool = PgPoolOptions::new()
-        .test_before_acquire(true)
-        .max_connections(50)
-        .min_connections(20)
-        .idle_timeout(Duration::from_secs(1800))
-        .max_lifetime(Duration::from_secs(1800))
-        .connect(
-            format!(
-                "postgres://{}:{}@{}:{}/{}",
-                secrets[0], secrets[1], secrets[2], secrets[3], secrets[4]
-            )
-            .as_str(),
-        )
-        .await
-        .unwrap();
+    let storage = Storage::connect().await?;
+    run_migrations(&storage).await?;
     let shared_state = Arc::new(AppState {
-        pool,
-        channel_map: Arc::new(RwLock::new(HashMap::new())),
+        storage,
+        connections: Arc::new(RwLock::new(HashMap::new())),
+        rollouts: Arc::new(RwLock::new(HashMap::new())),
+        metrics: ServerMetrics::new(),
     });
     let app = Router::new()
         .route("/api/v1/deployments", get(get_all_deployments))
         .route("/api/v1/deployment", get(get_deployment))
         .route("/api/v1/deployment", post(post_deployment))
         .route("/api/v1/deployment", put(update_deployment))
+        .route("/api/v1/deployment", patch(patch_deployment))
         .route("/api/v1/deployment", delete(delete_deployment))
+        .route("/api/v1/deployment/promote", post(promote_deployment))
+        .route("/api/v1/deployment/revisions", get(get_revisions))
+        .route("/api/v1/deployment/rollout-policy", get(get_rollout_policy))
+        .route("/api/v1/deployment/rollout-policy", put(put_rollout_policy))
+        .route(
+            "/api/v1/deployment/rollout-policy",
+            delete(delete_rollout_policy),
+        )
+        .route(
+            "/api/v1/deployment/rollout/resume",
+            post(resume_deployment_rollout),
+        )
+        .route(
+            "/api/v1/deployment/rollout/abort",
+            post(abort_deployment_rollout),
+        )
+        .route("/api/v1/rollouts", get(get_rollouts))
+        .route(
+            "/api/v1/deployment/maintenance-window",
+            get(get_maintenance_window),
+        )
+        .route(
+            "/api/v1/deployment/maintenance-window",
+            put(put_maintenance_window),
+        )
+        .route(
+            "/api/v1/deployment/maintenance-window",
+            delete(delete_maintenance_window),
+        )
+        .route("/api/v1/nodes", get(get_nodes))
+        .route("/api/v1/deployment/revision", get(get_revision))
+        .route("/api/v1/deployment/revision/diff", get(get_revision_diff))
+        .route("/api/v1/deployment/rollback", post(rollback_deployment))
         .route("/api/v1/stacks", get(get_all_stacks))
         .route("/api/v1/stack", get(get_stack))
         .route("/api/v1/stack", post(post_stack))
         .route("/api/v1/stack", put(update_stack))
+        .route("/api/v1/stack", patch(patch_stack))
         .route("/api/v1/stack", delete(delete_stack))
+        .route("/api/v1/stack/clone", post(clone_stack))
+        .route("/api/v1/stack/move", post(move_stack))
         .route("/api/v1/containers", get(get_all_containers))
         .route("/api/v1/container", get(get_container))
         .route("/api/v1/container", post(post_container))
         .route("/api/v1/container", put(update_container))
+        .route("/api/v1/container", patch(patch_container))
         .route("/api/v1/container", delete(delete_container))
+        .route("/api/v1/hikari", put(put_hikari))
+        .route("/api/v1/apply", post(apply_hikari))
+        .route("/api/v1/audit", get(get_audit_log))
+        .route("/api/v1/hikari/export", get(export_hikari))
         .route("/api/v1/hikari/metadata", get(get_hikari_by_metadata))
         .route("/api/v1/hikari/name", get(get_hikari_by_name))
+        .route("/api/v1/hikari/revisions", get(get_hikari_revisions))
+        .route_layer(middleware::from_fn(track_requests))
         .route("/ws", any(websocket_handler))
-        .layer(Extension(shared_state));
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .layer(Extension(shared_state))
+        .layer(middleware::from_fn(request_id));
 
     // run our app with hyper, listening globally on port 3000
     let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
     Ok(())
 }
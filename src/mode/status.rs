@@ -0,0 +1,376 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    Json, Router,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::IntoResponse,
+    routing::get,
+};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use serde::Serialize;
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+
+use crate::{
+    objects::structs::{NodeConfig, OperationOutcome, PendingChange, StackDrift},
+    utils::{
+        drift::DriftCheck,
+        manage::{NodeChanges, StackOutcome},
+    },
+};
+
+/// Telemetry of the daemon or agent running in this process, served by
+/// [`serve_status`].
+pub static TELEMETRY: LazyLock<NodeTelemetry> = LazyLock::new(NodeTelemetry::new);
+
+/// Outcome of the latest pass bringing the node to its configuration.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReconcileReport {
+    pub finished_at: DateTime<Utc>,
+    pub duration_ms: u128,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StackState {
+    pub deployment: String,
+    pub stack_name: String,
+    /// `healthy`, `unhealthy` or `starting`
+    pub state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_operation: Option<OperationOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drift: Option<StackDrift>,
+}
+
+impl StackState {
+    pub fn new(deployment: &str, stack_name: &str, healthy: Option<bool>) -> Self {
+        Self {
+            deployment: deployment.into(),
+            stack_name: stack_name.into(),
+            state: match healthy {
+                Some(true) => "healthy",
+                Some(false) => "unhealthy",
+                None => "starting",
+            }
+            .into(),
+            last_operation: None,
+            drift: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct NodeState {
+    pub revisions: HashMap<String, i64>,
+    pub stacks: Vec<StackState>,
+    pub pending: Vec<PendingChange>,
+    pub last_reconcile: Option<ReconcileReport>,
+}
+
+pub struct NodeTelemetry {
+    registry: Registry,
+    reconcile_duration: Histogram,
+    reconciles: IntCounterVec,
+    stacks: IntGaugeVec,
+    pending_changes: IntGauge,
+    stack_operations: IntCounterVec,
+    drifted_stacks: IntGauge,
+    drift_findings: IntCounterVec,
+    pub download_errors: IntCounter,
+    pub websocket_reconnects: IntCounter,
+    state: RwLock<NodeState>,
+    /// Latest operation per deployment and stack.
+    operations: RwLock<HashMap<(String, String), OperationOutcome>>,
+    /// Drift found by the latest check per deployment and stack.
+    drift: RwLock<HashMap<(String, String), StackDrift>>,
+}
+
+impl NodeTelemetry {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("hikari_node".into()), None)
+            .expect("the registry prefix is valid");
+        let reconcile_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "reconcile_duration_seconds",
+                "Time spent bringing the node to its configuration",
+            )
+            .buckets(vec![0.5, 1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0]),
+        )
+        .expect("the metric options are valid");
+        let reconciles = IntCounterVec::new(
+            Opts::new("reconciles_total", "Reconcile passes by result"),
+            &["result"],
+        )
+        .expect("the metric options are valid");
+        let stacks = IntGaugeVec::new(
+            Opts::new("stacks", "Stacks run by the node by state"),
+            &["state"],
+        )
+        .expect("the metric options are valid");
+        let pending_changes = IntGauge::new(
+            "pending_changes",
+            "Deployment changes waiting for a maintenance window",
+        )
+        .expect("the metric options are valid");
+        let stack_operations = IntCounterVec::new(
+            Opts::new(
+                "stack_operations_total",
+                "Operations run on stacks by operation and result",
+            ),
+            &["operation", "result"],
+        )
+        .expect("the metric options are valid");
+        let drifted_stacks = IntGauge::new(
+            "drifted_stacks",
+            "Stacks that drifted from their configuration at the latest check",
+        )
+        .expect("the metric options are valid");
+        let drift_findings = IntCounterVec::new(
+            Opts::new("drift_findings_total", "Drift found on stacks by kind"),
+            &["kind"],
+        )
+        .expect("the metric options are valid");
+        let download_errors = IntCounter::new(
+            "download_errors_total",
+            "Failed attempts to fetch the configuration",
+        )
+        .expect("the metric options are valid");
+        let websocket_reconnects = IntCounter::new(
+            "websocket_reconnects_total",
+            "Times the agent lost or failed to open its websocket",
+        )
+        .expect("the metric options are valid");
+        for collector in [
+            Box::new(reconcile_duration.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(reconciles.clone()),
+            Box::new(stacks.clone()),
+            Box::new(pending_changes.clone()),
+            Box::new(stack_operations.clone()),
+            Box::new(drifted_stacks.clone()),
+            Box::new(drift_findings.clone()),
+            Box::new(download_errors.clone()),
+            Box::new(websocket_reconnects.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+        Self {
+            registry,
+            reconcile_duration,
+            reconciles,
+            stacks,
+            pending_changes,
+            stack_operations,
+            drifted_stacks,
+            drift_findings,
+            download_errors,
+            websocket_reconnects,
+            state: RwLock::new(NodeState::default()),
+            operations: RwLock::new(HashMap::new()),
+            drift: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_reconcile(&self, duration: Duration, error: Option<String>) {
+        self.reconcile_duration.observe(duration.as_secs_f64());
+        self.reconciles
+            .with_label_values(&[if error.is_none() {
+                "success"
+            } else {
+                "failure"
+            }])
+            .inc();
+        if let Ok(mut state) = self.state.write() {
+            state.last_reconcile = Some(ReconcileReport {
+                finished_at: Utc::now(),
+                duration_ms: duration.as_millis(),
+                success: error.is_none(),
+                error,
+            });
+        }
+    }
+
+    /// Remembers the revisions of the deployments the node now runs, the
+    /// changes still queued and the outcome of the stack operations.
+    pub fn record_applied(&self, changes: &NodeChanges, node_config: &NodeConfig) {
+        self.pending_changes
+            .set(i64::try_from(changes.pending.len()).unwrap_or(i64::MAX));
+        self.record_operations(&changes.outcomes);
+        if let Ok(mut state) = self.state.write() {
+            state.revisions = changes
+                .applied
+                .deploy_configs
+                .iter()
+                .filter(|(_, deploy_config)| {
+                    deploy_config.client == node_config.client
+                        && deploy_config.environment == node_config.environment
+                        && deploy_config.solution == node_config.solution
+                })
+                .map(|(name, deploy_config)| (name.clone(), deploy_config.revision.unwrap_or(-1)))
+                .collect();
+            state.pending = changes.pending.clone();
+        }
+    }
+
+    /// Remembers the drift found by `check` in place of the previous one and
+    /// the operations healing it. Returns whether the drift differs from the
+    /// one found before.
+    pub fn record_drift(&self, check: &DriftCheck) -> bool {
+        self.record_operations(&check.outcomes);
+        self.drifted_stacks
+            .set(i64::try_from(check.reports.len()).unwrap_or(i64::MAX));
+        for report in &check.reports {
+            for finding in &report.drift.findings {
+                self.drift_findings
+                    .with_label_values(&[finding.kind()])
+                    .inc();
+            }
+        }
+        let drift: HashMap<_, _> = check
+            .reports
+            .iter()
+            .map(|report| {
+                (
+                    (report.deployment.clone(), report.stack_name.clone()),
+                    report.drift.clone(),
+                )
+            })
+            .collect();
+        let changed = self.drift.write().is_ok_and(|mut previous| {
+            let changed = previous.len() != drift.len()
+                || drift.iter().any(|(key, stack_drift)| {
+                    previous
+                        .get(key)
+                        .is_none_or(|before| before.findings != stack_drift.findings)
+                });
+            *previous = drift;
+            changed
+        });
+        if let Ok(mut state) = self.state.write() {
+            for stack in &mut state.stacks {
+                stack.drift = self.drift(&stack.deployment, &stack.stack_name);
+                stack.last_operation = self.last_operation(&stack.deployment, &stack.stack_name);
+            }
+        }
+        changed
+    }
+
+    /// Counts the operations run on stacks and keeps the latest per stack.
+    fn record_operations(&self, outcomes: &[StackOutcome]) {
+        for StackOutcome { outcome, .. } in outcomes {
+            let result = outcome.outcome.error.map_or("success", |kind| kind.code());
+            self.stack_operations
+                .with_label_values(&[outcome.operation.as_str(), result])
+                .inc();
+        }
+        if let Ok(mut operations) = self.operations.write() {
+            for StackOutcome {
+                deployment,
+                stack_name,
+                outcome,
+            } in outcomes
+            {
+                operations.insert((deployment.clone(), stack_name.clone()), outcome.clone());
+            }
+        }
+    }
+
+    /// Drift found on `stack_name` of `deployment` by the latest check.
+    pub fn drift(&self, deployment: &str, stack_name: &str) -> Option<StackDrift> {
+        self.drift.read().ok().and_then(|drift| {
+            drift
+                .get(&(deployment.to_string(), stack_name.to_string()))
+                .cloned()
+        })
+    }
+
+    /// Latest operation run on `stack_name` of `deployment`.
+    pub fn last_operation(&self, deployment: &str, stack_name: &str) -> Option<OperationOutcome> {
+        self.operations.read().ok().and_then(|operations| {
+            operations
+                .get(&(deployment.to_string(), stack_name.to_string()))
+                .cloned()
+        })
+    }
+
+    pub fn record_stacks(&self, mut stacks: Vec<StackState>) {
+        for stack in &mut stacks {
+            stack.last_operation = self.last_operation(&stack.deployment, &stack.stack_name);
+            stack.drift = self.drift(&stack.deployment, &stack.stack_name);
+        }
+        self.stacks.reset();
+        for state in ["healthy", "unhealthy", "starting"] {
+            let count = stacks.iter().filter(|stack| stack.state == state).count();
+            self.stacks
+                .with_label_values(&[state])
+                .set(i64::try_from(count).unwrap_or(i64::MAX));
+        }
+        if let Ok(mut state) = self.state.write() {
+            state.stacks = stacks;
+        }
+    }
+
+    fn snapshot(&self) -> NodeState {
+        self.state
+            .read()
+            .map(|state| state.clone())
+            .unwrap_or_default()
+    }
+
+    fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+async fn status() -> Json<NodeState> {
+    Json(TELEMETRY.snapshot())
+}
+
+async fn metrics() -> impl IntoResponse {
+    match TELEMETRY.encode() {
+        Ok(body) => ([(CONTENT_TYPE, TextEncoder::new().format_type())], body).into_response(),
+        Err(e) => {
+            error!("Unable to encode metrics: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Serves `/healthz`, `/status` and `/metrics` on `address` until the process
+/// exits. Failing to bind only costs the status API, the node keeps running.
+pub async fn serve_status(address: String) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Unable to serve the status API on {address}: {e}");
+            return;
+        }
+    };
+    info!("Serving the status API on {address}");
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/status", get(status))
+        .route("/metrics", get(metrics));
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("Status API stopped: {e}");
+    }
+}
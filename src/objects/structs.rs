@@ -1,8 +1,12 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use crate::{server::models::container::ContainerDTO, utils::error::ConfigError};
+use crate::{
+    server::models::{container::ContainerDTO, deploy_config::DeployConfigDTO},
+    utils::error::ConfigError,
+};
 
 pub trait Validate {
     fn validate(&self) -> Result<(), ConfigError>;
@@ -22,6 +26,63 @@ pub struct NodeConfig {
     pub solution: String,
     pub client: String,
     pub environment: String,
+    /// Name the node reports to the server, the hostname when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    /// Window for deployments that don't bring their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance_window: Option<MaintenanceWindow>,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+}
+
+/// How the node runs its stacks.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+    #[serde(default)]
+    pub kind: RuntimeKind,
+    /// Socket of the Docker Engine API, `DOCKER_HOST` or
+    /// `/var/run/docker.sock` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeKind {
+    /// The first compose CLI found on the node, in the order below.
+    #[default]
+    Auto,
+    /// The `docker compose` plugin, compose v2.
+    #[serde(alias = "compose")]
+    DockerCompose,
+    /// The legacy standalone `docker-compose` v1. Standalone v2 builds are run
+    /// like the plugin.
+    DockerComposeV1,
+    /// `podman compose`, which hands over to the compose provider of Podman.
+    Podman,
+    /// The standalone `podman-compose`.
+    PodmanCompose,
+    /// `nerdctl compose` on containerd.
+    Nerdctl,
+    /// The Docker Engine API, spoken over its unix socket.
+    Engine,
+}
+
+/// Recurring time span in which the stacks of a deployment may be restarted.
+/// Changes to running deployments wait for it unless they are urgent.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// Cron expression of when the window opens, e.g. `0 2 * * 1-5`.
+    pub schedule: String,
+    pub duration_minutes: i64,
+    /// IANA name of the zone `schedule` is read in.
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+fn default_timezone() -> String {
+    "UTC".into()
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -31,6 +92,12 @@ pub struct NodeUpdateOptions {
     pub encrypted_file_path: Option<String>,
     pub decrypted_file_path: Option<String>,
     pub reference_file_path: String,
+    pub resync_interval: Option<String>,
+    /// Seconds between drift checks, 300 when unset and off at `0`.
+    pub drift_interval: Option<String>,
+    /// Address the node's status API listens on, e.g. `127.0.0.1:9100`. Off
+    /// when unset.
+    pub status_address: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -58,6 +125,13 @@ pub struct DeployConfig {
     pub client: String,
     pub environment: String,
     pub solution: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance_window: Option<MaintenanceWindow>,
+    /// Applies the deployment right away, whatever its maintenance window says.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub urgent: bool,
     pub deploy_stacks: Vec<StackConfig>,
 }
 impl Validate for DeployConfig {
@@ -65,6 +139,9 @@ impl Validate for DeployConfig {
         validate_field!(self.client, "client");
         validate_field!(self.environment, "environment");
         validate_field!(self.solution, "solution");
+        if let Some(window) = &self.maintenance_window {
+            window.validate()?;
+        }
 
         if self.deploy_stacks.is_empty() {
             return Err(ConfigError::MissingField("deploy_stacks".to_string()));
@@ -86,6 +163,54 @@ pub struct StackConfig {
     pub filename: String,
     pub home_directory: String,
     pub compose_spec: ComposeSpec,
+    #[serde(default, skip_serializing_if = "DriftPolicy::is_default")]
+    pub drift_policy: DriftPolicy,
+}
+
+impl StackConfig {
+    /// Whether both run the same containers, whatever their drift policy.
+    pub fn runs_like(&self, other: &Self) -> bool {
+        Self {
+            drift_policy: other.drift_policy,
+            ..self.clone()
+        } == *other
+    }
+}
+
+/// What a node does when a stack no longer runs as configured.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftPolicy {
+    /// The stack is not checked.
+    Ignore,
+    /// Drift is logged and reported.
+    #[default]
+    Report,
+    /// Drift is reported, then the configuration is applied again.
+    Heal,
+}
+
+impl DriftPolicy {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ignore => "ignore",
+            Self::Report => "report",
+            Self::Heal => "heal",
+        }
+    }
+
+    /// Reads a policy as stored by the server, falling back to `report`.
+    pub fn parse(policy: &str) -> Self {
+        match policy {
+            "ignore" => Self::Ignore,
+            "heal" => Self::Heal,
+            _ => Self::Report,
+        }
+    }
 }
 
 impl Validate for StackConfig {
@@ -179,3 +304,318 @@ impl From<ContainerDTO> for Container {
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityKind {
+    Deployment,
+    Stack,
+    Container,
+    RolloutPolicy,
+    MaintenanceWindow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl fmt::Display for EntityKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntityKind::Deployment => write!(f, "deployment"),
+            EntityKind::Stack => write!(f, "stack"),
+            EntityKind::Container => write!(f, "container"),
+            EntityKind::RolloutPolicy => write!(f, "rollout_policy"),
+            EntityKind::MaintenanceWindow => write!(f, "maintenance_window"),
+        }
+    }
+}
+
+impl fmt::Display for ChangeAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeAction::Created => write!(f, "created"),
+            ChangeAction::Updated => write!(f, "updated"),
+            ChangeAction::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+/// Message pushed to agents over the websocket whenever a deployment changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeNotification {
+    pub deployment: String,
+    pub client: String,
+    pub environment: String,
+    pub solution: String,
+    pub revision: i64,
+    pub entity: EntityKind,
+    pub action: ChangeAction,
+    pub entity_ids: Vec<i64>,
+    /// Set for emergency changes, which don't wait for maintenance windows.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub urgent: bool,
+}
+
+impl ChangeNotification {
+    pub fn new(
+        deployment: &DeployConfigDTO,
+        revision: i64,
+        entity: EntityKind,
+        action: ChangeAction,
+        entity_ids: Vec<i64>,
+    ) -> Self {
+        Self {
+            deployment: deployment.name.clone(),
+            client: deployment.client.clone(),
+            environment: deployment.environment.clone(),
+            solution: deployment.solution.clone(),
+            revision,
+            entity,
+            action,
+            entity_ids,
+            urgent: false,
+        }
+    }
+}
+
+/// Report an agent sends back over its websocket once it has applied a
+/// revision of a deployment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub deployment: String,
+    pub revision: i64,
+    pub stacks: Vec<StackStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending: Option<PendingChange>,
+}
+
+/// Change to a deployment a node holds back until its maintenance window opens.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingChange {
+    pub deployment: String,
+    /// Revision waiting to be applied, none when the deployment is being
+    /// removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opens_at: Option<DateTime<Utc>>,
+}
+
+impl NodeStatus {
+    pub fn healthy(&self) -> bool {
+        self.stacks.iter().all(|stack| stack.healthy)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StackStatus {
+    pub stack_name: String,
+    pub healthy: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_operation: Option<OperationOutcome>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drift: Option<StackDrift>,
+}
+
+/// How a stack differs from its configuration, as found by the latest drift
+/// check.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StackDrift {
+    pub detected_at: DateTime<Utc>,
+    pub findings: Vec<DriftFinding>,
+    /// Whether the stack runs as configured after applying the configuration
+    /// again, for stacks whose policy is `heal`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub healed: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DriftFinding {
+    ComposeFileMissing,
+    /// The compose file no longer matches the one generated from the
+    /// configuration.
+    ComposeFileChanged,
+    /// No container of the stack runs the service.
+    ServiceMissing {
+        service: String,
+    },
+    /// The container of the service exists but doesn't run.
+    ServiceStopped {
+        service: String,
+        container: String,
+        state: String,
+    },
+    /// A container of the stack runs a service the configuration lacks.
+    UnexpectedService {
+        service: String,
+        container: String,
+    },
+}
+
+impl DriftFinding {
+    /// Short name of the finding, as used in metric labels.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ComposeFileMissing => "compose_file_missing",
+            Self::ComposeFileChanged => "compose_file_changed",
+            Self::ServiceMissing { .. } => "service_missing",
+            Self::ServiceStopped { .. } => "service_stopped",
+            Self::UnexpectedService { .. } => "unexpected_service",
+        }
+    }
+}
+
+impl fmt::Display for DriftFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ComposeFileMissing => f.write_str("compose file missing"),
+            Self::ComposeFileChanged => f.write_str("compose file changed"),
+            Self::ServiceMissing { service } => write!(f, "service {service} has no container"),
+            Self::ServiceStopped {
+                service,
+                container,
+                state,
+            } => write!(f, "container {container} of service {service} is {state}"),
+            Self::UnexpectedService { service, container } => {
+                write!(f, "container {container} runs unknown service {service}")
+            }
+        }
+    }
+}
+
+/// Latest `start`, `stop`, `pull` or `heal` the node ran on a stack.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OperationOutcome {
+    pub operation: String,
+    pub finished_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub outcome: CommandOutcome,
+}
+
+/// How a command run on the node went, with the tail of its output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandOutcome {
+    pub command: String,
+    /// None when the command could not be run or was killed by a signal, and
+    /// for operations done through the Docker Engine API.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<CommandErrorKind>,
+}
+
+impl CommandOutcome {
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Why a command failed, told apart from its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandErrorKind {
+    ComposeFileMissing,
+    InvalidCompose,
+    SpawnFailed,
+    DaemonUnavailable,
+    RegistryAuth,
+    ImageNotFound,
+    PortConflict,
+    Network,
+    Failed,
+}
+
+impl CommandErrorKind {
+    /// Name the kind is serialized with.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ComposeFileMissing => "compose_file_missing",
+            Self::InvalidCompose => "invalid_compose",
+            Self::SpawnFailed => "spawn_failed",
+            Self::DaemonUnavailable => "daemon_unavailable",
+            Self::RegistryAuth => "registry_auth",
+            Self::ImageNotFound => "image_not_found",
+            Self::PortConflict => "port_conflict",
+            Self::Network => "network",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for CommandErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::ComposeFileMissing => "compose file missing",
+            Self::InvalidCompose => "invalid compose file",
+            Self::SpawnFailed => "command could not be run",
+            Self::DaemonUnavailable => "docker daemon unavailable",
+            Self::RegistryAuth => "registry authentication failed",
+            Self::ImageNotFound => "image not found",
+            Self::PortConflict => "port already in use",
+            Self::Network => "network error",
+            Self::Failed => "command failed",
+        })
+    }
+}
+
+/// What changes when stacks are promoted into an environment, keyed by the
+/// target deployment's `environment`. Read from a TOML rules file by the CLI.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromotionRules {
+    #[serde(default)]
+    pub environments: HashMap<String, EnvironmentRules>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnvironmentRules {
+    /// Tag given to every image, unless the service has its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_tag: Option<String>,
+    /// `KEY=value` entries set on every service.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub environment: Vec<String>,
+    /// Home directory per stack name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub home_directories: HashMap<String, String>,
+    /// Overrides per service name, applied after the ones above.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub services: HashMap<String, ServiceRules>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceRules {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub environment: Vec<String>,
+    /// Replaces the ports of the service altogether.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<Vec<String>>,
+}
+
+/// Body of a promotion request, naming the deployments involved.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromotionRequest {
+    pub source: String,
+    pub target: String,
+    #[serde(default)]
+    pub rules: PromotionRules,
+    /// Drop the target's stacks that the source doesn't have.
+    #[serde(default)]
+    pub prune: bool,
+    /// Revision of the source the caller previewed, the promotion is refused
+    /// when the source moved on since.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_revision: Option<i64>,
+    /// Same for the target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_revision: Option<i64>,
+}
@@ -0,0 +1,70 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{Extension, Json, debug_handler};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mode::server::AppState,
+    objects::structs::{HikariConfig, Validate},
+    server::{
+        common::{
+            ApiJson, ApiQuery, ChangeMeta, apply_deployment_changes, assemble_hikari_config,
+            map_repo_error,
+        },
+        dal::{
+            container_dal::ContainerDAL, deploy_config_dal::DeployConfigDAL,
+            maintenance_window_dal::MaintenanceWindowDAL, stack_config_dal::StackConfigDAL,
+        },
+        diff::{DeploymentDiff, diff_hikari_configs},
+        error::ApiError,
+        models::deploy_config::DeployConfigDTO,
+        traits::model::DataRepository,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct QueryParamsApply {
+    #[serde(default)]
+    pub prune: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApplyResult {
+    pub dry_run: bool,
+    pub changes: Vec<DeploymentDiff>,
+}
+
+/// Brings the database in line with the desired config. Deployments present in
+/// the payload are created or replaced down to their containers; with `prune`
+/// the ones missing from it are deleted, otherwise they are left untouched.
+#[debug_handler]
+pub async fn apply_hikari(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiQuery(QueryParamsApply { prune, dry_run }): ApiQuery<QueryParamsApply>,
+    ApiJson(payload): ApiJson<HikariConfig>,
+) -> Result<Json<ApplyResult>, ApiError> {
+    payload.validate().map_err(ApiError::from)?;
+    let existing: HashMap<String, DeployConfigDTO> = DeployConfigDAL::new(&state.storage)
+        .find_all()
+        .await
+        .map_err(map_repo_error)?
+        .into_iter()
+        .filter(|deployment| prune || payload.deploy_configs.contains_key(&deployment.name))
+        .map(|deployment| (deployment.name.clone(), deployment))
+        .collect();
+    let current = assemble_hikari_config(
+        existing.values().cloned().collect(),
+        StackConfigDAL::new(&state.storage),
+        ContainerDAL::new(&state.storage),
+        MaintenanceWindowDAL::new(&state.storage),
+    )
+    .await?;
+    let changes = diff_hikari_configs(&current, &payload);
+    if !dry_run {
+        apply_deployment_changes(&state, &meta, &existing, &current, &payload, &changes).await?;
+    }
+    Ok(Json(ApplyResult { dry_run, changes }))
+}
@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, debug_handler};
+
+use crate::{
+    mode::server::AppState,
+    server::{
+        common::{ApiQuery, map_repo_error, page_request},
+        dal::audit_log_dal::{AUDIT_SORT_FIELDS, AuditLogDAL},
+        error::ApiError,
+        models::{
+            audit_log::{AuditFilter, AuditLogDTO},
+            page::{Page, PageParams},
+        },
+    },
+};
+
+#[debug_handler]
+pub async fn get_audit_log(
+    Extension(state): Extension<Arc<AppState>>,
+    ApiQuery(filter): ApiQuery<AuditFilter>,
+    ApiQuery(params): ApiQuery<PageParams>,
+) -> Result<Json<Page<AuditLogDTO>>, ApiError> {
+    let page = page_request(params, AUDIT_SORT_FIELDS)?;
+    let audit_log_dal = AuditLogDAL::new(&state.storage);
+    let value = audit_log_dal
+        .find_page(&filter, page)
+        .await
+        .map_err(map_repo_error)?;
+    Ok(Json(value))
+}
@@ -1,15 +1,29 @@
 use std::sync::Arc;
 
-use axum::{Extension, Json, debug_handler, extract::Query};
-use reqwest::StatusCode;
+use axum::{Extension, Json, debug_handler};
 use serde::Deserialize;
+use serde_json::Value;
 
 use crate::{
     mode::server::AppState,
+    objects::structs::{ChangeAction, EntityKind},
     server::{
-        common::map_repo_error,
-        dal::{deploy_config_dal::DeployConfigDAL, stack_config_dal::StackConfigDAL},
-        models::stack_config::StackConfigDTO,
+        common::{
+            ApiJson, ApiQuery, ChangeMeta, IfMatch, Tagged, audit, audit_value, map_repo_error,
+            merge_environment, page_request, patched, record_change, version_mismatch,
+            with_image_tag,
+        },
+        dal::{
+            container_dal::{ContainerDAL, Utils as _},
+            deploy_config_dal::DeployConfigDAL,
+            stack_config_dal::{STACK_SORT_FIELDS, StackConfigDAL, Utils as _},
+        },
+        error::{ApiError, ErrorCode},
+        models::{
+            container::ContainerDTO,
+            page::{Page, PageParams},
+            stack_config::{StackConfigDTO, StackFilter},
+        },
         traits::model::DataRepository,
         ws::websocket::broadcast,
     },
@@ -18,9 +32,15 @@ use crate::{
 #[debug_handler]
 pub async fn get_all_stacks(
     Extension(state): Extension<Arc<AppState>>,
-) -> Result<Json<Vec<StackConfigDTO>>, (StatusCode, String)> {
-    let stack_config_dal = StackConfigDAL::new(&state.pool);
-    let value = stack_config_dal.find_all().await.map_err(map_repo_error)?;
+    ApiQuery(filter): ApiQuery<StackFilter>,
+    ApiQuery(params): ApiQuery<PageParams>,
+) -> Result<Json<Page<StackConfigDTO>>, ApiError> {
+    let page = page_request(params, STACK_SORT_FIELDS)?;
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
+    let value = stack_config_dal
+        .find_page(&filter, page)
+        .await
+        .map_err(map_repo_error)?;
     Ok(Json(value))
 }
 #[derive(Deserialize)]
@@ -31,182 +51,434 @@ pub struct QueryParams {
 #[debug_handler]
 pub async fn get_stack(
     Extension(state): Extension<Arc<AppState>>,
-    Query(QueryParams { id }): Query<QueryParams>,
-) -> Result<Json<StackConfigDTO>, (StatusCode, String)> {
-    let stack_config_dal = StackConfigDAL::new(&state.pool);
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Tagged<StackConfigDTO>, ApiError> {
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
     let value = stack_config_dal
         .find_by_id(id)
         .await
         .map_err(map_repo_error)?;
-    Ok(Json(value))
+    Ok(Tagged(value))
 }
 
 #[debug_handler]
 pub async fn post_stack(
     Extension(state): Extension<Arc<AppState>>,
-    payload: Json<StackConfigDTO>,
-) -> Result<Json<StackConfigDTO>, (StatusCode, String)> {
+    meta: ChangeMeta,
+    ApiJson(payload): ApiJson<StackConfigDTO>,
+) -> Result<Json<StackConfigDTO>, ApiError> {
     if payload.id.is_some() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Received an unexpected field - id".to_string(),
-        ));
+        return Err(ApiError::unexpected_field("id"));
     }
-    let deploy_config_dal = DeployConfigDAL::new(&state.pool);
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
     let deployment_exists = deploy_config_dal
         .exists(payload.deployment_id)
         .await
         .map_err(map_repo_error)?;
     if !deployment_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("deployment_id - {} not found", payload.deployment_id),
-        ));
+        return Err(ApiError::not_found(format!(
+            "deployment_id - {} not found",
+            payload.deployment_id
+        )));
     }
-    let stack_config_dal = StackConfigDAL::new(&state.pool);
-    let stack = stack_config_dal
-        .create(StackConfigDTO {
-            id: payload.id,
-            deployment_id: payload.deployment_id,
-            stack_name: payload.stack_name.clone(),
-            filename: payload.filename.clone(),
-            home_directory: payload.home_directory.clone(),
-            containers: payload.containers.clone(),
-        })
+    let mut tx = state
+        .storage
+        .begin()
         .await
-        .map_err(map_repo_error)?;
-    let deployment = stack_config_dal
-        .get_deployment_metadata(stack.id.unwrap())
+        .map_err(|err| map_repo_error(err.into()))?;
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
+    let stack = stack_config_dal
+        .create(
+            &mut tx,
+            StackConfigDTO {
+                id: payload.id,
+                deployment_id: payload.deployment_id,
+                stack_name: payload.stack_name.clone(),
+                filename: payload.filename.clone(),
+                home_directory: payload.home_directory.clone(),
+                drift_policy: payload.drift_policy.clone(),
+                containers: payload.containers.clone(),
+                version: None,
+            },
+        )
         .await
         .map_err(map_repo_error)?;
-    tokio::spawn(async move {
-        broadcast(
-            state,
-            deployment.client,
-            deployment.solution,
-            deployment.environment,
-        )
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::Stack,
+        ChangeAction::Created,
+        stack.id,
+        None,
+        audit_value(&stack),
+    )
+    .await?;
+    let notification = record_change(
+        &mut tx,
+        stack.deployment_id,
+        EntityKind::Stack,
+        ChangeAction::Created,
+        vec![stack.id.unwrap_or_default()],
+        &meta,
+    )
+    .await?;
+    tx.commit()
         .await
-    });
+        .map_err(|err| map_repo_error(err.into()))?;
+    tokio::spawn(async move { broadcast(state, notification).await });
     Ok(Json(stack))
 }
 
 #[debug_handler]
 pub async fn update_stack(
     Extension(state): Extension<Arc<AppState>>,
-    payload: Json<StackConfigDTO>,
-) -> Result<Json<StackConfigDTO>, (StatusCode, String)> {
-    if payload.id.is_none() {
-        return Err((StatusCode::BAD_REQUEST, "Expected field - id".to_string()));
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    ApiJson(payload): ApiJson<StackConfigDTO>,
+) -> Result<Tagged<StackConfigDTO>, ApiError> {
+    let Some(id) = payload.id else {
+        return Err(ApiError::missing_field("id"));
+    };
+    let current = find_stack(&state, id).await?;
+    // a version in the body counts as If-Match, for clients sending back what they
+    // read
+    let if_match = IfMatch(if_match.0.or(payload.version));
+    save_stack(state, meta, if_match, current, payload).await
+}
+
+#[debug_handler]
+pub async fn patch_stack(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+    ApiJson(patch): ApiJson<Value>,
+) -> Result<Tagged<StackConfigDTO>, ApiError> {
+    let current = find_stack(&state, id).await?;
+    let desired = patched(&current, &patch)?;
+    save_stack(state, meta, if_match, current, desired).await
+}
+
+async fn find_stack(state: &AppState, id: i64) -> Result<StackConfigDTO, ApiError> {
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
+    let record_exists = stack_config_dal.exists(id).await.map_err(map_repo_error)?;
+    if !record_exists {
+        return Err(ApiError::not_found(format!("Stack of ID - {id} not found")));
     }
-    let deploy_config_dal = DeployConfigDAL::new(&state.pool);
+    stack_config_dal
+        .find_by_id(id)
+        .await
+        .map_err(map_repo_error)
+}
+
+/// Replaces `current` with `desired`, shared by PUT and PATCH. Unchanged
+/// stacks are returned as they are, without a new revision.
+async fn save_stack(
+    state: Arc<AppState>,
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    current: StackConfigDTO,
+    desired: StackConfigDTO,
+) -> Result<Tagged<StackConfigDTO>, ApiError> {
+    if_match.check(current.version)?;
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
     let deployment_exists = deploy_config_dal
-        .exists(payload.deployment_id)
+        .exists(desired.deployment_id)
         .await
         .map_err(map_repo_error)?;
     if !deployment_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("deployment_id - {} not found", payload.deployment_id),
-        ));
+        return Err(ApiError::not_found(format!(
+            "deployment_id - {} not found",
+            desired.deployment_id
+        )));
+    }
+    // the id and version are managed by the server, ignore whatever the client sent
+    let desired = StackConfigDTO {
+        id: current.id,
+        version: current.version,
+        containers: current.containers.clone(),
+        ..desired
+    };
+    if desired == current {
+        return Ok(Tagged(current));
     }
-    let stack_config_dal = StackConfigDAL::new(&state.pool);
-    let record_exists = stack_config_dal
-        .exists(payload.id.unwrap())
+    let id = current.id.unwrap_or_default();
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    let updated: bool = stack_config_dal
+        .update(&mut tx, desired.clone())
         .await
         .map_err(map_repo_error)?;
-    if !record_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("Stack of ID - {} not found", payload.id.unwrap()),
-        ));
+    if !updated {
+        // the row exists, so its version moved since it was read
+        return Err(version_mismatch(current.version));
     }
-    if payload.0
-        == stack_config_dal
-            .find_by_id(payload.id.unwrap())
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::Stack,
+        ChangeAction::Updated,
+        Some(id),
+        audit_value(&current),
+        audit_value(&desired),
+    )
+    .await?;
+    if desired.deployment_id == current.deployment_id {
+        let notification = record_change(
+            &mut tx,
+            desired.deployment_id,
+            EntityKind::Stack,
+            ChangeAction::Updated,
+            vec![id],
+            &meta,
+        )
+        .await?;
+        tx.commit()
             .await
-            .map_err(map_repo_error)?
-    {
-        return Err((
-            StatusCode::NOT_MODIFIED,
-            format!("Deployment of ID - {} is not modified", payload.id.unwrap()),
-        ));
+            .map_err(|err| map_repo_error(err.into()))?;
+        tokio::spawn(async move { broadcast(state, notification).await });
+    } else {
+        // a re-parented stack leaves one deployment and joins another, both need
+        // a revision and their nodes a notification. They are bumped in id
+        // order, so moves in opposite directions can't deadlock on each other.
+        let mut changes = [
+            (current.deployment_id, ChangeAction::Deleted),
+            (desired.deployment_id, ChangeAction::Created),
+        ];
+        changes.sort_by_key(|(deployment_id, _)| *deployment_id);
+        let mut notifications = Vec::with_capacity(changes.len());
+        for (deployment_id, action) in changes {
+            notifications.push(
+                record_change(
+                    &mut tx,
+                    deployment_id,
+                    EntityKind::Stack,
+                    action,
+                    vec![id],
+                    &meta,
+                )
+                .await?,
+            );
+        }
+        tx.commit()
+            .await
+            .map_err(|err| map_repo_error(err.into()))?;
+        tokio::spawn(async move {
+            for notification in notifications {
+                broadcast(state.clone(), notification).await;
+            }
+        });
     }
-    let updated: bool = stack_config_dal
-        .update(StackConfigDTO {
-            id: payload.id,
-            deployment_id: payload.deployment_id,
-            stack_name: payload.stack_name.clone(),
-            filename: payload.filename.clone(),
-            home_directory: payload.home_directory.clone(),
-            containers: payload.containers.clone(),
-        })
+    stack_config_dal
+        .find_by_id(id)
+        .await
+        .map(Tagged)
+        .map_err(map_repo_error)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StackMoveRequest {
+    pub stack_id: i64,
+    pub target_deployment_id: i64,
+}
+
+/// Re-parents a stack, containers included, onto another deployment.
+#[debug_handler]
+pub async fn move_stack(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    ApiJson(payload): ApiJson<StackMoveRequest>,
+) -> Result<Tagged<StackConfigDTO>, ApiError> {
+    let current = find_stack(&state, payload.stack_id).await?;
+    let desired = StackConfigDTO {
+        deployment_id: payload.target_deployment_id,
+        ..current.clone()
+    };
+    save_stack(state, meta, if_match, current, desired).await
+}
+
+/// Overrides applied to a cloned stack, anything left out is copied as is.
+#[derive(Debug, Deserialize)]
+pub struct StackCloneRequest {
+    pub stack_id: i64,
+    pub target_deployment_id: i64,
+    pub stack_name: Option<String>,
+    pub home_directory: Option<String>,
+    /// Replaces the tag of every container image.
+    pub image_tag: Option<String>,
+    /// `KEY=value` entries set on every container, replacing existing keys.
+    pub environment: Option<Vec<String>>,
+}
+
+/// Deep-copies a stack and its containers into the target deployment.
+#[debug_handler]
+pub async fn clone_stack(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiJson(payload): ApiJson<StackCloneRequest>,
+) -> Result<Json<StackConfigDTO>, ApiError> {
+    let source = find_stack(&state, payload.stack_id).await?;
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let deployment_exists = deploy_config_dal
+        .exists(payload.target_deployment_id)
         .await
         .map_err(map_repo_error)?;
-    if updated {
-        let deployment = stack_config_dal
-            .get_deployment_metadata(payload.id.unwrap())
-            .await
-            .map_err(map_repo_error)?;
-        tokio::spawn(async move {
-            broadcast(
-                state,
-                deployment.client,
-                deployment.solution,
-                deployment.environment,
+    if !deployment_exists {
+        return Err(ApiError::not_found(format!(
+            "target_deployment_id - {} not found",
+            payload.target_deployment_id
+        )));
+    }
+    let containers = ContainerDAL::new(&state.storage)
+        .find_by_stack_ids(&[payload.stack_id])
+        .await
+        .map_err(map_repo_error)?;
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
+    let stack = stack_config_dal
+        .create(
+            &mut tx,
+            StackConfigDTO {
+                id: None,
+                deployment_id: payload.target_deployment_id,
+                stack_name: payload.stack_name.unwrap_or(source.stack_name),
+                filename: source.filename,
+                home_directory: payload.home_directory.unwrap_or(source.home_directory),
+                drift_policy: source.drift_policy,
+                containers: None,
+                version: None,
+            },
+        )
+        .await
+        .map_err(map_repo_error)?;
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::Stack,
+        ChangeAction::Created,
+        stack.id,
+        None,
+        audit_value(&stack),
+    )
+    .await?;
+    let stack_id = stack.id.unwrap_or_default();
+    let container_dal = ContainerDAL::new(&state.storage);
+    for container in containers {
+        let image = match &payload.image_tag {
+            Some(tag) => with_image_tag(&container.image, tag),
+            None => container.image,
+        };
+        let environment = match &payload.environment {
+            Some(overrides) => Some(merge_environment(container.environment, overrides)),
+            None => container.environment,
+        };
+        let created = container_dal
+            .create(
+                &mut tx,
+                ContainerDTO {
+                    id: None,
+                    stack_id,
+                    image,
+                    environment,
+                    version: None,
+                    ..container
+                },
             )
             .await
-        });
-        stack_config_dal
-            .find_by_id(payload.id.unwrap())
-            .await
-            .map(Json)
-            .map_err(map_repo_error)
-    } else {
-        Err((
-            StatusCode::BAD_REQUEST,
-            format!("Unable to update Stack ID - {}", payload.id.unwrap()),
-        ))
+            .map_err(map_repo_error)?;
+        audit(
+            &mut tx,
+            &meta,
+            EntityKind::Container,
+            ChangeAction::Created,
+            created.id,
+            None,
+            audit_value(&created),
+        )
+        .await?;
     }
+    let notification = record_change(
+        &mut tx,
+        payload.target_deployment_id,
+        EntityKind::Stack,
+        ChangeAction::Created,
+        vec![stack_id],
+        &meta,
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    tokio::spawn(async move { broadcast(state, notification).await });
+    stack_config_dal
+        .find_by_id(stack_id)
+        .await
+        .map(Json)
+        .map_err(map_repo_error)
 }
 
 #[debug_handler]
 pub async fn delete_stack(
     Extension(state): Extension<Arc<AppState>>,
-    Query(QueryParams { id }): Query<QueryParams>,
-) -> Result<Json<StackConfigDTO>, (StatusCode, String)> {
-    let stack_config_dal = StackConfigDAL::new(&state.pool);
+    meta: ChangeMeta,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Json<StackConfigDTO>, ApiError> {
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
     let record_exists = stack_config_dal.exists(id).await.map_err(map_repo_error)?;
     if !record_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("Stack of ID - {id} not found"),
-        ));
+        return Err(ApiError::not_found(format!("Stack of ID - {id} not found")));
     }
-    let deployment = stack_config_dal
-        .get_deployment_metadata(id)
-        .await
-        .map_err(map_repo_error)?;
     let stack = stack_config_dal
         .find_by_id(id)
         .await
         .map_err(map_repo_error)?;
-    let deleted = stack_config_dal.delete(id).await.map_err(map_repo_error)?;
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    let deleted = stack_config_dal
+        .delete(&mut tx, id)
+        .await
+        .map_err(map_repo_error)?;
     if deleted {
-        tokio::spawn(async move {
-            broadcast(
-                state,
-                deployment.client,
-                deployment.solution,
-                deployment.environment,
-            )
+        audit(
+            &mut tx,
+            &meta,
+            EntityKind::Stack,
+            ChangeAction::Deleted,
+            Some(id),
+            audit_value(&stack),
+            None,
+        )
+        .await?;
+        let notification = record_change(
+            &mut tx,
+            stack.deployment_id,
+            EntityKind::Stack,
+            ChangeAction::Deleted,
+            vec![id],
+            &meta,
+        )
+        .await?;
+        tx.commit()
             .await
-        });
+            .map_err(|err| map_repo_error(err.into()))?;
+        tokio::spawn(async move { broadcast(state, notification).await });
         Ok(Json(stack))
     } else {
-        Err((
-            StatusCode::BAD_REQUEST,
-            "Unable to delete deployment".to_string(),
+        Err(ApiError::bad_request(
+            ErrorCode::DeleteFailed,
+            "Unable to delete deployment",
         ))
     }
 }
@@ -1,15 +1,26 @@
 use std::sync::Arc;
 
-use axum::{Extension, Json, debug_handler, extract::Query};
-use reqwest::StatusCode;
+use axum::{Extension, Json, debug_handler};
 use serde::Deserialize;
+use serde_json::Value;
 
 use crate::{
     mode::server::AppState,
+    objects::structs::{ChangeAction, EntityKind},
     server::{
-        common::map_repo_error,
-        dal::{container_dal::ContainerDAL, stack_config_dal::StackConfigDAL},
-        models::container::ContainerDTO,
+        common::{
+            ApiJson, ApiQuery, ChangeMeta, IfMatch, Tagged, audit, audit_value, map_repo_error,
+            page_request, patched, record_change, version_mismatch,
+        },
+        dal::{
+            container_dal::{CONTAINER_SORT_FIELDS, ContainerDAL, Utils as _},
+            stack_config_dal::StackConfigDAL,
+        },
+        error::{ApiError, ErrorCode},
+        models::{
+            container::{ContainerDTO, ContainerFilter},
+            page::{Page, PageParams},
+        },
         traits::model::DataRepository,
         ws::websocket::broadcast,
     },
@@ -18,10 +29,13 @@ use crate::{
 #[debug_handler]
 pub async fn get_all_containers(
     Extension(state): Extension<Arc<AppState>>,
-) -> Result<Json<Vec<ContainerDTO>>, (StatusCode, String)> {
-    let container_config_dal = ContainerDAL::new(&state.pool);
+    ApiQuery(filter): ApiQuery<ContainerFilter>,
+    ApiQuery(params): ApiQuery<PageParams>,
+) -> Result<Json<Page<ContainerDTO>>, ApiError> {
+    let page = page_request(params, CONTAINER_SORT_FIELDS)?;
+    let container_config_dal = ContainerDAL::new(&state.storage);
     let value = container_config_dal
-        .find_all()
+        .find_page(&filter, page)
         .await
         .map_err(map_repo_error)?;
     Ok(Json(value))
@@ -34,185 +48,240 @@ pub struct QueryParams {
 #[debug_handler]
 pub async fn get_container(
     Extension(state): Extension<Arc<AppState>>,
-    Query(QueryParams { id }): Query<QueryParams>,
-) -> Result<Json<ContainerDTO>, (StatusCode, String)> {
-    let container_config_dal = ContainerDAL::new(&state.pool);
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Tagged<ContainerDTO>, ApiError> {
+    let container_config_dal = ContainerDAL::new(&state.storage);
     let value = container_config_dal
         .find_by_id(id)
         .await
         .map_err(map_repo_error)?;
-    Ok(Json(value))
+    Ok(Tagged(value))
 }
 
 #[debug_handler]
 pub async fn post_container(
     Extension(state): Extension<Arc<AppState>>,
-    payload: Json<ContainerDTO>,
-) -> Result<Json<ContainerDTO>, (StatusCode, String)> {
+    meta: ChangeMeta,
+    ApiJson(payload): ApiJson<ContainerDTO>,
+) -> Result<Json<ContainerDTO>, ApiError> {
     if payload.id.is_some() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Received an unexpected field - id".to_string(),
-        ));
+        return Err(ApiError::unexpected_field("id"));
     }
-    let stack_config_dal = StackConfigDAL::new(&state.pool);
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
     let stack_exists = stack_config_dal
         .exists(payload.stack_id)
         .await
         .map_err(map_repo_error)?;
     if !stack_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("stack_id - {} not found", payload.stack_id),
-        ));
+        return Err(ApiError::not_found(format!(
+            "stack_id - {} not found",
+            payload.stack_id
+        )));
     }
-    let container_config_dal = ContainerDAL::new(&state.pool);
-    let container = container_config_dal
-        .create(ContainerDTO {
-            id: payload.id,
-            stack_id: payload.stack_id,
-            service_name: payload.service_name.clone(),
-            container_name: payload.container_name.clone(),
-            image: payload.image.clone(),
-            restart: payload.restart.clone(),
-            user: payload.user.clone(),
-            stdin_open: payload.stdin_open,
-            tty: payload.tty,
-            command: payload.command.clone(),
-            pull_policy: payload.pull_policy.clone(),
-            ports: payload.ports.clone(),
-            volumes: payload.volumes.clone(),
-            environment: payload.environment.clone(),
-            mem_reservation: payload.mem_reservation.clone(),
-            mem_limit: payload.mem_limit.clone(),
-            oom_kill_disable: payload.oom_kill_disable,
-            privileged: payload.privileged,
-        })
+    let deployment = stack_config_dal
+        .get_deployment_metadata(payload.stack_id)
         .await
         .map_err(map_repo_error)?;
-    let deployment = container_config_dal
-        .get_deployment_metadata(container.id.unwrap())
+    let mut tx = state
+        .storage
+        .begin()
         .await
-        .map_err(map_repo_error)?;
-    tokio::spawn(async move {
-        broadcast(
-            state,
-            deployment.client,
-            deployment.solution,
-            deployment.environment,
+        .map_err(|err| map_repo_error(err.into()))?;
+    let container_config_dal = ContainerDAL::new(&state.storage);
+    let container = container_config_dal
+        .create(
+            &mut tx,
+            ContainerDTO {
+                id: payload.id,
+                stack_id: payload.stack_id,
+                service_name: payload.service_name.clone(),
+                container_name: payload.container_name.clone(),
+                image: payload.image.clone(),
+                restart: payload.restart.clone(),
+                user: payload.user.clone(),
+                stdin_open: payload.stdin_open,
+                tty: payload.tty,
+                command: payload.command.clone(),
+                pull_policy: payload.pull_policy.clone(),
+                ports: payload.ports.clone(),
+                volumes: payload.volumes.clone(),
+                environment: payload.environment.clone(),
+                mem_reservation: payload.mem_reservation.clone(),
+                mem_limit: payload.mem_limit.clone(),
+                oom_kill_disable: payload.oom_kill_disable,
+                privileged: payload.privileged,
+                version: None,
+            },
         )
         .await
-    });
+        .map_err(map_repo_error)?;
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::Container,
+        ChangeAction::Created,
+        container.id,
+        None,
+        audit_value(&container),
+    )
+    .await?;
+    let notification = record_change(
+        &mut tx,
+        deployment.id.unwrap_or_default(),
+        EntityKind::Container,
+        ChangeAction::Created,
+        vec![container.id.unwrap_or_default()],
+        &meta,
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    tokio::spawn(async move { broadcast(state, notification).await });
     Ok(Json(container))
 }
 
 #[debug_handler]
 pub async fn update_container(
     Extension(state): Extension<Arc<AppState>>,
-    payload: Json<ContainerDTO>,
-) -> Result<Json<ContainerDTO>, (StatusCode, String)> {
-    if payload.id.is_none() {
-        return Err((StatusCode::BAD_REQUEST, "Expected field - id".to_string()));
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    ApiJson(payload): ApiJson<ContainerDTO>,
+) -> Result<Tagged<ContainerDTO>, ApiError> {
+    let Some(id) = payload.id else {
+        return Err(ApiError::missing_field("id"));
+    };
+    let current = find_container(&state, id).await?;
+    // a version in the body counts as If-Match, for clients sending back what they
+    // read
+    let if_match = IfMatch(if_match.0.or(payload.version));
+    save_container(state, meta, if_match, current, payload).await
+}
+
+#[debug_handler]
+pub async fn patch_container(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+    ApiJson(patch): ApiJson<Value>,
+) -> Result<Tagged<ContainerDTO>, ApiError> {
+    let current = find_container(&state, id).await?;
+    let desired = patched(&current, &patch)?;
+    save_container(state, meta, if_match, current, desired).await
+}
+
+async fn find_container(state: &AppState, id: i64) -> Result<ContainerDTO, ApiError> {
+    let container_config_dal = ContainerDAL::new(&state.storage);
+    let record_exists = container_config_dal
+        .exists(id)
+        .await
+        .map_err(map_repo_error)?;
+    if !record_exists {
+        return Err(ApiError::not_found(format!(
+            "Container of ID - {id} not found"
+        )));
     }
-    let stack_config_dal = StackConfigDAL::new(&state.pool);
+    container_config_dal
+        .find_by_id(id)
+        .await
+        .map_err(map_repo_error)
+}
+
+/// Replaces `current` with `desired`, shared by PUT and PATCH. Unchanged
+/// containers are returned as they are, without a new revision.
+async fn save_container(
+    state: Arc<AppState>,
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    current: ContainerDTO,
+    desired: ContainerDTO,
+) -> Result<Tagged<ContainerDTO>, ApiError> {
+    if_match.check(current.version)?;
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
     let stack_exists = stack_config_dal
-        .exists(payload.stack_id)
+        .exists(desired.stack_id)
         .await
         .map_err(map_repo_error)?;
     if !stack_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("stack_id - {} not found", payload.stack_id),
-        ));
+        return Err(ApiError::not_found(format!(
+            "stack_id - {} not found",
+            desired.stack_id
+        )));
     }
-    let container_config_dal = ContainerDAL::new(&state.pool);
-    let record_exists = container_config_dal
-        .exists(payload.id.unwrap())
+    // the id and version are managed by the server, ignore whatever the client sent
+    let desired = ContainerDTO {
+        id: current.id,
+        version: current.version,
+        ..desired
+    };
+    if desired == current {
+        return Ok(Tagged(current));
+    }
+    let id = current.id.unwrap_or_default();
+    let deployment = stack_config_dal
+        .get_deployment_metadata(desired.stack_id)
         .await
         .map_err(map_repo_error)?;
-    if !record_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("Container of ID - {} not found", payload.id.unwrap()),
-        ));
-    }
-    if payload.0
-        == container_config_dal
-            .find_by_id(payload.id.unwrap())
-            .await
-            .map_err(map_repo_error)?
-    {
-        return Err((
-            StatusCode::NOT_MODIFIED,
-            format!("Deployment of ID - {} is not modified", payload.id.unwrap()),
-        ));
-    }
-
+    let container_config_dal = ContainerDAL::new(&state.storage);
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
     let updated: bool = container_config_dal
-        .update(ContainerDTO {
-            id: payload.id,
-            stack_id: payload.stack_id,
-            service_name: payload.service_name.clone(),
-            container_name: payload.container_name.clone(),
-            image: payload.image.clone(),
-            restart: payload.restart.clone(),
-            user: payload.user.clone(),
-            stdin_open: payload.stdin_open,
-            tty: payload.tty,
-            command: payload.command.clone(),
-            pull_policy: payload.pull_policy.clone(),
-            ports: payload.ports.clone(),
-            volumes: payload.volumes.clone(),
-            environment: payload.environment.clone(),
-            mem_reservation: payload.mem_reservation.clone(),
-            mem_limit: payload.mem_limit.clone(),
-            oom_kill_disable: payload.oom_kill_disable,
-            privileged: payload.privileged,
-        })
+        .update(&mut tx, desired.clone())
         .await
         .map_err(map_repo_error)?;
-    if updated {
-        let deployment = container_config_dal
-            .get_deployment_metadata(payload.id.unwrap())
-            .await
-            .map_err(map_repo_error)?;
-        tokio::spawn(async move {
-            broadcast(
-                state,
-                deployment.client,
-                deployment.solution,
-                deployment.environment,
-            )
-            .await
-        });
-        container_config_dal
-            .find_by_id(payload.id.unwrap())
-            .await
-            .map(Json)
-            .map_err(map_repo_error)
-    } else {
-        Err((
-            StatusCode::BAD_REQUEST,
-            format!("Unable to update Conatainer ID - {}", payload.id.unwrap()),
-        ))
+    if !updated {
+        // the row exists, so its version moved since it was read
+        return Err(version_mismatch(current.version));
     }
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::Container,
+        ChangeAction::Updated,
+        Some(id),
+        audit_value(&current),
+        audit_value(&desired),
+    )
+    .await?;
+    let notification = record_change(
+        &mut tx,
+        deployment.id.unwrap_or_default(),
+        EntityKind::Container,
+        ChangeAction::Updated,
+        vec![id],
+        &meta,
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    tokio::spawn(async move { broadcast(state, notification).await });
+    container_config_dal
+        .find_by_id(id)
+        .await
+        .map(Tagged)
+        .map_err(map_repo_error)
 }
 
 #[debug_handler]
 pub async fn delete_container(
     Extension(state): Extension<Arc<AppState>>,
-    Query(QueryParams { id }): Query<QueryParams>,
-) -> Result<Json<ContainerDTO>, (StatusCode, String)> {
-    let container_config_dal = ContainerDAL::new(&state.pool);
+    meta: ChangeMeta,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Json<ContainerDTO>, ApiError> {
+    let container_config_dal = ContainerDAL::new(&state.storage);
     let record_exists = container_config_dal
         .exists(id)
         .await
         .map_err(map_repo_error)?;
     if !record_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("Container of ID - {id} not found"),
-        ));
+        return Err(ApiError::not_found(format!(
+            "Container of ID - {id} not found"
+        )));
     }
     let container = container_config_dal
         .find_by_id(id)
@@ -222,25 +291,44 @@ pub async fn delete_container(
         .get_deployment_metadata(id)
         .await
         .map_err(map_repo_error)?;
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
     let deleted = container_config_dal
-        .delete(id)
+        .delete(&mut tx, id)
         .await
         .map_err(map_repo_error)?;
     if deleted {
-        tokio::spawn(async move {
-            broadcast(
-                state,
-                deployment.client,
-                deployment.solution,
-                deployment.environment,
-            )
+        audit(
+            &mut tx,
+            &meta,
+            EntityKind::Container,
+            ChangeAction::Deleted,
+            Some(id),
+            audit_value(&container),
+            None,
+        )
+        .await?;
+        let notification = record_change(
+            &mut tx,
+            deployment.id.unwrap_or_default(),
+            EntityKind::Container,
+            ChangeAction::Deleted,
+            vec![id],
+            &meta,
+        )
+        .await?;
+        tx.commit()
             .await
-        });
+            .map_err(|err| map_repo_error(err.into()))?;
+        tokio::spawn(async move { broadcast(state, notification).await });
         Ok(Json(container))
     } else {
-        Err((
-            StatusCode::BAD_REQUEST,
-            "Unable to delete Container".to_string(),
+        Err(ApiError::bad_request(
+            ErrorCode::DeleteFailed,
+            "Unable to delete Container",
         ))
     }
 }
@@ -1,14 +1,24 @@
 use std::sync::Arc;
 
-use axum::{Extension, Json, debug_handler, extract::Query};
-use reqwest::StatusCode;
+use axum::{Extension, Json, debug_handler};
 use serde::Deserialize;
+use serde_json::Value;
 
 use crate::{
     mode::server::AppState,
+    objects::structs::{ChangeAction, ChangeNotification, EntityKind},
     server::{
-        common::map_repo_error, dal::deploy_config_dal::DeployConfigDAL,
-        models::deploy_config::DeployConfigDTO, traits::model::DataRepository,
+        common::{
+            ApiJson, ApiQuery, ChangeMeta, IfMatch, Tagged, audit, audit_value, map_repo_error,
+            page_request, patched, previous_notification, record_change, version_mismatch,
+        },
+        dal::deploy_config_dal::{DEPLOYMENT_SORT_FIELDS, DeployConfigDAL, Utils as _},
+        error::{ApiError, ErrorCode},
+        models::{
+            deploy_config::{DeployConfigDTO, DeploymentFilter},
+            page::{Page, PageParams},
+        },
+        traits::model::DataRepository,
         ws::websocket::broadcast,
     },
 };
@@ -16,9 +26,15 @@ use crate::{
 #[debug_handler]
 pub async fn get_all_deployments(
     Extension(state): Extension<Arc<AppState>>,
-) -> Result<Json<Vec<DeployConfigDTO>>, (StatusCode, String)> {
-    let deploy_config_dal = DeployConfigDAL::new(&state.pool);
-    let value = deploy_config_dal.find_all().await.map_err(map_repo_error)?;
+    ApiQuery(filter): ApiQuery<DeploymentFilter>,
+    ApiQuery(params): ApiQuery<PageParams>,
+) -> Result<Json<Page<DeployConfigDTO>>, ApiError> {
+    let page = page_request(params, DEPLOYMENT_SORT_FIELDS)?;
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let value = deploy_config_dal
+        .find_page(&filter, page)
+        .await
+        .map_err(map_repo_error)?;
     Ok(Json(value))
 }
 #[derive(Deserialize)]
@@ -29,162 +45,246 @@ pub struct QueryParams {
 #[debug_handler]
 pub async fn get_deployment(
     Extension(state): Extension<Arc<AppState>>,
-    Query(QueryParams { id }): Query<QueryParams>,
-) -> Result<Json<DeployConfigDTO>, (StatusCode, String)> {
-    let deploy_config_dal = DeployConfigDAL::new(&state.pool);
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Tagged<DeployConfigDTO>, ApiError> {
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
     let value = deploy_config_dal
         .find_by_id(id)
         .await
         .map_err(map_repo_error)?;
-    Ok(Json(value))
+    Ok(Tagged(value))
 }
 
 #[debug_handler]
 pub async fn post_deployment(
     Extension(state): Extension<Arc<AppState>>,
-    payload: Json<DeployConfigDTO>,
-) -> Result<Json<DeployConfigDTO>, (StatusCode, String)> {
+    meta: ChangeMeta,
+    ApiJson(payload): ApiJson<DeployConfigDTO>,
+) -> Result<Tagged<DeployConfigDTO>, ApiError> {
     if payload.id.is_some() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Received an unexpected field - id".to_string(),
-        ));
+        return Err(ApiError::unexpected_field("id"));
     }
-    let deploy_config_dal = DeployConfigDAL::new(&state.pool);
-    let deployment = deploy_config_dal
-        .create(DeployConfigDTO {
-            id: payload.id,
-            name: payload.name.clone(),
-            client: payload.client.clone(),
-            environment: payload.environment.clone(),
-            solution: payload.solution.clone(),
-            stack_ids: payload.stack_ids.clone(),
-        })
+    let mut tx = state
+        .storage
+        .begin()
         .await
-        .map_err(map_repo_error)?;
-    let deployment_temp = deployment.clone();
-    tokio::spawn(async move {
-        broadcast(
-            state,
-            deployment_temp.client,
-            deployment_temp.solution,
-            deployment_temp.environment,
+        .map_err(|err| map_repo_error(err.into()))?;
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let mut deployment = deploy_config_dal
+        .create(
+            &mut tx,
+            DeployConfigDTO {
+                id: payload.id,
+                name: payload.name.clone(),
+                client: payload.client.clone(),
+                environment: payload.environment.clone(),
+                solution: payload.solution.clone(),
+                revision: None,
+                version: None,
+                stack_ids: payload.stack_ids.clone(),
+            },
         )
         .await
-    });
-    Ok(Json(deployment))
+        .map_err(map_repo_error)?;
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::Deployment,
+        ChangeAction::Created,
+        deployment.id,
+        None,
+        audit_value(&deployment),
+    )
+    .await?;
+    let id = deployment.id.unwrap_or_default();
+    let notification = record_change(
+        &mut tx,
+        id,
+        EntityKind::Deployment,
+        ChangeAction::Created,
+        vec![id],
+        &meta,
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    deployment.revision = Some(notification.revision);
+    tokio::spawn(async move { broadcast(state, notification).await });
+    Ok(Tagged(deployment))
 }
 
 #[debug_handler]
 pub async fn update_deployment(
     Extension(state): Extension<Arc<AppState>>,
-    payload: Json<DeployConfigDTO>,
-) -> Result<Json<DeployConfigDTO>, (StatusCode, String)> {
-    if payload.id.is_none() {
-        return Err((StatusCode::BAD_REQUEST, "Expected field - id".to_string()));
-    }
-    let deploy_config_dal = DeployConfigDAL::new(&state.pool);
-    let record_exists = deploy_config_dal
-        .exists(payload.id.unwrap())
-        .await
-        .map_err(map_repo_error)?;
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    ApiJson(payload): ApiJson<DeployConfigDTO>,
+) -> Result<Tagged<DeployConfigDTO>, ApiError> {
+    let Some(id) = payload.id else {
+        return Err(ApiError::missing_field("id"));
+    };
+    let current = find_deployment(&state, id).await?;
+    // a version in the body counts as If-Match, for clients sending back what they
+    // read
+    let if_match = IfMatch(if_match.0.or(payload.version));
+    save_deployment(state, meta, if_match, current, payload).await
+}
+
+#[debug_handler]
+pub async fn patch_deployment(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+    ApiJson(patch): ApiJson<Value>,
+) -> Result<Tagged<DeployConfigDTO>, ApiError> {
+    let current = find_deployment(&state, id).await?;
+    let desired = patched(&current, &patch)?;
+    save_deployment(state, meta, if_match, current, desired).await
+}
+
+async fn find_deployment(state: &AppState, id: i64) -> Result<DeployConfigDTO, ApiError> {
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let record_exists = deploy_config_dal.exists(id).await.map_err(map_repo_error)?;
     if !record_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("Deployment of ID - {} not found", payload.id.unwrap()),
-        ));
+        return Err(ApiError::not_found(format!(
+            "Deployment of ID - {id} not found"
+        )));
     }
-    if payload.0
-        == deploy_config_dal
-            .find_by_id(payload.id.unwrap())
-            .await
-            .map_err(map_repo_error)?
-    {
-        return Err((
-            StatusCode::NOT_MODIFIED,
-            format!("Deployment of ID - {} is not modified", payload.id.unwrap()),
-        ));
+    deploy_config_dal
+        .find_by_id(id)
+        .await
+        .map_err(map_repo_error)
+}
+
+/// Replaces `current` with `desired`, shared by PUT and PATCH. Unchanged
+/// deployments are returned as they are, without a new revision.
+async fn save_deployment(
+    state: Arc<AppState>,
+    meta: ChangeMeta,
+    if_match: IfMatch,
+    current: DeployConfigDTO,
+    desired: DeployConfigDTO,
+) -> Result<Tagged<DeployConfigDTO>, ApiError> {
+    if_match.check(current.version)?;
+    // the id, revision, version and stacks are managed by the server, ignore
+    // whatever the client sent
+    let desired = DeployConfigDTO {
+        id: current.id,
+        revision: current.revision,
+        version: current.version,
+        stack_ids: current.stack_ids.clone(),
+        ..desired
+    };
+    if desired == current {
+        return Ok(Tagged(current));
     }
-    let deployment = deploy_config_dal
-        .get_deployment_metadata(payload.id.unwrap())
+    let id = current.id.unwrap_or_default();
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let mut tx = state
+        .storage
+        .begin()
         .await
-        .map_err(map_repo_error)?;
+        .map_err(|err| map_repo_error(err.into()))?;
     let updated: bool = deploy_config_dal
-        .update(DeployConfigDTO {
-            id: payload.id,
-            name: payload.name.clone(),
-            client: payload.client.clone(),
-            environment: payload.environment.clone(),
-            solution: payload.solution.clone(),
-            stack_ids: payload.stack_ids.clone(),
-        })
+        .update(&mut tx, desired.clone())
         .await
         .map_err(map_repo_error)?;
-    if updated {
-        let temp_payload = payload.clone();
-        tokio::spawn(async move {
-            let _ = broadcast(
-                state.clone(),
-                temp_payload.client.clone(),
-                temp_payload.solution.clone(),
-                temp_payload.environment.clone(),
-            )
-            .await;
-            let _ = broadcast(
-                state,
-                deployment.client,
-                deployment.solution,
-                deployment.environment,
-            )
-            .await;
-        });
-        deploy_config_dal
-            .find_by_id(payload.id.unwrap())
-            .await
-            .map(Json)
-            .map_err(map_repo_error)
-    } else {
-        Err((
-            StatusCode::BAD_REQUEST,
-            format!("Unable to update Deployment ID - {}", payload.id.unwrap()),
-        ))
+    if !updated {
+        // the row exists, so its version moved since it was read
+        return Err(version_mismatch(current.version));
     }
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::Deployment,
+        ChangeAction::Updated,
+        Some(id),
+        audit_value(&current),
+        audit_value(&desired),
+    )
+    .await?;
+    let notification = record_change(
+        &mut tx,
+        id,
+        EntityKind::Deployment,
+        ChangeAction::Updated,
+        vec![id],
+        &meta,
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    let previous = previous_notification(&current, &notification);
+    tokio::spawn(async move {
+        broadcast(state.clone(), notification).await;
+        if let Some(previous) = previous {
+            broadcast(state, previous).await;
+        }
+    });
+    deploy_config_dal
+        .find_by_id(id)
+        .await
+        .map(Tagged)
+        .map_err(map_repo_error)
 }
 
 #[debug_handler]
 pub async fn delete_deployment(
     Extension(state): Extension<Arc<AppState>>,
-    Query(QueryParams { id }): Query<QueryParams>,
-) -> Result<Json<DeployConfigDTO>, (StatusCode, String)> {
-    let deploy_config_dal = DeployConfigDAL::new(&state.pool);
+    meta: ChangeMeta,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Json<DeployConfigDTO>, ApiError> {
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
     let record_exists = deploy_config_dal.exists(id).await.map_err(map_repo_error)?;
     if !record_exists {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("Deployment of ID - {id} not found"),
-        ));
+        return Err(ApiError::not_found(format!(
+            "Deployment of ID - {id} not found"
+        )));
     }
     let deployment = deploy_config_dal
         .find_by_id(id)
         .await
         .map_err(map_repo_error)?;
-    let deleted = deploy_config_dal.delete(id).await.map_err(map_repo_error)?;
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    let deleted = deploy_config_dal
+        .delete(&mut tx, id)
+        .await
+        .map_err(map_repo_error)?;
     if deleted {
-        let deployment_temp = deployment.clone();
-        tokio::spawn(async move {
-            broadcast(
-                state,
-                deployment_temp.client,
-                deployment_temp.solution,
-                deployment_temp.environment,
-            )
+        audit(
+            &mut tx,
+            &meta,
+            EntityKind::Deployment,
+            ChangeAction::Deleted,
+            Some(id),
+            audit_value(&deployment),
+            None,
+        )
+        .await?;
+        tx.commit()
             .await
-        });
+            .map_err(|err| map_repo_error(err.into()))?;
+        // the row is gone, so announce the revision it would have reached
+        let notification = ChangeNotification::new(
+            &deployment,
+            deployment.revision.unwrap_or_default() + 1,
+            EntityKind::Deployment,
+            ChangeAction::Deleted,
+            vec![id],
+        );
+        tokio::spawn(async move { broadcast(state, notification).await });
         Ok(Json(deployment))
     } else {
-        Err((
-            StatusCode::BAD_REQUEST,
-            "Unable to delete deployment".to_string(),
+        Err(ApiError::bad_request(
+            ErrorCode::DeleteFailed,
+            "Unable to delete deployment",
         ))
     }
 }
@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json, debug_handler,
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Response},
+};
+use log::error;
+use prometheus::{Encoder, TextEncoder};
+use serde_json::{Value, json};
+
+use crate::{mode::server::AppState, server::error::ApiError};
+
+/// Liveness of the server. The database state is reported but doesn't fail the
+/// check, restarting the server wouldn't bring the database back.
+#[debug_handler]
+pub async fn healthz(Extension(state): Extension<Arc<AppState>>) -> Json<Value> {
+    let database = match state.storage.ping().await {
+        Ok(()) => "ok",
+        Err(_) => "unavailable",
+    };
+    Json(json!({ "status": "ok", "database": database }))
+}
+
+/// Readiness of the server, which needs a reachable database to serve the API.
+#[debug_handler]
+pub async fn readyz(Extension(state): Extension<Arc<AppState>>) -> Result<Json<Value>, ApiError> {
+    state.storage.ping().await.map_err(|err| {
+        error!("Readiness check failed: {err}");
+        ApiError::from(err)
+    })?;
+    Ok(Json(json!({ "status": "ok", "database": "ok" })))
+}
+
+#[debug_handler]
+pub async fn metrics(Extension(state): Extension<Arc<AppState>>) -> Result<Response, ApiError> {
+    let body = state.metrics.encode(&state).await.map_err(|err| {
+        error!("Unable to encode metrics: {err}");
+        ApiError::internal("Unable to encode metrics")
+    })?;
+    Ok(([(CONTENT_TYPE, TextEncoder::new().format_type())], body).into_response())
+}
@@ -1,19 +1,26 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use axum::{Extension, Json, debug_handler, extract::Query};
-use reqwest::StatusCode;
-use serde::Deserialize;
+use axum::{Extension, Json, debug_handler, http::HeaderMap, response::Response};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     mode::server::AppState,
-    objects::structs::HikariConfig,
+    objects::structs::{HikariConfig, Validate},
     server::{
-        common::{build_hikari_config, map_repo_error},
+        common::{
+            ApiJson, ApiQuery, ChangeMeta, apply_deployment_changes, assemble_hikari_config,
+            build_hikari_config, conditional_hikari_response, map_repo_error,
+        },
         dal::{
             container_dal::ContainerDAL,
             deploy_config_dal::{DeployConfigDAL, Utils},
+            maintenance_window_dal::MaintenanceWindowDAL,
             stack_config_dal::StackConfigDAL,
         },
+        diff::{DiffKind, diff_hikari_configs},
+        error::ApiError,
+        models::deploy_config::DeployConfigDTO,
+        rollout::{pin_to_rollouts, pinned_revisions},
         traits::model::DataRepository,
     },
 };
@@ -23,6 +30,8 @@ pub struct QueryParamsMetadata {
     pub client: String,
     pub environment: String,
     pub solution: String,
+    /// Node asking, so it gets the revisions its rollouts allow.
+    pub node: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -30,38 +39,157 @@ pub struct QueryParamsName {
     pub name: String,
 }
 
+/// Deployment names touched by an import, grouped by outcome.
+#[derive(Default, Debug, Serialize)]
+pub struct ImportSummary {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
 #[debug_handler]
 pub async fn get_hikari_by_metadata(
     Extension(state): Extension<Arc<AppState>>,
-    Query(QueryParamsMetadata {
+    headers: HeaderMap,
+    ApiQuery(QueryParamsMetadata {
         client,
         environment,
         solution,
-    }): Query<QueryParamsMetadata>,
-) -> Result<Json<HikariConfig>, (StatusCode, String)> {
-    let deploy_config_dal = DeployConfigDAL::new(&state.pool);
-    let stack_config_dal = StackConfigDAL::new(&state.pool);
-    let container_dal = ContainerDAL::new(&state.pool);
+        node,
+    }): ApiQuery<QueryParamsMetadata>,
+) -> Result<Response, ApiError> {
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
+    let container_dal = ContainerDAL::new(&state.storage);
+    let maintenance_window_dal = MaintenanceWindowDAL::new(&state.storage);
     let deployments = deploy_config_dal
         .find_by_metadata(&client, &environment, &solution)
         .await
         .map_err(map_repo_error)?;
-    let hikari = build_hikari_config(deployments, stack_config_dal, container_dal).await?;
-    Ok(Json(hikari))
+    let mut hikari = build_hikari_config(
+        deployments,
+        stack_config_dal,
+        container_dal,
+        maintenance_window_dal,
+    )
+    .await?;
+    pin_to_rollouts(&state, node.as_deref(), &mut hikari).await;
+    conditional_hikari_response(&headers, hikari)
 }
 
 #[debug_handler]
 pub async fn get_hikari_by_name(
     Extension(state): Extension<Arc<AppState>>,
-    Query(QueryParamsName { name }): Query<QueryParamsName>,
-) -> Result<Json<HikariConfig>, (StatusCode, String)> {
-    let deploy_config_dal = DeployConfigDAL::new(&state.pool);
-    let stack_config_dal = StackConfigDAL::new(&state.pool);
-    let container_dal = ContainerDAL::new(&state.pool);
+    headers: HeaderMap,
+    ApiQuery(QueryParamsName { name }): ApiQuery<QueryParamsName>,
+) -> Result<Response, ApiError> {
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
+    let container_dal = ContainerDAL::new(&state.storage);
+    let maintenance_window_dal = MaintenanceWindowDAL::new(&state.storage);
     let deployment = deploy_config_dal
         .find_by_name(&name)
         .await
         .map_err(map_repo_error)?;
-    let hikari = build_hikari_config(vec![deployment], stack_config_dal, container_dal).await?;
+    let hikari = build_hikari_config(
+        vec![deployment],
+        stack_config_dal,
+        container_dal,
+        maintenance_window_dal,
+    )
+    .await?;
+    conditional_hikari_response(&headers, hikari)
+}
+
+#[debug_handler]
+pub async fn get_hikari_revisions(
+    Extension(state): Extension<Arc<AppState>>,
+    ApiQuery(QueryParamsMetadata {
+        client,
+        environment,
+        solution,
+        node,
+    }): ApiQuery<QueryParamsMetadata>,
+) -> Result<Json<HashMap<String, i64>>, ApiError> {
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let deployments = deploy_config_dal
+        .find_by_metadata(&client, &environment, &solution)
+        .await
+        .map_err(map_repo_error)?;
+    let pinned = pinned_revisions(&state, node.as_deref()).await;
+    Ok(Json(
+        deployments
+            .into_iter()
+            .map(|deployment| {
+                let revision = match pinned.get(&deployment.name) {
+                    Some((_, revision)) => *revision,
+                    None => deployment.revision.unwrap_or_default(),
+                };
+                (deployment.name, revision)
+            })
+            .collect(),
+    ))
+}
+
+#[debug_handler]
+pub async fn export_hikari(
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Json<HikariConfig>, ApiError> {
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let stack_config_dal = StackConfigDAL::new(&state.storage);
+    let container_dal = ContainerDAL::new(&state.storage);
+    let maintenance_window_dal = MaintenanceWindowDAL::new(&state.storage);
+    let deployments = deploy_config_dal.find_all().await.map_err(map_repo_error)?;
+    let hikari = assemble_hikari_config(
+        deployments,
+        stack_config_dal,
+        container_dal,
+        maintenance_window_dal,
+    )
+    .await?;
     Ok(Json(hikari))
 }
+
+#[debug_handler]
+pub async fn put_hikari(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiJson(payload): ApiJson<HikariConfig>,
+) -> Result<Json<ImportSummary>, ApiError> {
+    payload.validate().map_err(ApiError::from)?;
+    let existing: HashMap<String, DeployConfigDTO> = DeployConfigDAL::new(&state.storage)
+        .find_all()
+        .await
+        .map_err(map_repo_error)?
+        .into_iter()
+        .filter(|deployment| payload.deploy_configs.contains_key(&deployment.name))
+        .map(|deployment| (deployment.name.clone(), deployment))
+        .collect();
+    let current = assemble_hikari_config(
+        existing.values().cloned().collect(),
+        StackConfigDAL::new(&state.storage),
+        ContainerDAL::new(&state.storage),
+        MaintenanceWindowDAL::new(&state.storage),
+    )
+    .await?;
+    // only deployments named in the payload are compared, so nothing is removed
+    let changes = diff_hikari_configs(&current, &payload);
+    apply_deployment_changes(&state, &meta, &existing, &current, &payload, &changes).await?;
+
+    let mut summary = ImportSummary::default();
+    let changed: HashMap<&String, DiffKind> = changes
+        .iter()
+        .map(|change| (&change.name, change.change))
+        .collect();
+    for name in payload.deploy_configs.keys() {
+        match changed.get(name) {
+            Some(DiffKind::Added) => summary.created.push(name.clone()),
+            Some(_) => summary.updated.push(name.clone()),
+            None => summary.unchanged.push(name.clone()),
+        }
+    }
+    summary.created.sort();
+    summary.updated.sort();
+    summary.unchanged.sort();
+    Ok(Json(summary))
+}
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, debug_handler};
+use serde::Deserialize;
+
+use crate::{
+    mode::server::AppState,
+    objects::structs::{ChangeAction, EntityKind, MaintenanceWindow, Validate},
+    server::{
+        common::{
+            ApiJson, ApiQuery, ChangeMeta, audit, audit_value, map_repo_error, record_change,
+        },
+        dal::{deploy_config_dal::DeployConfigDAL, maintenance_window_dal::MaintenanceWindowDAL},
+        error::ApiError,
+        models::maintenance_window::MaintenanceWindowDTO,
+        traits::model::DataRepository,
+        ws::websocket::broadcast,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+    pub id: i64,
+}
+
+#[debug_handler]
+pub async fn get_maintenance_window(
+    Extension(state): Extension<Arc<AppState>>,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Json<MaintenanceWindowDTO>, ApiError> {
+    MaintenanceWindowDAL::new(&state.storage)
+        .find_by_deployment(id)
+        .await
+        .map_err(map_repo_error)?
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError::not_found(format!(
+                "Maintenance window of deployment ID - {id} not found"
+            ))
+        })
+}
+
+/// Sets the window in which the nodes of the deployment apply its changes. The
+/// window travels with the deployment's configuration, so nodes are notified.
+#[debug_handler]
+pub async fn put_maintenance_window(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+    ApiJson(payload): ApiJson<MaintenanceWindowDTO>,
+) -> Result<Json<MaintenanceWindowDTO>, ApiError> {
+    DeployConfigDAL::new(&state.storage)
+        .find_by_id(id)
+        .await
+        .map_err(map_repo_error)?;
+    MaintenanceWindow::from(payload.clone())
+        .validate()
+        .map_err(ApiError::from)?;
+    let maintenance_window_dal = MaintenanceWindowDAL::new(&state.storage);
+    let before = maintenance_window_dal
+        .find_by_deployment(id)
+        .await
+        .map_err(map_repo_error)?;
+    let action = match before {
+        Some(_) => ChangeAction::Updated,
+        None => ChangeAction::Created,
+    };
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    let window = maintenance_window_dal
+        .upsert(
+            &mut tx,
+            MaintenanceWindowDTO {
+                deployment_id: id,
+                ..payload
+            },
+        )
+        .await
+        .map_err(map_repo_error)?;
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::MaintenanceWindow,
+        action,
+        Some(id),
+        before.as_ref().and_then(audit_value),
+        audit_value(&window),
+    )
+    .await?;
+    let notification = record_change(
+        &mut tx,
+        id,
+        EntityKind::MaintenanceWindow,
+        action,
+        vec![id],
+        &meta,
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    tokio::spawn(async move { broadcast(state, notification).await });
+    Ok(Json(window))
+}
+
+#[debug_handler]
+pub async fn delete_maintenance_window(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Json<MaintenanceWindowDTO>, ApiError> {
+    DeployConfigDAL::new(&state.storage)
+        .find_by_id(id)
+        .await
+        .map_err(map_repo_error)?;
+    let maintenance_window_dal = MaintenanceWindowDAL::new(&state.storage);
+    let Some(window) = maintenance_window_dal
+        .find_by_deployment(id)
+        .await
+        .map_err(map_repo_error)?
+    else {
+        return Err(ApiError::not_found(format!(
+            "Maintenance window of deployment ID - {id} not found"
+        )));
+    };
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    maintenance_window_dal
+        .delete(&mut tx, id)
+        .await
+        .map_err(map_repo_error)?;
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::MaintenanceWindow,
+        ChangeAction::Deleted,
+        Some(id),
+        audit_value(&window),
+        None,
+    )
+    .await?;
+    let notification = record_change(
+        &mut tx,
+        id,
+        EntityKind::MaintenanceWindow,
+        ChangeAction::Deleted,
+        vec![id],
+        &meta,
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    tokio::spawn(async move { broadcast(state, notification).await });
+    Ok(Json(window))
+}
@@ -1,4 +1,12 @@
+pub mod apply;
+pub mod audit;
 pub mod compose_stack;
 pub mod container;
 pub mod deployments;
+pub mod health;
 pub mod hikari;
+pub mod maintenance_windows;
+pub mod nodes;
+pub mod promote;
+pub mod revisions;
+pub mod rollouts;
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, debug_handler};
+use serde::Serialize;
+
+use crate::{mode::server::AppState, objects::structs::NodeStatus};
+
+/// A connected node with the last status it reported for each deployment,
+/// including changes it holds for a maintenance window.
+#[derive(Debug, Serialize)]
+pub struct NodeSummary {
+    pub node: String,
+    pub client: String,
+    pub environment: String,
+    pub solution: String,
+    pub statuses: Vec<NodeStatus>,
+}
+
+#[debug_handler]
+pub async fn get_nodes(Extension(state): Extension<Arc<AppState>>) -> Json<Vec<NodeSummary>> {
+    let mut nodes: Vec<NodeSummary> = state
+        .connections
+        .read()
+        .await
+        .values()
+        .map(|connection| {
+            let mut statuses: Vec<NodeStatus> = connection.statuses.values().cloned().collect();
+            statuses.sort_by(|a, b| a.deployment.cmp(&b.deployment));
+            NodeSummary {
+                node: connection.node.clone(),
+                client: connection.client.clone(),
+                environment: connection.environment.clone(),
+                solution: connection.solution.clone(),
+                statuses,
+            }
+        })
+        .collect();
+    nodes.sort_by(|a, b| a.node.cmp(&b.node));
+    Json(nodes)
+}
@@ -0,0 +1,214 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{Extension, Json, debug_handler};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    mode::server::AppState,
+    objects::structs::{
+        DeployConfig, EnvironmentRules, HikariConfig, PromotionRequest, StackConfig, Validate,
+    },
+    server::{
+        api::apply::ApplyResult,
+        common::{
+            ApiJson, ApiQuery, ChangeMeta, apply_deployment_changes, assemble_hikari_config,
+            map_repo_error, merge_environment, with_image_tag,
+        },
+        dal::{
+            container_dal::ContainerDAL,
+            deploy_config_dal::{DeployConfigDAL, Utils as _},
+            maintenance_window_dal::MaintenanceWindowDAL,
+            stack_config_dal::StackConfigDAL,
+        },
+        diff::diff_hikari_configs,
+        error::{ApiError, ErrorCode},
+        traits::model::DataRepository,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct QueryParamsPromote {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Changes of a promotion and the revisions they were computed from, for a
+/// preview to be applied only as long as neither deployment moves.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromotionResult {
+    #[serde(flatten)]
+    pub result: ApplyResult,
+    pub source_revision: Option<i64>,
+    pub target_revision: Option<i64>,
+}
+
+/// Copies the stacks of the `source` deployment into the `target` one,
+/// rewritten by the rules for the target's environment. Stacks are matched by
+/// name; the target keeps its metadata and, without `prune`, its other stacks.
+/// Revisions given in the request must still be those of the deployments.
+#[debug_handler]
+pub async fn promote_deployment(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiQuery(QueryParamsPromote { dry_run }): ApiQuery<QueryParamsPromote>,
+    ApiJson(payload): ApiJson<PromotionRequest>,
+) -> Result<Json<PromotionResult>, ApiError> {
+    if payload.source == payload.target {
+        return Err(ApiError::bad_request(
+            ErrorCode::ValidationFailed,
+            "A deployment can't be promoted into itself",
+        )
+        .with_field("target"));
+    }
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let source = deploy_config_dal
+        .find_by_name(&payload.source)
+        .await
+        .map_err(map_repo_error)?;
+    let target = deploy_config_dal
+        .find_by_name(&payload.target)
+        .await
+        .map_err(map_repo_error)?;
+    if source.client != target.client || source.solution != target.solution {
+        return Err(ApiError::bad_request(
+            ErrorCode::ValidationFailed,
+            format!(
+                "Deployments {} and {} belong to different clients or solutions",
+                source.name, target.name
+            ),
+        )
+        .with_field("target"));
+    }
+    let source_moved = payload
+        .source_revision
+        .is_some_and(|revision| Some(revision) != source.revision);
+    let target_moved = payload
+        .target_revision
+        .is_some_and(|revision| Some(revision) != target.revision);
+    if source_moved || target_moved {
+        return Err(ApiError::new(
+            StatusCode::PRECONDITION_FAILED,
+            ErrorCode::VersionMismatch,
+            "The deployments changed since the promotion was previewed, preview it again",
+        )
+        .with_details(json!({
+            "source_revision": source.revision,
+            "target_revision": target.revision,
+        })));
+    }
+    let (source_revision, target_revision) = (source.revision, target.revision);
+    let mut loaded = assemble_hikari_config(
+        vec![source.clone(), target.clone()],
+        StackConfigDAL::new(&state.storage),
+        ContainerDAL::new(&state.storage),
+        MaintenanceWindowDAL::new(&state.storage),
+    )
+    .await?;
+    let (Some(from), Some(into)) = (
+        loaded.deploy_configs.remove(&source.name),
+        loaded.deploy_configs.remove(&target.name),
+    ) else {
+        return Err(ApiError::internal(
+            "Unable to load the promoted deployments",
+        ));
+    };
+    let rules = payload
+        .rules
+        .environments
+        .get(&target.environment)
+        .cloned()
+        .unwrap_or_default();
+    let promoted = promote_deploy_config(&from, &into, &rules, payload.prune);
+    let current = HikariConfig {
+        version: loaded.version.clone(),
+        deploy_configs: HashMap::from([(target.name.clone(), into)]),
+    };
+    let desired = HikariConfig {
+        version: loaded.version,
+        deploy_configs: HashMap::from([(target.name.clone(), promoted)]),
+    };
+    desired.validate().map_err(ApiError::from)?;
+    let changes = diff_hikari_configs(&current, &desired);
+    if !dry_run && !changes.is_empty() {
+        // the revision on the target says where its stacks came from
+        let meta = ChangeMeta {
+            message: meta.message.clone().or_else(|| {
+                Some(format!(
+                    "promoted from {} revision {}",
+                    source.name,
+                    source.revision.unwrap_or_default()
+                ))
+            }),
+            ..meta
+        };
+        let existing = HashMap::from([(target.name.clone(), target)]);
+        apply_deployment_changes(&state, &meta, &existing, &current, &desired, &changes).await?;
+    }
+    Ok(Json(PromotionResult {
+        result: ApplyResult { dry_run, changes },
+        source_revision,
+        target_revision,
+    }))
+}
+
+/// `target` with the stacks of `source` laid over it, after `rules`.
+fn promote_deploy_config(
+    source: &DeployConfig,
+    target: &DeployConfig,
+    rules: &EnvironmentRules,
+    prune: bool,
+) -> DeployConfig {
+    let mut deploy_stacks: Vec<StackConfig> = target
+        .deploy_stacks
+        .iter()
+        .filter(|stack| {
+            !prune
+                && !source
+                    .deploy_stacks
+                    .iter()
+                    .any(|promoted| promoted.stack_name == stack.stack_name)
+        })
+        .cloned()
+        .collect();
+    for stack in &source.deploy_stacks {
+        let mut stack = stack.clone();
+        // unless a rule says otherwise, the stack lives where the target had it
+        let home_directory = rules.home_directories.get(&stack.stack_name).or(target
+            .deploy_stacks
+            .iter()
+            .find(|existing| existing.stack_name == stack.stack_name)
+            .map(|existing| &existing.home_directory));
+        if let Some(home_directory) = home_directory {
+            stack.home_directory.clone_from(home_directory);
+        }
+        for (service_name, service) in stack.compose_spec.services.iter_mut() {
+            let service_rules = rules.services.get(service_name);
+            let image_tag = service_rules
+                .and_then(|r| r.image_tag.as_ref())
+                .or(rules.image_tag.as_ref());
+            if let Some(tag) = image_tag {
+                service.image = with_image_tag(&service.image, tag);
+            }
+            let overrides: Vec<String> = rules
+                .environment
+                .iter()
+                .chain(service_rules.map_or(&[][..], |r| r.environment.as_slice()))
+                .cloned()
+                .collect();
+            if !overrides.is_empty() {
+                service.environment =
+                    Some(merge_environment(service.environment.take(), &overrides));
+            }
+            if let Some(ports) = service_rules.and_then(|r| r.ports.clone()) {
+                service.ports = Some(ports);
+            }
+        }
+        deploy_stacks.push(stack);
+    }
+    DeployConfig {
+        deploy_stacks,
+        ..target.clone()
+    }
+}
@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, debug_handler};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mode::server::AppState,
+    objects::structs::{ChangeAction, ChangeNotification, DeployConfig, EntityKind},
+    server::{
+        common::{
+            ApiQuery, ChangeMeta, audit, audit_value, deployment_snapshot, map_repo_error,
+            record_change,
+        },
+        dal::{
+            config_revision_dal::ConfigRevisionDAL, deploy_config_dal::DeployConfigDAL,
+            hikari_dal::sync_deploy_stacks,
+        },
+        diff::{DeploymentDiff, diff_hikari_configs},
+        error::ApiError,
+        models::{config_revision::ConfigRevisionDTO, deploy_config::DeployConfigDTO},
+        traits::model::DataRepository,
+        ws::websocket::broadcast,
+    },
+    utils::error::RepoError,
+};
+
+/// Deployment whose history is read, by ID or, to include deleted deployments,
+/// by name.
+#[derive(Deserialize)]
+pub struct QueryParamsHistory {
+    pub id: Option<i64>,
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct QueryParamsRevision {
+    pub id: i64,
+    pub revision: i64,
+}
+
+#[derive(Deserialize)]
+pub struct QueryParamsHistoryRevision {
+    pub id: Option<i64>,
+    pub name: Option<String>,
+    pub revision: i64,
+}
+
+#[derive(Deserialize)]
+pub struct QueryParamsDiff {
+    pub id: i64,
+    pub from: i64,
+    pub to: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevisionDiff {
+    pub deployment_id: i64,
+    pub from: i64,
+    pub to: i64,
+    pub changes: Vec<DeploymentDiff>,
+}
+
+/// Deployment a history is read for.
+enum History {
+    Id(i64),
+    /// Also covers deleted deployments that went by the name.
+    Name(String),
+}
+
+impl History {
+    /// Picks the deployment by either `id` or `name`, answering a request with
+    /// both or neither.
+    fn of(id: Option<i64>, name: Option<String>) -> Result<Self, ApiError> {
+        match (id, name) {
+            (Some(id), None) => Ok(Self::Id(id)),
+            (None, Some(name)) => Ok(Self::Name(name)),
+            (Some(_), Some(_)) => Err(ApiError::unexpected_field("name")),
+            (None, None) => Err(ApiError::missing_field("id")),
+        }
+    }
+}
+
+#[debug_handler]
+pub async fn get_revisions(
+    Extension(state): Extension<Arc<AppState>>,
+    ApiQuery(QueryParamsHistory { id, name }): ApiQuery<QueryParamsHistory>,
+) -> Result<Json<Vec<ConfigRevisionDTO>>, ApiError> {
+    let config_revision_dal = ConfigRevisionDAL::new(&state.storage);
+    let id = match History::of(id, name)? {
+        History::Id(id) => id,
+        History::Name(name) => {
+            return config_revision_dal
+                .find_by_name(&name)
+                .await
+                .map(Json)
+                .map_err(map_repo_error);
+        }
+    };
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let record_exists = deploy_config_dal.exists(id).await.map_err(map_repo_error)?;
+    if !record_exists {
+        return Err(ApiError::not_found(format!(
+            "Deployment of ID - {id} not found"
+        )));
+    }
+    let value = config_revision_dal
+        .find_by_deployment(id)
+        .await
+        .map_err(map_repo_error)?;
+    Ok(Json(value))
+}
+
+#[debug_handler]
+pub async fn get_revision(
+    Extension(state): Extension<Arc<AppState>>,
+    ApiQuery(QueryParamsHistoryRevision { id, name, revision }): ApiQuery<
+        QueryParamsHistoryRevision,
+    >,
+) -> Result<Json<ConfigRevisionDTO>, ApiError> {
+    let config_revision_dal = ConfigRevisionDAL::new(&state.storage);
+    let value = match History::of(id, name)? {
+        History::Id(id) => config_revision_dal.find_by_revision(id, revision).await,
+        History::Name(name) => {
+            config_revision_dal
+                .find_by_name_revision(&name, revision)
+                .await
+        }
+    }
+    .map_err(map_repo_error)?;
+    Ok(Json(value))
+}
+
+#[debug_handler]
+pub async fn get_revision_diff(
+    Extension(state): Extension<Arc<AppState>>,
+    ApiQuery(QueryParamsDiff { id, from, to }): ApiQuery<QueryParamsDiff>,
+) -> Result<Json<RevisionDiff>, ApiError> {
+    let config_revision_dal = ConfigRevisionDAL::new(&state.storage);
+    let from_revision = config_revision_dal
+        .find_by_revision(id, from)
+        .await
+        .map_err(map_repo_error)?;
+    let to_revision = config_revision_dal
+        .find_by_revision(id, to)
+        .await
+        .map_err(map_repo_error)?;
+    Ok(Json(RevisionDiff {
+        deployment_id: id,
+        from,
+        to,
+        changes: diff_hikari_configs(
+            &from_revision.snapshot.unwrap_or_default(),
+            &to_revision.snapshot.unwrap_or_default(),
+        ),
+    }))
+}
+
+#[debug_handler]
+pub async fn rollback_deployment(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiQuery(QueryParamsRevision { id, revision }): ApiQuery<QueryParamsRevision>,
+) -> Result<Json<DeployConfigDTO>, ApiError> {
+    let deploy_config_dal = DeployConfigDAL::new(&state.storage);
+    let record_exists = deploy_config_dal.exists(id).await.map_err(map_repo_error)?;
+    if !record_exists {
+        return Err(ApiError::not_found(format!(
+            "Deployment of ID - {id} not found"
+        )));
+    }
+    let deployment = deploy_config_dal
+        .find_by_id(id)
+        .await
+        .map_err(map_repo_error)?;
+    let notification = restore_revision(&state, &meta, &deployment, revision).await?;
+    tokio::spawn(async move { broadcast(state, notification).await });
+    deploy_config_dal
+        .find_by_id(id)
+        .await
+        .map(Json)
+        .map_err(map_repo_error)
+}
+
+/// Puts the stacks and containers of `revision` back in place as a new
+/// revision of `deployment`, in one transaction, returning the notification
+/// announcing it. A revision without a snapshot is not found.
+pub async fn restore_revision(
+    state: &AppState,
+    meta: &ChangeMeta,
+    deployment: &DeployConfigDTO,
+    revision: i64,
+) -> Result<ChangeNotification, ApiError> {
+    let id = deployment.id.unwrap_or_default();
+    let target = match ConfigRevisionDAL::new(&state.storage)
+        .find_by_revision(id, revision)
+        .await
+    {
+        Ok(target) => target,
+        Err(RepoError::Db(sqlx::Error::RowNotFound)) => {
+            return Err(ApiError::not_found(format!(
+                "Revision {revision} of deployment ID - {id} not found"
+            )));
+        }
+        Err(err) => return Err(map_repo_error(err)),
+    };
+    // only stacks and containers are restored, the deployment keeps its metadata
+    let stacks = target
+        .snapshot
+        .and_then(|snapshot| snapshot.deploy_configs.into_values().next())
+        .map(|deploy_config| deploy_config.deploy_stacks)
+        .unwrap_or_default();
+
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    let before = deployment_snapshot(&mut tx, deployment)
+        .await?
+        .deploy_configs
+        .into_values()
+        .next();
+    sync_deploy_stacks(&mut tx, id, &stacks)
+        .await
+        .map_err(map_repo_error)?;
+    let after = before.clone().map(|deploy_config| DeployConfig {
+        deploy_stacks: stacks,
+        ..deploy_config
+    });
+    audit(
+        &mut tx,
+        meta,
+        EntityKind::Deployment,
+        ChangeAction::Updated,
+        Some(id),
+        before.as_ref().and_then(audit_value),
+        after.as_ref().and_then(audit_value),
+    )
+    .await?;
+    let meta = ChangeMeta {
+        message: meta
+            .message
+            .clone()
+            .or_else(|| Some(format!("rollback to revision {revision}"))),
+        ..meta.clone()
+    };
+    let notification = record_change(
+        &mut tx,
+        id,
+        EntityKind::Deployment,
+        ChangeAction::Updated,
+        vec![id],
+        &meta,
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    Ok(notification)
+}
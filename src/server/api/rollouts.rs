@@ -0,0 +1,191 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, debug_handler};
+use serde::Deserialize;
+
+use crate::{
+    mode::server::AppState,
+    objects::structs::{ChangeAction, EntityKind},
+    server::{
+        common::{ApiJson, ApiQuery, ChangeMeta, audit, audit_value, map_repo_error},
+        dal::{deploy_config_dal::DeployConfigDAL, rollout_policy_dal::RolloutPolicyDAL},
+        error::{ApiError, ErrorCode},
+        models::rollout_policy::RolloutPolicyDTO,
+        rollout::{Rollout, abort_rollout, complete_rollout},
+        traits::model::DataRepository,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+    pub id: i64,
+}
+
+#[debug_handler]
+pub async fn get_rollouts(Extension(state): Extension<Arc<AppState>>) -> Json<Vec<Rollout>> {
+    let mut rollouts: Vec<Rollout> = state.rollouts.read().await.values().cloned().collect();
+    rollouts.sort_by(|a, b| a.deployment.cmp(&b.deployment));
+    Json(rollouts)
+}
+
+#[debug_handler]
+pub async fn get_rollout_policy(
+    Extension(state): Extension<Arc<AppState>>,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Json<RolloutPolicyDTO>, ApiError> {
+    RolloutPolicyDAL::new(&state.storage)
+        .find_by_deployment(id)
+        .await
+        .map_err(map_repo_error)?
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError::not_found(format!("Rollout policy of deployment ID - {id} not found"))
+        })
+}
+
+#[debug_handler]
+pub async fn put_rollout_policy(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+    ApiJson(payload): ApiJson<RolloutPolicyDTO>,
+) -> Result<Json<RolloutPolicyDTO>, ApiError> {
+    let deployment_exists = DeployConfigDAL::new(&state.storage)
+        .exists(id)
+        .await
+        .map_err(map_repo_error)?;
+    if !deployment_exists {
+        return Err(ApiError::not_found(format!(
+            "Deployment of ID - {id} not found"
+        )));
+    }
+    if payload.canary_nodes.is_some() && payload.canary_percent.is_some() {
+        return Err(ApiError::bad_request(
+            ErrorCode::ValidationFailed,
+            "Set either canary_nodes or canary_percent, not both",
+        )
+        .with_field("canary_percent"));
+    }
+    let rollout_policy_dal = RolloutPolicyDAL::new(&state.storage);
+    let before = rollout_policy_dal
+        .find_by_deployment(id)
+        .await
+        .map_err(map_repo_error)?;
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    let policy = rollout_policy_dal
+        .upsert(
+            &mut tx,
+            RolloutPolicyDTO {
+                deployment_id: id,
+                ..payload
+            },
+        )
+        .await
+        .map_err(map_repo_error)?;
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::RolloutPolicy,
+        match before {
+            Some(_) => ChangeAction::Updated,
+            None => ChangeAction::Created,
+        },
+        Some(id),
+        before.as_ref().and_then(audit_value),
+        audit_value(&policy),
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    Ok(Json(policy))
+}
+
+#[debug_handler]
+pub async fn delete_rollout_policy(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Json<RolloutPolicyDTO>, ApiError> {
+    let rollout_policy_dal = RolloutPolicyDAL::new(&state.storage);
+    let Some(policy) = rollout_policy_dal
+        .find_by_deployment(id)
+        .await
+        .map_err(map_repo_error)?
+    else {
+        return Err(ApiError::not_found(format!(
+            "Rollout policy of deployment ID - {id} not found"
+        )));
+    };
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    rollout_policy_dal
+        .delete(&mut tx, id)
+        .await
+        .map_err(map_repo_error)?;
+    audit(
+        &mut tx,
+        &meta,
+        EntityKind::RolloutPolicy,
+        ChangeAction::Deleted,
+        Some(id),
+        audit_value(&policy),
+        None,
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    Ok(Json(policy))
+}
+
+/// Name of the deployment of ID `id` when it is being rolled out.
+async fn rollout_name(state: &AppState, id: i64) -> Result<String, ApiError> {
+    state
+        .rollouts
+        .read()
+        .await
+        .values()
+        .find(|rollout| rollout.deployment_id == id)
+        .map(|rollout| rollout.deployment.clone())
+        .ok_or_else(|| ApiError::not_found(format!("No rollout of deployment ID - {id}")))
+}
+
+/// Sends the revision being rolled out to every remaining node, whatever the
+/// canaries reported.
+#[debug_handler]
+pub async fn resume_deployment_rollout(
+    Extension(state): Extension<Arc<AppState>>,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Json<Rollout>, ApiError> {
+    let name = rollout_name(&state, id).await?;
+    complete_rollout(&state, &name)
+        .await
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("No rollout of deployment ID - {id}")))
+}
+
+#[debug_handler]
+pub async fn abort_deployment_rollout(
+    Extension(state): Extension<Arc<AppState>>,
+    meta: ChangeMeta,
+    ApiQuery(QueryParams { id }): ApiQuery<QueryParams>,
+) -> Result<Json<Rollout>, ApiError> {
+    let name = rollout_name(&state, id).await?;
+    abort_rollout(
+        &state,
+        &meta,
+        &name,
+        &format!("aborted by {}", meta.actor()),
+    )
+    .await?
+    .map(Json)
+    .ok_or_else(|| ApiError::not_found(format!("No rollout of deployment ID - {id}")))
+}
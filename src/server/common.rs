@@ -1,124 +1,948 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
 
-use log::error;
+use axum::{
+    Json,
+    extract::{ConnectInfo, FromRequest, FromRequestParts, Query},
+    http::{
+        HeaderMap, HeaderValue,
+        header::{CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH},
+        request::Parts,
+    },
+    response::{IntoResponse, Response},
+};
+use openssl::sha::sha256;
 use reqwest::StatusCode;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
 
 use crate::{
-    objects::structs::{ComposeSpec, Container, DeployConfig, HikariConfig, StackConfig, Validate},
+    mode::server::AppState,
+    objects::structs::{
+        ChangeAction, ChangeNotification, ComposeSpec, Container, DeployConfig, DriftPolicy,
+        EntityKind, HikariConfig, StackConfig, Validate,
+    },
     server::{
-        dal::{container_dal::ContainerDAL, stack_config_dal::StackConfigDAL},
-        models::deploy_config::DeployConfigDTO,
-        traits::model::DataRepository,
+        dal::{
+            audit_log_dal::record_audit,
+            config_revision_dal::record_revision,
+            container_dal::{ContainerDAL, Utils as _},
+            hikari_dal::{
+                bump_revision, delete_deployment, find_deploy_tree, sync_deploy_stacks,
+                upsert_deployment,
+            },
+            maintenance_window_dal::{MaintenanceWindowDAL, find_window},
+            stack_config_dal::{StackConfigDAL, Utils as _},
+        },
+        diff::{DeploymentDiff, DiffKind},
+        error::{ApiError, ErrorCode},
+        models::{
+            audit_log::AuditLogDTO,
+            config_revision::ConfigRevisionDTO,
+            container::ContainerDTO,
+            deploy_config::DeployConfigDTO,
+            maintenance_window::MaintenanceWindowDTO,
+            page::{Cursor, PageParams, PageRequest},
+            stack_config::StackConfigDTO,
+        },
+        request_id::{RequestId, new_request_id},
+        storage::StorageTx,
+        traits::model::Versioned,
+        ws::websocket::broadcast,
     },
     utils::error::RepoError,
 };
 
-pub fn map_repo_error(e: RepoError) -> (StatusCode, String) {
-    error!("{e}");
-    match e {
-        RepoError::Db(db_err) => match db_err {
-            sqlx::Error::Database(db_err) => {
-                if db_err.is_unique_violation() {
-                    let c = db_err.constraint().unwrap_or("unknown");
-                    return (
-                        StatusCode::CONFLICT,
-                        format!("Duplicate entry: `{c}` constraint"),
-                    );
-                }
-                if db_err.is_foreign_key_violation() {
-                    let c = db_err.constraint().unwrap_or("unknown");
-                    return (
-                        StatusCode::CONFLICT,
-                        format!("Foreign key violation: `{c}` constraint"),
-                    );
-                }
-                if db_err.is_check_violation() {
-                    let c = db_err.constraint().unwrap_or("unknown");
-                    return (
-                        StatusCode::BAD_REQUEST,
-                        format!("Check violation: `{c}` constraint"),
-                    );
-                }
-                (StatusCode::INTERNAL_SERVER_ERROR, "Database error".into())
+const DEFAULT_PAGE_LIMIT: i64 = 100;
+const MAX_PAGE_LIMIT: i64 = 1000;
+
+/// Validates list query parameters against the sortable columns of the
+/// endpoint. The first entry of `sort_fields` is the default sort.
+pub fn page_request(
+    params: PageParams,
+    sort_fields: &[&'static str],
+) -> Result<PageRequest, ApiError> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if !(1..=MAX_PAGE_LIMIT).contains(&limit) {
+        return Err(ApiError::bad_request(
+            ErrorCode::InvalidQuery,
+            format!("limit must be between 1 and {MAX_PAGE_LIMIT}"),
+        )
+        .with_field("limit"));
+    }
+    let sort = match params.sort {
+        Some(sort) => *sort_fields
+            .iter()
+            .find(|field| **field == sort)
+            .ok_or_else(|| {
+                ApiError::bad_request(
+                    ErrorCode::InvalidQuery,
+                    format!(
+                        "Cannot sort by `{sort}`, expected one of: {}",
+                        sort_fields.join(", ")
+                    ),
+                )
+                .with_field("sort")
+                .with_details(json!({ "allowed": sort_fields }))
+            })?,
+        None => sort_fields[0],
+    };
+    let after = match params.cursor {
+        Some(cursor) => Some(
+            Cursor::decode(&cursor)
+                .filter(|after| after.sort == sort && after.order == params.order)
+                .ok_or_else(|| {
+                    ApiError::bad_request(
+                        ErrorCode::InvalidQuery,
+                        format!("Invalid cursor `{cursor}` for this sort and order"),
+                    )
+                    .with_field("cursor")
+                })?,
+        ),
+        None => None,
+    };
+    Ok(PageRequest {
+        limit,
+        after,
+        sort,
+        order: params.order,
+    })
+}
+
+pub fn map_repo_error(e: RepoError) -> ApiError {
+    e.into()
+}
+
+/// Who made a change and why, taken from the `X-Hikari-Author` and
+/// `X-Hikari-Message` request headers, along with where the request came from.
+/// `X-Hikari-Urgent: true` lets the change skip maintenance windows.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ChangeMeta {
+    pub author: Option<String>,
+    pub message: Option<String>,
+    pub urgent: bool,
+    pub request_id: Option<String>,
+    pub source_ip: Option<String>,
+}
+
+impl ChangeMeta {
+    pub fn actor(&self) -> String {
+        self.author.clone().unwrap_or_else(|| "anonymous".into())
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ChangeMeta {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        Ok(Self {
+            author: header("x-hikari-author"),
+            message: header("x-hikari-message"),
+            urgent: header("x-hikari-urgent")
+                .is_some_and(|value| matches!(value.trim(), "true" | "1" | "yes")),
+            request_id: parts
+                .extensions
+                .get::<RequestId>()
+                .map(|RequestId(id)| id.clone())
+                .or_else(|| Some(new_request_id())),
+            source_ip: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string()),
+        })
+    }
+}
+
+/// [`Json`] request body answering rejections with an [`ApiError`].
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+/// [`Query`] parameters answering rejections with an [`ApiError`].
+#[derive(FromRequestParts)]
+#[from_request(via(Query), rejection(ApiError))]
+pub struct ApiQuery<T>(pub T);
+
+/// Version the client based its change on, from an `If-Match` header holding
+/// the `ETag` of a previous response. Absent or `*` matches any version.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IfMatch(pub Option<i64>);
+
+impl IfMatch {
+    /// Fails with `412 Precondition Failed` unless `current` is the expected
+    /// version.
+    pub fn check(self, current: Option<i64>) -> Result<(), ApiError> {
+        match self.0 {
+            Some(expected) if Some(expected) != current => Err(version_mismatch(current)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(IF_MATCH) else {
+            return Ok(Self(None));
+        };
+        let tag = value.to_str().unwrap_or_default().trim();
+        if tag == "*" {
+            return Ok(Self(None));
+        }
+        tag.trim_start_matches("W/")
+            .trim_matches('"')
+            .parse::<i64>()
+            .map(|version| Self(Some(version)))
+            .map_err(|_| {
+                ApiError::bad_request(
+                    ErrorCode::InvalidHeader,
+                    format!("Invalid If-Match header `{tag}`, expected an ETag such as \"3\""),
+                )
+                .with_field("If-Match")
+            })
+    }
+}
+
+/// Answer for a change based on a version other than `current`.
+pub fn version_mismatch(current: Option<i64>) -> ApiError {
+    ApiError::new(
+        StatusCode::PRECONDITION_FAILED,
+        ErrorCode::VersionMismatch,
+        "The record was modified by someone else, fetch it again and retry",
+    )
+    .with_details(json!({ "current_version": current }))
+}
+
+/// JSON response carrying the version of the entity as its `ETag`, for the
+/// client to send back in `If-Match`.
+pub struct Tagged<T>(pub T);
+
+impl<T: Serialize + Versioned> IntoResponse for Tagged<T> {
+    fn into_response(self) -> Response {
+        let etag = self
+            .0
+            .version()
+            .and_then(|version| HeaderValue::from_str(&format!("\"{version}\"")).ok());
+        let mut response = Json(self.0).into_response();
+        if let Some(etag) = etag {
+            response.headers_mut().insert(ETAG, etag);
+        }
+        response
+    }
+}
+
+/// Applies `patch` to `target` following JSON Merge Patch (RFC 7386): objects
+/// merge recursively, `null` removes a member and anything else replaces it.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
             }
-            sqlx::Error::RowNotFound => (StatusCode::NOT_FOUND, "Record not found".into()),
-            sqlx::Error::Io(err) => (StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
-            sqlx::Error::Protocol(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-            sqlx::Error::Tls(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-            sqlx::Error::PoolTimedOut => (
-                StatusCode::SERVICE_UNAVAILABLE,
-                "Connection timed out".into(),
-            ),
-            sqlx::Error::TypeNotFound { type_name } => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Type not found: {type_name}"),
-            ),
-            sqlx::Error::ColumnNotFound(col) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Column not found: {col}"),
-            ),
-            sqlx::Error::ColumnIndexOutOfBounds { index, len } => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Column index out of bounds: {index}/{len}"),
-            ),
-            _ => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "INTERNAL SERVER ERROR".into(),
-            ),
-        },
-        RepoError::Validation(err) => (StatusCode::BAD_REQUEST, err.to_string()),
+        }
     }
 }
 
-pub async fn build_hikari_config(
-    deployments: Vec<DeployConfigDTO>,
-    stack_config_dal: StackConfigDAL,
-    container_dal: ContainerDAL,
-) -> Result<HikariConfig, (StatusCode, String)> {
-    let mut deploy_configs: HashMap<String, DeployConfig> = HashMap::new();
-    for deploy_config_dto in deployments {
-        let mut deploy_stacks: Vec<StackConfig> = Vec::new();
-        if let Some(stack_ids) = deploy_config_dto.stack_ids.clone() {
-            for stack_id in stack_ids {
-                let stack_config_dto = stack_config_dal
-                    .find_by_id(stack_id)
+/// `current` with a merge `patch` applied, rejecting patches that leave an
+/// invalid entity behind.
+pub fn patched<T: Serialize + DeserializeOwned>(current: &T, patch: &Value) -> Result<T, ApiError> {
+    let mut value = serde_json::to_value(current).map_err(|e| ApiError::internal(e.to_string()))?;
+    merge_patch(&mut value, patch);
+    serde_json::from_value(value).map_err(|err| {
+        ApiError::bad_request(
+            ErrorCode::InvalidPatch,
+            format!("The patched record is invalid: {err}"),
+        )
+    })
+}
+
+/// JSON form of an entity for the `before` and `after` of an audit entry.
+pub fn audit_value<T: Serialize>(value: &T) -> Option<Value> {
+    serde_json::to_value(value).ok()
+}
+
+/// Appends an audit entry for a mutation to `tx`, the transaction making it.
+pub async fn audit(
+    tx: &mut StorageTx,
+    meta: &ChangeMeta,
+    entity: EntityKind,
+    action: ChangeAction,
+    entity_id: Option<i64>,
+    before: Option<Value>,
+    after: Option<Value>,
+) -> Result<(), ApiError> {
+    record_audit(
+        tx,
+        &AuditLogDTO {
+            id: None,
+            actor: meta.actor(),
+            action: action.to_string(),
+            entity: entity.to_string(),
+            entity_id,
+            before,
+            after,
+            request_id: meta.request_id.clone().unwrap_or_default(),
+            source_ip: meta.source_ip.clone(),
+            created_at: None,
+        },
+    )
+    .await
+    .map_err(map_repo_error)
+}
+
+/// Bumps the revision of deployment `deployment_id` and stores a snapshot of
+/// the resulting config in its history, both within `tx`, the transaction
+/// making the change. Returns the notification announcing it to the agents of
+/// that deployment, to broadcast once `tx` is committed.
+pub async fn record_change(
+    tx: &mut StorageTx,
+    deployment_id: i64,
+    entity: EntityKind,
+    action: ChangeAction,
+    entity_ids: Vec<i64>,
+    meta: &ChangeMeta,
+) -> Result<ChangeNotification, ApiError> {
+    let deployment = bump_revision(tx, deployment_id)
+        .await
+        .map_err(map_repo_error)?;
+    let revision = deployment.revision.unwrap_or_default();
+    // the revision is implied by the history row, keep it out of the snapshot
+    let snapshot = deployment_snapshot(
+        tx,
+        &DeployConfigDTO {
+            revision: None,
+            ..deployment.clone()
+        },
+    )
+    .await?;
+    record_revision(
+        tx,
+        ConfigRevisionDTO {
+            id: None,
+            deployment_id: Some(deployment_id),
+            deployment_name: deployment.name.clone(),
+            revision,
+            author: meta.actor(),
+            message: meta
+                .message
+                .clone()
+                .unwrap_or_else(|| format!("{entity} {entity_ids:?} {action}")),
+            created_at: None,
+            snapshot: Some(snapshot),
+        },
+    )
+    .await
+    .map_err(map_repo_error)?;
+    Ok(ChangeNotification {
+        urgent: meta.urgent,
+        ..ChangeNotification::new(&deployment, revision, entity, action, entity_ids)
+    })
+}
+
+/// Config of `deployment` with its stacks, containers and maintenance window as
+/// `tx` sees them.
+pub async fn deployment_snapshot(
+    tx: &mut StorageTx,
+    deployment: &DeployConfigDTO,
+) -> Result<HikariConfig, ApiError> {
+    let id = deployment.id.unwrap_or_default();
+    let (stacks, containers) = find_deploy_tree(tx, id).await.map_err(map_repo_error)?;
+    let window = find_window(tx, id).await.map_err(map_repo_error)?;
+    Ok(hikari_tree(
+        vec![deployment.clone()],
+        stacks,
+        containers,
+        window.into_iter().collect(),
+    ))
+}
+
+/// Copy of `notification` addressed to the nodes matching `previous`, when the
+/// deployment's metadata changed and those nodes have to drop it.
+pub fn previous_notification(
+    previous: &DeployConfigDTO,
+    notification: &ChangeNotification,
+) -> Option<ChangeNotification> {
+    (previous.client != notification.client
+        || previous.environment != notification.environment
+        || previous.solution != notification.solution)
+        .then(|| ChangeNotification {
+            client: previous.client.clone(),
+            environment: previous.environment.clone(),
+            solution: previous.solution.clone(),
+            ..notification.clone()
+        })
+}
+
+/// Writes `changes` to the database in a single transaction, taking the
+/// content of added and modified deployments from `desired` and the IDs of
+/// removed ones from `existing`, with one audit entry per deployment comparing
+/// `current` to `desired`. Every deployment that is still around gets a new
+/// revision in the same transaction, the affected nodes are notified once it
+/// is committed.
+pub async fn apply_deployment_changes(
+    state: &Arc<AppState>,
+    meta: &ChangeMeta,
+    existing: &HashMap<String, DeployConfigDTO>,
+    current: &HikariConfig,
+    desired: &HikariConfig,
+    changes: &[DeploymentDiff],
+) -> Result<(), ApiError> {
+    let mut notifications: Vec<(ChangeNotification, Option<ChangeNotification>)> = Vec::new();
+    let maintenance_window_dal = MaintenanceWindowDAL::new(&state.storage);
+    let mut tx = state
+        .storage
+        .begin()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+    for change in changes {
+        let previous = existing.get(&change.name);
+        match (change.change, desired.deploy_configs.get(&change.name)) {
+            (DiffKind::Removed, _) | (_, None) => {
+                let Some(previous) = previous else {
+                    continue;
+                };
+                let id = previous.id.unwrap_or_default();
+                delete_deployment(&mut tx, id)
+                    .await
+                    .map_err(map_repo_error)?;
+                audit(
+                    &mut tx,
+                    meta,
+                    EntityKind::Deployment,
+                    ChangeAction::Deleted,
+                    Some(id),
+                    current
+                        .deploy_configs
+                        .get(&change.name)
+                        .and_then(audit_value),
+                    None,
+                )
+                .await?;
+                // the row is gone, so announce the revision it would have reached
+                let notification = ChangeNotification::new(
+                    previous,
+                    previous.revision.unwrap_or_default() + 1,
+                    EntityKind::Deployment,
+                    ChangeAction::Deleted,
+                    vec![id],
+                );
+                notifications.push((notification, None));
+            }
+            (_, Some(deploy_config)) => {
+                let id = upsert_deployment(&mut tx, &change.name, deploy_config)
+                    .await
+                    .map_err(map_repo_error)?;
+                sync_deploy_stacks(&mut tx, id, &deploy_config.deploy_stacks)
                     .await
                     .map_err(map_repo_error)?;
-                let mut services: HashMap<String, Container> = HashMap::new();
-                if let Some(container_ids) = stack_config_dto.containers.clone() {
-                    for container_id in container_ids {
-                        let container_dto = container_dal
-                            .find_by_id(container_id)
+                match &deploy_config.maintenance_window {
+                    Some(window) => {
+                        maintenance_window_dal
+                            .upsert(
+                                &mut tx,
+                                MaintenanceWindowDTO {
+                                    deployment_id: id,
+                                    schedule: window.schedule.clone(),
+                                    duration_minutes: window.duration_minutes,
+                                    timezone: window.timezone.clone(),
+                                },
+                            )
+                            .await
+                            .map_err(map_repo_error)?;
+                    }
+                    None => {
+                        maintenance_window_dal
+                            .delete(&mut tx, id)
                             .await
                             .map_err(map_repo_error)?;
-                        let container: Container = container_dto.clone().into();
-                        services.insert(container_dto.service_name, container);
                     }
                 }
-                deploy_stacks.push(StackConfig {
-                    stack_name: stack_config_dto.stack_name,
-                    filename: stack_config_dto.filename,
-                    home_directory: stack_config_dto.home_directory,
-                    compose_spec: ComposeSpec { services },
-                });
+                let action = match previous {
+                    Some(_) => ChangeAction::Updated,
+                    None => ChangeAction::Created,
+                };
+                audit(
+                    &mut tx,
+                    meta,
+                    EntityKind::Deployment,
+                    action,
+                    Some(id),
+                    current
+                        .deploy_configs
+                        .get(&change.name)
+                        .and_then(audit_value),
+                    audit_value(deploy_config),
+                )
+                .await?;
+                let notification =
+                    record_change(&mut tx, id, EntityKind::Deployment, action, vec![id], meta)
+                        .await?;
+                let previous = previous.and_then(|p| previous_notification(p, &notification));
+                notifications.push((notification, previous));
             }
         }
+    }
+    tx.commit()
+        .await
+        .map_err(|err| map_repo_error(err.into()))?;
+
+    for (notification, previous) in notifications {
+        let broadcast_state = state.clone();
+        tokio::spawn(async move {
+            broadcast(broadcast_state.clone(), notification).await;
+            if let Some(previous) = previous {
+                broadcast(broadcast_state, previous).await;
+            }
+        });
+    }
+    Ok(())
+}
+
+pub async fn build_hikari_config(
+    deployments: Vec<DeployConfigDTO>,
+    stack_config_dal: StackConfigDAL,
+    container_dal: ContainerDAL,
+    maintenance_window_dal: MaintenanceWindowDAL,
+) -> Result<HikariConfig, ApiError> {
+    let hikari = assemble_hikari_config(
+        deployments,
+        stack_config_dal,
+        container_dal,
+        maintenance_window_dal,
+    )
+    .await?;
+    hikari.validate().map_err(ApiError::from)?;
+    Ok(hikari)
+}
+
+/// Same as [`build_hikari_config`] without validation, so deployments that are
+/// still being put together (no stacks yet) can be snapshotted.
+pub async fn assemble_hikari_config(
+    deployments: Vec<DeployConfigDTO>,
+    stack_config_dal: StackConfigDAL,
+    container_dal: ContainerDAL,
+    maintenance_window_dal: MaintenanceWindowDAL,
+) -> Result<HikariConfig, ApiError> {
+    // load the whole tree with one query per level instead of one per row
+    let deployment_ids: Vec<i64> = deployments.iter().filter_map(|d| d.id).collect();
+    let stacks = stack_config_dal
+        .find_by_deployment_ids(&deployment_ids)
+        .await
+        .map_err(map_repo_error)?;
+    let stack_ids: Vec<i64> = stacks.iter().filter_map(|s| s.id).collect();
+    let containers = container_dal
+        .find_by_stack_ids(&stack_ids)
+        .await
+        .map_err(map_repo_error)?;
+    let windows = maintenance_window_dal
+        .find_by_deployment_ids(&deployment_ids)
+        .await
+        .map_err(map_repo_error)?;
+    Ok(hikari_tree(deployments, stacks, containers, windows))
+}
+
+/// Nests `containers` into their `stacks` and those, along with `windows`, into
+/// their `deployments`.
+fn hikari_tree(
+    deployments: Vec<DeployConfigDTO>,
+    stacks: Vec<StackConfigDTO>,
+    containers: Vec<ContainerDTO>,
+    windows: Vec<MaintenanceWindowDTO>,
+) -> HikariConfig {
+    let mut windows: HashMap<i64, MaintenanceWindowDTO> = windows
+        .into_iter()
+        .map(|window| (window.deployment_id, window))
+        .collect();
+    let mut services_by_stack: HashMap<i64, HashMap<String, Container>> = HashMap::new();
+    for container_dto in containers {
+        services_by_stack
+            .entry(container_dto.stack_id)
+            .or_default()
+            .insert(container_dto.service_name.clone(), container_dto.into());
+    }
+    let mut stacks_by_deployment: HashMap<i64, Vec<StackConfig>> = HashMap::new();
+    for stack_config_dto in stacks {
+        let services = stack_config_dto
+            .id
+            .and_then(|id| services_by_stack.remove(&id))
+            .unwrap_or_default();
+        stacks_by_deployment
+            .entry(stack_config_dto.deployment_id)
+            .or_default()
+            .push(StackConfig {
+                stack_name: stack_config_dto.stack_name,
+                filename: stack_config_dto.filename,
+                home_directory: stack_config_dto.home_directory,
+                compose_spec: ComposeSpec { services },
+                drift_policy: DriftPolicy::parse(&stack_config_dto.drift_policy),
+            });
+    }
+
+    let mut deploy_configs: HashMap<String, DeployConfig> = HashMap::new();
+    for deploy_config_dto in deployments {
+        let deploy_stacks = deploy_config_dto
+            .id
+            .and_then(|id| stacks_by_deployment.remove(&id))
+            .unwrap_or_default();
         deploy_configs.insert(
             deploy_config_dto.name.clone(),
             DeployConfig {
                 client: deploy_config_dto.client.clone(),
                 environment: deploy_config_dto.environment.clone(),
                 solution: deploy_config_dto.solution.clone(),
+                revision: deploy_config_dto.revision,
+                maintenance_window: deploy_config_dto
+                    .id
+                    .and_then(|id| windows.remove(&id))
+                    .map(Into::into),
+                urgent: false,
                 deploy_stacks,
             },
         );
     }
-    let hikari = HikariConfig {
+    HikariConfig {
         version: "1".to_string(),
         deploy_configs,
+    }
+}
+
+/// Serializes `hikari` into a canonical JSON body tagged with a content hash
+/// `ETag`, answering `304 Not Modified` when the client already holds it.
+pub fn conditional_hikari_response(
+    headers: &HeaderMap,
+    mut hikari: HikariConfig,
+) -> Result<Response, ApiError> {
+    // maps serialize with sorted keys through `Value`, stacks are sorted here
+    for deploy_config in hikari.deploy_configs.values_mut() {
+        deploy_config
+            .deploy_stacks
+            .sort_by(|a, b| (&a.stack_name, &a.filename).cmp(&(&b.stack_name, &b.filename)));
+    }
+    let body = serde_json::to_value(&hikari)
+        .and_then(|value| serde_json::to_vec(&value))
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let etag = format!(
+        "\"{}\"",
+        sha256(&body)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+    );
+    let etag_value = HeaderValue::from_str(&etag).map_err(|e| ApiError::internal(e.to_string()))?;
+
+    let matches = headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag);
+    if matches {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag_value)]).into_response());
+    }
+    Ok((
+        StatusCode::OK,
+        [
+            (ETAG, etag_value),
+            (CONTENT_TYPE, HeaderValue::from_static("application/json")),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Swaps the tag of `image`, dropping any digest. A colon only starts the tag
+/// after the last slash, `registry:5000/app` has no tag.
+pub fn with_image_tag(image: &str, tag: &str) -> String {
+    let name = image.split('@').next().unwrap_or(image);
+    let repository = match name.rfind(':') {
+        Some(colon) if colon > name.rfind('/').unwrap_or(0) => &name[..colon],
+        _ => name,
     };
-    hikari
-        .validate()
-        .map_err(|_err| (StatusCode::BAD_REQUEST, _err.to_string()))?;
-    Ok(hikari)
+    format!("{repository}:{tag}")
+}
+
+/// Sets each `KEY=value` of `overrides`, in place of the first entry with the
+/// same key. Later entries with that key are dropped so the override wins.
+pub fn merge_environment(environment: Option<Vec<String>>, overrides: &[String]) -> Vec<String> {
+    let key = |entry: &str| entry.split('=').next().unwrap_or_default().to_string();
+    let mut merged = environment.unwrap_or_default();
+    for entry in overrides {
+        let entry_key = key(entry);
+        match merged
+            .iter()
+            .position(|existing| key(existing) == entry_key)
+        {
+            Some(first) => {
+                merged[first].clone_from(entry);
+                let mut kept = false;
+                merged.retain(|existing| {
+                    key(existing) != entry_key || !std::mem::replace(&mut kept, true)
+                });
+            }
+            None => merged.push(entry.clone()),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::structs::MaintenanceWindow;
+
+    fn env(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn image_tag_is_replaced() {
+        assert_eq!(with_image_tag("nginx", "1.27"), "nginx:1.27");
+        assert_eq!(with_image_tag("nginx:1.25", "1.27"), "nginx:1.27");
+        assert_eq!(
+            with_image_tag("library/nginx:latest", "1.27"),
+            "library/nginx:1.27"
+        );
+    }
+
+    #[test]
+    fn registry_port_is_not_a_tag() {
+        assert_eq!(
+            with_image_tag("registry:5000/app", "2.0"),
+            "registry:5000/app:2.0"
+        );
+        assert_eq!(
+            with_image_tag("registry:5000/team/app:1.0", "2.0"),
+            "registry:5000/team/app:2.0"
+        );
+    }
+
+    #[test]
+    fn image_digest_is_dropped() {
+        let digest = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        assert_eq!(with_image_tag(&format!("app@{digest}"), "2.0"), "app:2.0");
+        assert_eq!(
+            with_image_tag(&format!("registry:5000/app:1.0@{digest}"), "2.0"),
+            "registry:5000/app:2.0"
+        );
+    }
+
+    #[test]
+    fn environment_overrides_in_place() {
+        let merged = merge_environment(Some(env(&["A=1", "B=2", "C=3"])), &env(&["B=20", "D=4"]));
+        assert_eq!(merged, env(&["A=1", "B=20", "C=3", "D=4"]));
+        assert_eq!(merge_environment(None, &env(&["A=1"])), env(&["A=1"]));
+        assert_eq!(merge_environment(Some(env(&["A=1"])), &[]), env(&["A=1"]));
+    }
+
+    #[test]
+    fn environment_values_may_contain_equals() {
+        let merged = merge_environment(
+            Some(env(&["URL=postgres://db?sslmode=require", "OPTS=a=b"])),
+            &env(&["OPTS=a=c=d"]),
+        );
+        assert_eq!(
+            merged,
+            env(&["URL=postgres://db?sslmode=require", "OPTS=a=c=d"])
+        );
+        // A key that is a prefix of another must not match it.
+        let merged = merge_environment(Some(env(&["A_B=1"])), &env(&["A=2"]));
+        assert_eq!(merged, env(&["A_B=1", "A=2"]));
+    }
+
+    #[test]
+    fn environment_duplicate_keys_collapse() {
+        let merged = merge_environment(Some(env(&["A=1", "B=2", "A=3"])), &env(&["A=4"]));
+        assert_eq!(merged, env(&["A=4", "B=2"]));
+        let merged = merge_environment(Some(env(&["B=2"])), &env(&["A=1", "A=5"]));
+        assert_eq!(merged, env(&["B=2", "A=5"]));
+        // Entries without a value pass the host variable through and still
+        // count as the key.
+        let merged = merge_environment(Some(env(&["TOKEN", "B=2"])), &env(&["TOKEN=x"]));
+        assert_eq!(merged, env(&["TOKEN=x", "B=2"]));
+    }
+
+    fn merged(target: Value, patch: Value) -> Value {
+        let mut target = target;
+        merge_patch(&mut target, &patch);
+        target
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7386_examples() {
+        // Appendix A of RFC 7386
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (target, patch, expected) in cases {
+            assert_eq!(
+                merged(target.clone(), patch.clone()),
+                expected,
+                "{target} + {patch}"
+            );
+        }
+    }
+
+    #[test]
+    fn merge_patch_merges_nested_objects() {
+        let target = json!({
+            "stack_name": "web",
+            "compose_spec": {"services": {"app": {"image": "app:1", "ports": ["80:80"]}}},
+        });
+        let patch = json!({
+            "compose_spec": {"services": {"app": {"image": "app:2"}, "cache": {"image": "redis"}}},
+        });
+        assert_eq!(
+            merged(target, patch),
+            json!({
+                "stack_name": "web",
+                "compose_spec": {"services": {
+                    "app": {"image": "app:2", "ports": ["80:80"]},
+                    "cache": {"image": "redis"},
+                }},
+            })
+        );
+    }
+
+    #[test]
+    fn merge_patch_replaces_arrays_whole() {
+        let target = json!({"environment": ["A=1", "B=2"]});
+        assert_eq!(
+            merged(target.clone(), json!({"environment": ["C=3"]})),
+            json!({"environment": ["C=3"]})
+        );
+        assert_eq!(
+            merged(target, json!({"environment": []})),
+            json!({"environment": []})
+        );
+    }
+
+    #[test]
+    fn patched_rejects_invalid_results() {
+        let window = MaintenanceWindow {
+            schedule: "0 2 * * *".into(),
+            duration_minutes: 60,
+            timezone: "UTC".into(),
+        };
+        let updated = patched(&window, &json!({"duration_minutes": 30})).unwrap();
+        assert_eq!(updated.duration_minutes, 30);
+        assert_eq!(updated.schedule, window.schedule);
+
+        let err = patched(&window, &json!({"schedule": null})).unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.code, ErrorCode::InvalidPatch);
+        let err = patched(&window, &json!({"duration_minutes": "soon"})).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidPatch);
+    }
+
+    async fn if_match(header: Option<&str>) -> Result<IfMatch, ApiError> {
+        let mut request = axum::http::Request::builder();
+        if let Some(header) = header {
+            request = request.header(IF_MATCH, header);
+        }
+        let (mut parts, ()) = request.body(()).unwrap().into_parts();
+        IfMatch::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn if_match_accepts_etags() {
+        assert_eq!(if_match(None).await.unwrap().0, None);
+        assert_eq!(if_match(Some("*")).await.unwrap().0, None);
+        assert_eq!(if_match(Some("\"3\"")).await.unwrap().0, Some(3));
+        assert_eq!(if_match(Some("W/\"12\"")).await.unwrap().0, Some(12));
+        assert_eq!(if_match(Some(" \"4\" ")).await.unwrap().0, Some(4));
+        assert_eq!(if_match(Some("5")).await.unwrap().0, Some(5));
+    }
+
+    #[tokio::test]
+    async fn if_match_rejects_other_values() {
+        for header in ["\"abc\"", "\"1\", \"2\"", "", "W/"] {
+            let err = if_match(Some(header)).await.unwrap_err();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST, "{header}");
+            assert_eq!(err.code, ErrorCode::InvalidHeader);
+            assert_eq!(err.field_path.as_deref(), Some("If-Match"));
+        }
+    }
+
+    #[test]
+    fn if_match_checks_the_current_version() {
+        assert!(IfMatch(None).check(Some(3)).is_ok());
+        assert!(IfMatch(None).check(None).is_ok());
+        assert!(IfMatch(Some(3)).check(Some(3)).is_ok());
+
+        let err = IfMatch(Some(2)).check(Some(3)).unwrap_err();
+        assert_eq!(err.status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(err.code, ErrorCode::VersionMismatch);
+        assert_eq!(err.details, Some(json!({"current_version": 3})));
+
+        let err = IfMatch(Some(2)).check(None).unwrap_err();
+        assert_eq!(err.details, Some(json!({"current_version": null})));
+    }
+
+    #[test]
+    fn version_mismatch_reports_the_current_version() {
+        let err = version_mismatch(Some(7));
+        assert_eq!(err.status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(err.code, ErrorCode::VersionMismatch);
+        assert_eq!(err.details, Some(json!({"current_version": 7})));
+        assert_eq!(err.field_path, None);
+    }
 }
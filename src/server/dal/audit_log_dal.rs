@@ -0,0 +1,103 @@
+use log::error;
+use sqlx::{query, types::Json};
+
+use crate::{
+    server::{
+        dal::paging::{Conditions, PageQuery, fetch_page},
+        models::{
+            audit_log::{AuditFilter, AuditLogDTO},
+            page::{Page, PageRequest},
+        },
+        storage::{Storage, StorageTx},
+    },
+    utils::error::RepoError,
+};
+
+/// Sortable columns of the audit log.
+pub const AUDIT_SORT_FIELDS: &[&str] = &["created_at", "id", "actor", "entity", "action"];
+
+/// Appends `entry` to the audit log. Runs inside the transaction of the
+/// mutation it describes so neither is stored without the other.
+pub async fn record_audit(tx: &mut StorageTx, entry: &AuditLogDTO) -> Result<(), RepoError> {
+    match tx {
+        StorageTx::Postgres(tx) => query!(
+            r#"
+            INSERT INTO
+            audit_log(actor, action, entity, entity_id, before, after, request_id, source_ip
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8);
+            "#,
+            entry.actor,
+            entry.action,
+            entry.entity,
+            entry.entity_id,
+            entry.before,
+            entry.after,
+            entry.request_id,
+            entry.source_ip
+        )
+        .execute(&mut **tx)
+        .await
+        .map(|_| ()),
+        StorageTx::Sqlite(tx) => query(
+            r#"
+            INSERT INTO
+            audit_log(actor, action, entity, entity_id, before, after, request_id, source_ip
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);
+            "#,
+        )
+        .bind(&entry.actor)
+        .bind(&entry.action)
+        .bind(&entry.entity)
+        .bind(entry.entity_id)
+        .bind(entry.before.as_ref().map(Json))
+        .bind(entry.after.as_ref().map(Json))
+        .bind(&entry.request_id)
+        .bind(&entry.source_ip)
+        .execute(&mut **tx)
+        .await
+        .map(|_| ()),
+    }
+    .map_err(|err| {
+        error!("Database query failed: {err}");
+        err
+    })?;
+    Ok(())
+}
+
+/// Read side of the audit log, entries are only ever added by [`record_audit`].
+pub struct AuditLogDAL {
+    pub storage: Storage,
+}
+impl AuditLogDAL {
+    pub fn new(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
+    }
+
+    pub async fn find_page(
+        &self,
+        filter: &AuditFilter,
+        page: PageRequest,
+    ) -> Result<Page<AuditLogDTO>, RepoError> {
+        let conditions = Conditions::default()
+            .eq("a.entity", filter.entity.clone())
+            .eq("a.entity_id", filter.entity_id)
+            .eq("a.actor", filter.actor.clone())
+            .ge("a.created_at", filter.since)
+            .le("a.created_at", filter.until);
+        let query = PageQuery {
+            count: "SELECT COUNT(*) FROM audit_log AS a",
+            select: "SELECT * FROM audit_log AS a",
+            tail: "",
+            alias: "a",
+        };
+        let entries = fetch_page(&self.storage, query, &conditions, page)
+            .await
+            .map_err(|err| {
+                error!("Database query failed: {err}");
+                err
+            })?;
+        Ok(entries)
+    }
+}
@@ -0,0 +1,298 @@
+use log::error;
+use sqlx::{query, query_as, types::Json};
+
+use crate::{
+    objects::structs::HikariConfig,
+    server::{
+        models::config_revision::ConfigRevisionDTO,
+        storage::{Storage, StorageTx},
+    },
+    utils::error::RepoError,
+};
+
+/// Appends the snapshot of a revision to the history. Runs inside the
+/// transaction making the change, see [`record_change`].
+///
+/// [`record_change`]: crate::server::common::record_change
+pub async fn record_revision(
+    tx: &mut StorageTx,
+    object: ConfigRevisionDTO,
+) -> Result<ConfigRevisionDTO, RepoError> {
+    let snapshot = Json(object.snapshot.clone().unwrap_or_default());
+    let (id, created_at) = match tx {
+        StorageTx::Postgres(tx) => query!(
+            r#"
+            INSERT INTO
+            config_revision(deployment_id, deployment_name, revision, author, message, snapshot
+            ) VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, created_at;
+            "#,
+            object.deployment_id,
+            object.deployment_name,
+            object.revision,
+            object.author,
+            object.message,
+            snapshot as _
+        )
+        .fetch_one(&mut **tx)
+        .await
+        .map(|row| (row.id, row.created_at)),
+        StorageTx::Sqlite(tx) => {
+            query_as(
+                r#"
+            INSERT INTO
+            config_revision(deployment_id, deployment_name, revision, author, message, snapshot
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            RETURNING id, created_at;
+            "#,
+            )
+            .bind(object.deployment_id)
+            .bind(&object.deployment_name)
+            .bind(object.revision)
+            .bind(&object.author)
+            .bind(&object.message)
+            .bind(snapshot)
+            .fetch_one(&mut **tx)
+            .await
+        }
+    }
+    .map_err(|err| {
+        error!("Database query failed: {err}");
+        err
+    })?;
+    Ok(ConfigRevisionDTO {
+        id: Some(id),
+        created_at: Some(created_at),
+        ..object
+    })
+}
+
+/// Append-only history of deployment snapshots, one row per revision, read
+/// here and written by [`record_revision`].
+pub struct ConfigRevisionDAL {
+    pub storage: Storage,
+}
+impl ConfigRevisionDAL {
+    pub fn new(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
+    }
+
+    pub async fn find_by_deployment(
+        &self,
+        deployment_id: i64,
+    ) -> Result<Vec<ConfigRevisionDTO>, RepoError> {
+        let revisions: Vec<ConfigRevisionDTO> = match &self.storage {
+            Storage::Postgres(pool) => query!(
+                r#"
+                SELECT id, deployment_id, deployment_name, revision, author, message, created_at
+                FROM config_revision
+                WHERE deployment_id = $1
+                ORDER BY revision DESC;
+                "#,
+                deployment_id
+            )
+            .fetch_all(pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| ConfigRevisionDTO {
+                        id: Some(row.id),
+                        deployment_id: row.deployment_id,
+                        deployment_name: row.deployment_name,
+                        revision: row.revision,
+                        author: row.author,
+                        message: row.message,
+                        created_at: Some(row.created_at),
+                        snapshot: None,
+                    })
+                    .collect()
+            }),
+            Storage::Sqlite(pool) => {
+                query_as(
+                    r#"
+                    SELECT id, deployment_id, deployment_name, revision, author, message, created_at, NULL AS snapshot
+                    FROM config_revision
+                    WHERE deployment_id = ?1
+                    ORDER BY revision DESC;
+                    "#,
+                )
+                .bind(deployment_id)
+                .fetch_all(pool)
+                .await
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(revisions)
+    }
+
+    pub async fn find_by_revision(
+        &self,
+        deployment_id: i64,
+        revision: i64,
+    ) -> Result<ConfigRevisionDTO, RepoError> {
+        let revision: ConfigRevisionDTO = match &self.storage {
+            Storage::Postgres(pool) => query!(
+                r#"
+                SELECT id,
+                deployment_id,
+                deployment_name,
+                revision,
+                author,
+                message,
+                created_at,
+                snapshot AS "snapshot: Json<HikariConfig>"
+                FROM config_revision
+                WHERE deployment_id = $1 AND revision = $2;
+                "#,
+                deployment_id,
+                revision
+            )
+            .fetch_one(pool)
+            .await
+            .map(|row| ConfigRevisionDTO {
+                id: Some(row.id),
+                deployment_id: row.deployment_id,
+                deployment_name: row.deployment_name,
+                revision: row.revision,
+                author: row.author,
+                message: row.message,
+                created_at: Some(row.created_at),
+                snapshot: Some(row.snapshot.0),
+            }),
+            Storage::Sqlite(pool) => {
+                query_as(
+                    r#"
+                    SELECT id, deployment_id, deployment_name, revision, author, message, created_at, snapshot
+                    FROM config_revision
+                    WHERE deployment_id = ?1 AND revision = ?2;
+                    "#,
+                )
+                .bind(deployment_id)
+                .bind(revision)
+                .fetch_one(pool)
+                .await
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(revision)
+    }
+
+    /// Revisions recorded under deployment `name`, including those of deleted
+    /// deployments that went by it, newest first.
+    pub async fn find_by_name(&self, name: &str) -> Result<Vec<ConfigRevisionDTO>, RepoError> {
+        let revisions: Vec<ConfigRevisionDTO> = match &self.storage {
+            Storage::Postgres(pool) => query!(
+                r#"
+                SELECT id, deployment_id, deployment_name, revision, author, message, created_at
+                FROM config_revision
+                WHERE deployment_name = $1
+                ORDER BY id DESC;
+                "#,
+                name
+            )
+            .fetch_all(pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| ConfigRevisionDTO {
+                        id: Some(row.id),
+                        deployment_id: row.deployment_id,
+                        deployment_name: row.deployment_name,
+                        revision: row.revision,
+                        author: row.author,
+                        message: row.message,
+                        created_at: Some(row.created_at),
+                        snapshot: None,
+                    })
+                    .collect()
+            }),
+            Storage::Sqlite(pool) => {
+                query_as(
+                    r#"
+                    SELECT id, deployment_id, deployment_name, revision, author, message, created_at, NULL AS snapshot
+                    FROM config_revision
+                    WHERE deployment_name = ?1
+                    ORDER BY id DESC;
+                    "#,
+                )
+                .bind(name)
+                .fetch_all(pool)
+                .await
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(revisions)
+    }
+
+    /// Revision `revision` recorded under deployment `name`. When deployments
+    /// deleted since went by the same name, the latest one recorded wins.
+    pub async fn find_by_name_revision(
+        &self,
+        name: &str,
+        revision: i64,
+    ) -> Result<ConfigRevisionDTO, RepoError> {
+        let revision: ConfigRevisionDTO = match &self.storage {
+            Storage::Postgres(pool) => query!(
+                r#"
+                SELECT id,
+                deployment_id,
+                deployment_name,
+                revision,
+                author,
+                message,
+                created_at,
+                snapshot AS "snapshot: Json<HikariConfig>"
+                FROM config_revision
+                WHERE deployment_name = $1 AND revision = $2
+                ORDER BY id DESC
+                LIMIT 1;
+                "#,
+                name,
+                revision
+            )
+            .fetch_one(pool)
+            .await
+            .map(|row| ConfigRevisionDTO {
+                id: Some(row.id),
+                deployment_id: row.deployment_id,
+                deployment_name: row.deployment_name,
+                revision: row.revision,
+                author: row.author,
+                message: row.message,
+                created_at: Some(row.created_at),
+                snapshot: Some(row.snapshot.0),
+            }),
+            Storage::Sqlite(pool) => {
+                query_as(
+                    r#"
+                    SELECT id, deployment_id, deployment_name, revision, author, message, created_at, snapshot
+                    FROM config_revision
+                    WHERE deployment_name = ?1 AND revision = ?2
+                    ORDER BY id DESC
+                    LIMIT 1;
+                    "#,
+                )
+                .bind(name)
+                .bind(revision)
+                .fetch_one(pool)
+                .await
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(revision)
+    }
+}
@@ -1,42 +1,102 @@
 use log::error;
-use sqlx::{PgPool, query, query_as, query_scalar};
+use sqlx::{query, query_as, query_scalar, types::Json};
 
 use crate::{
     server::{
-        models::{container::ContainerDTO, deploy_config::DeployConfigDTO},
+        dal::{
+            deploy_config_dal::{SQLITE_DEPLOYMENT_GROUP, SQLITE_DEPLOYMENT_SELECT},
+            paging::{Conditions, PageQuery, fetch_page},
+        },
+        models::{
+            container::{ContainerDTO, ContainerFilter},
+            deploy_config::DeployConfigDTO,
+            page::{Page, PageRequest},
+        },
+        storage::{Storage, StorageTx},
         traits::model::DataRepository,
     },
     utils::error::RepoError,
 };
 
+/// Container columns for Postgres runtime queries, with the array columns as
+/// JSON so both backends decode them the same way.
+const PG_CONTAINER_SELECT: &str = r#"
+    SELECT c.id,
+    c.stack_id,
+    c.service_name,
+    c.container_name,
+    c.image,
+    c.restart,
+    c."user",
+    c.stdin_open,
+    c.tty,
+    c.command,
+    c.pull_policy,
+    to_jsonb(c.ports) AS ports,
+    to_jsonb(c.volumes) AS volumes,
+    to_jsonb(c.environment) AS environment,
+    c.mem_reservation,
+    c.mem_limit,
+    c.oom_kill_disable,
+    c.privileged,
+    c.version
+    FROM container AS c
+"#;
+
+/// Sortable columns of the container list.
+pub const CONTAINER_SORT_FIELDS: &[&str] =
+    &["id", "stack_id", "service_name", "container_name", "image"];
+
 pub struct ContainerDAL {
-    pub pool: PgPool,
+    pub storage: Storage,
 }
 impl DataRepository<ContainerDTO> for ContainerDAL {
     type Payload = ContainerDTO;
 
-    fn new(pool: &PgPool) -> Self {
-        Self { pool: pool.clone() }
+    fn new(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
     }
 
     async fn exists(&self, id: i64) -> Result<bool, RepoError> {
-        let exists = query_scalar!("SELECT EXISTS(SELECT id FROM container WHERE id = $1)", id)
-            .fetch_one(&self.pool)
-            .await?;
-        Ok(exists.unwrap_or(false))
+        let exists = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_scalar!("SELECT EXISTS(SELECT id FROM container WHERE id = $1)", id)
+                    .fetch_one(pool)
+                    .await?
+                    .unwrap_or(false)
+            }
+            Storage::Sqlite(pool) => {
+                query_scalar("SELECT EXISTS(SELECT id FROM container WHERE id = ?1)")
+                    .bind(id)
+                    .fetch_one(pool)
+                    .await?
+            }
+        };
+        Ok(exists)
     }
 
     async fn find_all(&self) -> Result<Vec<ContainerDTO>, RepoError> {
-        let compose_stacks: Vec<ContainerDTO> = query_as!(
-            ContainerDTO,
-            r#"
-            SELECT *
-            FROM container AS c
-            ORDER BY c.id;
-            "#,
-        )
-        .fetch_all(&self.pool)
-        .await
+        let compose_stacks: Vec<ContainerDTO> = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    ContainerDTO,
+                    r#"
+                    SELECT *
+                    FROM container AS c
+                    ORDER BY c.id;
+                    "#,
+                )
+                .fetch_all(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(r#"SELECT * FROM container AS c ORDER BY c.id;"#)
+                    .fetch_all(pool)
+                    .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
@@ -45,17 +105,27 @@ impl DataRepository<ContainerDTO> for ContainerDAL {
     }
 
     async fn find_by_id(&self, id: i64) -> Result<ContainerDTO, RepoError> {
-        let compose_stack: ContainerDTO = query_as!(
-            ContainerDTO,
-            r#"
-            SELECT *
-            FROM container AS c
-            WHERE c.id = $1
-            "#,
-            id
-        )
-        .fetch_one(&self.pool)
-        .await
+        let compose_stack: ContainerDTO = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    ContainerDTO,
+                    r#"
+                    SELECT *
+                    FROM container AS c
+                    WHERE c.id = $1
+                    "#,
+                    id
+                )
+                .fetch_one(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(r#"SELECT * FROM container AS c WHERE c.id = ?1;"#)
+                    .bind(id)
+                    .fetch_one(pool)
+                    .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
@@ -64,30 +134,52 @@ impl DataRepository<ContainerDTO> for ContainerDAL {
     }
 
     async fn get_deployment_metadata(&self, id: i64) -> Result<DeployConfigDTO, RepoError> {
-        let deployment: DeployConfigDTO = query_as!(
-            DeployConfigDTO,
-            r#"
-            SELECT dc.id,
-            dc.name,
-            dc.client,
-            dc.environment,
-            dc.solution,
-            COALESCE(
-                array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
-                ARRAY[]::BIGINT[]
-            ) AS stack_ids
-            FROM container AS c
-            JOIN compose_stack AS cs
-              ON c.stack_id = cs.id
-            JOIN deploy_config AS dc
-              ON cs.deployment_id = dc.id
-            WHERE c.id = $1
-            GROUP BY dc.id, dc.client, dc.environment, dc.solution;
-            "#,
-            id
-        )
-        .fetch_one(&self.pool)
-        .await
+        let deployment: DeployConfigDTO = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    DeployConfigDTO,
+                    r#"
+                    SELECT dc.id,
+                    dc.name,
+                    dc.client,
+                    dc.environment,
+                    dc.solution,
+                    dc.revision,
+                    dc.version,
+                    COALESCE(
+                        array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
+                        ARRAY[]::BIGINT[]
+                    ) AS stack_ids
+                    FROM container AS c
+                    JOIN compose_stack AS cs
+                      ON c.stack_id = cs.id
+                    JOIN deploy_config AS dc
+                      ON cs.deployment_id = dc.id
+                    WHERE c.id = $1
+                    GROUP BY dc.id, dc.client, dc.environment, dc.solution;
+                    "#,
+                    id
+                )
+                .fetch_one(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(&format!(
+                    "{SQLITE_DEPLOYMENT_SELECT}
+                    WHERE dc.id = (
+                        SELECT cs.deployment_id
+                        FROM container AS c
+                        JOIN compose_stack AS cs
+                          ON c.stack_id = cs.id
+                        WHERE c.id = ?1
+                    )
+                    {SQLITE_DEPLOYMENT_GROUP};"
+                ))
+                .bind(id)
+                .fetch_one(pool)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
@@ -95,155 +187,309 @@ impl DataRepository<ContainerDTO> for ContainerDAL {
         Ok(deployment)
     }
 
-    async fn create(&self, object: ContainerDTO) -> Result<ContainerDTO, RepoError> {
-        let row = query!(
-            r#"
-            INSERT INTO
-            container(
-            stack_id,
-            service_name,
-            container_name,
-            image,
-            restart,
-            "user",
-            stdin_open,
-            tty,
-            command,
-            pull_policy,
-            ports,
-            volumes,
-            environment,
-            mem_reservation,
-            mem_limit,
-            oom_kill_disable,
-            privileged
-            )
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)
-            RETURNING
-            id,
-            stack_id,
-            service_name,
-            container_name,
-            image,
-            restart,
-            "user",
-            stdin_open,
-            tty,
-            command,
-            pull_policy,
-            ports,
-            volumes,
-            environment,
-            mem_reservation,
-            mem_limit,
-            oom_kill_disable,
-            privileged
-            "#,
-            object.stack_id,
-            object.service_name,
-            object.container_name,
-            object.image,
-            object.restart,
-            object.user,
-            object.stdin_open,
-            object.tty,
-            object.command,
-            object.pull_policy,
-            object.ports.as_deref(),
-            object.volumes.as_deref(),
-            object.environment.as_deref(),
-            object.mem_reservation,
-            object.mem_limit,
-            object.oom_kill_disable,
-            object.privileged,
-        )
-        .fetch_one(&self.pool)
-        .await
+    async fn create(
+        &self,
+        tx: &mut StorageTx,
+        object: ContainerDTO,
+    ) -> Result<ContainerDTO, RepoError> {
+        let id = match tx {
+            StorageTx::Postgres(tx) => {
+                query_scalar!(
+                    r#"
+                    INSERT INTO
+                    container(
+                    stack_id,
+                    service_name,
+                    container_name,
+                    image,
+                    restart,
+                    "user",
+                    stdin_open,
+                    tty,
+                    command,
+                    pull_policy,
+                    ports,
+                    volumes,
+                    environment,
+                    mem_reservation,
+                    mem_limit,
+                    oom_kill_disable,
+                    privileged
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)
+                    RETURNING id;
+                    "#,
+                    object.stack_id,
+                    object.service_name,
+                    object.container_name,
+                    object.image,
+                    object.restart,
+                    object.user,
+                    object.stdin_open,
+                    object.tty,
+                    object.command,
+                    object.pull_policy,
+                    object.ports.as_deref(),
+                    object.volumes.as_deref(),
+                    object.environment.as_deref(),
+                    object.mem_reservation,
+                    object.mem_limit,
+                    object.oom_kill_disable,
+                    object.privileged,
+                )
+                .fetch_one(&mut **tx)
+                .await
+            }
+            StorageTx::Sqlite(tx) => {
+                query_scalar(
+                    r#"
+                    INSERT INTO
+                    container(
+                    stack_id,
+                    service_name,
+                    container_name,
+                    image,
+                    restart,
+                    "user",
+                    stdin_open,
+                    tty,
+                    command,
+                    pull_policy,
+                    ports,
+                    volumes,
+                    environment,
+                    mem_reservation,
+                    mem_limit,
+                    oom_kill_disable,
+                    privileged
+                    )
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
+                    RETURNING id;
+                    "#,
+                )
+                .bind(object.stack_id)
+                .bind(&object.service_name)
+                .bind(&object.container_name)
+                .bind(&object.image)
+                .bind(&object.restart)
+                .bind(&object.user)
+                .bind(object.stdin_open)
+                .bind(object.tty)
+                .bind(&object.command)
+                .bind(&object.pull_policy)
+                .bind(object.ports.as_ref().map(Json))
+                .bind(object.volumes.as_ref().map(Json))
+                .bind(object.environment.as_ref().map(Json))
+                .bind(&object.mem_reservation)
+                .bind(&object.mem_limit)
+                .bind(object.oom_kill_disable)
+                .bind(object.privileged)
+                .fetch_one(&mut **tx)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
         })?;
         Ok(ContainerDTO {
-            id: Some(row.id),
-            stack_id: row.stack_id,
-            service_name: row.service_name,
-            container_name: row.container_name,
-            image: row.image,
-            restart: row.restart,
-            user: row.user,
-            stdin_open: row.stdin_open,
-            tty: row.tty,
-            command: row.command,
-            pull_policy: row.pull_policy,
-            ports: row.ports,
-            volumes: row.volumes,
-            environment: row.environment,
-            mem_reservation: row.mem_reservation,
-            mem_limit: row.mem_limit,
-            oom_kill_disable: row.oom_kill_disable,
-            privileged: row.privileged,
+            id: Some(id),
+            version: Some(1),
+            ..object
         })
     }
 
-    async fn update(&self, object: ContainerDTO) -> Result<bool, RepoError> {
-        let row = query!(
-            r#"
-        UPDATE container SET
-        stack_id = $2,
-        service_name = $3,
-        container_name = $4,
-        image = $5,
-        restart = $6,
-        "user" = $7,
-        stdin_open = $8,
-        tty = $9,
-        command = $10,
-        pull_policy = $11,
-        ports = $12,
-        volumes = $13,
-        environment = $14,
-        mem_reservation = $15,
-        mem_limit = $16,
-        oom_kill_disable = $17,
-        privileged = $18
-        WHERE id = $1;
-        "#,
-            object.id,
-            object.stack_id,
-            object.service_name,
-            object.container_name,
-            object.image,
-            object.restart,
-            object.user,
-            object.stdin_open,
-            object.tty,
-            object.command,
-            object.pull_policy,
-            object.ports.as_deref(),
-            object.volumes.as_deref(),
-            object.environment.as_deref(),
-            object.mem_reservation,
-            object.mem_limit,
-            object.oom_kill_disable,
-            object.privileged
-        )
-        .execute(&self.pool)
-        .await
+    async fn update(&self, tx: &mut StorageTx, object: ContainerDTO) -> Result<bool, RepoError> {
+        let rows_affected = match tx {
+            StorageTx::Postgres(tx) => query!(
+                r#"
+                UPDATE container SET
+                stack_id = $2,
+                service_name = $3,
+                container_name = $4,
+                image = $5,
+                restart = $6,
+                "user" = $7,
+                stdin_open = $8,
+                tty = $9,
+                command = $10,
+                pull_policy = $11,
+                ports = $12,
+                volumes = $13,
+                environment = $14,
+                mem_reservation = $15,
+                mem_limit = $16,
+                oom_kill_disable = $17,
+                privileged = $18,
+                version = version + 1
+                WHERE id = $1 AND ($19::BIGINT IS NULL OR version = $19);
+                "#,
+                object.id,
+                object.stack_id,
+                object.service_name,
+                object.container_name,
+                object.image,
+                object.restart,
+                object.user,
+                object.stdin_open,
+                object.tty,
+                object.command,
+                object.pull_policy,
+                object.ports.as_deref(),
+                object.volumes.as_deref(),
+                object.environment.as_deref(),
+                object.mem_reservation,
+                object.mem_limit,
+                object.oom_kill_disable,
+                object.privileged,
+                object.version
+            )
+            .execute(&mut **tx)
+            .await
+            .map(|row| row.rows_affected()),
+            StorageTx::Sqlite(tx) => query(
+                r#"
+                UPDATE container SET
+                stack_id = ?2,
+                service_name = ?3,
+                container_name = ?4,
+                image = ?5,
+                restart = ?6,
+                "user" = ?7,
+                stdin_open = ?8,
+                tty = ?9,
+                command = ?10,
+                pull_policy = ?11,
+                ports = ?12,
+                volumes = ?13,
+                environment = ?14,
+                mem_reservation = ?15,
+                mem_limit = ?16,
+                oom_kill_disable = ?17,
+                privileged = ?18,
+                version = version + 1
+                WHERE id = ?1 AND (?19 IS NULL OR version = ?19);
+                "#,
+            )
+            .bind(object.id)
+            .bind(object.stack_id)
+            .bind(&object.service_name)
+            .bind(&object.container_name)
+            .bind(&object.image)
+            .bind(&object.restart)
+            .bind(&object.user)
+            .bind(object.stdin_open)
+            .bind(object.tty)
+            .bind(&object.command)
+            .bind(&object.pull_policy)
+            .bind(object.ports.as_ref().map(Json))
+            .bind(object.volumes.as_ref().map(Json))
+            .bind(object.environment.as_ref().map(Json))
+            .bind(&object.mem_reservation)
+            .bind(&object.mem_limit)
+            .bind(object.oom_kill_disable)
+            .bind(object.privileged)
+            .bind(object.version)
+            .execute(&mut **tx)
+            .await
+            .map(|row| row.rows_affected()),
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(rows_affected > 0)
+    }
+
+    async fn delete(&self, tx: &mut StorageTx, id: i64) -> Result<bool, RepoError> {
+        let rows_affected = match tx {
+            StorageTx::Postgres(tx) => query!(r#"DELETE FROM container WHERE id=$1;"#, id)
+                .execute(&mut **tx)
+                .await
+                .map(|row| row.rows_affected()),
+            StorageTx::Sqlite(tx) => query(r#"DELETE FROM container WHERE id=?1;"#)
+                .bind(id)
+                .execute(&mut **tx)
+                .await
+                .map(|row| row.rows_affected()),
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(rows_affected > 0)
+    }
+}
+pub trait Utils {
+    async fn find_by_stack_ids(&self, stack_ids: &[i64]) -> Result<Vec<ContainerDTO>, RepoError>;
+    async fn find_page(
+        &self,
+        filter: &ContainerFilter,
+        page: PageRequest,
+    ) -> Result<Page<ContainerDTO>, RepoError>;
+}
+impl Utils for ContainerDAL {
+    async fn find_by_stack_ids(&self, stack_ids: &[i64]) -> Result<Vec<ContainerDTO>, RepoError> {
+        let containers: Vec<ContainerDTO> = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    ContainerDTO,
+                    r#"
+                    SELECT *
+                    FROM container AS c
+                    WHERE c.stack_id = ANY($1)
+                    ORDER BY c.id;
+                    "#,
+                    stack_ids
+                )
+                .fetch_all(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(
+                    r#"
+                    SELECT *
+                    FROM container AS c
+                    WHERE c.stack_id IN (SELECT value FROM json_each(?1))
+                    ORDER BY c.id;
+                    "#,
+                )
+                .bind(Json(stack_ids))
+                .fetch_all(pool)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
         })?;
-        Ok(row.rows_affected() > 0)
+        Ok(containers)
     }
 
-    async fn delete(&self, id: i64) -> Result<bool, RepoError> {
-        let row = query!(r#"DELETE FROM container WHERE id=$1;"#, id)
-            .execute(&self.pool)
+    async fn find_page(
+        &self,
+        filter: &ContainerFilter,
+        page: PageRequest,
+    ) -> Result<Page<ContainerDTO>, RepoError> {
+        let conditions = Conditions::default()
+            .eq("c.stack_id", filter.stack_id)
+            .eq("c.image", filter.image.clone());
+        let select = match &self.storage {
+            Storage::Postgres(_) => PG_CONTAINER_SELECT,
+            Storage::Sqlite(_) => "SELECT * FROM container AS c",
+        };
+        let query = PageQuery {
+            count: "SELECT COUNT(*) FROM container AS c",
+            select,
+            tail: "",
+            alias: "c",
+        };
+        let containers = fetch_page(&self.storage, query, &conditions, page)
             .await
             .map_err(|err| {
                 error!("Database query failed: {err}");
                 err
             })?;
-        Ok(row.rows_affected() > 0)
+        Ok(containers)
     }
 }
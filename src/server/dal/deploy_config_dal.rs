@@ -1,53 +1,128 @@
 use log::error;
-use sqlx::{PgPool, query, query_as, query_scalar};
+use sqlx::{query, query_as, query_scalar};
 
 use crate::{
-    server::{models::deploy_config::DeployConfigDTO, traits::model::DataRepository},
+    server::{
+        dal::paging::{Conditions, PageQuery, fetch_page},
+        models::{
+            deploy_config::{DeployConfigDTO, DeploymentFilter},
+            page::{Page, PageRequest},
+        },
+        storage::{Storage, StorageTx},
+        traits::model::DataRepository,
+    },
     utils::error::RepoError,
 };
 
+/// Sortable columns of the deployment list.
+pub const DEPLOYMENT_SORT_FIELDS: &[&str] = &[
+    "id",
+    "name",
+    "client",
+    "environment",
+    "solution",
+    "revision",
+];
+
+/// Postgres counterpart of [`SQLITE_DEPLOYMENT_SELECT`] for runtime queries,
+/// with the stack IDs as JSON so both backends decode them the same way.
+const PG_DEPLOYMENT_SELECT: &str = r#"
+    SELECT dc.id,
+    dc.name,
+    dc.client,
+    dc.environment,
+    dc.solution,
+    dc.revision,
+    dc.version,
+    COALESCE(json_agg(cs.id ORDER BY cs.id) FILTER (WHERE cs.id IS NOT NULL), '[]') AS stack_ids
+    FROM deploy_config AS dc
+    LEFT JOIN compose_stack AS cs
+    ON cs.deployment_id = dc.id
+"#;
+
+/// Deployment columns plus the IDs of their stacks, for SQLite. Append the
+/// `WHERE` clause and [`SQLITE_DEPLOYMENT_GROUP`].
+pub(crate) const SQLITE_DEPLOYMENT_SELECT: &str = r#"
+    SELECT dc.id,
+    dc.name,
+    dc.client,
+    dc.environment,
+    dc.solution,
+    dc.revision,
+    dc.version,
+    json_group_array(cs.id) FILTER (WHERE cs.id IS NOT NULL) AS stack_ids
+    FROM deploy_config AS dc
+    LEFT JOIN compose_stack AS cs
+    ON cs.deployment_id = dc.id
+"#;
+pub(crate) const SQLITE_DEPLOYMENT_GROUP: &str = "GROUP BY dc.id ORDER BY dc.id";
+
 pub struct DeployConfigDAL {
-    pub pool: PgPool,
+    pub storage: Storage,
 }
 impl DataRepository<DeployConfigDTO> for DeployConfigDAL {
     type Payload = DeployConfigDTO;
 
-    fn new(pool: &PgPool) -> Self {
-        Self { pool: pool.clone() }
+    fn new(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
     }
 
     async fn exists(&self, id: i64) -> Result<bool, RepoError> {
-        let exists = query_scalar!(
-            "SELECT EXISTS(SELECT id FROM deploy_config WHERE id = $1)",
-            id
-        )
-        .fetch_one(&self.pool)
-        .await?;
-        Ok(exists.unwrap_or(false))
+        let exists = match &self.storage {
+            Storage::Postgres(pool) => query_scalar!(
+                "SELECT EXISTS(SELECT id FROM deploy_config WHERE id = $1)",
+                id
+            )
+            .fetch_one(pool)
+            .await?
+            .unwrap_or(false),
+            Storage::Sqlite(pool) => {
+                query_scalar("SELECT EXISTS(SELECT id FROM deploy_config WHERE id = ?1)")
+                    .bind(id)
+                    .fetch_one(pool)
+                    .await?
+            }
+        };
+        Ok(exists)
     }
 
     async fn find_all(&self) -> Result<Vec<DeployConfigDTO>, RepoError> {
-        let deployments: Vec<DeployConfigDTO> = query_as!(
-            DeployConfigDTO,
-            r#"
-            SELECT dc.id,
-            dc.name,
-            dc.client,
-            dc.environment,
-            dc.solution,
-            COALESCE(
-                array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
-                ARRAY[]::BIGINT[]
-            ) AS stack_ids
-            FROM deploy_config AS dc
-            LEFT JOIN compose_stack AS cs
-            ON cs.deployment_id = dc.id
-            GROUP BY dc.id, dc.client, dc.environment, dc.solution
-            ORDER BY dc.id;
-            "#,
-        )
-        .fetch_all(&self.pool)
-        .await
+        let deployments: Vec<DeployConfigDTO> = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    DeployConfigDTO,
+                    r#"
+                    SELECT dc.id,
+                    dc.name,
+                    dc.client,
+                    dc.environment,
+                    dc.solution,
+                    dc.revision,
+                    dc.version,
+                    COALESCE(
+                        array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
+                        ARRAY[]::BIGINT[]
+                    ) AS stack_ids
+                    FROM deploy_config AS dc
+                    LEFT JOIN compose_stack AS cs
+                    ON cs.deployment_id = dc.id
+                    GROUP BY dc.id, dc.client, dc.environment, dc.solution
+                    ORDER BY dc.id;
+                    "#,
+                )
+                .fetch_all(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(&format!(
+                    "{SQLITE_DEPLOYMENT_SELECT} {SQLITE_DEPLOYMENT_GROUP};"
+                ))
+                .fetch_all(pool)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
@@ -56,28 +131,42 @@ impl DataRepository<DeployConfigDTO> for DeployConfigDAL {
     }
 
     async fn find_by_id(&self, id: i64) -> Result<DeployConfigDTO, RepoError> {
-        let deployment: DeployConfigDTO = query_as!(
-            DeployConfigDTO,
-            r#"
-            SELECT dc.id,
-            dc.name,
-            dc.client,
-            dc.environment,
-            dc.solution,
-            COALESCE(
-                array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
-                ARRAY[]::BIGINT[]
-            ) AS stack_ids
-            FROM deploy_config AS dc
-            LEFT JOIN compose_stack AS cs
-            ON cs.deployment_id = dc.id
-            WHERE dc.id = $1
-            GROUP BY dc.id, dc.client, dc.environment, dc.solution;
-            "#,
-            id
-        )
-        .fetch_one(&self.pool)
-        .await
+        let deployment: DeployConfigDTO = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    DeployConfigDTO,
+                    r#"
+                    SELECT dc.id,
+                    dc.name,
+                    dc.client,
+                    dc.environment,
+                    dc.solution,
+                    dc.revision,
+                    dc.version,
+                    COALESCE(
+                        array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
+                        ARRAY[]::BIGINT[]
+                    ) AS stack_ids
+                    FROM deploy_config AS dc
+                    LEFT JOIN compose_stack AS cs
+                    ON cs.deployment_id = dc.id
+                    WHERE dc.id = $1
+                    GROUP BY dc.id, dc.client, dc.environment, dc.solution;
+                    "#,
+                    id
+                )
+                .fetch_one(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(&format!(
+                    "{SQLITE_DEPLOYMENT_SELECT} WHERE dc.id = ?1 {SQLITE_DEPLOYMENT_GROUP};"
+                ))
+                .bind(id)
+                .fetch_one(pool)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
@@ -86,87 +175,107 @@ impl DataRepository<DeployConfigDTO> for DeployConfigDAL {
     }
 
     async fn get_deployment_metadata(&self, id: i64) -> Result<DeployConfigDTO, RepoError> {
-        let deployment: DeployConfigDTO = query_as!(
-            DeployConfigDTO,
-            r#"
-            SELECT dc.id,
-            dc.name,
-            dc.client,
-            dc.environment,
-            dc.solution,
-            COALESCE(
-                array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
-                ARRAY[]::BIGINT[]
-            ) AS stack_ids
-            FROM deploy_config AS dc
-            LEFT JOIN compose_stack AS cs
-            ON cs.deployment_id = dc.id
-            WHERE dc.id = $1
-            GROUP BY dc.id, dc.client, dc.environment, dc.solution;
-            "#,
-            id
-        )
-        .fetch_one(&self.pool)
-        .await
-        .map_err(|err| {
-            error!("Database query failed: {err}");
-            err
-        })?;
-        Ok(deployment)
+        self.find_by_id(id).await
     }
 
-    async fn create(&self, object: DeployConfigDTO) -> Result<DeployConfigDTO, RepoError> {
-        let row = query!(
-            "INSERT INTO deploy_config(name, client, environment, solution
-            ) VALUES ($1, $2, $3, $4) RETURNING id, name, client, environment, solution;",
-            object.name,
-            object.client,
-            object.environment,
-            object.solution
-        )
-        .fetch_one(&self.pool)
-        .await
+    async fn create(
+        &self,
+        tx: &mut StorageTx,
+        object: DeployConfigDTO,
+    ) -> Result<DeployConfigDTO, RepoError> {
+        let (id, revision) = match tx {
+            StorageTx::Postgres(tx) => query!(
+                "INSERT INTO deploy_config(name, client, environment, solution
+                ) VALUES ($1, $2, $3, $4) RETURNING id, revision;",
+                object.name,
+                object.client,
+                object.environment,
+                object.solution
+            )
+            .fetch_one(&mut **tx)
+            .await
+            .map(|row| (row.id, row.revision)),
+            StorageTx::Sqlite(tx) => {
+                query_as(
+                    "INSERT INTO deploy_config(name, client, environment, solution
+                ) VALUES (?1, ?2, ?3, ?4) RETURNING id, revision;",
+                )
+                .bind(&object.name)
+                .bind(&object.client)
+                .bind(&object.environment)
+                .bind(&object.solution)
+                .fetch_one(&mut **tx)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
         })?;
         Ok(DeployConfigDTO {
-            id: Some(row.id),
-            name: row.name,
-            client: row.client,
-            environment: row.environment,
-            solution: row.solution,
+            id: Some(id),
+            revision: Some(revision),
+            version: Some(1),
             stack_ids: Some(Vec::<i64>::new()),
+            ..object
         })
     }
 
-    async fn update(&self, object: DeployConfigDTO) -> Result<bool, RepoError> {
-        let row = query!(
-            r#"UPDATE deploy_config SET name = $2, client=$3, environment=$4, solution=$5 WHERE id=$1;"#,
-            object.id,
-            object.name,
-            object.client,
-            object.environment,
-            object.solution
-        )
-        .execute(&self.pool)
-        .await
+    async fn update(&self, tx: &mut StorageTx, object: DeployConfigDTO) -> Result<bool, RepoError> {
+        let rows_affected = match tx {
+            StorageTx::Postgres(tx) => query!(
+                r#"UPDATE deploy_config
+                SET name = $2, client=$3, environment=$4, solution=$5, version = version + 1
+                WHERE id=$1 AND ($6::BIGINT IS NULL OR version = $6);"#,
+                object.id,
+                object.name,
+                object.client,
+                object.environment,
+                object.solution,
+                object.version
+            )
+            .execute(&mut **tx)
+            .await
+            .map(|row| row.rows_affected()),
+            StorageTx::Sqlite(tx) => query(
+                r#"UPDATE deploy_config
+                SET name = ?2, client=?3, environment=?4, solution=?5, version = version + 1
+                WHERE id=?1 AND (?6 IS NULL OR version = ?6);"#,
+            )
+            .bind(object.id)
+            .bind(&object.name)
+            .bind(&object.client)
+            .bind(&object.environment)
+            .bind(&object.solution)
+            .bind(object.version)
+            .execute(&mut **tx)
+            .await
+            .map(|row| row.rows_affected()),
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
         })?;
-        Ok(row.rows_affected() > 0)
+        Ok(rows_affected > 0)
     }
 
-    async fn delete(&self, id: i64) -> Result<bool, RepoError> {
-        let row = query!(r#"DELETE FROM deploy_config WHERE id=$1;"#, id)
-            .execute(&self.pool)
-            .await
-            .map_err(|err| {
-                error!("Database query failed: {err}");
-                err
-            })?;
-        Ok(row.rows_affected() > 0)
+    async fn delete(&self, tx: &mut StorageTx, id: i64) -> Result<bool, RepoError> {
+        let rows_affected = match tx {
+            StorageTx::Postgres(tx) => query!(r#"DELETE FROM deploy_config WHERE id=$1;"#, id)
+                .execute(&mut **tx)
+                .await
+                .map(|row| row.rows_affected()),
+            StorageTx::Sqlite(tx) => query(r#"DELETE FROM deploy_config WHERE id=?1;"#)
+                .bind(id)
+                .execute(&mut **tx)
+                .await
+                .map(|row| row.rows_affected()),
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(rows_affected > 0)
     }
 }
 pub trait Utils {
@@ -177,7 +286,13 @@ pub trait Utils {
         solution: &str,
     ) -> Result<Vec<DeployConfigDTO>, RepoError>;
     async fn find_by_name(&self, name: &str) -> Result<DeployConfigDTO, RepoError>;
+    async fn find_page(
+        &self,
+        filter: &DeploymentFilter,
+        page: PageRequest,
+    ) -> Result<Page<DeployConfigDTO>, RepoError>;
 }
+
 impl Utils for DeployConfigDAL {
     async fn find_by_metadata(
         &self,
@@ -185,31 +300,49 @@ impl Utils for DeployConfigDAL {
         environment: &str,
         solution: &str,
     ) -> Result<Vec<DeployConfigDTO>, RepoError> {
-        let deployments: Vec<DeployConfigDTO> = query_as!(
-            DeployConfigDTO,
-            r#"
-            SELECT dc.id,
-            dc.name,
-            dc.client,
-            dc.environment,
-            dc.solution,
-            COALESCE(
-                array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
-                ARRAY[]::BIGINT[]
-            ) AS stack_ids
-            FROM deploy_config AS dc
-            LEFT JOIN compose_stack AS cs
-            ON cs.deployment_id = dc.id
-            WHERE dc.client = $1 AND dc.environment = $2 AND dc.solution = $3
-            GROUP BY dc.id, dc.client, dc.environment, dc.solution
-            ORDER BY dc.id;
-            "#,
-            client,
-            environment,
-            solution,
-        )
-        .fetch_all(&self.pool)
-        .await
+        let deployments: Vec<DeployConfigDTO> = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    DeployConfigDTO,
+                    r#"
+                    SELECT dc.id,
+                    dc.name,
+                    dc.client,
+                    dc.environment,
+                    dc.solution,
+                    dc.revision,
+                    dc.version,
+                    COALESCE(
+                        array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
+                        ARRAY[]::BIGINT[]
+                    ) AS stack_ids
+                    FROM deploy_config AS dc
+                    LEFT JOIN compose_stack AS cs
+                    ON cs.deployment_id = dc.id
+                    WHERE dc.client = $1 AND dc.environment = $2 AND dc.solution = $3
+                    GROUP BY dc.id, dc.client, dc.environment, dc.solution
+                    ORDER BY dc.id;
+                    "#,
+                    client,
+                    environment,
+                    solution,
+                )
+                .fetch_all(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(&format!(
+                    "{SQLITE_DEPLOYMENT_SELECT}
+                    WHERE dc.client = ?1 AND dc.environment = ?2 AND dc.solution = ?3
+                    {SQLITE_DEPLOYMENT_GROUP};"
+                ))
+                .bind(client)
+                .bind(environment)
+                .bind(solution)
+                .fetch_all(pool)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
@@ -218,33 +351,75 @@ impl Utils for DeployConfigDAL {
     }
 
     async fn find_by_name(&self, name: &str) -> Result<DeployConfigDTO, RepoError> {
-        let deployment: DeployConfigDTO = query_as!(
-            DeployConfigDTO,
-            r#"
-            SELECT dc.id,
-            dc.name,
-            dc.client,
-            dc.environment,
-            dc.solution,
-            COALESCE(
-                array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
-                ARRAY[]::BIGINT[]
-            ) AS stack_ids
-            FROM deploy_config AS dc
-            LEFT JOIN compose_stack AS cs
-            ON cs.deployment_id = dc.id
-            WHERE dc.name = $1
-            GROUP BY dc.id, dc.client, dc.environment, dc.solution
-            ORDER BY dc.id;
-            "#,
-            name
-        )
-        .fetch_one(&self.pool)
-        .await
+        let deployment: DeployConfigDTO = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    DeployConfigDTO,
+                    r#"
+                    SELECT dc.id,
+                    dc.name,
+                    dc.client,
+                    dc.environment,
+                    dc.solution,
+                    dc.revision,
+                    dc.version,
+                    COALESCE(
+                        array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
+                        ARRAY[]::BIGINT[]
+                    ) AS stack_ids
+                    FROM deploy_config AS dc
+                    LEFT JOIN compose_stack AS cs
+                    ON cs.deployment_id = dc.id
+                    WHERE dc.name = $1
+                    GROUP BY dc.id, dc.client, dc.environment, dc.solution
+                    ORDER BY dc.id;
+                    "#,
+                    name
+                )
+                .fetch_one(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(&format!(
+                    "{SQLITE_DEPLOYMENT_SELECT} WHERE dc.name = ?1 {SQLITE_DEPLOYMENT_GROUP};"
+                ))
+                .bind(name)
+                .fetch_one(pool)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
         })?;
         Ok(deployment)
     }
+
+    async fn find_page(
+        &self,
+        filter: &DeploymentFilter,
+        page: PageRequest,
+    ) -> Result<Page<DeployConfigDTO>, RepoError> {
+        let conditions = Conditions::default()
+            .eq("dc.client", filter.client.clone())
+            .eq("dc.environment", filter.environment.clone())
+            .eq("dc.solution", filter.solution.clone());
+        let select = match &self.storage {
+            Storage::Postgres(_) => PG_DEPLOYMENT_SELECT,
+            Storage::Sqlite(_) => SQLITE_DEPLOYMENT_SELECT,
+        };
+        let query = PageQuery {
+            count: "SELECT COUNT(*) FROM deploy_config AS dc",
+            select,
+            tail: " GROUP BY dc.id",
+            alias: "dc",
+        };
+        let deployments = fetch_page(&self.storage, query, &conditions, page)
+            .await
+            .map_err(|err| {
+                error!("Database query failed: {err}");
+                err
+            })?;
+        Ok(deployments)
+    }
 }
@@ -0,0 +1,633 @@
+use std::collections::{HashMap, HashSet, hash_map::Entry};
+
+use log::error;
+use sqlx::{query, query_as, query_scalar, types::Json};
+
+use crate::{
+    objects::structs::{Container, DeployConfig, StackConfig},
+    server::{
+        dal::stack_config_dal::{SQLITE_STACK_GROUP, SQLITE_STACK_SELECT},
+        models::{
+            container::ContainerDTO, deploy_config::DeployConfigDTO, stack_config::StackConfigDTO,
+        },
+        storage::StorageTx,
+    },
+    utils::error::RepoError,
+};
+
+/// Creates the deployment called `name` or updates its metadata, returning its
+/// ID. Stacks are left alone, see [`sync_deploy_stacks`].
+pub async fn upsert_deployment(
+    tx: &mut StorageTx,
+    name: &str,
+    deploy_config: &DeployConfig,
+) -> Result<i64, RepoError> {
+    let id = match tx {
+        StorageTx::Postgres(tx) => {
+            query_scalar!(
+                r#"
+                INSERT INTO deploy_config(name, client, environment, solution
+                ) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (name) DO UPDATE
+                SET client = EXCLUDED.client,
+                environment = EXCLUDED.environment,
+                solution = EXCLUDED.solution,
+                version = CASE
+                    WHEN (deploy_config.client, deploy_config.environment, deploy_config.solution)
+                        IS DISTINCT FROM (EXCLUDED.client, EXCLUDED.environment, EXCLUDED.solution)
+                    THEN deploy_config.version + 1
+                    ELSE deploy_config.version
+                END
+                RETURNING id;
+                "#,
+                name,
+                deploy_config.client,
+                deploy_config.environment,
+                deploy_config.solution
+            )
+            .fetch_one(&mut **tx)
+            .await
+        }
+        StorageTx::Sqlite(tx) => {
+            query_scalar(
+                r#"
+                INSERT INTO deploy_config(name, client, environment, solution
+                ) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (name) DO UPDATE
+                SET client = excluded.client,
+                environment = excluded.environment,
+                solution = excluded.solution,
+                version = CASE
+                    WHEN (deploy_config.client, deploy_config.environment, deploy_config.solution)
+                        IS NOT (excluded.client, excluded.environment, excluded.solution)
+                    THEN deploy_config.version + 1
+                    ELSE deploy_config.version
+                END
+                RETURNING id;
+                "#,
+            )
+            .bind(name)
+            .bind(&deploy_config.client)
+            .bind(&deploy_config.environment)
+            .bind(&deploy_config.solution)
+            .fetch_one(&mut **tx)
+            .await
+        }
+    }
+    .map_err(|err| {
+        error!("Database query failed: {err}");
+        err
+    })?;
+    Ok(id)
+}
+
+/// Deletes the deployment `id`, its stacks and containers going with it.
+pub async fn delete_deployment(tx: &mut StorageTx, id: i64) -> Result<(), RepoError> {
+    match tx {
+        StorageTx::Postgres(tx) => query!(r#"DELETE FROM deploy_config WHERE id = $1;"#, id)
+            .execute(&mut **tx)
+            .await
+            .map(|_| ()),
+        StorageTx::Sqlite(tx) => query(r#"DELETE FROM deploy_config WHERE id = ?1;"#)
+            .bind(id)
+            .execute(&mut **tx)
+            .await
+            .map(|_| ()),
+    }
+    .map_err(|err| {
+        error!("Database query failed: {err}");
+        err
+    })?;
+    Ok(())
+}
+
+/// Moves deployment `id` to its next revision, returning it as `tx` now sees
+/// it. The row stays locked until `tx` ends, so changes to one deployment get
+/// their revisions one after the other.
+pub async fn bump_revision(tx: &mut StorageTx, id: i64) -> Result<DeployConfigDTO, RepoError> {
+    let deployment = match tx {
+        StorageTx::Postgres(tx) => {
+            query_as!(
+                DeployConfigDTO,
+                r#"
+                UPDATE deploy_config SET revision = revision + 1 WHERE id = $1
+                RETURNING id, name, client, environment, solution, revision, version,
+                NULL::BIGINT[] AS stack_ids;
+                "#,
+                id
+            )
+            .fetch_one(&mut **tx)
+            .await
+        }
+        StorageTx::Sqlite(tx) => {
+            query_as(
+                r#"
+                UPDATE deploy_config SET revision = revision + 1 WHERE id = ?1
+                RETURNING id, name, client, environment, solution, revision, version,
+                NULL AS stack_ids;
+                "#,
+            )
+            .bind(id)
+            .fetch_one(&mut **tx)
+            .await
+        }
+    }
+    .map_err(|err| {
+        error!("Database query failed: {err}");
+        err
+    })?;
+    Ok(deployment)
+}
+
+/// Stacks of `deployment_id` and their containers as `tx` sees them, for
+/// snapshots taken by the transaction changing them.
+pub async fn find_deploy_tree(
+    tx: &mut StorageTx,
+    deployment_id: i64,
+) -> Result<(Vec<StackConfigDTO>, Vec<ContainerDTO>), RepoError> {
+    let stacks: Vec<StackConfigDTO> = match &mut *tx {
+        StorageTx::Postgres(tx) => {
+            query_as!(
+                StackConfigDTO,
+                r#"
+                SELECT cs.id,
+                cs.deployment_id,
+                cs.stack_name,
+                cs.filename,
+                cs.home_directory,
+                cs.drift_policy,
+                cs.version,
+                COALESCE(
+                    array_agg(c.id) FILTER (WHERE c.id IS NOT NULL),
+                    ARRAY[]::BIGINT[]
+                ) AS containers
+                FROM compose_stack AS cs
+                LEFT JOIN container AS c
+                ON c.stack_id = cs.id
+                WHERE cs.deployment_id = $1
+                GROUP BY cs.id
+                ORDER BY cs.id;
+                "#,
+                deployment_id
+            )
+            .fetch_all(&mut **tx)
+            .await
+        }
+        StorageTx::Sqlite(tx) => {
+            query_as(&format!(
+                "{SQLITE_STACK_SELECT} WHERE cs.deployment_id = ?1 {SQLITE_STACK_GROUP};"
+            ))
+            .bind(deployment_id)
+            .fetch_all(&mut **tx)
+            .await
+        }
+    }
+    .map_err(|err| {
+        error!("Database query failed: {err}");
+        err
+    })?;
+    let containers: Vec<ContainerDTO> = match tx {
+        StorageTx::Postgres(tx) => {
+            query_as!(
+                ContainerDTO,
+                r#"
+                SELECT c.*
+                FROM container AS c
+                JOIN compose_stack AS cs
+                ON cs.id = c.stack_id
+                WHERE cs.deployment_id = $1
+                ORDER BY c.id;
+                "#,
+                deployment_id
+            )
+            .fetch_all(&mut **tx)
+            .await
+        }
+        StorageTx::Sqlite(tx) => {
+            query_as(
+                r#"
+                SELECT c.*
+                FROM container AS c
+                JOIN compose_stack AS cs
+                ON cs.id = c.stack_id
+                WHERE cs.deployment_id = ?1
+                ORDER BY c.id;
+                "#,
+            )
+            .bind(deployment_id)
+            .fetch_all(&mut **tx)
+            .await
+        }
+    }
+    .map_err(|err| {
+        error!("Database query failed: {err}");
+        err
+    })?;
+    Ok((stacks, containers))
+}
+
+/// Maps the names of existing rows to their IDs. Rows repeating a name are
+/// pushed to `stale`, along with every row whose name is not in `desired`.
+fn match_existing(
+    rows: Vec<(i64, String)>,
+    desired: HashSet<&String>,
+    stale: &mut Vec<i64>,
+) -> HashMap<String, i64> {
+    let mut existing: HashMap<String, i64> = HashMap::new();
+    for (id, name) in rows {
+        match existing.entry(name) {
+            Entry::Occupied(_) => stale.push(id),
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+            }
+        }
+    }
+    existing.retain(|name, id| {
+        let keep = desired.contains(name);
+        if !keep {
+            stale.push(*id);
+        }
+        keep
+    });
+    existing
+}
+
+/// Makes the stacks of `deployment_id` match `stacks`, matching existing rows
+/// by stack name and service name so unchanged entries keep their IDs and
+/// versions. Meant to run inside a transaction.
+pub async fn sync_deploy_stacks(
+    tx: &mut StorageTx,
+    deployment_id: i64,
+    stacks: &[StackConfig],
+) -> Result<(), RepoError> {
+    let rows: Vec<(i64, String)> =
+        match tx {
+            StorageTx::Postgres(tx) => query!(
+                r#"SELECT id, stack_name FROM compose_stack WHERE deployment_id = $1 ORDER BY id;"#,
+                deployment_id
+            )
+            .fetch_all(&mut **tx)
+            .await
+            .map(|rows| rows.into_iter().map(|r| (r.id, r.stack_name)).collect()),
+            StorageTx::Sqlite(tx) => query_as(
+                r#"SELECT id, stack_name FROM compose_stack WHERE deployment_id = ?1 ORDER BY id;"#,
+            )
+            .bind(deployment_id)
+            .fetch_all(&mut **tx)
+            .await,
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+    let mut stale: Vec<i64> = Vec::new();
+    let mut existing = match_existing(
+        rows,
+        stacks.iter().map(|stack| &stack.stack_name).collect(),
+        &mut stale,
+    );
+    // drop removed rows first so they can't collide with the ones inserted below
+    if !stale.is_empty() {
+        match tx {
+            StorageTx::Postgres(tx) => {
+                query!(r#"DELETE FROM compose_stack WHERE id = ANY($1);"#, &stale)
+                    .execute(&mut **tx)
+                    .await
+                    .map(|_| ())
+            }
+            StorageTx::Sqlite(tx) => {
+                query(r#"DELETE FROM compose_stack WHERE id IN (SELECT value FROM json_each(?1));"#)
+                    .bind(Json(&stale))
+                    .execute(&mut **tx)
+                    .await
+                    .map(|_| ())
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+    }
+
+    for stack in stacks {
+        let stack_id = match (existing.remove(&stack.stack_name), &mut *tx) {
+            (Some(stack_id), StorageTx::Postgres(tx)) => query!(
+                r#"
+                UPDATE compose_stack
+                SET filename=$2, home_directory=$3, drift_policy=$4, version = version + 1
+                WHERE id=$1
+                AND (filename, home_directory, drift_policy) IS DISTINCT FROM ($2, $3, $4);
+                "#,
+                stack_id,
+                stack.filename,
+                stack.home_directory,
+                stack.drift_policy.as_str()
+            )
+            .execute(&mut **tx)
+            .await
+            .map(|_| stack_id),
+            (Some(stack_id), StorageTx::Sqlite(tx)) => query(
+                r#"
+                    UPDATE compose_stack
+                    SET filename=?2, home_directory=?3, drift_policy=?4, version = version + 1
+                    WHERE id=?1
+                    AND (filename, home_directory, drift_policy) IS NOT (?2, ?3, ?4);
+                    "#,
+            )
+            .bind(stack_id)
+            .bind(&stack.filename)
+            .bind(&stack.home_directory)
+            .bind(stack.drift_policy.as_str())
+            .execute(&mut **tx)
+            .await
+            .map(|_| stack_id),
+            (None, StorageTx::Postgres(tx)) => {
+                query_scalar!(
+                    r#"
+                    INSERT INTO
+                    compose_stack(deployment_id, stack_name, filename, home_directory, drift_policy
+                    ) VALUES ($1, $2, $3, $4, $5)
+                    RETURNING id;
+                    "#,
+                    deployment_id,
+                    stack.stack_name,
+                    stack.filename,
+                    stack.home_directory,
+                    stack.drift_policy.as_str()
+                )
+                .fetch_one(&mut **tx)
+                .await
+            }
+            (None, StorageTx::Sqlite(tx)) => {
+                query_scalar(
+                    r#"
+                    INSERT INTO
+                    compose_stack(deployment_id, stack_name, filename, home_directory, drift_policy
+                    ) VALUES (?1, ?2, ?3, ?4, ?5)
+                    RETURNING id;
+                    "#,
+                )
+                .bind(deployment_id)
+                .bind(&stack.stack_name)
+                .bind(&stack.filename)
+                .bind(&stack.home_directory)
+                .bind(stack.drift_policy.as_str())
+                .fetch_one(&mut **tx)
+                .await
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        sync_stack_services(tx, stack_id, &stack.compose_spec.services).await?;
+    }
+
+    Ok(())
+}
+
+/// Makes the containers of `stack_id` match `services`, keyed by service name.
+pub async fn sync_stack_services(
+    tx: &mut StorageTx,
+    stack_id: i64,
+    services: &HashMap<String, Container>,
+) -> Result<(), RepoError> {
+    let rows: Vec<(i64, String)> = match tx {
+        StorageTx::Postgres(tx) => query!(
+            r#"SELECT id, service_name FROM container WHERE stack_id = $1 ORDER BY id;"#,
+            stack_id
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .map(|rows| rows.into_iter().map(|r| (r.id, r.service_name)).collect()),
+        StorageTx::Sqlite(tx) => {
+            query_as(r#"SELECT id, service_name FROM container WHERE stack_id = ?1 ORDER BY id;"#)
+                .bind(stack_id)
+                .fetch_all(&mut **tx)
+                .await
+        }
+    }
+    .map_err(|err| {
+        error!("Database query failed: {err}");
+        err
+    })?;
+    let mut stale: Vec<i64> = Vec::new();
+    let mut existing = match_existing(rows, services.keys().collect(), &mut stale);
+    // drop removed rows first so they can't collide with the ones inserted below
+    if !stale.is_empty() {
+        match tx {
+            StorageTx::Postgres(tx) => {
+                query!(r#"DELETE FROM container WHERE id = ANY($1);"#, &stale)
+                    .execute(&mut **tx)
+                    .await
+                    .map(|_| ())
+            }
+            StorageTx::Sqlite(tx) => {
+                query(r#"DELETE FROM container WHERE id IN (SELECT value FROM json_each(?1));"#)
+                    .bind(Json(&stale))
+                    .execute(&mut **tx)
+                    .await
+                    .map(|_| ())
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+    }
+
+    for (service_name, container) in services {
+        match (existing.remove(service_name), &mut *tx) {
+            (Some(container_id), StorageTx::Postgres(tx)) => {
+                query!(
+                    r#"
+                    UPDATE container SET
+                    container_name = $2,
+                    image = $3,
+                    restart = $4,
+                    "user" = $5,
+                    stdin_open = $6,
+                    tty = $7,
+                    command = $8,
+                    pull_policy = $9,
+                    ports = $10,
+                    volumes = $11,
+                    environment = $12,
+                    mem_reservation = $13,
+                    mem_limit = $14,
+                    oom_kill_disable = $15,
+                    privileged = $16,
+                    version = version + 1
+                    WHERE id = $1
+                    AND (container_name, image, restart, "user", stdin_open, tty, command, pull_policy, ports, volumes, environment, mem_reservation, mem_limit, oom_kill_disable, privileged)
+                        IS DISTINCT FROM ($2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16);
+                    "#,
+                    container_id,
+                    container.container_name,
+                    container.image,
+                    container.restart,
+                    container.user,
+                    container.stdin_open,
+                    container.tty,
+                    container.command,
+                    container.pull_policy,
+                    container.ports.as_deref(),
+                    container.volumes.as_deref(),
+                    container.environment.as_deref(),
+                    container.mem_reservation,
+                    container.mem_limit,
+                    container.oom_kill_disable,
+                    container.privileged
+                )
+                .execute(&mut **tx)
+                .await
+                .map(|_| ())
+            }
+            (Some(container_id), StorageTx::Sqlite(tx)) => {
+                query(
+                    r#"
+                    UPDATE container SET
+                    container_name = ?2,
+                    image = ?3,
+                    restart = ?4,
+                    "user" = ?5,
+                    stdin_open = ?6,
+                    tty = ?7,
+                    command = ?8,
+                    pull_policy = ?9,
+                    ports = ?10,
+                    volumes = ?11,
+                    environment = ?12,
+                    mem_reservation = ?13,
+                    mem_limit = ?14,
+                    oom_kill_disable = ?15,
+                    privileged = ?16,
+                    version = version + 1
+                    WHERE id = ?1
+                    AND (container_name, image, restart, "user", stdin_open, tty, command, pull_policy, ports, volumes, environment, mem_reservation, mem_limit, oom_kill_disable, privileged)
+                        IS NOT (?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16);
+                    "#,
+                )
+                .bind(container_id)
+                .bind(&container.container_name)
+                .bind(&container.image)
+                .bind(&container.restart)
+                .bind(&container.user)
+                .bind(container.stdin_open)
+                .bind(container.tty)
+                .bind(&container.command)
+                .bind(&container.pull_policy)
+                .bind(container.ports.as_ref().map(Json))
+                .bind(container.volumes.as_ref().map(Json))
+                .bind(container.environment.as_ref().map(Json))
+                .bind(&container.mem_reservation)
+                .bind(&container.mem_limit)
+                .bind(container.oom_kill_disable)
+                .bind(container.privileged)
+                .execute(&mut **tx)
+                .await
+                .map(|_| ())
+            }
+            (None, StorageTx::Postgres(tx)) => {
+                query!(
+                    r#"
+                    INSERT INTO
+                    container(
+                    stack_id,
+                    service_name,
+                    container_name,
+                    image,
+                    restart,
+                    "user",
+                    stdin_open,
+                    tty,
+                    command,
+                    pull_policy,
+                    ports,
+                    volumes,
+                    environment,
+                    mem_reservation,
+                    mem_limit,
+                    oom_kill_disable,
+                    privileged
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17);
+                    "#,
+                    stack_id,
+                    service_name,
+                    container.container_name,
+                    container.image,
+                    container.restart,
+                    container.user,
+                    container.stdin_open,
+                    container.tty,
+                    container.command,
+                    container.pull_policy,
+                    container.ports.as_deref(),
+                    container.volumes.as_deref(),
+                    container.environment.as_deref(),
+                    container.mem_reservation,
+                    container.mem_limit,
+                    container.oom_kill_disable,
+                    container.privileged
+                )
+                .execute(&mut **tx)
+                .await
+                .map(|_| ())
+            }
+            (None, StorageTx::Sqlite(tx)) => {
+                query(
+                    r#"
+                    INSERT INTO
+                    container(
+                    stack_id,
+                    service_name,
+                    container_name,
+                    image,
+                    restart,
+                    "user",
+                    stdin_open,
+                    tty,
+                    command,
+                    pull_policy,
+                    ports,
+                    volumes,
+                    environment,
+                    mem_reservation,
+                    mem_limit,
+                    oom_kill_disable,
+                    privileged
+                    )
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17);
+                    "#,
+                )
+                .bind(stack_id)
+                .bind(service_name)
+                .bind(&container.container_name)
+                .bind(&container.image)
+                .bind(&container.restart)
+                .bind(&container.user)
+                .bind(container.stdin_open)
+                .bind(container.tty)
+                .bind(&container.command)
+                .bind(&container.pull_policy)
+                .bind(container.ports.as_ref().map(Json))
+                .bind(container.volumes.as_ref().map(Json))
+                .bind(container.environment.as_ref().map(Json))
+                .bind(&container.mem_reservation)
+                .bind(&container.mem_limit)
+                .bind(container.oom_kill_disable)
+                .bind(container.privileged)
+                .execute(&mut **tx)
+                .await
+                .map(|_| ())
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+    }
+
+    Ok(())
+}
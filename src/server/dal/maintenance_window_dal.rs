@@ -0,0 +1,212 @@
+use log::error;
+use sqlx::{query, query_as, types::Json};
+
+use crate::{
+    server::{
+        models::maintenance_window::MaintenanceWindowDTO,
+        storage::{Storage, StorageTx},
+    },
+    utils::error::RepoError,
+};
+
+/// Maintenance windows, at most one per deployment.
+pub struct MaintenanceWindowDAL {
+    pub storage: Storage,
+}
+impl MaintenanceWindowDAL {
+    pub fn new(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
+    }
+
+    pub async fn find_by_deployment(
+        &self,
+        deployment_id: i64,
+    ) -> Result<Option<MaintenanceWindowDTO>, RepoError> {
+        let window: Option<MaintenanceWindowDTO> = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    MaintenanceWindowDTO,
+                    r#"
+                    SELECT deployment_id, schedule, duration_minutes, timezone
+                    FROM maintenance_window
+                    WHERE deployment_id = $1;
+                    "#,
+                    deployment_id
+                )
+                .fetch_optional(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(
+                    r#"
+                    SELECT deployment_id, schedule, duration_minutes, timezone
+                    FROM maintenance_window
+                    WHERE deployment_id = ?1;
+                    "#,
+                )
+                .bind(deployment_id)
+                .fetch_optional(pool)
+                .await
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(window)
+    }
+
+    pub async fn find_by_deployment_ids(
+        &self,
+        deployment_ids: &[i64],
+    ) -> Result<Vec<MaintenanceWindowDTO>, RepoError> {
+        let windows: Vec<MaintenanceWindowDTO> = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    MaintenanceWindowDTO,
+                    r#"
+                    SELECT deployment_id, schedule, duration_minutes, timezone
+                    FROM maintenance_window
+                    WHERE deployment_id = ANY($1);
+                    "#,
+                    deployment_ids
+                )
+                .fetch_all(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(
+                    r#"
+                    SELECT deployment_id, schedule, duration_minutes, timezone
+                    FROM maintenance_window
+                    WHERE deployment_id IN (SELECT value FROM json_each(?1));
+                    "#,
+                )
+                .bind(Json(deployment_ids))
+                .fetch_all(pool)
+                .await
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(windows)
+    }
+
+    pub async fn upsert(
+        &self,
+        tx: &mut StorageTx,
+        object: MaintenanceWindowDTO,
+    ) -> Result<MaintenanceWindowDTO, RepoError> {
+        match tx {
+            StorageTx::Postgres(tx) => query!(
+                r#"
+                INSERT INTO maintenance_window(
+                    deployment_id, schedule, duration_minutes, timezone
+                ) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (deployment_id) DO UPDATE
+                SET schedule = EXCLUDED.schedule,
+                duration_minutes = EXCLUDED.duration_minutes,
+                timezone = EXCLUDED.timezone;
+                "#,
+                object.deployment_id,
+                object.schedule,
+                object.duration_minutes,
+                object.timezone
+            )
+            .execute(&mut **tx)
+            .await
+            .map(|_| ()),
+            StorageTx::Sqlite(tx) => query(
+                r#"
+                INSERT INTO maintenance_window(
+                    deployment_id, schedule, duration_minutes, timezone
+                ) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (deployment_id) DO UPDATE
+                SET schedule = excluded.schedule,
+                duration_minutes = excluded.duration_minutes,
+                timezone = excluded.timezone;
+                "#,
+            )
+            .bind(object.deployment_id)
+            .bind(&object.schedule)
+            .bind(object.duration_minutes)
+            .bind(&object.timezone)
+            .execute(&mut **tx)
+            .await
+            .map(|_| ()),
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(object)
+    }
+
+    pub async fn delete(&self, tx: &mut StorageTx, deployment_id: i64) -> Result<bool, RepoError> {
+        let rows_affected = match tx {
+            StorageTx::Postgres(tx) => query!(
+                r#"DELETE FROM maintenance_window WHERE deployment_id = $1;"#,
+                deployment_id
+            )
+            .execute(&mut **tx)
+            .await
+            .map(|row| row.rows_affected()),
+            StorageTx::Sqlite(tx) => {
+                query(r#"DELETE FROM maintenance_window WHERE deployment_id = ?1;"#)
+                    .bind(deployment_id)
+                    .execute(&mut **tx)
+                    .await
+                    .map(|row| row.rows_affected())
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(rows_affected > 0)
+    }
+}
+
+/// Window of `deployment_id` as `tx` sees it, for snapshots taken by the
+/// transaction changing it.
+pub async fn find_window(
+    tx: &mut StorageTx,
+    deployment_id: i64,
+) -> Result<Option<MaintenanceWindowDTO>, RepoError> {
+    let window: Option<MaintenanceWindowDTO> = match tx {
+        StorageTx::Postgres(tx) => {
+            query_as!(
+                MaintenanceWindowDTO,
+                r#"
+                SELECT deployment_id, schedule, duration_minutes, timezone
+                FROM maintenance_window
+                WHERE deployment_id = $1;
+                "#,
+                deployment_id
+            )
+            .fetch_optional(&mut **tx)
+            .await
+        }
+        StorageTx::Sqlite(tx) => {
+            query_as(
+                r#"
+                SELECT deployment_id, schedule, duration_minutes, timezone
+                FROM maintenance_window
+                WHERE deployment_id = ?1;
+                "#,
+            )
+            .bind(deployment_id)
+            .fetch_optional(&mut **tx)
+            .await
+        }
+    }
+    .map_err(|err| {
+        error!("Database query failed: {err}");
+        err
+    })?;
+    Ok(window)
+}
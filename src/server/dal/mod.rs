@@ -1,3 +1,11 @@
+pub mod audit_log_dal;
+pub mod config_revision_dal;
 pub mod container_dal;
 pub mod deploy_config_dal;
+pub mod hikari_dal;
+pub mod maintenance_window_dal;
+pub mod paging;
+pub mod rollout_policy_dal;
 pub mod stack_config_dal;
+#[cfg(test)]
+mod tests;
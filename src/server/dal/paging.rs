@@ -0,0 +1,191 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::{Postgres, QueryBuilder, Row, Sqlite, postgres::PgRow, sqlite::SqliteRow};
+
+use crate::server::{
+    models::page::{CursorKey, Page, PageRequest, SortOrder},
+    storage::Storage,
+};
+
+/// Value compared against a column by a list filter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterValue {
+    Int(i64),
+    Text(String),
+    Time(DateTime<Utc>),
+}
+impl From<i64> for FilterValue {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+impl From<String> for FilterValue {
+    fn from(value: String) -> Self {
+        Self::Text(value)
+    }
+}
+impl From<DateTime<Utc>> for FilterValue {
+    fn from(value: DateTime<Utc>) -> Self {
+        Self::Time(value)
+    }
+}
+impl From<CursorKey> for FilterValue {
+    fn from(value: CursorKey) -> Self {
+        match value {
+            CursorKey::Int(value) => Self::Int(value),
+            CursorKey::Text(value) => Self::Text(value),
+            CursorKey::Time(value) => Self::Time(value),
+        }
+    }
+}
+
+/// Conditions ANDed into the `WHERE` clause of a list query. Columns and
+/// operators are fixed by the DAL, only the values come from the request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Conditions(Vec<(&'static str, &'static str, FilterValue)>);
+impl Conditions {
+    pub fn eq<V: Into<FilterValue>>(self, column: &'static str, value: Option<V>) -> Self {
+        self.push(column, "=", value)
+    }
+
+    pub fn ge<V: Into<FilterValue>>(self, column: &'static str, value: Option<V>) -> Self {
+        self.push(column, ">=", value)
+    }
+
+    pub fn le<V: Into<FilterValue>>(self, column: &'static str, value: Option<V>) -> Self {
+        self.push(column, "<=", value)
+    }
+
+    fn push<V: Into<FilterValue>>(
+        mut self,
+        column: &'static str,
+        operator: &'static str,
+        value: Option<V>,
+    ) -> Self {
+        if let Some(value) = value {
+            self.0.push((column, operator, value.into()));
+        }
+        self
+    }
+}
+
+/// Timestamps are TEXT on SQLite, written in the format of its column defaults
+/// so that they compare in order.
+fn sqlite_time(value: &DateTime<Utc>) -> String {
+    value.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Shape of a paged list query. `select` and `count` stop before the `WHERE`
+/// clause, `tail` goes between it and the `ORDER BY` (e.g. a `GROUP BY`).
+pub struct PageQuery<'a> {
+    pub count: &'a str,
+    pub select: &'a str,
+    pub tail: &'a str,
+    pub alias: &'a str,
+}
+
+macro_rules! fetch {
+    ($db:ty, $pool:expr, $query:expr, $conditions:expr, $page:expr, $time:expr) => {{
+        let push_value = |builder: &mut QueryBuilder<'_, $db>, value: &FilterValue| {
+            match value {
+                FilterValue::Int(value) => builder.push_bind(*value),
+                FilterValue::Text(value) => builder.push_bind(value.clone()),
+                FilterValue::Time(value) => builder.push_bind($time(value)),
+            };
+        };
+        let push_conditions = |builder: &mut QueryBuilder<'_, $db>| {
+            builder.push(" WHERE 1 = 1");
+            for (column, operator, value) in &$conditions.0 {
+                builder.push(format!(" AND {column} {operator} "));
+                push_value(builder, value);
+            }
+        };
+
+        let mut count = QueryBuilder::<$db>::new($query.count);
+        push_conditions(&mut count);
+        let total: i64 = count.build_query_scalar().fetch_one($pool).await?;
+
+        let alias = $query.alias;
+        let sort = $page.sort;
+        let order = $page.order.as_sql();
+        let mut select = QueryBuilder::<$db>::new($query.select);
+        push_conditions(&mut select);
+        if let Some(after) = &$page.after {
+            let operator = match $page.order {
+                SortOrder::Asc => ">",
+                SortOrder::Desc => "<",
+            };
+            let key = FilterValue::from(after.key.clone());
+            select.push(format!(" AND ({alias}.{sort} {operator} "));
+            push_value(&mut select, &key);
+            select.push(format!(" OR ({alias}.{sort} = "));
+            push_value(&mut select, &key);
+            select
+                .push(format!(" AND {alias}.id {operator} "))
+                .push_bind(after.id)
+                .push("))");
+        }
+        select
+            .push(format!(
+                "{} ORDER BY {alias}.{sort} {order}, {alias}.id {order} LIMIT ",
+                $query.tail
+            ))
+            .push_bind($page.limit + 1);
+        let mut rows = select.build().fetch_all($pool).await?;
+        let next = if rows.len() as i64 > $page.limit {
+            rows.truncate($page.limit as usize);
+            rows.last().map(|row| page_key(row, sort)).transpose()?
+        } else {
+            None
+        };
+        let items = rows
+            .iter()
+            .map(T::from_row)
+            .collect::<Result<Vec<T>, _>>()?;
+        Ok($page.into_page(items, total, next))
+    }};
+}
+
+/// Sort key and ID of `row`, where the next page starts. Integer and text
+/// columns decode on both backends, timestamps are TEXT on SQLite.
+fn page_key<R>(row: &R, sort: &str) -> Result<(CursorKey, i64), sqlx::Error>
+where
+    R: Row,
+    for<'r> i64: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+    for<'r> String: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+    for<'r> DateTime<Utc>: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+    for<'a> &'a str: sqlx::ColumnIndex<R>,
+{
+    let key = if let Ok(value) = row.try_get::<i64, _>(sort) {
+        CursorKey::Int(value)
+    } else if let Ok(value) = row.try_get::<String, _>(sort) {
+        CursorKey::Text(value)
+    } else {
+        CursorKey::Time(row.try_get::<DateTime<Utc>, _>(sort)?)
+    };
+    Ok((key, row.try_get("id")?))
+}
+
+/// Counts the rows matching `conditions` and fetches the page after the
+/// request's cursor. Rows tie-break on `{alias}.id`, so the sort key and ID of
+/// the last row say exactly where the next page starts.
+pub async fn fetch_page<T>(
+    storage: &Storage,
+    query: PageQuery<'_>,
+    conditions: &Conditions,
+    page: PageRequest,
+) -> Result<Page<T>, sqlx::Error>
+where
+    T: for<'r> sqlx::FromRow<'r, PgRow> + for<'r> sqlx::FromRow<'r, SqliteRow> + Send + Unpin,
+{
+    match storage {
+        Storage::Postgres(pool) => fetch!(
+            Postgres,
+            pool,
+            query,
+            conditions,
+            page,
+            |value: &DateTime<Utc>| *value
+        ),
+        Storage::Sqlite(pool) => fetch!(Sqlite, pool, query, conditions, page, sqlite_time),
+    }
+}
@@ -0,0 +1,138 @@
+use log::error;
+use sqlx::{query, query_as};
+
+use crate::{
+    server::{
+        models::rollout_policy::RolloutPolicyDTO,
+        storage::{Storage, StorageTx},
+    },
+    utils::error::RepoError,
+};
+
+/// Rollout policies, at most one per deployment.
+pub struct RolloutPolicyDAL {
+    pub storage: Storage,
+}
+impl RolloutPolicyDAL {
+    pub fn new(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
+    }
+
+    pub async fn find_by_deployment(
+        &self,
+        deployment_id: i64,
+    ) -> Result<Option<RolloutPolicyDTO>, RepoError> {
+        let policy: Option<RolloutPolicyDTO> = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    RolloutPolicyDTO,
+                    r#"
+                    SELECT deployment_id, canary_nodes, canary_percent, health_timeout_secs, on_failure
+                    FROM rollout_policy
+                    WHERE deployment_id = $1;
+                    "#,
+                    deployment_id
+                )
+                .fetch_optional(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(
+                    r#"
+                    SELECT deployment_id, canary_nodes, canary_percent, health_timeout_secs, on_failure
+                    FROM rollout_policy
+                    WHERE deployment_id = ?1;
+                    "#,
+                )
+                .bind(deployment_id)
+                .fetch_optional(pool)
+                .await
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(policy)
+    }
+
+    pub async fn upsert(
+        &self,
+        tx: &mut StorageTx,
+        object: RolloutPolicyDTO,
+    ) -> Result<RolloutPolicyDTO, RepoError> {
+        match tx {
+            StorageTx::Postgres(tx) => query!(
+                r#"
+                INSERT INTO rollout_policy(
+                    deployment_id, canary_nodes, canary_percent, health_timeout_secs, on_failure
+                ) VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (deployment_id) DO UPDATE
+                SET canary_nodes = EXCLUDED.canary_nodes,
+                canary_percent = EXCLUDED.canary_percent,
+                health_timeout_secs = EXCLUDED.health_timeout_secs,
+                on_failure = EXCLUDED.on_failure;
+                "#,
+                object.deployment_id,
+                object.canary_nodes,
+                object.canary_percent,
+                object.health_timeout_secs,
+                object.on_failure
+            )
+            .execute(&mut **tx)
+            .await
+            .map(|_| ()),
+            StorageTx::Sqlite(tx) => query(
+                r#"
+                INSERT INTO rollout_policy(
+                    deployment_id, canary_nodes, canary_percent, health_timeout_secs, on_failure
+                ) VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (deployment_id) DO UPDATE
+                SET canary_nodes = excluded.canary_nodes,
+                canary_percent = excluded.canary_percent,
+                health_timeout_secs = excluded.health_timeout_secs,
+                on_failure = excluded.on_failure;
+                "#,
+            )
+            .bind(object.deployment_id)
+            .bind(object.canary_nodes)
+            .bind(object.canary_percent)
+            .bind(object.health_timeout_secs)
+            .bind(&object.on_failure)
+            .execute(&mut **tx)
+            .await
+            .map(|_| ()),
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(object)
+    }
+
+    pub async fn delete(&self, tx: &mut StorageTx, deployment_id: i64) -> Result<bool, RepoError> {
+        let rows_affected = match tx {
+            StorageTx::Postgres(tx) => query!(
+                r#"DELETE FROM rollout_policy WHERE deployment_id = $1;"#,
+                deployment_id
+            )
+            .execute(&mut **tx)
+            .await
+            .map(|row| row.rows_affected()),
+            StorageTx::Sqlite(tx) => {
+                query(r#"DELETE FROM rollout_policy WHERE deployment_id = ?1;"#)
+                    .bind(deployment_id)
+                    .execute(&mut **tx)
+                    .await
+                    .map(|row| row.rows_affected())
+            }
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(rows_affected > 0)
+    }
+}
@@ -1,56 +1,129 @@
 use log::error;
-use sqlx::{PgPool, query, query_as, query_scalar};
+use sqlx::{query, query_as, query_scalar, types::Json};
 
 use crate::{
     server::{
-        models::{deploy_config::DeployConfigDTO, stack_config::StackConfigDTO},
+        dal::{
+            deploy_config_dal::{SQLITE_DEPLOYMENT_GROUP, SQLITE_DEPLOYMENT_SELECT},
+            paging::{Conditions, PageQuery, fetch_page},
+        },
+        models::{
+            deploy_config::DeployConfigDTO,
+            page::{Page, PageRequest},
+            stack_config::{StackConfigDTO, StackFilter},
+        },
+        storage::{Storage, StorageTx},
         traits::model::DataRepository,
     },
     utils::error::RepoError,
 };
 
+/// Stack columns plus the IDs of their containers, for SQLite. Append the
+/// `WHERE` clause and [`SQLITE_STACK_GROUP`].
+pub(crate) const SQLITE_STACK_SELECT: &str = r#"
+    SELECT cs.id,
+    cs.deployment_id,
+    cs.stack_name,
+    cs.filename,
+    cs.home_directory,
+    cs.drift_policy,
+    cs.version,
+    json_group_array(c.id) FILTER (WHERE c.id IS NOT NULL) AS containers
+    FROM compose_stack AS cs
+    LEFT JOIN container AS c
+    ON c.stack_id = cs.id
+"#;
+pub(crate) const SQLITE_STACK_GROUP: &str = "GROUP BY cs.id ORDER BY cs.id";
+
+/// Postgres counterpart of [`SQLITE_STACK_SELECT`] for runtime queries, with
+/// the container IDs as JSON so both backends decode them the same way.
+const PG_STACK_SELECT: &str = r#"
+    SELECT cs.id,
+    cs.deployment_id,
+    cs.stack_name,
+    cs.filename,
+    cs.home_directory,
+    cs.drift_policy,
+    cs.version,
+    COALESCE(json_agg(c.id ORDER BY c.id) FILTER (WHERE c.id IS NOT NULL), '[]') AS containers
+    FROM compose_stack AS cs
+    LEFT JOIN container AS c
+    ON c.stack_id = cs.id
+"#;
+
+/// Sortable columns of the stack list.
+pub const STACK_SORT_FIELDS: &[&str] = &[
+    "id",
+    "deployment_id",
+    "stack_name",
+    "filename",
+    "home_directory",
+];
+
 pub struct StackConfigDAL {
-    pub pool: PgPool,
+    pub storage: Storage,
 }
 impl DataRepository<StackConfigDTO> for StackConfigDAL {
     type Payload = StackConfigDTO;
 
-    fn new(pool: &PgPool) -> Self {
-        Self { pool: pool.clone() }
+    fn new(storage: &Storage) -> Self {
+        Self {
+            storage: storage.clone(),
+        }
     }
 
     async fn exists(&self, id: i64) -> Result<bool, RepoError> {
-        let exists = query_scalar!(
-            "SELECT EXISTS(SELECT id FROM compose_stack WHERE id = $1)",
-            id
-        )
-        .fetch_one(&self.pool)
-        .await?;
-        Ok(exists.unwrap_or(false))
+        let exists = match &self.storage {
+            Storage::Postgres(pool) => query_scalar!(
+                "SELECT EXISTS(SELECT id FROM compose_stack WHERE id = $1)",
+                id
+            )
+            .fetch_one(pool)
+            .await?
+            .unwrap_or(false),
+            Storage::Sqlite(pool) => {
+                query_scalar("SELECT EXISTS(SELECT id FROM compose_stack WHERE id = ?1)")
+                    .bind(id)
+                    .fetch_one(pool)
+                    .await?
+            }
+        };
+        Ok(exists)
     }
 
     async fn find_all(&self) -> Result<Vec<StackConfigDTO>, RepoError> {
-        let compose_stacks: Vec<StackConfigDTO> = query_as!(
-            StackConfigDTO,
-            r#"
-            SELECT cs.id,
-            cs.deployment_id,
-            cs.stack_name,
-            cs.filename,
-            cs.home_directory,
-            COALESCE(
-                array_agg(c.id) FILTER (WHERE c.id IS NOT NULL),
-                ARRAY[]::BIGINT[]
-            ) AS containers
-            FROM compose_stack AS cs
-            LEFT JOIN container AS c
-            ON c.stack_id = cs.id
-            GROUP BY cs.id, cs.deployment_id, cs.stack_name, cs.filename, cs.home_directory
-            ORDER BY cs.id;
-            "#,
-        )
-        .fetch_all(&self.pool)
-        .await
+        let compose_stacks: Vec<StackConfigDTO> = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    StackConfigDTO,
+                    r#"
+                    SELECT cs.id,
+                    cs.deployment_id,
+                    cs.stack_name,
+                    cs.filename,
+                    cs.home_directory,
+                    cs.drift_policy,
+                    cs.version,
+                    COALESCE(
+                        array_agg(c.id) FILTER (WHERE c.id IS NOT NULL),
+                        ARRAY[]::BIGINT[]
+                    ) AS containers
+                    FROM compose_stack AS cs
+                    LEFT JOIN container AS c
+                    ON c.stack_id = cs.id
+                    GROUP BY cs.id, cs.deployment_id, cs.stack_name, cs.filename, cs.home_directory
+                    ORDER BY cs.id;
+                    "#,
+                )
+                .fetch_all(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(&format!("{SQLITE_STACK_SELECT} {SQLITE_STACK_GROUP};"))
+                    .fetch_all(pool)
+                    .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
@@ -59,28 +132,42 @@ impl DataRepository<StackConfigDTO> for StackConfigDAL {
     }
 
     async fn find_by_id(&self, id: i64) -> Result<StackConfigDTO, RepoError> {
-        let compose_stack: StackConfigDTO = query_as!(
-            StackConfigDTO,
-            r#"
-            SELECT cs.id,
-            cs.deployment_id,
-            cs.stack_name,
-            cs.filename,
-            cs.home_directory,
-            COALESCE(
-                array_agg(c.id) FILTER (WHERE c.id IS NOT NULL),
-                ARRAY[]::BIGINT[]
-            ) AS containers
-            FROM compose_stack AS cs
-            LEFT JOIN container AS c
-            ON c.stack_id = cs.id
-            WHERE cs.id = $1
-            GROUP BY cs.id, cs.deployment_id, cs.stack_name, cs.filename, cs.home_directory;
-            "#,
-            id
-        )
-        .fetch_one(&self.pool)
-        .await
+        let compose_stack: StackConfigDTO = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    StackConfigDTO,
+                    r#"
+                    SELECT cs.id,
+                    cs.deployment_id,
+                    cs.stack_name,
+                    cs.filename,
+                    cs.home_directory,
+                    cs.drift_policy,
+                    cs.version,
+                    COALESCE(
+                        array_agg(c.id) FILTER (WHERE c.id IS NOT NULL),
+                        ARRAY[]::BIGINT[]
+                    ) AS containers
+                    FROM compose_stack AS cs
+                    LEFT JOIN container AS c
+                    ON c.stack_id = cs.id
+                    WHERE cs.id = $1
+                    GROUP BY cs.id, cs.deployment_id, cs.stack_name, cs.filename, cs.home_directory;
+                    "#,
+                    id
+                )
+                .fetch_one(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(&format!(
+                    "{SQLITE_STACK_SELECT} WHERE cs.id = ?1 {SQLITE_STACK_GROUP};"
+                ))
+                .bind(id)
+                .fetch_one(pool)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
@@ -89,29 +176,45 @@ impl DataRepository<StackConfigDTO> for StackConfigDAL {
     }
 
     async fn get_deployment_metadata(&self, id: i64) -> Result<DeployConfigDTO, RepoError> {
-        let deployment: DeployConfigDTO = query_as!(
-            DeployConfigDTO,
-            r#"
-            SELECT
-            dc.id,
-            dc.name,
-            dc.client,
-            dc.environment,
-            dc.solution,
-            COALESCE(
-                array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
-                ARRAY[]::BIGINT[]
-            ) AS stack_ids
-            FROM compose_stack AS cs
-            JOIN deploy_config AS dc
-            ON cs.deployment_id = dc.id
-            WHERE cs.id = $1
-            GROUP BY dc.id, dc.client, dc.environment, dc.solution;
-            "#,
-            id
-        )
-        .fetch_one(&self.pool)
-        .await
+        let deployment: DeployConfigDTO = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    DeployConfigDTO,
+                    r#"
+                    SELECT
+                    dc.id,
+                    dc.name,
+                    dc.client,
+                    dc.environment,
+                    dc.solution,
+                    dc.revision,
+                    dc.version,
+                    COALESCE(
+                        array_agg(cs.id) FILTER (WHERE cs.id IS NOT NULL),
+                        ARRAY[]::BIGINT[]
+                    ) AS stack_ids
+                    FROM compose_stack AS cs
+                    JOIN deploy_config AS dc
+                    ON cs.deployment_id = dc.id
+                    WHERE cs.id = $1
+                    GROUP BY dc.id, dc.client, dc.environment, dc.solution;
+                    "#,
+                    id
+                )
+                .fetch_one(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(&format!(
+                    "{SQLITE_DEPLOYMENT_SELECT}
+                    WHERE dc.id = (SELECT deployment_id FROM compose_stack WHERE id = ?1)
+                    {SQLITE_DEPLOYMENT_GROUP};"
+                ))
+                .bind(id)
+                .fetch_one(pool)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
@@ -119,66 +222,212 @@ impl DataRepository<StackConfigDTO> for StackConfigDAL {
         Ok(deployment)
     }
 
-    async fn create(&self, object: StackConfigDTO) -> Result<StackConfigDTO, RepoError> {
-        let row = query!(
-            r#"
-            INSERT INTO
-            compose_stack(deployment_id, stack_name, filename, home_directory
-            ) VALUES ($1, $2, $3, $4)
-            RETURNING id, deployment_id, stack_name, filename, home_directory;
-            "#,
-            object.deployment_id,
-            object.stack_name,
-            object.filename,
-            object.home_directory
-        )
-        .fetch_one(&self.pool)
-        .await
+    async fn create(
+        &self,
+        tx: &mut StorageTx,
+        object: StackConfigDTO,
+    ) -> Result<StackConfigDTO, RepoError> {
+        let id = match tx {
+            StorageTx::Postgres(tx) => {
+                query_scalar!(
+                    r#"
+                    INSERT INTO
+                    compose_stack(deployment_id, stack_name, filename, home_directory, drift_policy
+                    ) VALUES ($1, $2, $3, $4, $5)
+                    RETURNING id;
+                    "#,
+                    object.deployment_id,
+                    object.stack_name,
+                    object.filename,
+                    object.home_directory,
+                    object.drift_policy
+                )
+                .fetch_one(&mut **tx)
+                .await
+            }
+            StorageTx::Sqlite(tx) => {
+                query_scalar(
+                    r#"
+                    INSERT INTO
+                    compose_stack(deployment_id, stack_name, filename, home_directory, drift_policy
+                    ) VALUES (?1, ?2, ?3, ?4, ?5)
+                    RETURNING id;
+                    "#,
+                )
+                .bind(object.deployment_id)
+                .bind(&object.stack_name)
+                .bind(&object.filename)
+                .bind(&object.home_directory)
+                .bind(&object.drift_policy)
+                .fetch_one(&mut **tx)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
         })?;
         Ok(StackConfigDTO {
-            id: Some(row.id),
-            deployment_id: row.deployment_id,
-            stack_name: row.stack_name,
-            filename: row.filename,
-            home_directory: row.home_directory,
+            id: Some(id),
+            version: Some(1),
             containers: Some(Vec::<i64>::new()),
+            ..object
         })
     }
 
-    async fn update(&self, object: StackConfigDTO) -> Result<bool, RepoError> {
-        let row = query!(
-            r#"UPDATE compose_stack
-            SET deployment_id=$2,
-            stack_name=$3,
-            filename=$4,
-            home_directory=$5
-            WHERE id=$1;"#,
-            object.id,
-            object.deployment_id,
-            object.stack_name,
-            object.filename,
-            object.home_directory
-        )
-        .execute(&self.pool)
-        .await
+    async fn update(&self, tx: &mut StorageTx, object: StackConfigDTO) -> Result<bool, RepoError> {
+        let rows_affected = match tx {
+            StorageTx::Postgres(tx) => query!(
+                r#"UPDATE compose_stack
+                SET deployment_id=$2,
+                stack_name=$3,
+                filename=$4,
+                home_directory=$5,
+                drift_policy=$7,
+                version = version + 1
+                WHERE id=$1 AND ($6::BIGINT IS NULL OR version = $6);"#,
+                object.id,
+                object.deployment_id,
+                object.stack_name,
+                object.filename,
+                object.home_directory,
+                object.version,
+                object.drift_policy
+            )
+            .execute(&mut **tx)
+            .await
+            .map(|row| row.rows_affected()),
+            StorageTx::Sqlite(tx) => query(
+                r#"UPDATE compose_stack
+                SET deployment_id=?2,
+                stack_name=?3,
+                filename=?4,
+                home_directory=?5,
+                drift_policy=?7,
+                version = version + 1
+                WHERE id=?1 AND (?6 IS NULL OR version = ?6);"#,
+            )
+            .bind(object.id)
+            .bind(object.deployment_id)
+            .bind(&object.stack_name)
+            .bind(&object.filename)
+            .bind(&object.home_directory)
+            .bind(object.version)
+            .bind(&object.drift_policy)
+            .execute(&mut **tx)
+            .await
+            .map(|row| row.rows_affected()),
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(rows_affected > 0)
+    }
+
+    async fn delete(&self, tx: &mut StorageTx, id: i64) -> Result<bool, RepoError> {
+        let rows_affected = match tx {
+            StorageTx::Postgres(tx) => query!(r#"DELETE FROM compose_stack WHERE id=$1;"#, id)
+                .execute(&mut **tx)
+                .await
+                .map(|row| row.rows_affected()),
+            StorageTx::Sqlite(tx) => query(r#"DELETE FROM compose_stack WHERE id=?1;"#)
+                .bind(id)
+                .execute(&mut **tx)
+                .await
+                .map(|row| row.rows_affected()),
+        }
+        .map_err(|err| {
+            error!("Database query failed: {err}");
+            err
+        })?;
+        Ok(rows_affected > 0)
+    }
+}
+pub trait Utils {
+    async fn find_by_deployment_ids(
+        &self,
+        deployment_ids: &[i64],
+    ) -> Result<Vec<StackConfigDTO>, RepoError>;
+    async fn find_page(
+        &self,
+        filter: &StackFilter,
+        page: PageRequest,
+    ) -> Result<Page<StackConfigDTO>, RepoError>;
+}
+impl Utils for StackConfigDAL {
+    async fn find_by_deployment_ids(
+        &self,
+        deployment_ids: &[i64],
+    ) -> Result<Vec<StackConfigDTO>, RepoError> {
+        let compose_stacks: Vec<StackConfigDTO> = match &self.storage {
+            Storage::Postgres(pool) => {
+                query_as!(
+                    StackConfigDTO,
+                    r#"
+                    SELECT cs.id,
+                    cs.deployment_id,
+                    cs.stack_name,
+                    cs.filename,
+                    cs.home_directory,
+                    cs.drift_policy,
+                    cs.version,
+                    COALESCE(
+                        array_agg(c.id) FILTER (WHERE c.id IS NOT NULL),
+                        ARRAY[]::BIGINT[]
+                    ) AS containers
+                    FROM compose_stack AS cs
+                    LEFT JOIN container AS c
+                    ON c.stack_id = cs.id
+                    WHERE cs.deployment_id = ANY($1)
+                    GROUP BY cs.id, cs.deployment_id, cs.stack_name, cs.filename, cs.home_directory
+                    ORDER BY cs.id;
+                    "#,
+                    deployment_ids
+                )
+                .fetch_all(pool)
+                .await
+            }
+            Storage::Sqlite(pool) => {
+                query_as(&format!(
+                    "{SQLITE_STACK_SELECT}
+                    WHERE cs.deployment_id IN (SELECT value FROM json_each(?1))
+                    {SQLITE_STACK_GROUP};"
+                ))
+                .bind(Json(deployment_ids))
+                .fetch_all(pool)
+                .await
+            }
+        }
         .map_err(|err| {
             error!("Database query failed: {err}");
             err
         })?;
-        Ok(row.rows_affected() > 0)
+        Ok(compose_stacks)
     }
 
-    async fn delete(&self, id: i64) -> Result<bool, RepoError> {
-        let row = query!(r#"DELETE FROM compose_stack WHERE id=$1;"#, id)
-            .execute(&self.pool)
+    async fn find_page(
+        &self,
+        filter: &StackFilter,
+        page: PageRequest,
+    ) -> Result<Page<StackConfigDTO>, RepoError> {
+        let conditions = Conditions::default().eq("cs.deployment_id", filter.deployment_id);
+        let select = match &self.storage {
+            Storage::Postgres(_) => PG_STACK_SELECT,
+            Storage::Sqlite(_) => SQLITE_STACK_SELECT,
+        };
+        let query = PageQuery {
+            count: "SELECT COUNT(*) FROM compose_stack AS cs",
+            select,
+            tail: " GROUP BY cs.id",
+            alias: "cs",
+        };
+        let compose_stacks = fetch_page(&self.storage, query, &conditions, page)
             .await
             .map_err(|err| {
                 error!("Database query failed: {err}");
                 err
             })?;
-        Ok(row.rows_affected() > 0)
+        Ok(compose_stacks)
     }
 }
@@ -0,0 +1,570 @@
+use std::{collections::HashMap, fmt::Debug, str::FromStr, sync::Arc};
+
+use axum::{Extension, Json};
+use reqwest::StatusCode;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use tokio::sync::RwLock;
+
+use crate::{
+    mode::server::AppState,
+    objects::structs::{
+        ComposeSpec, Container, DeployConfig, HikariConfig, MaintenanceWindow, StackConfig,
+    },
+    server::{
+        api::hikari::{export_hikari, put_hikari},
+        common::{ApiJson, ChangeMeta},
+        dal::{
+            config_revision_dal::{ConfigRevisionDAL, record_revision},
+            container_dal::ContainerDAL,
+            deploy_config_dal::{DeployConfigDAL, Utils},
+            stack_config_dal::StackConfigDAL,
+        },
+        error::{ApiError, ErrorCode},
+        metrics::ServerMetrics,
+        migrate::SQLITE_MIGRATOR,
+        models::{
+            config_revision::ConfigRevisionDTO,
+            container::ContainerDTO,
+            deploy_config::{DeployConfigDTO, DeploymentFilter},
+            page::{Cursor, PageRequest, SortOrder},
+            stack_config::StackConfigDTO,
+        },
+        storage::Storage,
+        traits::model::{DataRepository, Versioned},
+    },
+    utils::error::RepoError,
+};
+
+/// Migrated in-memory SQLite database. A single connection that never expires
+/// keeps the database alive for the whole test.
+async fn storage() -> Storage {
+    let options = SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap()
+        .foreign_keys(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(options)
+        .await
+        .unwrap();
+    SQLITE_MIGRATOR.run(&pool).await.unwrap();
+    Storage::Sqlite(pool)
+}
+
+/// Rows the [`DataRepository`] checks run against.
+trait Fixture: Versioned + Clone + Debug {
+    fn id(&self) -> Option<i64>;
+    /// Copy with an edited column and the given version.
+    fn edited(&self, version: Option<i64>) -> Self;
+    /// Whether `self` carries the edit made by [`Fixture::edited`].
+    fn is_edited(&self) -> bool;
+}
+
+impl Fixture for DeployConfigDTO {
+    fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    fn edited(&self, version: Option<i64>) -> Self {
+        Self {
+            solution: "edited".into(),
+            version,
+            ..self.clone()
+        }
+    }
+
+    fn is_edited(&self) -> bool {
+        self.solution == "edited"
+    }
+}
+
+impl Fixture for StackConfigDTO {
+    fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    fn edited(&self, version: Option<i64>) -> Self {
+        Self {
+            home_directory: "/srv/edited".into(),
+            version,
+            ..self.clone()
+        }
+    }
+
+    fn is_edited(&self) -> bool {
+        self.home_directory == "/srv/edited"
+    }
+}
+
+impl Fixture for ContainerDTO {
+    fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    fn edited(&self, version: Option<i64>) -> Self {
+        Self {
+            image: "nginx:edited".into(),
+            version,
+            ..self.clone()
+        }
+    }
+
+    fn is_edited(&self) -> bool {
+        self.image == "nginx:edited"
+    }
+}
+
+fn deployment(name: &str) -> DeployConfigDTO {
+    DeployConfigDTO {
+        name: name.into(),
+        client: name.into(),
+        environment: "test".into(),
+        solution: "hikari".into(),
+        ..Default::default()
+    }
+}
+
+fn stack(deployment_id: i64, stack_name: &str) -> StackConfigDTO {
+    StackConfigDTO {
+        deployment_id,
+        stack_name: stack_name.into(),
+        filename: "docker-compose.yml".into(),
+        home_directory: "/srv".into(),
+        drift_policy: "report".into(),
+        ..Default::default()
+    }
+}
+
+fn container(stack_id: i64, service_name: &str) -> ContainerDTO {
+    ContainerDTO {
+        stack_id,
+        service_name: service_name.into(),
+        container_name: service_name.into(),
+        image: "nginx:1.27".into(),
+        restart: "always".into(),
+        ports: Some(vec!["8080:80".into()]),
+        environment: Some(vec!["KEY=value".into()]),
+        ..Default::default()
+    }
+}
+
+async fn create<T, D>(storage: &Storage, payload: T) -> Result<T, RepoError>
+where
+    D: DataRepository<T, Payload = T>,
+{
+    let mut tx = storage.begin().await.unwrap();
+    let created = D::new(storage).create(&mut tx, payload).await?;
+    tx.commit().await.unwrap();
+    Ok(created)
+}
+
+/// Create, find, versioned update and delete of `payload` through `D`.
+async fn check_repository<T, D>(storage: &Storage, payload: T)
+where
+    T: Fixture,
+    D: DataRepository<T, Payload = T>,
+{
+    let dal = D::new(storage);
+    let created = create::<T, D>(storage, payload).await.unwrap();
+    let id = created.id().expect("created rows have an ID");
+    assert_eq!(created.version(), Some(1));
+
+    assert!(dal.exists(id).await.unwrap());
+    let found = dal.find_by_id(id).await.unwrap();
+    assert_eq!(found.id(), Some(id));
+    assert_eq!(found.version(), Some(1));
+    assert!(!found.is_edited());
+    let all = dal.find_all().await.unwrap();
+    assert!(all.iter().any(|row| row.id() == Some(id)));
+
+    let mut tx = storage.begin().await.unwrap();
+    assert!(dal.update(&mut tx, found.edited(Some(1))).await.unwrap());
+    assert!(
+        !dal.update(&mut tx, found.edited(Some(1))).await.unwrap(),
+        "stale versions must not update"
+    );
+    tx.commit().await.unwrap();
+    let updated = dal.find_by_id(id).await.unwrap();
+    assert!(updated.is_edited());
+    assert_eq!(updated.version(), Some(2));
+
+    let mut tx = storage.begin().await.unwrap();
+    assert!(dal.update(&mut tx, updated.edited(None)).await.unwrap());
+    tx.commit().await.unwrap();
+    assert_eq!(dal.find_by_id(id).await.unwrap().version(), Some(3));
+
+    let mut tx = storage.begin().await.unwrap();
+    assert!(dal.delete(&mut tx, id).await.unwrap());
+    assert!(!dal.delete(&mut tx, id).await.unwrap());
+    tx.commit().await.unwrap();
+    assert!(!dal.exists(id).await.unwrap());
+    assert!(matches!(
+        dal.find_by_id(id).await,
+        Err(RepoError::Db(sqlx::Error::RowNotFound))
+    ));
+}
+
+/// Asserts `result` failed with the API error `code`, answered with 409.
+fn assert_conflict<T: Debug>(result: Result<T, RepoError>, code: ErrorCode) {
+    let err = ApiError::from(result.expect_err("expected a constraint violation"));
+    assert_eq!(err.status, StatusCode::CONFLICT);
+    assert_eq!(err.code, code);
+}
+
+#[tokio::test]
+async fn deployments_repository() {
+    let storage = storage().await;
+    check_repository::<_, DeployConfigDAL>(&storage, deployment("alpha")).await;
+}
+
+#[tokio::test]
+async fn stacks_repository() {
+    let storage = storage().await;
+    let deployment = create::<_, DeployConfigDAL>(&storage, deployment("alpha"))
+        .await
+        .unwrap();
+    check_repository::<_, StackConfigDAL>(&storage, stack(deployment.id.unwrap(), "web")).await;
+}
+
+#[tokio::test]
+async fn containers_repository() {
+    let storage = storage().await;
+    let deployment = create::<_, DeployConfigDAL>(&storage, deployment("alpha"))
+        .await
+        .unwrap();
+    let stack = create::<_, StackConfigDAL>(&storage, stack(deployment.id.unwrap(), "web"))
+        .await
+        .unwrap();
+    check_repository::<_, ContainerDAL>(&storage, container(stack.id.unwrap(), "nginx")).await;
+}
+
+#[tokio::test]
+async fn related_ids_are_listed() {
+    let storage = storage().await;
+    let deployment = create::<_, DeployConfigDAL>(&storage, deployment("alpha"))
+        .await
+        .unwrap();
+    let deployment_id = deployment.id.unwrap();
+    let stack = create::<_, StackConfigDAL>(&storage, stack(deployment_id, "web"))
+        .await
+        .unwrap();
+    let stack_id = stack.id.unwrap();
+    let container = create::<_, ContainerDAL>(&storage, container(stack_id, "nginx"))
+        .await
+        .unwrap();
+
+    let found = DeployConfigDAL::new(&storage)
+        .find_by_id(deployment_id)
+        .await
+        .unwrap();
+    assert_eq!(found.stack_ids, Some(vec![stack_id]));
+    let found = StackConfigDAL::new(&storage)
+        .find_by_id(stack_id)
+        .await
+        .unwrap();
+    assert_eq!(found.containers, Some(vec![container.id.unwrap()]));
+    let found = ContainerDAL::new(&storage)
+        .find_by_id(container.id.unwrap())
+        .await
+        .unwrap();
+    assert_eq!(found.ports, Some(vec!["8080:80".to_string()]));
+    assert_eq!(found.environment, Some(vec!["KEY=value".to_string()]));
+}
+
+#[tokio::test]
+async fn deleting_a_deployment_cascades() {
+    let storage = storage().await;
+    let deployment = create::<_, DeployConfigDAL>(&storage, deployment("alpha"))
+        .await
+        .unwrap();
+    let stack = create::<_, StackConfigDAL>(&storage, stack(deployment.id.unwrap(), "web"))
+        .await
+        .unwrap();
+    let container = create::<_, ContainerDAL>(&storage, container(stack.id.unwrap(), "nginx"))
+        .await
+        .unwrap();
+
+    let mut tx = storage.begin().await.unwrap();
+    assert!(
+        DeployConfigDAL::new(&storage)
+            .delete(&mut tx, deployment.id.unwrap())
+            .await
+            .unwrap()
+    );
+    tx.commit().await.unwrap();
+    assert!(
+        !StackConfigDAL::new(&storage)
+            .exists(stack.id.unwrap())
+            .await
+            .unwrap()
+    );
+    assert!(
+        !ContainerDAL::new(&storage)
+            .exists(container.id.unwrap())
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn deleting_a_deployment_keeps_its_revisions() {
+    let storage = storage().await;
+    let deployment = create::<_, DeployConfigDAL>(&storage, deployment("alpha"))
+        .await
+        .unwrap();
+    let id = deployment.id.unwrap();
+    let mut tx = storage.begin().await.unwrap();
+    record_revision(
+        &mut tx,
+        ConfigRevisionDTO {
+            deployment_id: Some(id),
+            deployment_name: "alpha".into(),
+            revision: 1,
+            author: "tester".into(),
+            message: "created".into(),
+            snapshot: Some(HikariConfig::default()),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert!(
+        DeployConfigDAL::new(&storage)
+            .delete(&mut tx, id)
+            .await
+            .unwrap()
+    );
+    tx.commit().await.unwrap();
+
+    let revisions = ConfigRevisionDAL::new(&storage);
+    let history = revisions.find_by_name("alpha").await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].deployment_id, None);
+    assert_eq!(history[0].message, "created");
+    let revision = revisions.find_by_name_revision("alpha", 1).await.unwrap();
+    assert_eq!(revision.snapshot, Some(HikariConfig::default()));
+    assert!(revisions.find_by_deployment(id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn duplicates_are_rejected() {
+    let storage = storage().await;
+    let deployment = create::<_, DeployConfigDAL>(&storage, deployment("alpha"))
+        .await
+        .unwrap();
+    assert_conflict(
+        create::<_, DeployConfigDAL>(&storage, self::deployment("alpha")).await,
+        ErrorCode::DuplicateDeployment,
+    );
+
+    let deployment_id = deployment.id.unwrap();
+    let stack = create::<_, StackConfigDAL>(&storage, stack(deployment_id, "web"))
+        .await
+        .unwrap();
+    assert_conflict(
+        create::<_, StackConfigDAL>(&storage, self::stack(deployment_id, "web")).await,
+        ErrorCode::DuplicateStack,
+    );
+
+    let stack_id = stack.id.unwrap();
+    create::<_, ContainerDAL>(&storage, container(stack_id, "nginx"))
+        .await
+        .unwrap();
+    assert_conflict(
+        create::<_, ContainerDAL>(&storage, container(stack_id, "nginx")).await,
+        ErrorCode::DuplicateContainer,
+    );
+}
+
+#[tokio::test]
+async fn missing_parents_are_rejected() {
+    let storage = storage().await;
+    assert_conflict(
+        create::<_, StackConfigDAL>(&storage, stack(42, "web")).await,
+        ErrorCode::FkViolation,
+    );
+    assert_conflict(
+        create::<_, ContainerDAL>(&storage, container(42, "nginx")).await,
+        ErrorCode::FkViolation,
+    );
+
+    let deployment = create::<_, DeployConfigDAL>(&storage, deployment("alpha"))
+        .await
+        .unwrap();
+    let stack = create::<_, StackConfigDAL>(&storage, stack(deployment.id.unwrap(), "web"))
+        .await
+        .unwrap();
+    let mut tx = storage.begin().await.unwrap();
+    let moved = StackConfigDAL::new(&storage)
+        .update(
+            &mut tx,
+            StackConfigDTO {
+                deployment_id: 42,
+                ..stack
+            },
+        )
+        .await;
+    assert_conflict(moved, ErrorCode::FkViolation);
+}
+
+/// Walks every page of the deployment list, checking each cursor resumes
+/// right after the previous page.
+async fn page_names(storage: &Storage, sort: &'static str, order: SortOrder) -> Vec<String> {
+    let dal = DeployConfigDAL::new(storage);
+    let mut names = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = PageRequest {
+            limit: 2,
+            after: cursor
+                .as_deref()
+                .map(|cursor| Cursor::decode(cursor).unwrap()),
+            sort,
+            order,
+        };
+        let page = dal
+            .find_page(&DeploymentFilter::default(), page)
+            .await
+            .unwrap();
+        assert_eq!(page.total, 5);
+        assert!(page.items.len() <= 2);
+        names.extend(page.items.into_iter().map(|item| item.name));
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return names,
+        }
+    }
+}
+
+#[tokio::test]
+async fn pages_continue_after_the_cursor() {
+    let storage = storage().await;
+    for name in ["delta", "alpha", "echo", "charlie", "bravo"] {
+        create::<_, DeployConfigDAL>(&storage, deployment(name))
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        page_names(&storage, "name", SortOrder::Asc).await,
+        ["alpha", "bravo", "charlie", "delta", "echo"]
+    );
+    assert_eq!(
+        page_names(&storage, "name", SortOrder::Desc).await,
+        ["echo", "delta", "charlie", "bravo", "alpha"]
+    );
+    assert_eq!(
+        page_names(&storage, "id", SortOrder::Asc).await,
+        ["delta", "alpha", "echo", "charlie", "bravo"]
+    );
+    // Rows sharing a sort key are told apart by ID.
+    assert_eq!(
+        page_names(&storage, "environment", SortOrder::Desc).await,
+        ["bravo", "charlie", "echo", "alpha", "delta"]
+    );
+}
+
+#[tokio::test]
+async fn deleted_rows_do_not_shift_pages() {
+    let storage = storage().await;
+    for name in ["alpha", "bravo", "charlie", "delta"] {
+        create::<_, DeployConfigDAL>(&storage, deployment(name))
+            .await
+            .unwrap();
+    }
+    let dal = DeployConfigDAL::new(&storage);
+    let page = |after| PageRequest {
+        limit: 2,
+        after,
+        sort: "name",
+        order: SortOrder::Asc,
+    };
+    let first = dal
+        .find_page(&DeploymentFilter::default(), page(None))
+        .await
+        .unwrap();
+    let mut tx = storage.begin().await.unwrap();
+    dal.delete(&mut tx, first.items[0].id.unwrap())
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+
+    let cursor = Cursor::decode(&first.next_cursor.unwrap()).unwrap();
+    let second = dal
+        .find_page(&DeploymentFilter::default(), page(Some(cursor)))
+        .await
+        .unwrap();
+    let names: Vec<_> = second.items.iter().map(|item| item.name.as_str()).collect();
+    assert_eq!(names, ["charlie", "delta"]);
+    assert_eq!(second.next_cursor, None);
+}
+
+fn hikari_with_window() -> HikariConfig {
+    let service = Container {
+        container_name: "web".into(),
+        image: "nginx:1.27".into(),
+        restart: "always".into(),
+        ..Default::default()
+    };
+    let deploy_config = DeployConfig {
+        client: "acme".into(),
+        environment: "prod".into(),
+        solution: "shop".into(),
+        maintenance_window: Some(MaintenanceWindow {
+            schedule: "0 2 * * *".into(),
+            duration_minutes: 60,
+            timezone: "Europe/Berlin".into(),
+        }),
+        deploy_stacks: vec![StackConfig {
+            stack_name: "web".into(),
+            filename: "compose.yaml".into(),
+            home_directory: "/srv/shop".into(),
+            compose_spec: ComposeSpec {
+                services: HashMap::from([("web".to_string(), service)]),
+            },
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    HikariConfig {
+        version: "1".into(),
+        deploy_configs: HashMap::from([("shop".to_string(), deploy_config)]),
+    }
+}
+
+#[tokio::test]
+async fn exported_config_imports_unchanged() {
+    let state = Arc::new(AppState {
+        storage: storage().await,
+        connections: Arc::new(RwLock::new(HashMap::new())),
+        rollouts: Arc::new(RwLock::new(HashMap::new())),
+        metrics: ServerMetrics::new(),
+    });
+    let Json(created) = put_hikari(
+        Extension(state.clone()),
+        ChangeMeta::default(),
+        ApiJson(hikari_with_window()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(created.created, vec!["shop".to_string()]);
+
+    let Json(exported) = export_hikari(Extension(state.clone())).await.unwrap();
+    let revision = exported.deploy_configs["shop"].revision;
+    assert_eq!(
+        exported.deploy_configs["shop"].maintenance_window,
+        hikari_with_window().deploy_configs["shop"].maintenance_window
+    );
+
+    let Json(reimported) = put_hikari(
+        Extension(state.clone()),
+        ChangeMeta::default(),
+        ApiJson(exported),
+    )
+    .await
+    .unwrap();
+    assert_eq!(reimported.unchanged, vec!["shop".to_string()]);
+    assert!(reimported.updated.is_empty());
+    let Json(exported) = export_hikari(Extension(state)).await.unwrap();
+    assert_eq!(exported.deploy_configs["shop"].revision, revision);
+}
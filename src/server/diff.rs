@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::objects::structs::{Container, DeployConfig, HikariConfig, StackConfig};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub from: Option<Value>,
+    pub to: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceDiff {
+    pub service_name: String,
+    pub change: DiffKind,
+    pub fields: Vec<FieldChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StackDiff {
+    pub stack_name: String,
+    pub change: DiffKind,
+    pub fields: Vec<FieldChange>,
+    pub services: Vec<ServiceDiff>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeploymentDiff {
+    pub name: String,
+    pub change: DiffKind,
+    pub fields: Vec<FieldChange>,
+    pub stacks: Vec<StackDiff>,
+}
+
+/// Field level differences between the serialized forms of `from` and `to`,
+/// leaving out the nested collections listed in `skip`.
+fn diff_fields<T: Serialize>(from: Option<&T>, to: Option<&T>, skip: &[&str]) -> Vec<FieldChange> {
+    let as_map = |value: Option<&T>| -> BTreeMap<String, Value> {
+        match value.map(serde_json::to_value) {
+            Some(Ok(Value::Object(map))) => map
+                .into_iter()
+                .filter(|(key, _)| !skip.contains(&key.as_str()))
+                .collect(),
+            _ => BTreeMap::new(),
+        }
+    };
+    let from = as_map(from);
+    let to = as_map(to);
+    let fields: BTreeSet<&String> = from.keys().chain(to.keys()).collect();
+    fields
+        .into_iter()
+        .filter(|field| from.get(*field) != to.get(*field))
+        .map(|field| FieldChange {
+            field: field.clone(),
+            from: from.get(field).cloned(),
+            to: to.get(field).cloned(),
+        })
+        .collect()
+}
+
+fn change_kind(from: bool, to: bool) -> DiffKind {
+    match (from, to) {
+        (false, _) => DiffKind::Added,
+        (_, false) => DiffKind::Removed,
+        _ => DiffKind::Modified,
+    }
+}
+
+fn diff_services(
+    from: &HashMap<String, Container>,
+    to: &HashMap<String, Container>,
+) -> Vec<ServiceDiff> {
+    let names: BTreeSet<&String> = from.keys().chain(to.keys()).collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (old, new) = (from.get(name), to.get(name));
+            let fields = diff_fields(old, new, &[]);
+            (!fields.is_empty()).then(|| ServiceDiff {
+                service_name: name.clone(),
+                change: change_kind(old.is_some(), new.is_some()),
+                fields,
+            })
+        })
+        .collect()
+}
+
+fn diff_stacks(from: &[StackConfig], to: &[StackConfig]) -> Vec<StackDiff> {
+    // stacks are matched by name, the same way nodes reconcile them
+    let from: HashMap<&String, &StackConfig> = from.iter().map(|s| (&s.stack_name, s)).collect();
+    let to: HashMap<&String, &StackConfig> = to.iter().map(|s| (&s.stack_name, s)).collect();
+    let names: BTreeSet<&String> = from.keys().chain(to.keys()).copied().collect();
+    let empty = HashMap::new();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (old, new) = (from.get(name).copied(), to.get(name).copied());
+            let fields = diff_fields(old, new, &["compose_spec"]);
+            let services = diff_services(
+                old.map_or(&empty, |s| &s.compose_spec.services),
+                new.map_or(&empty, |s| &s.compose_spec.services),
+            );
+            (!fields.is_empty() || !services.is_empty()).then(|| StackDiff {
+                stack_name: name.clone(),
+                change: change_kind(old.is_some(), new.is_some()),
+                fields,
+                services,
+            })
+        })
+        .collect()
+}
+
+pub fn diff_deploy_configs(
+    name: &str,
+    from: Option<&DeployConfig>,
+    to: Option<&DeployConfig>,
+) -> Option<DeploymentDiff> {
+    let fields = diff_fields(from, to, &["deploy_stacks", "revision", "urgent"]);
+    let stacks = diff_stacks(
+        from.map_or(&[], |d| d.deploy_stacks.as_slice()),
+        to.map_or(&[], |d| d.deploy_stacks.as_slice()),
+    );
+    (!fields.is_empty() || !stacks.is_empty()).then(|| DeploymentDiff {
+        name: name.to_string(),
+        change: change_kind(from.is_some(), to.is_some()),
+        fields,
+        stacks,
+    })
+}
+
+/// Structured differences between two configs, down to individual service
+/// fields. Unchanged deployments, stacks and services are left out.
+pub fn diff_hikari_configs(from: &HikariConfig, to: &HikariConfig) -> Vec<DeploymentDiff> {
+    let names: BTreeSet<&String> = from
+        .deploy_configs
+        .keys()
+        .chain(to.deploy_configs.keys())
+        .collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            diff_deploy_configs(
+                name,
+                from.deploy_configs.get(name),
+                to.deploy_configs.get(name),
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,286 @@
+use axum::{
+    Json,
+    extract::rejection::{JsonRejection, QueryRejection},
+    response::{IntoResponse, Response},
+};
+use log::{debug, error};
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::{
+    server::request_id::current_request_id,
+    utils::error::{ConfigError, RepoError},
+};
+
+/// Stable, machine-readable reason of an [`ApiError`]. Clients branch on these,
+/// so existing variants must keep their names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidQuery,
+    InvalidBody,
+    InvalidHeader,
+    InvalidPatch,
+    MissingField,
+    UnexpectedField,
+    ValidationFailed,
+    NotFound,
+    VersionMismatch,
+    DuplicateDeployment,
+    DuplicateStack,
+    DuplicateContainer,
+    DuplicateRevision,
+    DuplicateEntry,
+    FkViolation,
+    CheckViolation,
+    DeleteFailed,
+    DatabaseUnavailable,
+    DatabaseError,
+    InternalError,
+}
+
+/// Error returned by the API handlers, rendered as
+/// `{code, message, details, field_path, request_id}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<Value>,
+    pub field_path: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: ErrorCode,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field_path: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+            field_path: None,
+        }
+    }
+
+    pub fn bad_request(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, ErrorCode::NotFound, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            message,
+        )
+    }
+
+    /// The request carried `field` although it must not.
+    pub fn unexpected_field(field: &str) -> Self {
+        Self::bad_request(
+            ErrorCode::UnexpectedField,
+            format!("Received an unexpected field - {field}"),
+        )
+        .with_field(field)
+    }
+
+    /// The request lacked `field` although it is required.
+    pub fn missing_field(field: &str) -> Self {
+        Self::bad_request(ErrorCode::MissingField, format!("Expected field - {field}"))
+            .with_field(field)
+    }
+
+    pub fn with_field(mut self, field_path: impl Into<String>) -> Self {
+        self.field_path = Some(field_path.into());
+        self
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code,
+            message: &self.message,
+            details: self.details.as_ref(),
+            field_path: self.field_path.as_deref(),
+            request_id: current_request_id(),
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// Code of a unique violation, by the table the duplicate row belongs to.
+fn duplicate_code(table: Option<&str>) -> ErrorCode {
+    match table {
+        Some("deploy_config") => ErrorCode::DuplicateDeployment,
+        Some("compose_stack") => ErrorCode::DuplicateStack,
+        Some("container") => ErrorCode::DuplicateContainer,
+        Some("config_revision") => ErrorCode::DuplicateRevision,
+        _ => ErrorCode::DuplicateEntry,
+    }
+}
+
+/// Table named by a SQLite constraint message such as
+/// `UNIQUE constraint failed: compose_stack.stack_name, ...`, since SQLite
+/// reports neither the constraint nor the table on its own.
+fn sqlite_table(message: &str) -> Option<&str> {
+    message
+        .split_once("failed: ")
+        .and_then(|(_, columns)| columns.split_once('.'))
+        .map(|(table, _)| table)
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::Database(db_err) => {
+                let table = db_err.table().or_else(|| sqlite_table(db_err.message()));
+                let details = json!({
+                    "constraint": db_err.constraint(),
+                    "table": table,
+                });
+                let (status, code, message) = if db_err.is_unique_violation() {
+                    (
+                        StatusCode::CONFLICT,
+                        duplicate_code(table),
+                        "Duplicate entry",
+                    )
+                } else if db_err.is_foreign_key_violation() {
+                    (
+                        StatusCode::CONFLICT,
+                        ErrorCode::FkViolation,
+                        "Foreign key violation",
+                    )
+                } else if db_err.is_check_violation() {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        ErrorCode::CheckViolation,
+                        "Check violation",
+                    )
+                } else {
+                    return Self::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        ErrorCode::DatabaseError,
+                        "Database error",
+                    );
+                };
+                Self::new(status, code, message).with_details(details)
+            }
+            sqlx::Error::RowNotFound => Self::not_found("Record not found"),
+            sqlx::Error::Io(err) => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorCode::DatabaseUnavailable,
+                err.to_string(),
+            ),
+            sqlx::Error::PoolTimedOut => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorCode::DatabaseUnavailable,
+                "Connection timed out",
+            ),
+            sqlx::Error::Protocol(msg) => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::DatabaseError,
+                msg,
+            ),
+            sqlx::Error::Tls(err) => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::DatabaseError,
+                err.to_string(),
+            ),
+            sqlx::Error::TypeNotFound { type_name } => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::DatabaseError,
+                format!("Type not found: {type_name}"),
+            ),
+            sqlx::Error::ColumnNotFound(col) => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::DatabaseError,
+                format!("Column not found: {col}"),
+            ),
+            sqlx::Error::ColumnIndexOutOfBounds { index, len } => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::DatabaseError,
+                format!("Column index out of bounds: {index}/{len}"),
+            ),
+            _ => Self::internal("INTERNAL SERVER ERROR"),
+        }
+    }
+}
+
+impl From<ConfigError> for ApiError {
+    fn from(err: ConfigError) -> Self {
+        match err {
+            // nested validation errors read `parent[0]: Missing or empty field: child`
+            ConfigError::MissingField(path) => {
+                let field_path = path
+                    .split(": Missing or empty field: ")
+                    .collect::<Vec<_>>()
+                    .join(".");
+                Self::bad_request(
+                    ErrorCode::ValidationFailed,
+                    format!("Missing or empty field: {field_path}"),
+                )
+                .with_field(field_path)
+            }
+            err => Self::bad_request(ErrorCode::ValidationFailed, err.to_string()),
+        }
+    }
+}
+
+impl From<RepoError> for ApiError {
+    fn from(err: RepoError) -> Self {
+        let message = err.to_string();
+        let api_error: Self = match err {
+            RepoError::Db(err) => err.into(),
+            RepoError::Validation(err) => err.into(),
+        };
+        if api_error.status.is_server_error() {
+            error!("{message}");
+        } else {
+            debug!("{message}");
+        }
+        api_error
+    }
+}
+
+/// Request bodies that are not JSON or don't deserialize into the payload.
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(
+            rejection.status(),
+            ErrorCode::InvalidBody,
+            rejection.body_text(),
+        )
+    }
+}
+
+/// Query strings that don't deserialize into the handler's parameters.
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(
+            rejection.status(),
+            ErrorCode::InvalidQuery,
+            rejection.body_text(),
+        )
+    }
+}
@@ -0,0 +1,171 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    Extension,
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::{mode::server::AppState, server::storage::Storage};
+
+/// Prometheus metrics of server mode, served on `/metrics`. Gauges describing
+/// the pool and the connected agents are sampled when scraped.
+#[derive(Debug, Clone)]
+pub struct ServerMetrics {
+    registry: Registry,
+    request_duration: HistogramVec,
+    requests: IntCounterVec,
+    db_pool_connections: IntGaugeVec,
+    db_pool_max_connections: IntGauge,
+    connected_agents: IntGaugeVec,
+    pub broadcasts: IntCounter,
+    pub broadcast_messages: IntCounter,
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("hikari_server".into()), None)
+            .expect("the registry prefix is valid");
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling API requests",
+            ),
+            &["method", "route"],
+        )
+        .expect("the metric options are valid");
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "API requests by route and status"),
+            &["method", "route", "status"],
+        )
+        .expect("the metric options are valid");
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Database connections by state"),
+            &["state"],
+        )
+        .expect("the metric options are valid");
+        let db_pool_max_connections = IntGauge::new(
+            "db_pool_max_connections",
+            "Connections the database pool may open",
+        )
+        .expect("the metric options are valid");
+        let connected_agents = IntGaugeVec::new(
+            Opts::new(
+                "connected_agents",
+                "Agents connected over the websocket by environment, solution and client",
+            ),
+            &["key"],
+        )
+        .expect("the metric options are valid");
+        let broadcasts = IntCounter::new(
+            "broadcasts_total",
+            "Change notifications handed to the connected agents",
+        )
+        .expect("the metric options are valid");
+        let broadcast_messages = IntCounter::new(
+            "broadcast_messages_total",
+            "Change notifications sent to individual agents",
+        )
+        .expect("the metric options are valid");
+        for collector in [
+            Box::new(request_duration.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(requests.clone()),
+            Box::new(db_pool_connections.clone()),
+            Box::new(db_pool_max_connections.clone()),
+            Box::new(connected_agents.clone()),
+            Box::new(broadcasts.clone()),
+            Box::new(broadcast_messages.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+        Self {
+            registry,
+            request_duration,
+            requests,
+            db_pool_connections,
+            db_pool_max_connections,
+            connected_agents,
+            broadcasts,
+            broadcast_messages,
+        }
+    }
+
+    /// Samples the pool and the connections of `state`, then renders every
+    /// metric in the Prometheus text format.
+    pub async fn encode(&self, state: &AppState) -> Result<String, prometheus::Error> {
+        let (size, idle, max) = match &state.storage {
+            Storage::Postgres(pool) => (
+                pool.size(),
+                pool.num_idle(),
+                pool.options().get_max_connections(),
+            ),
+            Storage::Sqlite(pool) => (
+                pool.size(),
+                pool.num_idle(),
+                pool.options().get_max_connections(),
+            ),
+        };
+        let idle = i64::try_from(idle).unwrap_or(i64::MAX);
+        self.db_pool_connections
+            .with_label_values(&["idle"])
+            .set(idle);
+        self.db_pool_connections
+            .with_label_values(&["in_use"])
+            .set(i64::from(size) - idle);
+        self.db_pool_max_connections.set(i64::from(max));
+
+        self.connected_agents.reset();
+        for connection in state.connections.read().await.values() {
+            self.connected_agents
+                .with_label_values(&[format!(
+                    "{}_{}_{}",
+                    connection.environment, connection.solution, connection.client
+                )])
+                .inc();
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts and times each request by the route it matched. Used as a route
+/// layer, so unknown paths don't grow the label set.
+pub async fn track_requests(
+    Extension(state): Extension<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = request.extensions().get::<MatchedPath>().map_or_else(
+        || request.uri().path().to_string(),
+        |path| path.as_str().into(),
+    );
+    let started = Instant::now();
+    let response = next.run(request).await;
+    state
+        .metrics
+        .request_duration
+        .with_label_values(&[&method, &route])
+        .observe(started.elapsed().as_secs_f64());
+    state
+        .metrics
+        .requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    response
+}
@@ -0,0 +1,63 @@
+use log::info;
+use sqlx::{migrate::Migrator, query_scalar};
+
+use crate::{server::storage::Storage, utils::error::ConfigError};
+
+/// Migrations under `migrations/`, embedded into the binary at build time. Both
+/// backends share version numbers so a schema version means the same thing.
+pub static PG_MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// Highest migration version recorded in the database, `None` when the
+/// database has never been migrated.
+async fn applied_version(storage: &Storage) -> Result<Option<i64>, ConfigError> {
+    let version = match storage {
+        Storage::Postgres(pool) => {
+            let table: Option<String> =
+                query_scalar("SELECT to_regclass('_sqlx_migrations')::TEXT;")
+                    .fetch_one(pool)
+                    .await?;
+            if table.is_none() {
+                return Ok(None);
+            }
+            query_scalar("SELECT MAX(version) FROM _sqlx_migrations;")
+                .fetch_one(pool)
+                .await?
+        }
+        Storage::Sqlite(pool) => {
+            let table: Option<String> = query_scalar(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations';",
+            )
+            .fetch_optional(pool)
+            .await?;
+            if table.is_none() {
+                return Ok(None);
+            }
+            query_scalar("SELECT MAX(version) FROM _sqlx_migrations;")
+                .fetch_one(pool)
+                .await?
+        }
+    };
+    Ok(version)
+}
+
+/// Applies any pending migrations, refusing to touch a database whose schema
+/// was written by a newer hikari than this one.
+pub async fn run_migrations(storage: &Storage) -> Result<(), ConfigError> {
+    let migrator = match storage {
+        Storage::Postgres(_) => &PG_MIGRATOR,
+        Storage::Sqlite(_) => &SQLITE_MIGRATOR,
+    };
+    let known = migrator.iter().map(|m| m.version).max().unwrap_or_default();
+    if let Some(applied) = applied_version(storage).await?
+        && applied > known
+    {
+        return Err(ConfigError::UnknownSchema(applied, known));
+    }
+    match storage {
+        Storage::Postgres(pool) => migrator.run(pool).await?,
+        Storage::Sqlite(pool) => migrator.run(pool).await?,
+    }
+    info!("Database schema is at version {known}");
+    Ok(())
+}
@@ -1,6 +1,13 @@
 pub mod api;
 pub mod common;
 pub mod dal;
+pub mod diff;
+pub mod error;
+pub mod metrics;
+pub mod migrate;
 pub mod models;
+pub mod request_id;
+pub mod rollout;
+pub mod storage;
 pub mod traits;
 pub mod ws;
@@ -0,0 +1,22 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::objects::structs::HikariConfig;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ConfigRevisionDTO {
+    pub id: Option<i64>,
+    /// Unset once the deployment is deleted, its history is kept.
+    pub deployment_id: Option<i64>,
+    /// Name of the deployment when the revision was recorded.
+    pub deployment_name: String,
+    pub revision: i64,
+    pub author: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[sqlx(json(nullable))]
+    pub snapshot: Option<HikariConfig>,
+}
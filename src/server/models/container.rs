@@ -1,6 +1,10 @@
 use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+use crate::server::traits::model::Versioned;
+
+/// Array columns are JSON text on SQLite, hence the `json` decoding.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
 pub struct ContainerDTO {
     pub id: Option<i64>,
     pub stack_id: i64,
@@ -19,10 +23,13 @@ pub struct ContainerDTO {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pull_policy: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[sqlx(json(nullable))]
     pub ports: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[sqlx(json(nullable))]
     pub volumes: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[sqlx(json(nullable))]
     pub environment: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mem_reservation: Option<String>,
@@ -32,4 +39,19 @@ pub struct ContainerDTO {
     pub oom_kill_disable: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub privileged: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+}
+
+impl Versioned for ContainerDTO {
+    fn version(&self) -> Option<i64> {
+        self.version
+    }
+}
+
+/// Filters accepted when listing containers, all optional.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+pub struct ContainerFilter {
+    pub stack_id: Option<i64>,
+    pub image: Option<String>,
 }
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+use crate::server::traits::model::Versioned;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
 pub struct DeployConfigDTO {
     pub id: Option<i64>,
     pub name: String,
@@ -8,5 +11,24 @@ pub struct DeployConfigDTO {
     pub environment: String,
     pub solution: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[sqlx(json(nullable))]
     pub stack_ids: Option<Vec<i64>>,
 }
+
+impl Versioned for DeployConfigDTO {
+    fn version(&self) -> Option<i64> {
+        self.version
+    }
+}
+
+/// Filters accepted when listing deployments, all optional.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+pub struct DeploymentFilter {
+    pub client: Option<String>,
+    pub environment: Option<String>,
+    pub solution: Option<String>,
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::objects::structs::MaintenanceWindow;
+
+/// When the nodes of a deployment may restart its stacks, see
+/// [`MaintenanceWindow`].
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct MaintenanceWindowDTO {
+    #[serde(default)]
+    pub deployment_id: i64,
+    pub schedule: String,
+    pub duration_minutes: i64,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+fn default_timezone() -> String {
+    "UTC".into()
+}
+
+impl From<MaintenanceWindowDTO> for MaintenanceWindow {
+    fn from(dto: MaintenanceWindowDTO) -> Self {
+        MaintenanceWindow {
+            schedule: dto.schedule,
+            duration_minutes: dto.duration_minutes,
+            timezone: dto.timezone,
+        }
+    }
+}
@@ -1,3 +1,8 @@
+pub mod audit_log;
+pub mod config_revision;
 pub mod container;
 pub mod deploy_config;
+pub mod maintenance_window;
+pub mod page;
+pub mod rollout_policy;
 pub mod stack_config;
@@ -0,0 +1,107 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// One page of a list endpoint. `next_cursor` is absent on the last page.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            Self::Asc => "ASC",
+            Self::Desc => "DESC",
+        }
+    }
+}
+
+/// Query parameters shared by the list endpoints. `cursor` is the opaque
+/// `next_cursor` of the previous page.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct PageParams {
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    pub sort: Option<String>,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+/// Value of the sort column in the last row of a page.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CursorKey {
+    Int(i64),
+    Text(String),
+    Time(DateTime<Utc>),
+}
+
+/// Position after the last row of a page, handed out as `next_cursor`. Pages
+/// continue from the sort key and ID of that row rather than skip the rows
+/// before it, so inserts and deletes don't shift later pages.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub sort: String,
+    pub order: SortOrder,
+    pub key: CursorKey,
+    pub id: i64,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    /// `None` when `cursor` was not handed out by [`Cursor::encode`].
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+/// Window and ordering of a list query. `sort` is always one of the column
+/// names whitelisted by the endpoint, so it can be written into the SQL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageRequest {
+    pub limit: i64,
+    pub after: Option<Cursor>,
+    pub sort: &'static str,
+    pub order: SortOrder,
+}
+
+impl PageRequest {
+    /// `next` is the key and ID of the last row when more rows follow it.
+    pub fn into_page<T>(
+        self,
+        items: Vec<T>,
+        total: i64,
+        next: Option<(CursorKey, i64)>,
+    ) -> Page<T> {
+        let next_cursor = next.map(|(key, id)| {
+            Cursor {
+                sort: self.sort.to_string(),
+                order: self.order,
+                key,
+                id,
+            }
+            .encode()
+        });
+        Page {
+            items,
+            total,
+            next_cursor,
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// How changes to a deployment reach its nodes. Without `canary_nodes` or
+/// `canary_percent` a single node goes first.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct RolloutPolicyDTO {
+    #[serde(default)]
+    pub deployment_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canary_nodes: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canary_percent: Option<i64>,
+    #[serde(default = "default_health_timeout")]
+    pub health_timeout_secs: i64,
+    /// `pause` or `abort`
+    #[serde(default = "default_on_failure")]
+    pub on_failure: String,
+}
+
+fn default_health_timeout() -> i64 {
+    300
+}
+
+fn default_on_failure() -> String {
+    "pause".into()
+}
+
+impl RolloutPolicyDTO {
+    /// Number of the `nodes` connected nodes updated before the others.
+    pub fn canary_size(&self, nodes: usize) -> usize {
+        let size = match (self.canary_nodes, self.canary_percent) {
+            (Some(count), _) => usize::try_from(count).unwrap_or(1),
+            (None, Some(percent)) => {
+                (nodes * usize::try_from(percent).unwrap_or(100)).div_ceil(100)
+            }
+            (None, None) => 1,
+        };
+        size.clamp(1, nodes.max(1))
+    }
+
+    pub fn aborts_on_failure(&self) -> bool {
+        self.on_failure == "abort"
+    }
+}
@@ -1,12 +1,37 @@
 use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+use crate::server::traits::model::Versioned;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
 pub struct StackConfigDTO {
     pub id: Option<i64>,
     pub deployment_id: i64,
     pub stack_name: String,
     pub filename: String,
     pub home_directory: String,
+    /// `ignore`, `report` or `heal`
+    #[serde(default = "default_drift_policy")]
+    pub drift_policy: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[sqlx(json(nullable))]
     pub containers: Option<Vec<i64>>,
 }
+
+fn default_drift_policy() -> String {
+    "report".into()
+}
+
+impl Versioned for StackConfigDTO {
+    fn version(&self) -> Option<i64> {
+        self.version
+    }
+}
+
+/// Filters accepted when listing stacks, all optional.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+pub struct StackFilter {
+    pub deployment_id: Option<i64>,
+}
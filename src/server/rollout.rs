@@ -0,0 +1,417 @@
+use std::{
+    collections::{BTreeSet, HashMap, hash_map::Entry},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::time::{Duration, Instant, sleep};
+
+use crate::{
+    mode::server::AppState,
+    objects::structs::{ChangeAction, ChangeNotification, DeployConfig, EntityKind, HikariConfig},
+    server::{
+        api::revisions::restore_revision,
+        common::{ChangeMeta, map_repo_error},
+        dal::{
+            config_revision_dal::ConfigRevisionDAL,
+            deploy_config_dal::{DeployConfigDAL, Utils as _},
+            rollout_policy_dal::RolloutPolicyDAL,
+        },
+        error::ApiError,
+        models::rollout_policy::RolloutPolicyDTO,
+        request_id::new_request_id,
+        traits::model::DataRepository,
+        ws::websocket::deliver,
+    },
+    utils::error::RepoError,
+};
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+/// How often the canaries' status reports are looked at.
+const HEALTH_POLL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RolloutPhase {
+    Canary,
+    Paused,
+}
+
+/// A revision on its way to the nodes of a deployment. Nodes outside
+/// `promoted` keep being served `from_revision` until the rollout completes.
+/// Rollouts only live in memory, after a restart every node catches up at once.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rollout {
+    pub deployment: String,
+    pub deployment_id: i64,
+    pub from_revision: i64,
+    pub to_revision: i64,
+    pub phase: RolloutPhase,
+    pub promoted: BTreeSet<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub policy: RolloutPolicyDTO,
+    #[serde(skip)]
+    notification: ChangeNotification,
+    #[serde(skip)]
+    generation: u64,
+}
+
+enum CanaryHealth {
+    Pending,
+    Healthy,
+    Failed(String),
+}
+
+/// Starts rolling `notification` out when its deployment has a rollout policy,
+/// taking over any rollout already running for it. Returns `false` when the
+/// notification should go to every node right away.
+pub async fn start_rollout(state: &Arc<AppState>, notification: &ChangeNotification) -> bool {
+    if notification.entity == EntityKind::Deployment && notification.action == ChangeAction::Deleted
+    {
+        state
+            .rollouts
+            .write()
+            .await
+            .remove(&notification.deployment);
+        return false;
+    }
+    let Ok(deployment) = DeployConfigDAL::new(&state.storage)
+        .find_by_name(&notification.deployment)
+        .await
+    else {
+        return false;
+    };
+    // nodes dropping a deployment after its metadata changed don't wait for anyone
+    if deployment.client != notification.client
+        || deployment.environment != notification.environment
+        || deployment.solution != notification.solution
+    {
+        return false;
+    }
+    let deployment_id = deployment.id.unwrap_or_default();
+    let policy = match RolloutPolicyDAL::new(&state.storage)
+        .find_by_deployment(deployment_id)
+        .await
+    {
+        Ok(Some(policy)) => policy,
+        Ok(None) => {
+            state
+                .rollouts
+                .write()
+                .await
+                .remove(&notification.deployment);
+            return false;
+        }
+        Err(e) => {
+            error!(
+                "Unable to load the rollout policy of '{}': {e}",
+                deployment.name
+            );
+            return false;
+        }
+    };
+    // looked up ahead so the rollouts stay locked only while being changed
+    let baseline = notification.revision - 1;
+    let baseline_stored = has_snapshot(state, deployment_id, baseline).await;
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut rollouts = state.rollouts.write().await;
+        // a newer revision keeps the baseline and the canaries of the one it replaces
+        let (from_revision, promoted) = match rollouts.entry(deployment.name.clone()) {
+            Entry::Occupied(previous) => {
+                let previous = previous.remove();
+                (previous.from_revision, previous.promoted)
+            }
+            Entry::Vacant(_) if baseline_stored => (baseline, BTreeSet::new()),
+            Entry::Vacant(_) => {
+                warn!(
+                    "No snapshot of revision {baseline} of '{}' to hold nodes on, sending revision {} to every node",
+                    deployment.name, notification.revision
+                );
+                return false;
+            }
+        };
+        info!(
+            "Rolling out revision {} of '{}' from revision {from_revision}",
+            notification.revision, deployment.name
+        );
+        rollouts.insert(
+            deployment.name.clone(),
+            Rollout {
+                deployment: deployment.name.clone(),
+                deployment_id,
+                from_revision,
+                to_revision: notification.revision,
+                phase: RolloutPhase::Canary,
+                promoted,
+                reason: None,
+                started_at: Utc::now(),
+                policy,
+                notification: notification.clone(),
+                generation,
+            },
+        );
+    }
+    tokio::spawn(run_rollout(state.clone(), deployment.name, generation));
+    true
+}
+
+/// Whether the snapshot of `revision` is stored. Nodes can neither be held on
+/// nor rolled back to a revision without one, e.g. revision 0 or revisions
+/// recorded before snapshots were.
+async fn has_snapshot(state: &AppState, deployment_id: i64, revision: i64) -> bool {
+    match ConfigRevisionDAL::new(&state.storage)
+        .find_by_revision(deployment_id, revision)
+        .await
+    {
+        Ok(revision) => revision.snapshot.is_some(),
+        Err(RepoError::Db(sqlx::Error::RowNotFound)) => false,
+        Err(e) => {
+            error!("Unable to load revision {revision} of deployment {deployment_id}: {e}");
+            false
+        }
+    }
+}
+
+/// Updates the canaries, waits for their reports and carries on, pauses or
+/// aborts depending on what they say and on the policy.
+async fn run_rollout(state: Arc<AppState>, deployment: String, generation: u64) {
+    let Some((notification, canaries, policy)) =
+        pick_canaries(&state, &deployment, generation).await
+    else {
+        return;
+    };
+    info!(
+        "Sending revision {} of '{deployment}' to {canaries:?}",
+        notification.revision
+    );
+    deliver(&state, &notification, |connection| {
+        canaries.contains(&connection.node)
+    })
+    .await;
+    let timeout = Duration::from_secs(u64::try_from(policy.health_timeout_secs).unwrap_or(300));
+    let deadline = Instant::now() + timeout;
+    let outcome = loop {
+        sleep(HEALTH_POLL).await;
+        match canary_health(&state, &deployment, generation).await {
+            // superseded, resumed or aborted in the meantime
+            None => return,
+            Some(CanaryHealth::Healthy) => break Ok(()),
+            Some(CanaryHealth::Failed(reason)) => break Err(reason),
+            Some(CanaryHealth::Pending) if Instant::now() >= deadline => {
+                break Err(format!(
+                    "no healthy report from every canary within {}s",
+                    policy.health_timeout_secs
+                ));
+            }
+            Some(CanaryHealth::Pending) => {}
+        }
+    };
+    match outcome {
+        Ok(()) => {
+            complete_rollout(&state, &deployment).await;
+        }
+        Err(reason) if policy.aborts_on_failure() => {
+            let meta = ChangeMeta {
+                author: Some("hikari".into()),
+                request_id: Some(new_request_id()),
+                ..ChangeMeta::default()
+            };
+            if let Err(e) = abort_rollout(&state, &meta, &deployment, &reason).await {
+                error!(
+                    "Unable to abort the rollout of '{deployment}': {}",
+                    e.message
+                );
+            }
+        }
+        Err(reason) => {
+            let mut rollouts = state.rollouts.write().await;
+            if let Some(rollout) = rollouts
+                .get_mut(&deployment)
+                .filter(|rollout| rollout.generation == generation)
+            {
+                warn!(
+                    "Rollout of revision {} of '{deployment}' paused: {reason}",
+                    rollout.to_revision
+                );
+                rollout.phase = RolloutPhase::Paused;
+                rollout.reason = Some(reason);
+            }
+        }
+    }
+}
+
+async fn pick_canaries(
+    state: &AppState,
+    deployment: &str,
+    generation: u64,
+) -> Option<(ChangeNotification, BTreeSet<String>, RolloutPolicyDTO)> {
+    let mut rollouts = state.rollouts.write().await;
+    let rollout = rollouts
+        .get_mut(deployment)
+        .filter(|rollout| rollout.generation == generation)?;
+    let connections = state.connections.read().await;
+    let nodes: BTreeSet<&String> = connections
+        .values()
+        .filter(|connection| connection.matches(&rollout.notification))
+        .map(|connection| &connection.node)
+        .collect();
+    let size = rollout.policy.canary_size(nodes.len());
+    for node in nodes {
+        if rollout.promoted.len() >= size {
+            break;
+        }
+        rollout.promoted.insert(node.clone());
+    }
+    Some((
+        rollout.notification.clone(),
+        rollout.promoted.clone(),
+        rollout.policy.clone(),
+    ))
+}
+
+async fn canary_health(
+    state: &AppState,
+    deployment: &str,
+    generation: u64,
+) -> Option<CanaryHealth> {
+    let rollouts = state.rollouts.read().await;
+    let rollout = rollouts.get(deployment).filter(|rollout| {
+        rollout.generation == generation && rollout.phase == RolloutPhase::Canary
+    })?;
+    let connections = state.connections.read().await;
+    let mut pending = false;
+    for node in &rollout.promoted {
+        let status = connections
+            .values()
+            .filter(|connection| &connection.node == node)
+            .filter_map(|connection| connection.statuses.get(deployment))
+            .filter(|status| status.revision >= rollout.to_revision)
+            .max_by_key(|status| status.revision);
+        match status {
+            Some(status) if !status.healthy() => {
+                let unhealthy: Vec<&str> = status
+                    .stacks
+                    .iter()
+                    .filter(|stack| !stack.healthy)
+                    .map(|stack| stack.stack_name.as_str())
+                    .collect();
+                return Some(CanaryHealth::Failed(format!(
+                    "node {node} reports unhealthy stacks {unhealthy:?}"
+                )));
+            }
+            Some(_) => {}
+            None => pending = true,
+        }
+    }
+    Some(match pending {
+        true => CanaryHealth::Pending,
+        false => CanaryHealth::Healthy,
+    })
+}
+
+/// Sends the rolled out revision to the nodes still waiting for it. Returns
+/// the finished rollout, if there was one.
+pub async fn complete_rollout(state: &AppState, deployment: &str) -> Option<Rollout> {
+    let rollout = state.rollouts.write().await.remove(deployment)?;
+    info!(
+        "Revision {} of '{deployment}' is healthy on {:?}, rolling out to the remaining nodes",
+        rollout.to_revision, rollout.promoted
+    );
+    deliver(state, &rollout.notification, |connection| {
+        !rollout.promoted.contains(&connection.node)
+    })
+    .await;
+    Some(rollout)
+}
+
+/// Ends the rollout by restoring the revision the nodes started from, as a new
+/// revision sent to every node. Returns the aborted rollout, if there was one.
+pub async fn abort_rollout(
+    state: &AppState,
+    meta: &ChangeMeta,
+    deployment: &str,
+    reason: &str,
+) -> Result<Option<Rollout>, ApiError> {
+    let Some(rollout) = state.rollouts.write().await.remove(deployment) else {
+        return Ok(None);
+    };
+    warn!(
+        "Rollout of revision {} of '{deployment}' aborted, restoring revision {}: {reason}",
+        rollout.to_revision, rollout.from_revision
+    );
+    let deployment_config = DeployConfigDAL::new(&state.storage)
+        .find_by_id(rollout.deployment_id)
+        .await
+        .map_err(map_repo_error)?;
+    let meta = ChangeMeta {
+        message: meta.message.clone().or_else(|| {
+            Some(format!(
+                "rollout of revision {} aborted: {reason}",
+                rollout.to_revision
+            ))
+        }),
+        ..meta.clone()
+    };
+    let notification =
+        restore_revision(state, &meta, &deployment_config, rollout.from_revision).await?;
+    deliver(state, &notification, |_| true).await;
+    Ok(Some(rollout))
+}
+
+/// Revisions `node` has to stay on while rollouts it isn't part of are under
+/// way, by deployment name along with the deployment ID.
+pub async fn pinned_revisions(state: &AppState, node: Option<&str>) -> HashMap<String, (i64, i64)> {
+    state
+        .rollouts
+        .read()
+        .await
+        .values()
+        .filter(|rollout| !node.is_some_and(|node| rollout.promoted.contains(node)))
+        .map(|rollout| {
+            (
+                rollout.deployment.clone(),
+                (rollout.deployment_id, rollout.from_revision),
+            )
+        })
+        .collect()
+}
+
+/// Swaps the deployments of `hikari` that are being rolled out without `node`
+/// for the snapshot of the revision the node has to stay on. Deployments whose
+/// snapshot can't be loaded are served as they are.
+pub async fn pin_to_rollouts(state: &AppState, node: Option<&str>, hikari: &mut HikariConfig) {
+    for (name, (deployment_id, revision)) in pinned_revisions(state, node).await {
+        if !hikari.deploy_configs.contains_key(&name) {
+            continue;
+        }
+        let snapshot = match ConfigRevisionDAL::new(&state.storage)
+            .find_by_revision(deployment_id, revision)
+            .await
+        {
+            Ok(config_revision) => config_revision.snapshot,
+            Err(e) => {
+                warn!(
+                    "Unable to pin '{name}' to revision {revision}, serving the current one: {e}"
+                );
+                continue;
+            }
+        };
+        if let Some(deploy_config) = snapshot.and_then(|s| s.deploy_configs.into_values().next()) {
+            hikari.deploy_configs.insert(
+                name,
+                DeployConfig {
+                    revision: Some(revision),
+                    ..deploy_config
+                },
+            );
+        }
+    }
+}
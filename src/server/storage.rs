@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use sqlx::{
+    PgPool, Postgres, Sqlite, SqlitePool, Transaction,
+    postgres::PgPoolOptions,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+};
+
+use crate::utils::{error::ConfigError, secrets::load_secrets};
+
+/// Database backing server mode, picked with `STORAGE_BACKEND`. Postgres suits
+/// larger fleets, the embedded SQLite file needs no extra service.
+#[derive(Clone, Debug)]
+pub enum Storage {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+/// Open transaction on either backend, see [`Storage::begin`].
+pub enum StorageTx {
+    Postgres(Transaction<'static, Postgres>),
+    Sqlite(Transaction<'static, Sqlite>),
+}
+
+impl Storage {
+    pub async fn connect() -> Result<Self, ConfigError> {
+        let backend = load_secrets("storage")?;
+        match backend[0].as_str() {
+            "postgres" => {
+                let secrets = load_secrets("server")?;
+                let pool = PgPoolOptions::new()
+                    .test_before_acquire(true)
+                    .max_connections(50)
+                    .min_connections(20)
+                    .idle_timeout(Duration::from_secs(1800))
+                    .max_lifetime(Duration::from_secs(1800))
+                    .connect(
+                        format!(
+                            "postgres://{}:{}@{}:{}/{}",
+                            secrets[0], secrets[1], secrets[2], secrets[3], secrets[4]
+                        )
+                        .as_str(),
+                    )
+                    .await?;
+                Ok(Self::Postgres(pool))
+            }
+            "sqlite" => {
+                let secrets = load_secrets("sqlite")?;
+                let options = SqliteConnectOptions::new()
+                    .filename(&secrets[0])
+                    .create_if_missing(true)
+                    .journal_mode(SqliteJournalMode::Wal)
+                    .foreign_keys(true);
+                let pool = SqlitePoolOptions::new()
+                    .max_connections(5)
+                    .connect_with(options)
+                    .await?;
+                Ok(Self::Sqlite(pool))
+            }
+            other => Err(ConfigError::UnsupportedBackend(other.to_string())),
+        }
+    }
+
+    /// Runs a trivial query, giving up after a few seconds so health checks
+    /// answer while the database hangs.
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        let ping = async {
+            match self {
+                Self::Postgres(pool) => sqlx::query("SELECT 1").execute(pool).await.map(|_| ()),
+                Self::Sqlite(pool) => sqlx::query("SELECT 1").execute(pool).await.map(|_| ()),
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), ping)
+            .await
+            .unwrap_or(Err(sqlx::Error::PoolTimedOut))
+    }
+
+    pub async fn begin(&self) -> Result<StorageTx, sqlx::Error> {
+        Ok(match self {
+            Self::Postgres(pool) => StorageTx::Postgres(pool.begin().await?),
+            Self::Sqlite(pool) => StorageTx::Sqlite(pool.begin().await?),
+        })
+    }
+}
+
+impl StorageTx {
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        match self {
+            Self::Postgres(tx) => tx.commit().await,
+            Self::Sqlite(tx) => tx.commit().await,
+        }
+    }
+}
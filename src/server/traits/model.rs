@@ -1,16 +1,27 @@
-use sqlx::PgPool;
-
-use crate::{server::models::deploy_config::DeployConfigDTO, utils::error::RepoError};
+use crate::{
+    server::{
+        models::deploy_config::DeployConfigDTO,
+        storage::{Storage, StorageTx},
+    },
+    utils::error::RepoError,
+};
 
 pub trait DataRepository<T> {
     type Payload;
 
-    fn new(pool: &PgPool) -> Self;
+    fn new(storage: &Storage) -> Self;
     async fn exists(&self, id: i64) -> Result<bool, RepoError>;
     async fn find_by_id(&self, id: i64) -> Result<T, RepoError>;
     async fn find_all(&self) -> Result<Vec<T>, RepoError>;
-    async fn create(&self, payload: Self::Payload) -> Result<T, RepoError>;
-    async fn update(&self, payload: Self::Payload) -> Result<bool, RepoError>;
-    async fn delete(&self, id: i64) -> Result<bool, RepoError>;
+    async fn create(&self, tx: &mut StorageTx, payload: Self::Payload) -> Result<T, RepoError>;
+    /// Applies `payload` when its `version` is unset or still the stored one,
+    /// bumping the version. Returns whether a row was updated.
+    async fn update(&self, tx: &mut StorageTx, payload: Self::Payload) -> Result<bool, RepoError>;
+    async fn delete(&self, tx: &mut StorageTx, id: i64) -> Result<bool, RepoError>;
     async fn get_deployment_metadata(&self, id: i64) -> Result<DeployConfigDTO, RepoError>;
 }
+
+/// Entities carrying the row version used for optimistic concurrency.
+pub trait Versioned {
+    fn version(&self) -> Option<i64>;
+}
@@ -1,54 +1,106 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
 use axum::{
     Extension, debug_handler,
     extract::{
-        Query, WebSocketUpgrade,
+        WebSocketUpgrade,
         ws::{Message, WebSocket},
     },
     response::Response,
 };
 use futures::{SinkExt, StreamExt};
-use log::info;
+use log::{error, info, warn};
 use serde::Deserialize;
-use tokio::sync::broadcast::{channel, error::SendError};
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+
+use crate::{
+    mode::server::AppState,
+    objects::structs::{ChangeNotification, NodeStatus},
+    server::{common::ApiQuery, rollout::start_rollout},
+};
 
-use crate::mode::server::AppState;
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Deserialize)]
 pub struct QueryParamsWS {
     client: String,
     solution: String,
     environment: String,
+    node: Option<String>,
+}
+
+/// An agent connected over the websocket, with its own outgoing channel and
+/// the latest status it reported per deployment.
+#[derive(Debug)]
+pub struct NodeConnection {
+    pub node: String,
+    pub client: String,
+    pub environment: String,
+    pub solution: String,
+    pub sender: UnboundedSender<String>,
+    pub statuses: HashMap<String, NodeStatus>,
+}
+
+impl NodeConnection {
+    pub fn matches(&self, notification: &ChangeNotification) -> bool {
+        self.client == notification.client
+            && self.environment == notification.environment
+            && self.solution == notification.solution
+    }
 }
 
 #[debug_handler]
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
-    Query(QueryParamsWS {
+    ApiQuery(QueryParamsWS {
         client,
         solution,
         environment,
-    }): Query<QueryParamsWS>,
+        node,
+    }): ApiQuery<QueryParamsWS>,
     Extension(state): Extension<Arc<AppState>>,
 ) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, state, client, solution, environment))
+    ws.on_upgrade(|socket| handle_socket(socket, state, client, solution, environment, node))
 }
 
-pub async fn broadcast(
-    state: Arc<AppState>,
-    client: String,
-    solution: String,
-    environment: String,
-) -> Result<(), SendError<String>> {
-    let sender = {
-        let mut map = state.channel_map.write().await;
-        map.entry(format!("{environment}_{solution}_{client}").clone())
-            .or_insert_with(|| channel(100).0)
-            .clone()
+/// Hands `notification` to the nodes of its deployment, through the rollout
+/// controller when the deployment has a rollout policy.
+pub async fn broadcast(state: Arc<AppState>, notification: ChangeNotification) {
+    state.metrics.broadcasts.inc();
+    if start_rollout(&state, &notification).await {
+        return;
+    }
+    deliver(&state, &notification, |_| true).await;
+}
+
+/// Sends `notification` to the matching connections accepted by `filter`.
+pub async fn deliver(
+    state: &AppState,
+    notification: &ChangeNotification,
+    filter: impl Fn(&NodeConnection) -> bool,
+) {
+    let message = match serde_json::to_string(notification) {
+        Ok(message) => message,
+        Err(e) => {
+            error!("Unable to serialize change notification: {e}");
+            return;
+        }
     };
-    sender.send("DEPLOYMENT UPDATED".to_string())?;
-    Ok(())
+    let connections = state.connections.read().await;
+    for connection in connections.values() {
+        if connection.matches(notification) && filter(connection) {
+            // a closed channel means the socket is going away, it cleans up after itself
+            if connection.sender.send(message.clone()).is_ok() {
+                state.metrics.broadcast_messages.inc();
+            }
+        }
+    }
 }
 
 pub async fn handle_socket(
@@ -57,31 +109,62 @@ pub async fn handle_socket(
     client: String,
     solution: String,
     environment: String,
+    node: Option<String>,
 ) {
-    let (mut ws_tx, _ws_rx) = socket.split();
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let (sender, mut receiver) = unbounded_channel::<String>();
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let node = node.unwrap_or_else(|| format!("connection-{id}"));
+    info!("Node {node} connected for {environment}_{solution}_{client}");
+    state.connections.write().await.insert(
+        id,
+        NodeConnection {
+            node: node.clone(),
+            client,
+            environment,
+            solution,
+            sender,
+            statuses: HashMap::new(),
+        },
+    );
 
-    let key = format!("{environment}_{solution}_{client}");
-    let sender = {
-        let mut map = state.channel_map.write().await;
-        map.entry(key.clone())
-            .or_insert_with(|| channel(100).0)
-            .clone()
-    };
-
-    // Spawn task to forward broadcast → WebSocket
-    let mut rx = sender.subscribe();
-    tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
+    // Spawn task to forward this connection's channel → WebSocket
+    let forward = tokio::spawn(async move {
+        while let Some(msg) = receiver.recv().await {
             if ws_tx.send(Message::Text(msg.into())).await.is_err() {
-                info!("something wrong");
                 break;
             }
         }
+    });
 
-        // Clean up if no more receivers exist
-        if sender.receiver_count() == 0 {
-            let mut map = state.channel_map.write().await;
-            map.remove(&key);
+    while let Some(Ok(message)) = ws_rx.next().await {
+        match message {
+            Message::Text(text) => match serde_json::from_str::<NodeStatus>(text.as_str()) {
+                Ok(status) => {
+                    info!(
+                        "Node {node} reports revision {} of '{}' as {}",
+                        status.revision,
+                        status.deployment,
+                        if status.healthy() {
+                            "healthy"
+                        } else {
+                            "unhealthy"
+                        }
+                    );
+                    if let Some(connection) = state.connections.write().await.get_mut(&id) {
+                        connection
+                            .statuses
+                            .insert(status.deployment.clone(), status);
+                    }
+                }
+                Err(e) => warn!("Unable to parse status report from {node}: {e}"),
+            },
+            Message::Close(_) => break,
+            _ => {}
         }
-    });
+    }
+
+    state.connections.write().await.remove(&id);
+    forward.abort();
+    info!("Node {node} disconnected");
 }
@@ -3,6 +3,15 @@ use clap::{Parser, Subcommand};
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct HikariCli {
+    #[arg(
+        long,
+        global = true,
+        env = "HIKARI_LOG_CONFIG",
+        default_value = "log4rs.yaml",
+        value_name = "path",
+        help = "Path to the log4rs configuration file"
+    )]
+    pub log_config: String,
     #[command(subcommand)]
     pub command: HikariCommands,
 }
@@ -56,7 +65,51 @@ pub enum HikariCommands {
     /// Run hikari in Daemon Mode (Standalone Mode)
     Daemon,
     /// Run hikari in Server Mode
-    Server,
+    Server {
+        #[command(subcommand)]
+        command: Option<ServerCommands>,
+    },
     /// Run hikari in Agent Mode
     Agent,
+    /// Promote the stacks of one deployment into another through the server
+    Promote {
+        #[arg(short = 's', long, help = "Name of the deployment to promote from")]
+        source: String,
+        #[arg(short = 't', long, help = "Name of the deployment to promote into")]
+        target: String,
+        #[arg(
+            short = 'r',
+            long,
+            value_name = "rules",
+            help = "Path to the TOML file with the per-environment promotion rules"
+        )]
+        rules_file: Option<String>,
+        #[arg(
+            long,
+            default_value = "http://localhost:3000",
+            help = "Base URL of the hikari server"
+        )]
+        server: String,
+        #[arg(long, help = "Remove the target's stacks missing from the source")]
+        prune: bool,
+        #[arg(short = 'y', long, help = "Apply without asking for confirmation")]
+        yes: bool,
+        #[arg(
+            short = 'm',
+            long,
+            help = "Message recorded with the target's revision"
+        )]
+        message: Option<String>,
+        #[arg(
+            long,
+            help = "Apply on the nodes without waiting for maintenance windows"
+        )]
+        urgent: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ServerCommands {
+    /// Apply pending database migrations and exit
+    Migrate,
 }
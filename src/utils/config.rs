@@ -11,6 +11,9 @@ pub fn load_config() -> Result<(NodeConfig, NodeUpdateOptions), ConfigError> {
         let contents = fs::read_to_string("node.toml").map_err(ConfigError::FileError)?;
         toml::from_str(&contents).map_err(ConfigError::TomlParseError)?
     };
+    if let Some(window) = &node_config.maintenance_window {
+        window.validate()?;
+    }
 
     let node_update_config: NodeUpdateOptions = {
         let contents = fs::read_to_string("config.toml").map_err(ConfigError::FileError)?;
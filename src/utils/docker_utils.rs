@@ -1,21 +1,25 @@
 use std::{
+    collections::VecDeque,
     fs::{File, create_dir_all},
     io::{self, BufRead, Write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
     thread,
+    time::Instant,
 };
 
 use log::{error, info};
 
-use crate::objects::structs::ComposeSpec;
+use crate::{
+    objects::structs::{CommandErrorKind, CommandOutcome, ComposeSpec},
+    utils::logging::LogContext,
+};
 
 pub fn dry_run_generate_compose(
     filename: String,
     compose_config: ComposeSpec,
 ) -> Result<PathBuf, io::Error> {
-    let yaml = serde_yaml::to_string(&compose_config)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let yaml = serde_yaml::to_string(&compose_config).map_err(io::Error::other)?;
     let base_path = PathBuf::from(format!("./{filename}"));
     let mut file = File::create(&base_path)?;
     file.write_all(yaml.as_bytes())?;
@@ -27,8 +31,7 @@ pub fn generate_compose(
     filename: &str,
     compose_config: &ComposeSpec,
 ) -> Result<PathBuf, io::Error> {
-    let yaml = serde_yaml::to_string(compose_config)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let yaml = serde_yaml::to_string(compose_config).map_err(io::Error::other)?;
     if !Path::new(compose_directory).exists() {
         create_dir_all(compose_directory)?;
         info!("Directory created:{compose_directory}");
@@ -42,7 +45,18 @@ pub fn generate_compose(
     Ok(base_path)
 }
 
-pub fn execute_command(command: &str, args: Vec<&str>) -> bool {
+/// Output kept per stream of a command, the tail being what explains failures.
+const MAX_CAPTURED_BYTES: usize = 8 * 1024;
+
+/// Runs `command`, logging its output as it comes and keeping the tail of it.
+pub fn execute_command(command: &str, args: Vec<&str>) -> CommandOutcome {
+    let command_line = [command]
+        .iter()
+        .chain(&args)
+        .copied()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let started = Instant::now();
     match Command::new(command)
         .args(&args)
         .stdin(Stdio::null()) // No input needed
@@ -53,91 +67,325 @@ pub fn execute_command(command: &str, args: Vec<&str>) -> bool {
         Ok(mut child) => {
             let stdout = child.stdout.take();
             let stderr = child.stderr.take();
+            // output is logged from other threads, tagged with the stack it belongs to
+            let context = LogContext::current();
+            let tag = context.stack.clone().unwrap_or_else(|| command.to_string());
 
             // Thread to handle stdout
-            let stdout_thread: Option<thread::JoinHandle<()>> = stdout.map(|stdout| {
+            let stdout_thread: Option<thread::JoinHandle<String>> = stdout.map(|stdout| {
                 let reader = io::BufReader::new(stdout);
+                let (context, tag) = (context.clone(), tag.clone());
                 thread::spawn(move || {
-                    for line in reader.lines() {
-                        match line {
-                            Ok(line) => info!("{line}"), // Print each line of stdout
-                            Err(e) => error!("Error reading stdout: {e}"),
+                    context.enter(|| {
+                        let mut captured = OutputTail::default();
+                        for line in reader.lines() {
+                            match line {
+                                Ok(line) => {
+                                    info!("[{tag}] {line}"); // Print each line of stdout
+                                    captured.push(line);
+                                }
+                                Err(e) => error!("[{tag}] Error reading stdout: {e}"),
+                            }
                         }
-                    }
+                        captured.into_string()
+                    })
                 })
             });
 
             // Thread to handle stderr
-            let stderr_thread: Option<thread::JoinHandle<()>> = stderr.map(|stderr| {
+            let stderr_thread: Option<thread::JoinHandle<String>> = stderr.map(|stderr| {
                 let reader = io::BufReader::new(stderr);
                 thread::spawn(move || {
-                    for line in reader.lines() {
-                        match line {
-                            Ok(line) => error!("ERROR: {line}"), // Print each line of stderr
-                            Err(e) => error!("Error reading stderr: {e}"),
+                    context.enter(|| {
+                        let mut captured = OutputTail::default();
+                        for line in reader.lines() {
+                            match line {
+                                Ok(line) => {
+                                    error!("[{tag}] ERROR: {line}"); // Print each line of stderr
+                                    captured.push(line);
+                                }
+                                Err(e) => error!("[{tag}] Error reading stderr: {e}"),
+                            }
                         }
-                    }
+                        captured.into_string()
+                    })
                 })
             });
 
             // Wait for the process to finish
-            match child.wait() {
+            let status = child.wait();
+            let stdout = stdout_thread
+                .and_then(|handle| handle.join().ok())
+                .unwrap_or_default();
+            let stderr = stderr_thread
+                .and_then(|handle| handle.join().ok())
+                .unwrap_or_default();
+            let (exit_code, error) = match status {
+                Ok(status) if status.success() => (status.code(), None),
                 Ok(status) => {
-                    if let Some(handle) = stdout_thread {
-                        let _ = handle.join();
-                    }
-                    if let Some(handle) = stderr_thread {
-                        let _ = handle.join();
-                    }
-
-                    if status.success() {
-                        true
-                    } else {
-                        error!("Command exited with status: {status}");
-                        false
-                    }
+                    let kind = classify_failure(&stderr);
+                    error!("Command exited with status: {status} ({kind})");
+                    (status.code(), Some(kind))
                 }
                 Err(e) => {
                     error!("Failed to wait for the command: {e}");
-                    false
+                    (None, Some(CommandErrorKind::Failed))
                 }
+            };
+            CommandOutcome {
+                command: command_line,
+                exit_code,
+                stdout,
+                stderr,
+                duration_ms: elapsed_ms(started),
+                error,
             }
         }
         Err(e) => {
             error!("Failed to execute command '{command}': {e}");
-            false
+            not_run(command_line, CommandErrorKind::SpawnFailed, e.to_string())
         }
     }
 }
 
-pub fn pull_compose(compose_file_path: &str) -> bool {
-    info!("{}", &compose_file_path);
-    let command = "docker";
-    let args = ["compose", "-f", compose_file_path, "pull"];
-    if Path::exists(Path::new(compose_file_path)) {
-        return execute_command(command, args.to_vec());
+/// Outcome of a command that never ran.
+pub fn not_run(command: String, kind: CommandErrorKind, reason: String) -> CommandOutcome {
+    CommandOutcome {
+        command,
+        exit_code: None,
+        stdout: String::new(),
+        stderr: reason,
+        duration_ms: 0,
+        error: Some(kind),
+    }
+}
+
+pub fn elapsed_ms(started: Instant) -> u64 {
+    u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Last lines of a stream, at most [`MAX_CAPTURED_BYTES`] of them.
+#[derive(Default)]
+pub struct OutputTail {
+    lines: VecDeque<String>,
+    bytes: usize,
+}
+
+impl OutputTail {
+    pub fn push(&mut self, line: String) {
+        self.bytes += line.len() + 1;
+        self.lines.push_back(line);
+        while self.bytes > MAX_CAPTURED_BYTES && self.lines.len() > 1 {
+            if let Some(dropped) = self.lines.pop_front() {
+                self.bytes -= dropped.len() + 1;
+            }
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        let mut output = Vec::from(self.lines).join("\n");
+        if output.len() > MAX_CAPTURED_BYTES {
+            let mut cut = output.len() - MAX_CAPTURED_BYTES;
+            while !output.is_char_boundary(cut) {
+                cut += 1;
+            }
+            output.drain(..cut);
+        }
+        output
     }
-    error!("compose file does not exist");
-    false
 }
 
-pub fn start_compose(compose_file_path: &str) -> bool {
-    info!("{}", &compose_file_path);
-    let command = "docker";
-    let args = ["compose", "-f", compose_file_path, "up", "-d"];
-    if Path::exists(Path::new(compose_file_path)) {
-        return execute_command(command, args.to_vec());
+/// Tells the usual docker failures apart by what they print.
+pub fn classify_failure(stderr: &str) -> CommandErrorKind {
+    let stderr = stderr.to_lowercase();
+    let mentions = |patterns: &[&str]| patterns.iter().any(|pattern| stderr.contains(pattern));
+    if mentions(&[
+        "cannot connect to the docker daemon",
+        "is the docker daemon running",
+        "couldn't connect to docker daemon",
+        "cannot connect to podman",
+        "unable to connect to podman socket",
+        "cannot access containerd socket",
+    ]) {
+        CommandErrorKind::DaemonUnavailable
+    } else if mentions(&[
+        "manifest unknown",
+        "repository does not exist",
+        "not found: manifest",
+    ]) {
+        CommandErrorKind::ImageNotFound
+    } else if mentions(&[
+        "unauthorized",
+        "authentication required",
+        "no basic auth credentials",
+        "denied: requested access",
+    ]) {
+        CommandErrorKind::RegistryAuth
+    } else if mentions(&["port is already allocated", "address already in use"]) {
+        CommandErrorKind::PortConflict
+    } else if mentions(&[
+        "yaml:",
+        "validating ",
+        "invalid compose project",
+        "unsupported config option",
+    ]) {
+        CommandErrorKind::InvalidCompose
+    } else if mentions(&[
+        "no such host",
+        "i/o timeout",
+        "tls handshake timeout",
+        "network is unreachable",
+        "connection refused",
+    ]) {
+        CommandErrorKind::Network
+    } else {
+        CommandErrorKind::Failed
     }
-    error!("compose file does not exist");
-    false
 }
-pub fn stop_compose(compose_file_path: &str) -> bool {
-    info!("{}", &compose_file_path);
-    let command = "docker";
-    let args = ["compose", "-f", compose_file_path, "down"];
-    if Path::exists(Path::new(compose_file_path)) {
-        return execute_command(command, args.to_vec());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_are_classified() {
+        let cases = [
+            (
+                "Cannot connect to the Docker daemon at unix:///var/run/docker.sock. Is the docker daemon running?",
+                CommandErrorKind::DaemonUnavailable,
+            ),
+            (
+                "Couldn't connect to Docker daemon at http+docker://localhost",
+                CommandErrorKind::DaemonUnavailable,
+            ),
+            (
+                "Error: unable to connect to Podman socket: dial unix",
+                CommandErrorKind::DaemonUnavailable,
+            ),
+            (
+                "FATA[0000] cannot access containerd socket \"/run/containerd/containerd.sock\"",
+                CommandErrorKind::DaemonUnavailable,
+            ),
+            (
+                "Error response from daemon: manifest unknown: manifest unknown",
+                CommandErrorKind::ImageNotFound,
+            ),
+            (
+                "pull access denied for nope, repository does not exist or may require 'docker login'",
+                CommandErrorKind::ImageNotFound,
+            ),
+            (
+                "failed to resolve reference: not found: manifest for nginx:9",
+                CommandErrorKind::ImageNotFound,
+            ),
+            (
+                "Error response from daemon: Head \"https://registry/v2/app/manifests/1\": unauthorized: authentication required",
+                CommandErrorKind::RegistryAuth,
+            ),
+            ("no basic auth credentials", CommandErrorKind::RegistryAuth),
+            (
+                "denied: requested access to the resource is denied",
+                CommandErrorKind::RegistryAuth,
+            ),
+            (
+                "Bind for 0.0.0.0:8080 failed: port is already allocated",
+                CommandErrorKind::PortConflict,
+            ),
+            (
+                "listen tcp4 0.0.0.0:80: bind: address already in use",
+                CommandErrorKind::PortConflict,
+            ),
+            (
+                "yaml: line 3: mapping values are not allowed in this context",
+                CommandErrorKind::InvalidCompose,
+            ),
+            (
+                "validating /srv/shop/shop.yaml: services.web Additional property foo is not allowed",
+                CommandErrorKind::InvalidCompose,
+            ),
+            ("invalid compose project", CommandErrorKind::InvalidCompose),
+            (
+                "ERROR: The Compose file is invalid because: Unsupported config option for services",
+                CommandErrorKind::InvalidCompose,
+            ),
+            (
+                "dial tcp: lookup registry.example.com: no such host",
+                CommandErrorKind::Network,
+            ),
+            ("net/http: TLS handshake timeout", CommandErrorKind::Network),
+            (
+                "read tcp 10.0.0.2:5000: i/o timeout",
+                CommandErrorKind::Network,
+            ),
+            ("connect: network is unreachable", CommandErrorKind::Network),
+            (
+                "dial tcp 10.0.0.1:443: connect: connection refused",
+                CommandErrorKind::Network,
+            ),
+            ("container exited with code 1", CommandErrorKind::Failed),
+            ("", CommandErrorKind::Failed),
+        ];
+        for (stderr, kind) in cases {
+            assert_eq!(classify_failure(stderr), kind, "{stderr}");
+        }
+    }
+
+    #[test]
+    fn earlier_kinds_win() {
+        // the daemon being down explains any registry error that follows
+        assert_eq!(
+            classify_failure("Cannot connect to the Docker daemon; unauthorized"),
+            CommandErrorKind::DaemonUnavailable
+        );
+        assert_eq!(
+            classify_failure("manifest unknown: unauthorized"),
+            CommandErrorKind::ImageNotFound
+        );
+    }
+
+    #[test]
+    fn tail_keeps_everything_up_to_the_limit() {
+        let mut tail = OutputTail::default();
+        // 8 lines of 1023 bytes and their newlines fill the limit exactly
+        let line = "x".repeat(1023);
+        for _ in 0..8 {
+            tail.push(line.clone());
+        }
+        assert_eq!(tail.bytes, MAX_CAPTURED_BYTES);
+        assert_eq!(tail.lines.len(), 8);
+        let output = tail.into_string();
+        assert_eq!(output.len(), MAX_CAPTURED_BYTES - 1);
+        assert_eq!(output.lines().count(), 8);
+    }
+
+    #[test]
+    fn tail_drops_the_oldest_lines_past_the_limit() {
+        let mut tail = OutputTail::default();
+        tail.push("first".into());
+        for _ in 0..8 {
+            tail.push("x".repeat(1023));
+        }
+        tail.push("last".into());
+        assert!(tail.bytes <= MAX_CAPTURED_BYTES);
+        let output = tail.into_string();
+        assert!(!output.contains("first"));
+        assert!(output.ends_with("\nlast"));
+        assert_eq!(output.lines().count(), 8);
+    }
+
+    #[test]
+    fn tail_cuts_a_single_long_line_on_a_char_boundary() {
+        let mut tail = OutputTail::default();
+        tail.push(format!("{}a", "é".repeat(5000)));
+        let output = tail.into_string();
+        // one byte more would split an `é`
+        assert_eq!(output.len(), MAX_CAPTURED_BYTES - 1);
+        assert!(output.starts_with('é'));
+        assert!(output.ends_with('a'));
+    }
+
+    #[test]
+    fn empty_tail_is_empty() {
+        assert_eq!(OutputTail::default().into_string(), "");
     }
-    error!("compose file does not exist");
-    false
 }
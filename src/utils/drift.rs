@@ -0,0 +1,411 @@
+use std::{
+    fs, io,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use log::{error, info, warn};
+
+use crate::{
+    objects::structs::{
+        DriftFinding, DriftPolicy, HikariConfig, NodeConfig, NodeUpdateOptions, StackConfig,
+        StackDrift,
+    },
+    utils::{
+        logging::LogContext,
+        maintenance::runs_on,
+        manage::{StackOperation, StackOutcome, manage_stack},
+        runtime::{Runtime, compose_file_path, runtime},
+    },
+};
+
+/// Seconds between drift checks when `drift_interval` is not set.
+const DEFAULT_DRIFT_INTERVAL: u64 = 300;
+
+/// Drift found on a stack by [`check_drift`].
+#[derive(Debug, Clone)]
+pub struct DriftReport {
+    pub deployment: String,
+    pub stack_name: String,
+    pub drift: StackDrift,
+}
+
+/// What [`check_drift`] found and did.
+#[derive(Debug, Default)]
+pub struct DriftCheck {
+    /// Stacks that drifted, the others run as configured.
+    pub reports: Vec<DriftReport>,
+    /// Operations run to heal stacks, in order.
+    pub outcomes: Vec<StackOutcome>,
+}
+
+/// Time between drift checks, `None` when `drift_interval` turns them off.
+pub fn drift_period(options: &NodeUpdateOptions) -> Option<Duration> {
+    let secs = match &options.drift_interval {
+        Some(val) => match val.parse::<u64>() {
+            Ok(secs) => secs,
+            Err(_) => {
+                error!("Invalid drift_interval value");
+                DEFAULT_DRIFT_INTERVAL
+            }
+        },
+        None => DEFAULT_DRIFT_INTERVAL,
+    };
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// When a node polling on its own schedule checks for drift next.
+pub struct DriftSchedule {
+    period: Option<Duration>,
+    next: Instant,
+}
+
+impl DriftSchedule {
+    pub fn new(options: &NodeUpdateOptions) -> Self {
+        let period = drift_period(options);
+        Self {
+            period,
+            next: Instant::now() + period.unwrap_or_default(),
+        }
+    }
+
+    /// Whether a check is due, moving the next one a period ahead if so.
+    pub fn due(&mut self) -> bool {
+        let Some(period) = self.period else {
+            return false;
+        };
+        if Instant::now() < self.next {
+            return false;
+        }
+        self.next = Instant::now() + period;
+        true
+    }
+}
+
+/// Compares the stacks `reference` runs on this node with their compose files
+/// and containers, applying the configuration again to drifted stacks whose
+/// policy is `heal`.
+pub fn check_drift(reference: &HikariConfig, node_config: &NodeConfig) -> DriftCheck {
+    let mut check = DriftCheck::default();
+    let mut deployments: Vec<_> = reference
+        .deploy_configs
+        .iter()
+        .filter(|(_, deploy_config)| runs_on(deploy_config, node_config))
+        .collect();
+    deployments.sort_by_key(|(name, _)| *name);
+    for (name, deploy_config) in deployments {
+        LogContext::current().deployment(name).enter(|| {
+            for stack in &deploy_config.deploy_stacks {
+                if stack.drift_policy == DriftPolicy::Ignore {
+                    continue;
+                }
+                let findings = match detect(stack, runtime()) {
+                    Ok(findings) => findings,
+                    Err(e) => {
+                        error!("Unable to check stack {} for drift: {e}", stack.stack_name);
+                        continue;
+                    }
+                };
+                if findings.is_empty() {
+                    continue;
+                }
+                for finding in &findings {
+                    warn!("Stack {} drifted: {finding}", stack.stack_name);
+                }
+                let healed = (stack.drift_policy == DriftPolicy::Heal).then(|| {
+                    info!("Healing stack {}", stack.stack_name);
+                    let outcome = manage_stack(stack, StackOperation::Heal);
+                    let success = outcome.outcome.success();
+                    check.outcomes.push(StackOutcome {
+                        deployment: name.clone(),
+                        stack_name: stack.stack_name.clone(),
+                        outcome,
+                    });
+                    // the runtime succeeding doesn't mean every finding is gone
+                    success && runs_as_configured(stack)
+                });
+                check.reports.push(DriftReport {
+                    deployment: name.clone(),
+                    stack_name: stack.stack_name.clone(),
+                    drift: StackDrift {
+                        detected_at: Utc::now(),
+                        findings,
+                        healed,
+                    },
+                });
+            }
+        });
+    }
+    check
+}
+
+/// Whether the drift of a healed `stack` is gone, logging what is left.
+fn runs_as_configured(stack: &StackConfig) -> bool {
+    match detect(stack, runtime()) {
+        Ok(findings) => {
+            for finding in &findings {
+                warn!("Stack {} still drifted: {finding}", stack.stack_name);
+            }
+            findings.is_empty()
+        }
+        Err(e) => {
+            error!("Unable to check healed stack {}: {e}", stack.stack_name);
+            false
+        }
+    }
+}
+
+/// Ways `stack` no longer runs as configured. Containers that restart count as
+/// running, that is for health checks to report.
+fn detect(stack: &StackConfig, runtime: &dyn Runtime) -> Result<Vec<DriftFinding>, String> {
+    let mut findings = Vec::new();
+    findings.extend(compose_file_drift(stack)?);
+    let containers = runtime.containers(stack)?;
+    let mut services: Vec<&String> = stack.compose_spec.services.keys().collect();
+    services.sort();
+    for service in services {
+        let mut running = containers
+            .iter()
+            .filter(|container| &container.service == service)
+            .peekable();
+        if running.peek().is_none() {
+            findings.push(DriftFinding::ServiceMissing {
+                service: service.clone(),
+            });
+        }
+        for container in running {
+            if !matches!(container.state.as_str(), "running" | "restarting") {
+                findings.push(DriftFinding::ServiceStopped {
+                    service: service.clone(),
+                    container: container.name.clone(),
+                    state: container.state.clone(),
+                });
+            }
+        }
+    }
+    for container in &containers {
+        if !stack.compose_spec.services.contains_key(&container.service) {
+            findings.push(DriftFinding::UnexpectedService {
+                service: container.service.clone(),
+                container: container.name.clone(),
+            });
+        }
+    }
+    Ok(findings)
+}
+
+/// Compares the compose file on disk with the one `stack` generates. Both are
+/// read as YAML, so key order and formatting don't count.
+fn compose_file_drift(stack: &StackConfig) -> Result<Option<DriftFinding>, String> {
+    let path = compose_file_path(stack);
+    let written = match fs::read_to_string(&path) {
+        Ok(written) => written,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Some(DriftFinding::ComposeFileMissing));
+        }
+        Err(e) => return Err(format!("Unable to read {path}: {e}")),
+    };
+    let desired = serde_yaml::to_value(&stack.compose_spec).map_err(|e| e.to_string())?;
+    let changed = serde_yaml::from_str::<serde_yaml::Value>(&written)
+        .map_or(true, |written| written != desired);
+    Ok(changed.then_some(DriftFinding::ComposeFileChanged))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        objects::structs::{CommandOutcome, ComposeSpec, Container},
+        utils::runtime::{ContainerState, RuntimeEvent},
+    };
+
+    /// Runtime answering [`Runtime::containers`] with a fixed listing.
+    struct FakeRuntime(Result<Vec<ContainerState>, String>);
+
+    impl FakeRuntime {
+        fn running(containers: &[(&str, &str, &str)]) -> Self {
+            Self(Ok(containers
+                .iter()
+                .map(|(name, service, state)| ContainerState {
+                    name: name.to_string(),
+                    service: service.to_string(),
+                    state: state.to_string(),
+                    health: String::new(),
+                })
+                .collect()))
+        }
+    }
+
+    impl Runtime for FakeRuntime {
+        fn name(&self) -> &'static str {
+            "a fake runtime"
+        }
+
+        fn pull(&self, _: &StackConfig) -> CommandOutcome {
+            unreachable!("drift detection doesn't pull")
+        }
+
+        fn up(&self, _: &StackConfig) -> CommandOutcome {
+            unreachable!("drift detection doesn't start stacks")
+        }
+
+        fn down(&self, _: &StackConfig) -> CommandOutcome {
+            unreachable!("drift detection doesn't stop stacks")
+        }
+
+        fn containers(&self, _: &StackConfig) -> Result<Vec<ContainerState>, String> {
+            self.0.clone()
+        }
+
+        fn watch_events(&self, _: &mut dyn FnMut(RuntimeEvent)) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    /// Stack of a `web` and a `worker` service living in `dir`, its compose
+    /// file written as generated.
+    fn stack(dir: &TempDir) -> StackConfig {
+        let service = |name: &str| Container {
+            container_name: format!("shop-{name}"),
+            image: format!("{name}:1"),
+            restart: "always".into(),
+            ..Default::default()
+        };
+        let stack = StackConfig {
+            stack_name: "shop".into(),
+            filename: "shop.yaml".into(),
+            home_directory: dir.path().to_string_lossy().into(),
+            compose_spec: ComposeSpec {
+                services: HashMap::from([
+                    ("web".into(), service("web")),
+                    ("worker".into(), service("worker")),
+                ]),
+            },
+            ..Default::default()
+        };
+        write(&stack, &serde_yaml::to_string(&stack.compose_spec).unwrap());
+        stack
+    }
+
+    fn write(stack: &StackConfig, contents: &str) {
+        fs::write(compose_file_path(stack), contents).unwrap();
+    }
+
+    #[test]
+    fn stack_running_as_configured_has_no_drift() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = FakeRuntime::running(&[
+            ("shop-web", "web", "running"),
+            ("shop-worker", "worker", "restarting"),
+        ]);
+        assert_eq!(detect(&stack(&dir), &runtime).unwrap(), []);
+    }
+
+    #[test]
+    fn missing_stopped_and_unexpected_services_are_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = FakeRuntime::running(&[
+            ("shop-worker", "worker", "exited"),
+            ("shop-cache", "cache", "running"),
+        ]);
+        assert_eq!(
+            detect(&stack(&dir), &runtime).unwrap(),
+            [
+                DriftFinding::ServiceMissing {
+                    service: "web".into(),
+                },
+                DriftFinding::ServiceStopped {
+                    service: "worker".into(),
+                    container: "shop-worker".into(),
+                    state: "exited".into(),
+                },
+                DriftFinding::UnexpectedService {
+                    service: "cache".into(),
+                    container: "shop-cache".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn compose_file_findings_come_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let stack = stack(&dir);
+        fs::remove_file(compose_file_path(&stack)).unwrap();
+        let runtime = FakeRuntime::running(&[]);
+        assert_eq!(
+            detect(&stack, &runtime).unwrap(),
+            [
+                DriftFinding::ComposeFileMissing,
+                DriftFinding::ServiceMissing {
+                    service: "web".into(),
+                },
+                DriftFinding::ServiceMissing {
+                    service: "worker".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn runtime_errors_are_passed_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = FakeRuntime(Err("engine is on fire".into()));
+        assert_eq!(
+            detect(&stack(&dir), &runtime).unwrap_err(),
+            "engine is on fire"
+        );
+    }
+
+    #[test]
+    fn compose_file_is_compared_as_yaml() {
+        let dir = tempfile::tempdir().unwrap();
+        let stack = stack(&dir);
+        assert_eq!(compose_file_drift(&stack).unwrap(), None);
+
+        // the same document with its services swapped and another indentation
+        let services = &stack.compose_spec.services;
+        let reordered = format!(
+            "services:\n    worker:\n{}    web:\n{}",
+            indent(&serde_yaml::to_string(&services["worker"]).unwrap()),
+            indent(&serde_yaml::to_string(&services["web"]).unwrap()),
+        );
+        write(&stack, &reordered);
+        assert_eq!(compose_file_drift(&stack).unwrap(), None);
+
+        write(&stack, &reordered.replace("web:1", "web:2"));
+        assert_eq!(
+            compose_file_drift(&stack).unwrap(),
+            Some(DriftFinding::ComposeFileChanged)
+        );
+        write(&stack, "services: [unclosed");
+        assert_eq!(
+            compose_file_drift(&stack).unwrap(),
+            Some(DriftFinding::ComposeFileChanged)
+        );
+        fs::remove_file(compose_file_path(&stack)).unwrap();
+        assert_eq!(
+            compose_file_drift(&stack).unwrap(),
+            Some(DriftFinding::ComposeFileMissing)
+        );
+    }
+
+    #[test]
+    fn unreadable_compose_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let stack = stack(&dir);
+        fs::remove_file(compose_file_path(&stack)).unwrap();
+        fs::create_dir(compose_file_path(&stack)).unwrap();
+        assert!(compose_file_drift(&stack).is_err());
+    }
+
+    fn indent(yaml: &str) -> String {
+        yaml.lines()
+            .map(|line| format!("        {line}\n"))
+            .collect()
+    }
+}
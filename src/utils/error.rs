@@ -13,6 +13,27 @@ pub enum ConfigError {
 
     #[error("Failed to parse TOML: {0}")]
     TomlParseError(#[from] toml::de::Error),
+
+    #[error("Unsupported storage backend: {0}")]
+    UnsupportedBackend(String),
+
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+
+    #[error("Failed to migrate the database: {0}")]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
+
+    #[error("Database schema version {0} is newer than the latest known version {1}")]
+    UnknownSchema(i64, i64),
+
+    #[error("Request to the server failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+
+    #[error("Server rejected the request: {0}")]
+    ServerError(String),
+
+    #[error("Invalid maintenance window: {0}")]
+    InvalidSchedule(String),
 }
 
 #[derive(Debug, Error)]
@@ -1,5 +1,10 @@
+use std::{collections::HashMap, path::Path};
+
 use log::error;
-use reqwest::Error;
+use reqwest::{
+    Error, StatusCode,
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+};
 use tokio::{
     fs::{self, File},
     io::AsyncWriteExt,
@@ -7,26 +12,65 @@ use tokio::{
 
 use crate::objects::structs::HikariConfig;
 
-pub async fn download_file(file_url: &str, filename: &str) -> Result<bool, Error> {
+/// Validators of the last applied download, replayed as `If-None-Match` and
+/// `If-Modified-Since` so unchanged remote files are not fetched again.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CacheValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadOutcome {
+    Downloaded(CacheValidators),
+    NotModified,
+    Failed,
+}
+
+pub async fn download_file(
+    file_url: &str,
+    filename: &str,
+    validators: &CacheValidators,
+) -> Result<DownloadOutcome, Error> {
     let client = reqwest::Client::new();
-    let mut response = client.get(file_url).send().await?;
+    let mut request = client.get(file_url);
+    // only ask for a conditional response when there is a local copy to fall
+    // back on
+    if Path::new(filename).exists() {
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let mut response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(DownloadOutcome::NotModified);
+    }
     if !response.status().is_success() {
-        return Ok(false);
+        return Ok(DownloadOutcome::Failed);
     }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let fetched = CacheValidators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
     let mut file = File::create(filename).await.unwrap();
     while let Some(chunk) = response.chunk().await? {
         if let Err(err) = file.write_all(&chunk).await {
             error!("Unable to download the file - Error {err}");
         }
     }
-    Ok(true)
+    Ok(DownloadOutcome::Downloaded(fetched))
 }
 
-pub async fn copy_file(source: &str, destination: &str) {
-    if let Ok(contents) = fs::read(source).await {
-        let _ = fs::write(destination, contents).await;
-    }
-}
 pub async fn write_file(contents: &str, destination: &str) -> std::io::Result<()> {
     fs::write(destination, contents).await
 }
@@ -35,3 +79,8 @@ pub async fn load_config_from_url(url: &str) -> Result<HikariConfig, Error> {
     let cfg = response.json::<HikariConfig>().await?;
     Ok(cfg)
 }
+pub async fn load_revisions_from_url(url: &str) -> Result<HashMap<String, i64>, Error> {
+    let response = reqwest::get(url).await?.error_for_status()?;
+    let revisions = response.json::<HashMap<String, i64>>().await?;
+    Ok(revisions)
+}
@@ -0,0 +1,147 @@
+use std::{fmt, future::Future, sync::OnceLock};
+
+use chrono::Utc;
+use log::Record;
+use log4rs::{
+    config::{Deserialize, Deserializers},
+    encode::{Encode, Write},
+};
+use serde::Serialize;
+
+static MODE: OnceLock<String> = OnceLock::new();
+static NODE: OnceLock<String> = OnceLock::new();
+
+tokio::task_local! {
+    static CONTEXT: LogContext;
+}
+
+/// What the node is busy with, attached to the log lines written meanwhile by
+/// the `json` encoder.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LogContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<String>,
+}
+
+impl LogContext {
+    /// Context of a new reconcile pass, with a run ID of its own.
+    pub fn reconcile() -> Self {
+        Self {
+            run_id: Some(format!("{:016x}", rand::random::<u64>())),
+            operation: Some("reconcile".into()),
+            ..Self::default()
+        }
+    }
+
+    /// Context of the code being run, empty outside of any scope.
+    pub fn current() -> Self {
+        CONTEXT.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    pub fn deployment(mut self, deployment: &str) -> Self {
+        self.deployment = Some(deployment.into());
+        self
+    }
+
+    pub fn stack(mut self, stack: &str) -> Self {
+        self.stack = Some(stack.into());
+        self
+    }
+
+    pub fn operation(mut self, operation: impl fmt::Display) -> Self {
+        self.operation = Some(operation.to_string());
+        self
+    }
+
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CONTEXT.scope(self, f).await
+    }
+
+    /// Runs `f` within this context. Also works on plain threads, which is how
+    /// subprocess output keeps the context of the stack it belongs to.
+    pub fn enter<R>(self, f: impl FnOnce() -> R) -> R {
+        CONTEXT.sync_scope(self, f)
+    }
+}
+
+/// Loads the log4rs configuration at `path`, where encoders may be of the
+/// `json` kind provided here.
+pub fn init_logging(path: &str) {
+    let deserializers = {
+        let mut deserializers = Deserializers::default();
+        deserializers.insert("json", JsonEncoderDeserializer);
+        deserializers
+    };
+    if let Err(e) = log4rs::init_file(path, deserializers) {
+        eprintln!("Unable to load the log configuration '{path}': {e}");
+    }
+}
+
+/// Names the mode and the node reported with every JSON log line.
+pub fn set_process(mode: &str, node: Option<String>) {
+    let _ = MODE.set(mode.into());
+    if let Some(node) = node {
+        let _ = NODE.set(node);
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node: Option<&'a str>,
+    #[serde(flatten)]
+    context: LogContext,
+}
+
+/// Writes each record as a JSON object on its own line, with the process and
+/// [`LogContext`] fields next to the message.
+#[derive(Debug)]
+pub struct JsonEncoder;
+
+impl Encode for JsonEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> anyhow::Result<()> {
+        let line = JsonLine {
+            time: Utc::now().to_rfc3339(),
+            level: record.level().as_str(),
+            target: record.target(),
+            message: record.args().to_string(),
+            mode: MODE.get().map(String::as_str),
+            node: NODE.get().map(String::as_str),
+            context: LogContext::current(),
+        };
+        serde_json::to_writer(&mut *w, &line)?;
+        w.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonEncoderConfig {}
+
+struct JsonEncoderDeserializer;
+
+impl Deserialize for JsonEncoderDeserializer {
+    type Config = JsonEncoderConfig;
+    type Trait = dyn Encode;
+
+    fn deserialize(
+        &self,
+        _: JsonEncoderConfig,
+        _: &Deserializers,
+    ) -> anyhow::Result<Box<dyn Encode>> {
+        Ok(Box::new(JsonEncoder))
+    }
+}
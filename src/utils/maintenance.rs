@@ -0,0 +1,397 @@
+use chrono::{DateTime, Duration, LocalResult, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use log::{error, info};
+
+use crate::{
+    objects::structs::{
+        DeployConfig, HikariConfig, MaintenanceWindow, NodeConfig, PendingChange, Validate,
+    },
+    utils::error::ConfigError,
+};
+
+impl MaintenanceWindow {
+    fn cron(&self) -> Result<Cron, ConfigError> {
+        Cron::new(&self.schedule)
+            .with_seconds_optional()
+            .parse()
+            .map_err(|e| ConfigError::InvalidSchedule(format!("'{}': {e}", self.schedule)))
+    }
+
+    fn tz(&self) -> Result<Tz, ConfigError> {
+        self.timezone
+            .parse()
+            .map_err(|e| ConfigError::InvalidSchedule(format!("'{}': {e}", self.timezone)))
+    }
+
+    /// Whether the window opened less than `duration_minutes` before `now`.
+    pub fn is_open(&self, now: DateTime<Utc>) -> Result<bool, ConfigError> {
+        Ok(self.opening_after(now - Duration::minutes(self.duration_minutes))? <= now)
+    }
+
+    /// When the window opens next after `now`.
+    pub fn next_opening(&self, now: DateTime<Utc>) -> Result<DateTime<Utc>, ConfigError> {
+        self.opening_after(now)
+    }
+
+    fn opening_after(&self, after: DateTime<Utc>) -> Result<DateTime<Utc>, ConfigError> {
+        let (cron, tz) = (self.cron()?, self.tz()?);
+        let mut after = after.with_timezone(&tz);
+        let mut inclusive = false;
+        loop {
+            let opening = cron
+                .find_next_occurrence(&after, inclusive)
+                .map_err(|e| ConfigError::InvalidSchedule(e.to_string()))?;
+            if opening > after || (inclusive && opening == after) {
+                return Ok(opening.with_timezone(&Utc));
+            }
+            // from inside the hour the clocks go back over, croner answers
+            // with its first pass; the window already opened then, so carry
+            // on from where the wall clock is past the repeated hour
+            after = after
+                .with_nanosecond(0)
+                .and_then(|at| at.with_second(0))
+                .unwrap_or(after);
+            loop {
+                after += Duration::minutes(1);
+                if !matches!(
+                    tz.from_local_datetime(&after.naive_local()),
+                    LocalResult::Ambiguous(..)
+                ) {
+                    break;
+                }
+            }
+            inclusive = true;
+        }
+    }
+}
+
+impl Validate for MaintenanceWindow {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.duration_minutes <= 0 {
+            return Err(ConfigError::InvalidSchedule(
+                "duration_minutes must be positive".into(),
+            ));
+        }
+        self.cron()?;
+        self.tz()?;
+        Ok(())
+    }
+}
+
+/// Splits `incoming` into what this node may apply at `now` and what has to
+/// wait. Deployments running here keep their `current` configuration while
+/// their maintenance window is closed, unless the change is urgent; new
+/// deployments and changes that leave the stacks alone go through.
+pub fn hold_for_maintenance(
+    current: &HikariConfig,
+    incoming: &HikariConfig,
+    node_config: &NodeConfig,
+    now: DateTime<Utc>,
+) -> (HikariConfig, Vec<PendingChange>) {
+    let mut applied = incoming.clone();
+    let mut pending = Vec::new();
+    for (name, current_deploy_config) in &current.deploy_configs {
+        if !runs_on(current_deploy_config, node_config) {
+            continue;
+        }
+        let incoming_deploy_config = incoming.deploy_configs.get(name);
+        if incoming_deploy_config.is_some_and(|incoming| {
+            incoming.urgent
+                || (runs_on(incoming, node_config)
+                    && incoming.deploy_stacks.len() == current_deploy_config.deploy_stacks.len()
+                    && incoming
+                        .deploy_stacks
+                        .iter()
+                        .zip(&current_deploy_config.deploy_stacks)
+                        .all(|(incoming, current)| incoming.runs_like(current)))
+        }) {
+            continue;
+        }
+        let Some(window) = incoming_deploy_config
+            .and_then(|incoming| incoming.maintenance_window.as_ref())
+            .or(current_deploy_config.maintenance_window.as_ref())
+            .or(node_config.maintenance_window.as_ref())
+        else {
+            continue;
+        };
+        match window.is_open(now) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                error!("Ignoring maintenance window of '{name}': {e}");
+                continue;
+            }
+        }
+        let opens_at = window.next_opening(now).ok();
+        info!(
+            "Holding changes to '{name}' until its maintenance window opens at {}",
+            opens_at.map_or_else(|| "an unknown time".into(), |at| at.to_rfc3339())
+        );
+        pending.push(PendingChange {
+            deployment: name.clone(),
+            revision: incoming_deploy_config.and_then(|incoming| incoming.revision),
+            opens_at,
+        });
+        applied
+            .deploy_configs
+            .insert(name.clone(), current_deploy_config.clone());
+    }
+    (applied, pending)
+}
+
+pub fn runs_on(deploy_config: &DeployConfig, node_config: &NodeConfig) -> bool {
+    deploy_config.client == node_config.client
+        && deploy_config.environment == node_config.environment
+        && deploy_config.solution == node_config.solution
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::structs::{DriftPolicy, StackConfig};
+
+    fn window(schedule: &str, duration_minutes: i64, timezone: &str) -> MaintenanceWindow {
+        MaintenanceWindow {
+            schedule: schedule.into(),
+            duration_minutes,
+            timezone: timezone.into(),
+        }
+    }
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc()
+    }
+
+    #[test]
+    fn window_is_open_for_its_duration() {
+        let window = window("0 2 * * *", 60, "UTC");
+        assert!(!window.is_open(at("2026-06-10T01:59:59Z")).unwrap());
+        assert!(window.is_open(at("2026-06-10T02:00:00Z")).unwrap());
+        assert!(window.is_open(at("2026-06-10T02:59:59Z")).unwrap());
+        assert!(!window.is_open(at("2026-06-10T03:00:01Z")).unwrap());
+        assert_eq!(
+            window.next_opening(at("2026-06-10T02:30:00Z")).unwrap(),
+            at("2026-06-11T02:00:00Z")
+        );
+    }
+
+    #[test]
+    fn window_crossing_midnight_stays_open_into_the_next_day() {
+        let window = window("0 23 * * *", 120, "UTC");
+        assert!(window.is_open(at("2026-06-10T23:30:00Z")).unwrap());
+        assert!(window.is_open(at("2026-06-11T00:59:00Z")).unwrap());
+        assert!(!window.is_open(at("2026-06-11T01:01:00Z")).unwrap());
+        assert_eq!(
+            window.next_opening(at("2026-06-11T00:30:00Z")).unwrap(),
+            at("2026-06-11T23:00:00Z")
+        );
+    }
+
+    #[test]
+    fn window_crossing_midnight_on_a_weekday_schedule() {
+        // opens Friday 22:00 in New York, which is already Saturday in UTC
+        let window = window("0 22 * * 5", 180, "America/New_York");
+        assert!(window.is_open(at("2026-06-13T02:00:00Z")).unwrap());
+        assert!(window.is_open(at("2026-06-13T04:59:00Z")).unwrap());
+        assert!(!window.is_open(at("2026-06-13T05:01:00Z")).unwrap());
+        assert!(!window.is_open(at("2026-06-14T02:00:00Z")).unwrap());
+        assert_eq!(
+            window.next_opening(at("2026-06-13T03:00:00Z")).unwrap(),
+            at("2026-06-20T02:00:00Z")
+        );
+    }
+
+    #[test]
+    fn window_follows_its_zone_across_dst() {
+        let window = window("0 1 * * *", 30, "Europe/Berlin");
+        // CET, UTC+1
+        assert_eq!(
+            window.next_opening(at("2026-03-28T12:00:00Z")).unwrap(),
+            at("2026-03-29T00:00:00Z")
+        );
+        // CEST, UTC+2
+        assert_eq!(
+            window.next_opening(at("2026-03-29T12:00:00Z")).unwrap(),
+            at("2026-03-29T23:00:00Z")
+        );
+        assert!(window.is_open(at("2026-03-29T23:10:00Z")).unwrap());
+        assert!(!window.is_open(at("2026-03-30T00:10:00Z")).unwrap());
+    }
+
+    #[test]
+    fn window_in_the_skipped_hour_opens_after_the_jump() {
+        // 02:30 doesn't exist in Berlin on 2026-03-29, the clocks go from
+        // 02:00 straight to 03:00 CEST
+        let window = window("30 2 * * *", 60, "Europe/Berlin");
+        assert_eq!(
+            window.next_opening(at("2026-03-28T12:00:00Z")).unwrap(),
+            at("2026-03-29T01:00:00Z")
+        );
+        assert!(window.is_open(at("2026-03-29T01:30:00Z")).unwrap());
+        assert!(!window.is_open(at("2026-03-29T02:01:00Z")).unwrap());
+    }
+
+    #[test]
+    fn window_in_the_repeated_hour_opens_once() {
+        // 02:30 happens twice in Berlin on 2026-10-25, first in CEST
+        let window = window("30 2 * * *", 30, "Europe/Berlin");
+        assert_eq!(
+            window.next_opening(at("2026-10-24T12:00:00Z")).unwrap(),
+            at("2026-10-25T00:30:00Z")
+        );
+        assert_eq!(
+            window.next_opening(at("2026-10-25T00:31:00Z")).unwrap(),
+            at("2026-10-26T01:30:00Z")
+        );
+        // from inside the second 02:xx
+        assert_eq!(
+            window.next_opening(at("2026-10-25T01:15:00Z")).unwrap(),
+            at("2026-10-26T01:30:00Z")
+        );
+        assert!(window.is_open(at("2026-10-25T00:45:00Z")).unwrap());
+        assert!(!window.is_open(at("2026-10-25T01:45:00Z")).unwrap());
+    }
+
+    #[test]
+    fn window_right_after_the_repeated_hour_opens() {
+        let window = window("0 3 * * *", 30, "Europe/Berlin");
+        assert_eq!(
+            window.next_opening(at("2026-10-25T01:15:00Z")).unwrap(),
+            at("2026-10-25T02:00:00Z")
+        );
+    }
+
+    #[test]
+    fn invalid_windows_are_rejected() {
+        assert!(window("not a cron", 60, "UTC").validate().is_err());
+        assert!(window("0 2 * * *", 60, "Mars/Olympus").validate().is_err());
+        assert!(window("0 2 * * *", 0, "UTC").validate().is_err());
+        assert!(window("0 2 * * *", 60, "Asia/Tokyo").validate().is_ok());
+        assert!(
+            window("0 2 * * *", 60, "Mars/Olympus")
+                .is_open(Utc::now())
+                .is_err()
+        );
+    }
+
+    fn node() -> NodeConfig {
+        NodeConfig {
+            client: "acme".into(),
+            environment: "prod".into(),
+            solution: "shop".into(),
+            ..Default::default()
+        }
+    }
+
+    fn deploy(filename: &str, window: Option<MaintenanceWindow>) -> DeployConfig {
+        DeployConfig {
+            client: "acme".into(),
+            environment: "prod".into(),
+            solution: "shop".into(),
+            maintenance_window: window,
+            deploy_stacks: vec![StackConfig {
+                stack_name: "web".into(),
+                filename: filename.into(),
+                home_directory: "/srv/shop".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn hikari(
+        deploy_configs: impl IntoIterator<Item = (&'static str, DeployConfig)>,
+    ) -> HikariConfig {
+        HikariConfig {
+            version: "1".into(),
+            deploy_configs: deploy_configs
+                .into_iter()
+                .map(|(name, config)| (name.to_string(), config))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn changes_wait_for_a_closed_window() {
+        let nightly = window("0 2 * * *", 60, "UTC");
+        let current = hikari([("shop", deploy("a.yaml", Some(nightly.clone())))]);
+        let mut changed = deploy("b.yaml", Some(nightly));
+        changed.revision = Some(7);
+        let incoming = hikari([("shop", changed)]);
+
+        let (applied, pending) =
+            hold_for_maintenance(&current, &incoming, &node(), at("2026-06-10T12:00:00Z"));
+        assert_eq!(
+            applied.deploy_configs["shop"],
+            current.deploy_configs["shop"]
+        );
+        assert_eq!(
+            pending,
+            vec![PendingChange {
+                deployment: "shop".into(),
+                revision: Some(7),
+                opens_at: Some(at("2026-06-11T02:00:00Z")),
+            }]
+        );
+
+        let (applied, pending) =
+            hold_for_maintenance(&current, &incoming, &node(), at("2026-06-11T02:15:00Z"));
+        assert_eq!(applied, incoming);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn urgent_changes_new_deployments_and_drift_policy_go_through() {
+        let nightly = window("0 2 * * *", 60, "UTC");
+        let noon = at("2026-06-10T12:00:00Z");
+        let current = hikari([("shop", deploy("a.yaml", Some(nightly.clone())))]);
+
+        let mut urgent = deploy("b.yaml", Some(nightly.clone()));
+        urgent.urgent = true;
+        let incoming = hikari([("shop", urgent), ("blog", deploy("c.yaml", None))]);
+        let (applied, pending) = hold_for_maintenance(&current, &incoming, &node(), noon);
+        assert_eq!(applied, incoming);
+        assert!(pending.is_empty());
+
+        let mut watched = deploy("a.yaml", Some(nightly));
+        watched.deploy_stacks[0].drift_policy = DriftPolicy::Heal;
+        let incoming = hikari([("shop", watched)]);
+        let (applied, pending) = hold_for_maintenance(&current, &incoming, &node(), noon);
+        assert_eq!(applied, incoming);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn removal_falls_back_to_the_node_window() {
+        let mut node = node();
+        node.maintenance_window = Some(window("0 3 * * *", 30, "Asia/Tokyo"));
+        let current = hikari([("shop", deploy("a.yaml", None))]);
+        let incoming = hikari([]);
+
+        let (applied, pending) =
+            hold_for_maintenance(&current, &incoming, &node, at("2026-06-10T12:00:00Z"));
+        assert_eq!(applied, current);
+        assert_eq!(
+            pending,
+            vec![PendingChange {
+                deployment: "shop".into(),
+                revision: None,
+                opens_at: Some(at("2026-06-10T18:00:00Z")),
+            }]
+        );
+    }
+
+    #[test]
+    fn other_nodes_deployments_are_not_held() {
+        let mut other = deploy("a.yaml", Some(window("0 2 * * *", 60, "UTC")));
+        other.client = "globex".into();
+        let current = hikari([("other", other)]);
+        let incoming = hikari([]);
+
+        let (applied, pending) =
+            hold_for_maintenance(&current, &incoming, &node(), at("2026-06-10T12:00:00Z"));
+        assert_eq!(applied, incoming);
+        assert!(pending.is_empty());
+    }
+}
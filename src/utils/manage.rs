@@ -1,10 +1,19 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
 
+use chrono::Utc;
 use log::{error, info};
 
 use crate::{
-    objects::structs::{DeployConfig, HikariConfig, StackConfig},
-    utils::docker_utils::{generate_compose, pull_compose, start_compose, stop_compose},
+    objects::structs::{
+        CommandErrorKind, CommandOutcome, DeployConfig, HikariConfig, NodeConfig, OperationOutcome,
+        PendingChange, StackConfig,
+    },
+    utils::{
+        docker_utils::{generate_compose, not_run},
+        logging::LogContext,
+        maintenance::hold_for_maintenance,
+        runtime::runtime,
+    },
 };
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -12,15 +21,41 @@ pub enum StackOperation {
     Start,
     Stop,
     Pull,
+    /// Writes the compose file again and brings the containers back to it.
+    Heal,
 }
 
+/// What [`manage_node`] did to the node.
+#[derive(Debug)]
+pub struct NodeChanges {
+    /// Configuration the node now runs, to be kept as its reference.
+    pub applied: HikariConfig,
+    /// Changes queued until their maintenance window opens.
+    pub pending: Vec<PendingChange>,
+    /// Operations run on stacks, in order.
+    pub outcomes: Vec<StackOutcome>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StackOutcome {
+    pub deployment: String,
+    pub stack_name: String,
+    pub outcome: OperationOutcome,
+}
+
+/// Moves the node from `current_config` to `incoming_config`, queueing changes
+/// whose maintenance window is closed.
 pub fn manage_node(
     current_config: &HikariConfig,
     incoming_config: &HikariConfig,
-    client: &str,
-    environment: &str,
-    solution: &str,
-) {
+    node_config: &NodeConfig,
+) -> NodeChanges {
+    let (incoming_config, pending) =
+        hold_for_maintenance(current_config, incoming_config, node_config, Utc::now());
+    let mut outcomes = Vec::new();
+    let client = node_config.client.as_str();
+    let environment = node_config.environment.as_str();
+    let solution = node_config.solution.as_str();
     for (key, current_deploy_config) in &current_config.deploy_configs {
         // filter current config matches the node's parameters
         if current_deploy_config.client != client
@@ -30,39 +65,46 @@ pub fn manage_node(
             info!("Skipping config '{key}' as it does not match the node parameters.");
             continue;
         }
-        // get the incoming config w.r.t to current config by key
-        if let Some(incoming_deploy_config) = incoming_config.deploy_configs.get(key) {
-            // Compare `current_config` and `incoming_config` values
-            if current_deploy_config.client != incoming_deploy_config.client
-                || current_deploy_config.environment != incoming_deploy_config.environment
-                || current_deploy_config.solution != incoming_deploy_config.solution
-            {
-                // Values differ but still match the node; restart stacks
-                info!(
-                    "Config '{key}' parameters have changed, config no longer matches the node, Stopping associated stacks..."
-                );
+        LogContext::current().deployment(key).enter(|| {
+            // get the incoming config w.r.t to current config by key
+            if let Some(incoming_deploy_config) = incoming_config.deploy_configs.get(key) {
+                // Compare `current_config` and `incoming_config` values
+                if current_deploy_config.client != incoming_deploy_config.client
+                    || current_deploy_config.environment != incoming_deploy_config.environment
+                    || current_deploy_config.solution != incoming_deploy_config.solution
+                {
+                    // Values differ but still match the node; restart stacks
+                    info!(
+                        "Config '{key}' parameters have changed, config no longer matches the node, Stopping associated stacks..."
+                    );
+                    current_deploy_config
+                        .deploy_stacks
+                        .iter()
+                        .for_each(|stack| {
+                            info!("Stopping Stack {}", stack.stack_name);
+                            run(&mut outcomes, key, stack, StackOperation::Stop);
+                        });
+                } else {
+                    // Parameters match; compare stacks
+                    compare_stacks(
+                        key,
+                        current_deploy_config,
+                        incoming_deploy_config,
+                        &mut outcomes,
+                    );
+                }
+            } else {
+                // Config is removed (not in incoming_config)
+                info!("Config '{key}' has been removed. Stopping associated stacks...");
                 current_deploy_config
                     .deploy_stacks
                     .iter()
                     .for_each(|stack| {
                         info!("Stopping Stack {}", stack.stack_name);
-                        manage_stack(stack, StackOperation::Stop);
+                        run(&mut outcomes, key, stack, StackOperation::Stop);
                     });
-            } else {
-                // Parameters match; compare stacks
-                compare_stacks(current_deploy_config, incoming_deploy_config);
             }
-        } else {
-            // Config is removed (not in incoming_config)
-            info!("Config '{key}' has been removed. Stopping associated stacks...");
-            current_deploy_config
-                .deploy_stacks
-                .iter()
-                .for_each(|stack| {
-                    info!("Stopping Stack {}", stack.stack_name);
-                    manage_stack(stack, StackOperation::Stop);
-                });
-        }
+        });
     }
 
     // Handle new deploy configs in incoming_config
@@ -76,41 +118,53 @@ pub fn manage_node(
             continue;
         }
 
-        // Check if the config exists in current_config
-        if let Some(current_deploy_config) = current_config.deploy_configs.get(key) {
-            // Detect changes in client/environment/solution
-            if incoming_deploy_config.client != *current_deploy_config.client
-                || incoming_deploy_config.environment != *current_deploy_config.environment
-                || incoming_deploy_config.solution != *current_deploy_config.solution
-            {
-                info!(
-                    "Changes detected in config '{key}'. client/environment/solution now match the node parameters, Starting associated stacks..."
-                );
+        LogContext::current().deployment(key).enter(|| {
+            // Check if the config exists in current_config
+            if let Some(current_deploy_config) = current_config.deploy_configs.get(key) {
+                // Detect changes in client/environment/solution
+                if incoming_deploy_config.client != *current_deploy_config.client
+                    || incoming_deploy_config.environment != *current_deploy_config.environment
+                    || incoming_deploy_config.solution != *current_deploy_config.solution
+                {
+                    info!(
+                        "Changes detected in config '{key}'. client/environment/solution now match the node parameters, Starting associated stacks..."
+                    );
+                    incoming_deploy_config
+                        .deploy_stacks
+                        .iter()
+                        .for_each(|stack| {
+                            info!("Starting Stack {}", stack.stack_name);
+                            run(&mut outcomes, key, stack, StackOperation::Start);
+                        });
+                } else {
+                    // No changes detected
+                    info!("No changes detected for config '{key}'. Skipping.");
+                }
+            } else {
+                // Handle new deploy configurations
+                info!("New Deploy Config '{key}' found. Starting associated stacks...");
                 incoming_deploy_config
                     .deploy_stacks
                     .iter()
                     .for_each(|stack| {
-                        info!("Starting Stack {}", stack.stack_name);
-                        manage_stack(stack, StackOperation::Start);
+                        run(&mut outcomes, key, stack, StackOperation::Start);
                     });
-            } else {
-                // No changes detected
-                info!("No changes detected for config '{key}'. Skipping.");
             }
-        } else {
-            // Handle new deploy configurations
-            info!("New Deploy Config '{key}' found. Starting associated stacks...");
-            incoming_deploy_config
-                .deploy_stacks
-                .iter()
-                .for_each(|stack| {
-                    manage_stack(stack, StackOperation::Start);
-                });
-        }
+        });
+    }
+    NodeChanges {
+        applied: incoming_config,
+        pending,
+        outcomes,
     }
 }
 
-fn compare_stacks(current_deploy_config: &DeployConfig, incoming_deploy_config: &DeployConfig) {
+fn compare_stacks(
+    key: &str,
+    current_deploy_config: &DeployConfig,
+    incoming_deploy_config: &DeployConfig,
+    outcomes: &mut Vec<StackOutcome>,
+) {
     // check if any stack has been deleted
     let current_stacks: HashMap<String, &StackConfig> = current_deploy_config
         .deploy_stacks
@@ -132,92 +186,141 @@ fn compare_stacks(current_deploy_config: &DeployConfig, incoming_deploy_config:
         .collect();
     removed_stacks.iter().for_each(|stack| {
         info!("Stopping stack {}", stack.stack_name);
-        let _ = manage_stack(stack, StackOperation::Stop);
+        run(outcomes, key, stack, StackOperation::Stop);
     });
     for (stack_name, incoming_stack) in &incoming_stacks {
         if let Some(current_stack) = current_stacks.get(stack_name) {
             if current_stack == incoming_stack {
                 info!("{} stack is unchanged", current_stack.stack_name);
                 continue;
+            } else if current_stack.runs_like(incoming_stack) {
+                info!(
+                    "Drift policy of stack {} is now {}",
+                    incoming_stack.stack_name,
+                    incoming_stack.drift_policy.as_str()
+                );
+                continue;
             } else {
                 info!("changes detected in stack {}", current_stack.stack_name);
                 info!("Stopping stack {}", current_stack.stack_name);
-                match manage_stack(current_stack, StackOperation::Stop) {
+                match run(outcomes, key, current_stack, StackOperation::Stop) {
                     true => {
                         info!("Starting Stack {}", incoming_stack.stack_name);
-                        manage_stack(incoming_stack, StackOperation::Start);
+                        run(outcomes, key, incoming_stack, StackOperation::Start);
                     }
                     false => continue,
                 }
             }
         } else {
-            manage_stack(incoming_stack, StackOperation::Start);
+            run(outcomes, key, incoming_stack, StackOperation::Start);
         }
     }
 }
 
-pub fn manage_stack(stack: &StackConfig, operation: StackOperation) -> bool {
+impl fmt::Display for StackOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Start => "start",
+            Self::Stop => "stop",
+            Self::Pull => "pull",
+            Self::Heal => "heal",
+        })
+    }
+}
+
+/// Runs `operation` on `stack` of `deployment`, keeping its outcome. Returns
+/// whether it succeeded.
+fn run(
+    outcomes: &mut Vec<StackOutcome>,
+    deployment: &str,
+    stack: &StackConfig,
+    operation: StackOperation,
+) -> bool {
+    let outcome = manage_stack(stack, operation);
+    let success = outcome.outcome.success();
+    outcomes.push(StackOutcome {
+        deployment: deployment.into(),
+        stack_name: stack.stack_name.clone(),
+        outcome,
+    });
+    success
+}
+
+pub fn manage_stack(stack: &StackConfig, operation: StackOperation) -> OperationOutcome {
+    let outcome = LogContext::current()
+        .stack(&stack.stack_name)
+        .operation(operation)
+        .enter(|| run_stack_operation(stack, operation));
+    OperationOutcome {
+        operation: operation.to_string(),
+        finished_at: Utc::now(),
+        outcome,
+    }
+}
+
+fn run_stack_operation(stack: &StackConfig, operation: StackOperation) -> CommandOutcome {
     match operation {
         StackOperation::Stop => {
-            match stop_compose(format!("{}/{}", stack.home_directory, stack.filename).as_str()) {
-                true => {
-                    info!("Successfully stopped removed stack {}", stack.stack_name);
-                    true
-                }
-                false => {
-                    error!("Could not stop removed stack {}", stack.stack_name);
-                    false
-                }
+            let outcome = runtime().down(stack);
+            match &outcome.error {
+                None => info!("Successfully stopped removed stack {}", stack.stack_name),
+                Some(kind) => error!("Could not stop removed stack {}: {kind}", stack.stack_name),
             }
+            outcome
         }
         StackOperation::Start => {
             manage_stack(stack, StackOperation::Pull);
-            let stack_filepath = match generate_compose(
-                &stack.home_directory,
-                &stack.stack_name,
-                &stack.filename,
-                &stack.compose_spec,
-            ) {
-                Ok(path) => path,
-                Err(e) => {
-                    error!("Could not generate compose for {}: {e}", stack.stack_name);
-                    return false;
-                }
-            };
-            match start_compose(stack_filepath.to_str().unwrap()) {
-                true => {
-                    info!("Successfully started added stack {}", stack.stack_name);
-                    true
-                }
-                false => {
-                    error!("Could not start added stack {}", stack.stack_name);
-                    false
-                }
+            if let Err(outcome) = generate(stack) {
+                return outcome;
             }
+            let outcome = runtime().up(stack);
+            match &outcome.error {
+                None => info!("Successfully started added stack {}", stack.stack_name),
+                Some(kind) => error!("Could not start added stack {}: {kind}", stack.stack_name),
+            }
+            outcome
         }
         StackOperation::Pull => {
-            let stack_filepath = match generate_compose(
-                &stack.home_directory,
-                &stack.stack_name,
-                &stack.filename,
-                &stack.compose_spec,
-            ) {
-                Ok(path) => path,
-                Err(e) => {
-                    error!("Could not generate compose for {}: {e}", stack.stack_name);
-                    return false;
-                }
-            };
-            match pull_compose(stack_filepath.to_str().unwrap()) {
-                true => {
-                    info!("Successfully pulled stack {}", stack.stack_name);
-                    true
-                }
-                false => {
-                    error!("Could not pull stack {}", stack.stack_name);
-                    false
-                }
+            if let Err(outcome) = generate(stack) {
+                return outcome;
+            }
+            let outcome = runtime().pull(stack);
+            match &outcome.error {
+                None => info!("Successfully pulled stack {}", stack.stack_name),
+                Some(kind) => error!("Could not pull stack {}: {kind}", stack.stack_name),
+            }
+            outcome
+        }
+        StackOperation::Heal => {
+            if let Err(outcome) = generate(stack) {
+                return outcome;
+            }
+            let outcome = runtime().up(stack);
+            match &outcome.error {
+                None => info!("Successfully healed stack {}", stack.stack_name),
+                Some(kind) => error!("Could not heal stack {}: {kind}", stack.stack_name),
             }
+            outcome
+        }
+    }
+}
+
+/// Writes the compose file of `stack`.
+fn generate(stack: &StackConfig) -> Result<(), CommandOutcome> {
+    match generate_compose(
+        &stack.home_directory,
+        &stack.stack_name,
+        &stack.filename,
+        &stack.compose_spec,
+    ) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Could not generate compose for {}: {e}", stack.stack_name);
+            Err(not_run(
+                format!("generate {}", stack.filename),
+                CommandErrorKind::InvalidCompose,
+                e.to_string(),
+            ))
         }
     }
 }
@@ -2,7 +2,12 @@ pub mod cli;
 pub mod config;
 pub mod crypto;
 pub mod docker_utils;
+pub mod drift;
 pub mod error;
 pub mod file_utils;
+pub mod logging;
+pub mod maintenance;
 pub mod manage;
+pub mod promote;
+pub mod runtime;
 pub mod secrets;
@@ -0,0 +1,156 @@
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+};
+
+use log::info;
+use serde_json::Value;
+
+use super::error::ConfigError;
+use crate::{
+    objects::structs::{PromotionRequest, PromotionRules},
+    server::{
+        api::{apply::ApplyResult, promote::PromotionResult},
+        diff::{DiffKind, FieldChange},
+    },
+};
+
+/// Options of the `promote` subcommand.
+pub struct PromoteOptions<'a> {
+    pub server: &'a str,
+    pub source: &'a str,
+    pub target: &'a str,
+    pub rules_file: Option<&'a str>,
+    pub prune: bool,
+    pub yes: bool,
+    pub message: Option<&'a str>,
+    pub urgent: bool,
+}
+
+pub fn load_promotion_rules(file_path: &str) -> Result<PromotionRules, ConfigError> {
+    let contents = fs::read_to_string(file_path)?;
+    Ok(toml::from_str(&contents)?)
+}
+
+/// Asks the server for the changes a promotion would make, shows them and,
+/// once confirmed, runs it for real.
+pub async fn promote(options: PromoteOptions<'_>) -> Result<(), ConfigError> {
+    let rules = match options.rules_file {
+        Some(file_path) => load_promotion_rules(file_path)?,
+        None => PromotionRules::default(),
+    };
+    let request = PromotionRequest {
+        source: options.source.to_string(),
+        target: options.target.to_string(),
+        rules,
+        prune: options.prune,
+        ..PromotionRequest::default()
+    };
+    let preview = send_promotion(&options, &request, true).await?;
+    if preview.result.changes.is_empty() {
+        println!(
+            "{} is already up to date with {}",
+            options.target, options.source
+        );
+        return Ok(());
+    }
+    print_changes(&preview.result);
+    if !options.yes
+        && !confirm(&format!(
+            "Promote {} into {}?",
+            options.source, options.target
+        ))?
+    {
+        info!(
+            "Promotion of {} into {} cancelled",
+            options.source, options.target
+        );
+        return Ok(());
+    }
+    // applied only if neither deployment moved since the preview
+    let request = PromotionRequest {
+        source_revision: preview.source_revision,
+        target_revision: preview.target_revision,
+        ..request
+    };
+    send_promotion(&options, &request, false).await?;
+    info!("Promoted {} into {}", options.source, options.target);
+    Ok(())
+}
+
+async fn send_promotion(
+    options: &PromoteOptions<'_>,
+    request: &PromotionRequest,
+    dry_run: bool,
+) -> Result<PromotionResult, ConfigError> {
+    let url = format!(
+        "{}/api/v1/deployment/promote?dry_run={dry_run}",
+        options.server.trim_end_matches('/')
+    );
+    let mut builder = reqwest::Client::new().post(url).json(request);
+    if let Ok(author) = std::env::var("USER") {
+        builder = builder.header("x-hikari-author", author);
+    }
+    if let Some(message) = options.message {
+        builder = builder.header("x-hikari-message", message);
+    }
+    if options.urgent {
+        builder = builder.header("x-hikari-urgent", "true");
+    }
+    let response = builder.send().await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or_default();
+        let message = body["message"].as_str().unwrap_or_default();
+        return Err(ConfigError::ServerError(format!("{status} {message}")));
+    }
+    Ok(response.json().await?)
+}
+
+fn confirm(question: &str) -> Result<bool, ConfigError> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn marker(change: DiffKind) -> &'static str {
+    match change {
+        DiffKind::Added => "+",
+        DiffKind::Removed => "-",
+        DiffKind::Modified => "~",
+    }
+}
+
+fn print_fields(fields: &[FieldChange], indent: usize) {
+    let show = |value: &Option<Value>| value.as_ref().map_or("-".into(), Value::to_string);
+    for field in fields {
+        println!(
+            "{:indent$}{}: {} -> {}",
+            "",
+            field.field,
+            show(&field.from),
+            show(&field.to)
+        );
+    }
+}
+
+fn print_changes(result: &ApplyResult) {
+    for deployment in &result.changes {
+        println!("{} {}", marker(deployment.change), deployment.name);
+        print_fields(&deployment.fields, 4);
+        for stack in &deployment.stacks {
+            println!("  {} stack {}", marker(stack.change), stack.stack_name);
+            print_fields(&stack.fields, 6);
+            for service in &stack.services {
+                println!(
+                    "    {} service {}",
+                    marker(service.change),
+                    service.service_name
+                );
+                print_fields(&service.fields, 8);
+            }
+        }
+    }
+}
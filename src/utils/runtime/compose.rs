@@ -0,0 +1,312 @@
+use std::{
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use log::{error, info};
+use serde_json::Value;
+
+use crate::{
+    objects::structs::{CommandErrorKind, CommandOutcome, StackConfig},
+    utils::{
+        docker_utils::{execute_command, not_run},
+        runtime::{
+            ContainerState, PROJECT_LABEL, Runtime, RuntimeEvent, SERVICE_LABEL, compose_file_path,
+        },
+    },
+};
+
+/// Runs stacks through a compose CLI. The tools agree on `pull`, `up -d` and
+/// `down`, but not on how they list containers or report events.
+#[derive(Debug, Clone)]
+pub struct ComposeCli {
+    name: &'static str,
+    program: &'static str,
+    /// Arguments naming the compose subcommand, empty for standalone tools.
+    prefix: &'static [&'static str],
+    /// CLI of the container engine below, which inspects containers and
+    /// reports their events.
+    engine: &'static str,
+    listing: Listing,
+    events: Events,
+}
+
+/// How the containers of a stack are looked at.
+#[derive(Debug, Clone, Copy)]
+enum Listing {
+    /// `ps --all --format json` reports their state and health.
+    Json,
+    /// These `ps` arguments only print container IDs, which the engine CLI
+    /// then inspects.
+    Ids(&'static [&'static str]),
+}
+
+/// Format of the container events printed by the engine CLI.
+#[derive(Debug, Clone, Copy)]
+enum Events {
+    Docker,
+    Podman,
+    /// nerdctl only relays containerd events, which carry no labels.
+    Unsupported,
+}
+
+impl ComposeCli {
+    /// The `docker compose` plugin.
+    pub fn docker() -> Self {
+        Self {
+            name: "docker compose",
+            program: "docker",
+            prefix: &["compose"],
+            engine: "docker",
+            listing: Listing::Json,
+            events: Events::Docker,
+        }
+    }
+
+    /// The standalone `docker-compose`. Legacy v1 releases can't print JSON,
+    /// v2 builds take the flags of the plugin.
+    pub fn docker_compose() -> Self {
+        let version = Command::new("docker-compose")
+            .args(["version", "--short"])
+            .stdin(Stdio::null())
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            .unwrap_or_default();
+        let legacy = version
+            .trim()
+            .trim_start_matches('v')
+            .split('.')
+            .next()
+            .and_then(|major| major.parse::<u32>().ok())
+            .is_none_or(|major| major < 2);
+        Self {
+            name: "docker-compose",
+            program: "docker-compose",
+            prefix: &[],
+            engine: "docker",
+            // v1 lists stopped containers without being asked
+            listing: if legacy {
+                Listing::Ids(&["ps", "-q"])
+            } else {
+                Listing::Json
+            },
+            events: Events::Docker,
+        }
+    }
+
+    /// `podman compose`, which runs whichever compose provider Podman is
+    /// configured with.
+    pub fn podman() -> Self {
+        Self {
+            name: "podman compose",
+            program: "podman",
+            prefix: &["compose"],
+            engine: "podman",
+            listing: Listing::Ids(&["ps", "-q"]),
+            events: Events::Podman,
+        }
+    }
+
+    pub fn podman_compose() -> Self {
+        Self {
+            name: "podman-compose",
+            program: "podman-compose",
+            prefix: &[],
+            engine: "podman",
+            // podman-compose has no --all, it always lists stopped containers
+            listing: Listing::Ids(&["ps", "-q"]),
+            events: Events::Podman,
+        }
+    }
+
+    pub fn nerdctl() -> Self {
+        Self {
+            name: "nerdctl compose",
+            program: "nerdctl",
+            prefix: &["compose"],
+            engine: "nerdctl",
+            listing: Listing::Ids(&["ps", "--all", "--quiet"]),
+            events: Events::Unsupported,
+        }
+    }
+
+    /// First compose CLI answering on the node, trying the Docker ones, then
+    /// Podman and nerdctl.
+    pub fn detect() -> Option<Self> {
+        [
+            Self::docker as fn() -> Self,
+            Self::docker_compose,
+            Self::podman,
+            Self::podman_compose,
+            Self::nerdctl,
+        ]
+        .into_iter()
+        .map(|cli| cli())
+        .find(Self::available)
+    }
+
+    /// Whether the CLI is installed and answers `version`.
+    pub fn available(&self) -> bool {
+        Command::new(self.program)
+            .args(self.prefix)
+            .arg("version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    fn run(&self, stack: &StackConfig, args: &[&str]) -> CommandOutcome {
+        let compose_file_path = compose_file_path(stack);
+        info!("{}", &compose_file_path);
+        let args = [self.prefix, &["-f", &compose_file_path], args].concat();
+        if Path::exists(Path::new(&compose_file_path)) {
+            return execute_command(self.program, args);
+        }
+        error!("compose file does not exist");
+        not_run(
+            [self.program]
+                .iter()
+                .chain(&args)
+                .copied()
+                .collect::<Vec<_>>()
+                .join(" "),
+            CommandErrorKind::ComposeFileMissing,
+            format!("{compose_file_path} does not exist"),
+        )
+    }
+
+    /// Stdout of `program args`.
+    fn output(program: &str, args: &[&str]) -> Result<String, String> {
+        match Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+        {
+            Ok(output) if output.status.success() => {
+                Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+            }
+            Ok(output) => Err(format!(
+                "'{program} {}' failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(e) => Err(format!("Failed to execute command '{program}': {e}")),
+        }
+    }
+}
+
+impl Runtime for ComposeCli {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn pull(&self, stack: &StackConfig) -> CommandOutcome {
+        self.run(stack, &["pull"])
+    }
+
+    fn up(&self, stack: &StackConfig) -> CommandOutcome {
+        self.run(stack, &["up", "-d", "--remove-orphans"])
+    }
+
+    fn down(&self, stack: &StackConfig) -> CommandOutcome {
+        self.run(stack, &["down"])
+    }
+
+    fn containers(&self, stack: &StackConfig) -> Result<Vec<ContainerState>, String> {
+        let compose_file_path = compose_file_path(stack);
+        let field = |value: &Value| value.as_str().unwrap_or_default().to_string();
+        match self.listing {
+            Listing::Json => {
+                let args = [
+                    self.prefix,
+                    &["-f", &compose_file_path, "ps", "--all", "--format", "json"],
+                ]
+                .concat();
+                let stdout = Self::output(self.program, &args)?;
+                // older compose releases print one array, newer ones a line per container
+                let containers: Vec<Value> = match serde_json::from_str(stdout.trim()) {
+                    Ok(Value::Array(containers)) => containers,
+                    _ => stdout
+                        .lines()
+                        .filter_map(|line| serde_json::from_str(line).ok())
+                        .collect(),
+                };
+                Ok(containers
+                    .iter()
+                    .map(|container| ContainerState {
+                        name: field(&container["Name"]),
+                        service: field(&container["Service"]),
+                        state: field(&container["State"]),
+                        health: field(&container["Health"]),
+                    })
+                    .collect())
+            }
+            Listing::Ids(ps) => {
+                let args = [self.prefix, &["-f", &compose_file_path], ps].concat();
+                let stdout = Self::output(self.program, &args)?;
+                let ids: Vec<&str> = stdout.split_whitespace().collect();
+                if ids.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let args = [&["inspect", "--format", "{{json .}}"], ids.as_slice()].concat();
+                let stdout = Self::output(self.engine, &args)?;
+                Ok(stdout
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+                    .map(|container| {
+                        let state = &container["State"];
+                        // older Podman releases call it Healthcheck
+                        let health = match &state["Health"] {
+                            Value::Null => &state["Healthcheck"],
+                            health => health,
+                        };
+                        ContainerState {
+                            name: field(&container["Name"]).trim_start_matches('/').into(),
+                            service: field(&container["Config"]["Labels"][SERVICE_LABEL]),
+                            state: field(&state["Status"]),
+                            health: field(&health["Status"]),
+                        }
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    fn watch_events(&self, on_event: &mut dyn FnMut(RuntimeEvent)) -> Result<(), String> {
+        let (format, parse): (&str, fn(&str) -> Option<RuntimeEvent>) = match self.events {
+            Events::Docker => ("{{json .}}", RuntimeEvent::parse),
+            Events::Podman => ("json", RuntimeEvent::parse_podman),
+            Events::Unsupported => return Ok(()),
+        };
+        let mut child = Command::new(self.engine)
+            .args([
+                "events",
+                "--format",
+                format,
+                "--filter",
+                "type=container",
+                "--filter",
+                &format!("label={PROJECT_LABEL}"),
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to execute command '{}': {e}", self.engine))?;
+        if let Some(stdout) = child.stdout.take() {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if let Some(event) = parse(&line) {
+                    on_event(event);
+                }
+            }
+        }
+        let status = child.wait().map_err(|e| e.to_string())?;
+        Err(format!(
+            "{} events exited with status: {status}",
+            self.engine
+        ))
+    }
+}
@@ -0,0 +1,1574 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info};
+use openssl::sha::sha256;
+use serde_json::{Map, Value, json};
+
+use crate::{
+    objects::structs::{CommandErrorKind, CommandOutcome, Container, StackConfig},
+    utils::{
+        docker_utils::{OutputTail, classify_failure, elapsed_ms},
+        runtime::{ContainerState, PROJECT_LABEL, Runtime, RuntimeEvent, SERVICE_LABEL},
+    },
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+/// Label holding a digest of the configuration a container was created from,
+/// the one compose uses to tell whether a service needs recreating.
+const CONFIG_HASH_LABEL: &str = "com.docker.compose.config-hash";
+
+/// Runs stacks through the Docker Engine API on a unix socket, without the
+/// compose CLI. Containers carry the compose labels, with the stack name as
+/// their project, and share a `<stack_name>_default` network.
+#[derive(Debug, Clone)]
+pub struct Engine {
+    socket: String,
+}
+
+impl Engine {
+    pub fn new(socket: Option<String>) -> Self {
+        let socket = socket
+            .or_else(|| {
+                std::env::var("DOCKER_HOST")
+                    .ok()
+                    .and_then(|host| host.strip_prefix("unix://").map(String::from))
+            })
+            .unwrap_or_else(|| "/var/run/docker.sock".into());
+        Self { socket }
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+        timeout: Option<Duration>,
+    ) -> io::Result<Reply> {
+        let mut stream = UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let mut request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: docker\r\nUser-Agent: hikari\r\nConnection: close\r\n"
+        );
+        if !body.is_empty() {
+            request.push_str(&format!(
+                "Content-Type: application/json\r\nContent-Length: {}\r\n",
+                body.len()
+            ));
+        }
+        request.push_str("\r\n");
+        request.push_str(&body);
+        stream.write_all(request.as_bytes())?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("invalid response: {status_line:?}")))?;
+        let (mut chunked, mut length) = (false, None);
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                let value = value.trim();
+                if name.eq_ignore_ascii_case("transfer-encoding") {
+                    chunked = value.eq_ignore_ascii_case("chunked");
+                } else if name.eq_ignore_ascii_case("content-length") {
+                    length = value.parse().ok();
+                }
+            }
+        }
+        Ok(Reply {
+            status,
+            reader,
+            chunked,
+            length,
+        })
+    }
+
+    fn request(&self, method: &str, path: &str, body: Option<&Value>) -> io::Result<Response> {
+        let reply = self.send(method, path, body, Some(REQUEST_TIMEOUT))?;
+        let status = reply.status;
+        let mut body = Vec::new();
+        reply.read_body(&mut |bytes| body.extend_from_slice(bytes))?;
+        Ok(Response { status, body })
+    }
+
+    /// Containers of `stack`, stopped ones included.
+    fn list_containers(&self, stack: &StackConfig) -> io::Result<Vec<Value>> {
+        let filters = json!({ "label": [format!("{PROJECT_LABEL}={}", stack.stack_name)] });
+        let response = self.request(
+            "GET",
+            &format!(
+                "/containers/json?all=true&filters={}",
+                encode(&filters.to_string())
+            ),
+            None,
+        )?;
+        match response.status {
+            200 => Ok(response.json().as_array().cloned().unwrap_or_default()),
+            _ => Err(io::Error::other(response.message())),
+        }
+    }
+
+    fn image_exists(&self, image: &str) -> bool {
+        self.image_id(image).is_some()
+    }
+
+    /// ID of the local image `image` names, none when it isn't there.
+    fn image_id(&self, image: &str) -> Option<String> {
+        self.request("GET", &format!("/images/{image}/json"), None)
+            .ok()
+            .filter(|response| response.status == 200)
+            .and_then(|response| response.json()["Id"].as_str().map(String::from))
+    }
+
+    /// Pulls `image`, logging its progress.
+    fn pull_image(&self, image: &str, run: &mut Run) {
+        let (name, tag) = split_image(image);
+        let mut path = format!("/images/create?fromImage={}", encode(name));
+        if let Some(tag) = tag {
+            path.push_str(&format!("&tag={}", encode(tag)));
+        }
+        let reply = match self.send("POST", &path, None, None) {
+            Ok(reply) => reply,
+            Err(e) => return run.unreachable(&e),
+        };
+        let status = reply.status;
+        let mut lines = LineSplitter::default();
+        let mut progress = |line: &str| {
+            let Ok(message) = serde_json::from_str::<Value>(line) else {
+                return;
+            };
+            if let Some(error) = message["error"].as_str() {
+                let kind = classify_failure(error);
+                run.fail(kind, format!("{image}: {error}"));
+                return;
+            }
+            let status = message["status"].as_str().unwrap_or_default();
+            let line = match message["id"].as_str() {
+                Some(id) => format!("{image} {id}: {status}"),
+                None => format!("{image}: {status}"),
+            };
+            // progress bars tick many times per layer
+            if message["progressDetail"]["current"].is_number() {
+                debug!("{line}");
+            } else {
+                run.out(line);
+            }
+        };
+        let result = reply.read_body(&mut |bytes| lines.feed(bytes, &mut progress));
+        lines.finish(&mut progress);
+        if let Err(e) = result {
+            run.fail(CommandErrorKind::Network, format!("{image}: {e}"));
+        } else if status != 200 {
+            let kind = match status {
+                404 => CommandErrorKind::ImageNotFound,
+                401 | 403 => CommandErrorKind::RegistryAuth,
+                _ => CommandErrorKind::Failed,
+            };
+            run.fail(kind, format!("{image}: pull answered with status {status}"));
+        }
+    }
+
+    fn ensure_network(&self, stack: &StackConfig, run: &mut Run) -> bool {
+        let network = network_name(stack);
+        match self.request("GET", &format!("/networks/{network}"), None) {
+            Ok(response) if response.status == 200 => return true,
+            Ok(response) if response.status == 404 => {}
+            Ok(response) => {
+                run.fail(CommandErrorKind::Failed, response.message());
+                return false;
+            }
+            Err(e) => {
+                run.unreachable(&e);
+                return false;
+            }
+        }
+        let body = json!({
+            "Name": network,
+            "Labels": { PROJECT_LABEL: stack.stack_name },
+        });
+        match self.request("POST", "/networks/create", Some(&body)) {
+            Ok(response) if response.status == 201 => {
+                run.out(format!("Network {network} created"));
+                true
+            }
+            Ok(response) => {
+                run.fail(classify_failure(&response.message()), response.message());
+                false
+            }
+            Err(e) => {
+                run.unreachable(&e);
+                false
+            }
+        }
+    }
+
+    /// Removes the containers of `stack` that `remove` picks from the engine's
+    /// listing. Returns whether the containers could be listed.
+    fn remove_containers(
+        &self,
+        stack: &StackConfig,
+        remove: impl Fn(&Value) -> bool,
+        run: &mut Run,
+    ) -> bool {
+        let containers = match self.list_containers(stack) {
+            Ok(containers) => containers,
+            Err(e) => {
+                run.unreachable(&e);
+                return false;
+            }
+        };
+        for container in containers.iter().filter(|container| remove(container)) {
+            let id = container["Id"].as_str().unwrap_or_default();
+            let name = container["Names"][0]
+                .as_str()
+                .unwrap_or(id)
+                .trim_start_matches('/');
+            match self.request("DELETE", &format!("/containers/{id}?force=true"), None) {
+                Ok(response) if matches!(response.status, 204 | 404) => {
+                    run.out(format!("Container {name} removed"));
+                }
+                Ok(response) => run.fail(CommandErrorKind::Failed, response.message()),
+                Err(e) => run.unreachable(&e),
+            }
+        }
+        true
+    }
+
+    /// Brings the container of `service` up, replacing it when it was created
+    /// from another configuration or image and leaving it be when it already
+    /// runs as configured.
+    fn up_service(&self, stack: &StackConfig, service: &str, container: &Container, run: &mut Run) {
+        let name = &container.container_name;
+        let body = container_body(stack, service, container);
+        let Some(existing) = self.existing(container, &body, run) else {
+            return;
+        };
+        if existing == Existing::Running {
+            return run.out(format!("Container {name} is up to date"));
+        }
+        if existing == Existing::Outdated {
+            match self.request("DELETE", &format!("/containers/{name}?force=true"), None) {
+                Ok(response) if response.status == 204 => {
+                    run.out(format!("Container {name} removed"));
+                }
+                Ok(response) if response.status == 404 => {}
+                Ok(response) => return run.fail(CommandErrorKind::Failed, response.message()),
+                Err(e) => return run.unreachable(&e),
+            }
+        }
+        if existing != Existing::Stopped {
+            self.create(container, &body, run);
+            if run.failed() {
+                return;
+            }
+        }
+        match self.request("POST", &format!("/containers/{name}/start"), None) {
+            Ok(response) if matches!(response.status, 204 | 304) => {
+                run.out(format!("Container {name} started"));
+            }
+            Ok(response) => run.fail(classify_failure(&response.message()), response.message()),
+            Err(e) => run.unreachable(&e),
+        }
+    }
+
+    /// How the container of `container` compares with `body`, none when the
+    /// engine couldn't say.
+    fn existing(&self, container: &Container, body: &Value, run: &mut Run) -> Option<Existing> {
+        let name = &container.container_name;
+        let inspect = match self.request("GET", &format!("/containers/{name}/json"), None) {
+            Ok(response) if response.status == 200 => response.json(),
+            Ok(response) if response.status == 404 => return Some(Existing::Missing),
+            Ok(response) => {
+                run.fail(CommandErrorKind::Failed, response.message());
+                return None;
+            }
+            Err(e) => {
+                run.unreachable(&e);
+                return None;
+            }
+        };
+        let hash = &body["Labels"][CONFIG_HASH_LABEL];
+        // a tag pulled again points at a new image, compose recreates then too
+        if inspect["Config"]["Labels"][CONFIG_HASH_LABEL] != *hash
+            || self.image_id(&container.image).as_deref() != inspect["Image"].as_str()
+        {
+            return Some(Existing::Outdated);
+        }
+        Some(match inspect["State"]["Running"].as_bool() {
+            Some(true) => Existing::Running,
+            _ => Existing::Stopped,
+        })
+    }
+
+    /// Creates the container of `body`, pulling its image when missing.
+    fn create(&self, container: &Container, body: &Value, run: &mut Run) {
+        let name = &container.container_name;
+        let path = format!("/containers/create?name={}", encode(name));
+        let mut created = self.request("POST", &path, Some(body));
+        if created
+            .as_ref()
+            .is_ok_and(|response| response.status == 404)
+        {
+            // compose pulls missing images on its own, so do we
+            self.pull_image(&container.image, run);
+            if run.failed() {
+                return;
+            }
+            created = self.request("POST", &path, Some(body));
+        }
+        match created {
+            Ok(response) if response.status == 201 => run.out(format!("Container {name} created")),
+            Ok(response) => run.fail(classify_failure(&response.message()), response.message()),
+            Err(e) => run.unreachable(&e),
+        }
+    }
+}
+
+/// What stands in the way of a service's container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Existing {
+    Missing,
+    /// Created from another configuration or image.
+    Outdated,
+    Stopped,
+    Running,
+}
+
+impl Runtime for Engine {
+    fn name(&self) -> &'static str {
+        "the Docker Engine API"
+    }
+
+    fn pull(&self, stack: &StackConfig) -> CommandOutcome {
+        let mut run = Run::new(format!("engine pull {}", stack.stack_name));
+        for (_, container) in services(stack) {
+            match container.pull_policy.as_deref() {
+                Some("never" | "build") => continue,
+                Some("missing" | "if_not_present") if self.image_exists(&container.image) => {
+                    continue;
+                }
+                _ => self.pull_image(&container.image, &mut run),
+            }
+        }
+        run.finish()
+    }
+
+    fn up(&self, stack: &StackConfig) -> CommandOutcome {
+        let mut run = Run::new(format!("engine up {}", stack.stack_name));
+        if self.ensure_network(stack, &mut run) {
+            for (service, container) in services(stack) {
+                self.up_service(stack, service, container, &mut run);
+                if run.failed() {
+                    break;
+                }
+            }
+        }
+        if !run.failed() {
+            // containers of services the stack no longer has, as compose's
+            // `--remove-orphans` does
+            self.remove_containers(
+                stack,
+                |container| {
+                    container["Labels"][SERVICE_LABEL]
+                        .as_str()
+                        .is_none_or(|service| !stack.compose_spec.services.contains_key(service))
+                },
+                &mut run,
+            );
+        }
+        run.finish()
+    }
+
+    fn down(&self, stack: &StackConfig) -> CommandOutcome {
+        let mut run = Run::new(format!("engine down {}", stack.stack_name));
+        if !self.remove_containers(stack, |_| true, &mut run) {
+            return run.finish();
+        }
+        let network = network_name(stack);
+        match self.request("DELETE", &format!("/networks/{network}"), None) {
+            Ok(response) if response.status == 204 => run.out(format!("Network {network} removed")),
+            Ok(response) if response.status == 404 => {}
+            Ok(response) => run.fail(CommandErrorKind::Failed, response.message()),
+            Err(e) => run.unreachable(&e),
+        }
+        run.finish()
+    }
+
+    fn containers(&self, stack: &StackConfig) -> Result<Vec<ContainerState>, String> {
+        let containers = self.list_containers(stack).map_err(|e| e.to_string())?;
+        let field = |value: &Value| value.as_str().unwrap_or_default().to_string();
+        let mut states = Vec::new();
+        for container in &containers {
+            let id = container["Id"].as_str().unwrap_or_default();
+            // the list only tells the health apart within its status text
+            let inspect = match self.request("GET", &format!("/containers/{id}/json"), None) {
+                Ok(response) if response.status == 200 => response.json(),
+                Ok(response) => {
+                    return Err(format!(
+                        "Unable to inspect container {id}: {}",
+                        response.message()
+                    ));
+                }
+                Err(e) => return Err(format!("Unable to inspect container {id}: {e}")),
+            };
+            states.push(ContainerState {
+                name: field(&container["Names"][0]).trim_start_matches('/').into(),
+                service: field(&container["Labels"][SERVICE_LABEL]),
+                state: field(&inspect["State"]["Status"]),
+                health: field(&inspect["State"]["Health"]["Status"]),
+            });
+        }
+        Ok(states)
+    }
+
+    fn watch_events(&self, on_event: &mut dyn FnMut(RuntimeEvent)) -> Result<(), String> {
+        let filters = json!({ "type": ["container"], "label": [PROJECT_LABEL] });
+        let reply = self
+            .send(
+                "GET",
+                &format!("/events?filters={}", encode(&filters.to_string())),
+                None,
+                None,
+            )
+            .map_err(|e| format!("Unable to reach {}: {e}", self.socket))?;
+        if reply.status != 200 {
+            return Err(format!(
+                "Event stream answered with status {}",
+                reply.status
+            ));
+        }
+        let mut lines = LineSplitter::default();
+        let mut handle = |line: &str| {
+            if let Some(event) = RuntimeEvent::parse(line) {
+                on_event(event);
+            }
+        };
+        reply
+            .read_body(&mut |bytes| lines.feed(bytes, &mut handle))
+            .map_err(|e| e.to_string())?;
+        Err("Event stream ended".into())
+    }
+}
+
+/// Response being read from the socket.
+struct Reply {
+    status: u16,
+    reader: BufReader<UnixStream>,
+    chunked: bool,
+    length: Option<usize>,
+}
+
+impl Reply {
+    /// Hands the body to `on_bytes` as it arrives.
+    fn read_body(mut self, on_bytes: &mut dyn FnMut(&[u8])) -> io::Result<()> {
+        if !self.chunked {
+            let mut body = Vec::new();
+            match self.length {
+                Some(length) => (&mut self.reader)
+                    .take(u64::try_from(length).unwrap_or(u64::MAX))
+                    .read_to_end(&mut body)?,
+                None => self.reader.read_to_end(&mut body)?,
+            };
+            on_bytes(&body);
+            return Ok(());
+        }
+        loop {
+            let mut size_line = String::new();
+            if self.reader.read_line(&mut size_line)? == 0 {
+                return Ok(());
+            }
+            let size = size_line.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| io::Error::other(format!("invalid chunk size: {size_line:?}")))?;
+            if size == 0 {
+                return Ok(());
+            }
+            let mut chunk = vec![0; size + 2];
+            self.reader.read_exact(&mut chunk)?;
+            on_bytes(&chunk[..size]);
+        }
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or_default()
+    }
+
+    /// Error message the engine answered with.
+    fn message(&self) -> String {
+        self.json()["message"]
+            .as_str()
+            .map(String::from)
+            .unwrap_or_else(|| {
+                format!(
+                    "status {}: {}",
+                    self.status,
+                    String::from_utf8_lossy(&self.body).trim()
+                )
+            })
+    }
+}
+
+/// Cuts streamed bytes into the JSON lines the engine sends.
+#[derive(Default)]
+struct LineSplitter {
+    buffer: Vec<u8>,
+}
+
+impl LineSplitter {
+    fn feed(&mut self, bytes: &[u8], on_line: &mut dyn FnMut(&str)) {
+        self.buffer.extend_from_slice(bytes);
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                on_line(line.trim());
+            }
+        }
+    }
+
+    fn finish(self, on_line: &mut dyn FnMut(&str)) {
+        let line = String::from_utf8_lossy(&self.buffer);
+        if !line.trim().is_empty() {
+            on_line(line.trim());
+        }
+    }
+}
+
+/// Progress and errors of one operation, turned into its [`CommandOutcome`].
+struct Run {
+    command: String,
+    started: Instant,
+    stdout: OutputTail,
+    stderr: OutputTail,
+    error: Option<CommandErrorKind>,
+}
+
+impl Run {
+    fn new(command: String) -> Self {
+        Self {
+            command,
+            started: Instant::now(),
+            stdout: OutputTail::default(),
+            stderr: OutputTail::default(),
+            error: None,
+        }
+    }
+
+    fn out(&mut self, line: String) {
+        info!("{line}");
+        self.stdout.push(line);
+    }
+
+    /// Records a failure, the first one giving the kind of the outcome.
+    fn fail(&mut self, kind: CommandErrorKind, message: String) {
+        error!("{message}");
+        self.stderr.push(message);
+        self.error.get_or_insert(kind);
+    }
+
+    fn unreachable(&mut self, e: &io::Error) {
+        self.fail(
+            CommandErrorKind::DaemonUnavailable,
+            format!("Unable to reach the Docker Engine: {e}"),
+        );
+    }
+
+    fn failed(&self) -> bool {
+        self.error.is_some()
+    }
+
+    fn finish(self) -> CommandOutcome {
+        CommandOutcome {
+            command: self.command,
+            exit_code: None,
+            stdout: self.stdout.into_string(),
+            stderr: self.stderr.into_string(),
+            duration_ms: elapsed_ms(self.started),
+            error: self.error,
+        }
+    }
+}
+
+/// Services of `stack` in a stable order.
+fn services(stack: &StackConfig) -> Vec<(&String, &Container)> {
+    let mut services: Vec<_> = stack.compose_spec.services.iter().collect();
+    services.sort_by_key(|(service, _)| *service);
+    services
+}
+
+fn network_name(stack: &StackConfig) -> String {
+    format!("{}_default", stack.stack_name)
+}
+
+/// Body of `POST /containers/create` for `service`, following what compose
+/// makes of the same definition.
+fn container_body(stack: &StackConfig, service: &str, container: &Container) -> Value {
+    let mut exposed_ports = Map::new();
+    let mut port_bindings = Map::new();
+    for (key, binding) in container
+        .ports
+        .iter()
+        .flatten()
+        .flat_map(|port| parse_port(port))
+    {
+        exposed_ports.insert(key.clone(), json!({}));
+        if let Some(binding) = binding
+            && let Some(bindings) = port_bindings
+                .entry(key)
+                .or_insert_with(|| json!([]))
+                .as_array_mut()
+        {
+            bindings.push(binding);
+        }
+    }
+    let mut binds = Vec::new();
+    let mut volumes = Map::new();
+    for volume in container.volumes.iter().flatten() {
+        match volume.split_once(':') {
+            // relative sources are read from the stack's directory, as compose does
+            Some((source, target)) if source.starts_with('.') => binds.push(format!(
+                "{}/{}:{target}",
+                stack.home_directory,
+                source.trim_start_matches("./")
+            )),
+            Some(_) => binds.push(volume.clone()),
+            None => {
+                volumes.insert(volume.clone(), json!({}));
+            }
+        }
+    }
+    let (restart, retries) = match container.restart.split_once(':') {
+        Some((name, retries)) => (name, retries.parse().unwrap_or(0)),
+        None => (container.restart.as_str(), 0),
+    };
+    let mut body = json!({
+        "Image": container.image,
+        "Cmd": container.command.as_deref().map(split_command),
+        "Env": container.environment,
+        "User": container.user,
+        "Tty": container.tty.unwrap_or(false),
+        "OpenStdin": container.stdin_open.unwrap_or(false),
+        "Labels": {
+            PROJECT_LABEL: stack.stack_name,
+            SERVICE_LABEL: service,
+        },
+        "ExposedPorts": exposed_ports,
+        "Volumes": volumes,
+        "HostConfig": {
+            "RestartPolicy": { "Name": restart, "MaximumRetryCount": retries },
+            "PortBindings": port_bindings,
+            "Binds": binds,
+            "Privileged": container.privileged.unwrap_or(false),
+            "Memory": container.mem_limit.as_deref().and_then(parse_bytes).unwrap_or(0),
+            "MemoryReservation": container
+                .mem_reservation
+                .as_deref()
+                .and_then(parse_bytes)
+                .unwrap_or(0),
+            "OomKillDisable": container.oom_kill_disable,
+        },
+        "NetworkingConfig": {
+            "EndpointsConfig": { network_name(stack): { "Aliases": [service] } },
+        },
+    });
+    let hash = sha256(body.to_string().as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    body["Labels"][CONFIG_HASH_LABEL] = hash.into();
+    body
+}
+
+/// Reads `[ip:][host:]container[/protocol]` into the port keys of the engine
+/// and their host bindings, if any. Ranges such as `8000-8001:80-81` map port
+/// by port as compose does, a host range for a single port lets the engine
+/// pick one.
+fn parse_port(port: &str) -> Vec<(String, Option<Value>)> {
+    let (spec, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+    let parts: Vec<&str> = spec.rsplitn(3, ':').collect();
+    let (host, ip) = match parts.as_slice() {
+        [_, host] => (Some(*host), ""),
+        [_, host, ip] => (
+            Some(*host),
+            ip.trim_start_matches('[').trim_end_matches(']'),
+        ),
+        _ => (None, ""),
+    };
+    let binding = |host_port: String| json!({ "HostIp": ip, "HostPort": host_port });
+    let container_ports = port_range(parts[0]);
+    match (container_ports, host.map(|host| (host, port_range(host)))) {
+        (Some((first, last)), host) if first < last => (first..=last)
+            .map(|container_port| {
+                let host_port = match host {
+                    Some((_, Some((host_first, _)))) => {
+                        Some(binding((host_first + container_port - first).to_string()))
+                    }
+                    Some((host, None)) => Some(binding(host.to_string())),
+                    None => None,
+                };
+                (format!("{container_port}/{protocol}"), host_port)
+            })
+            .collect(),
+        _ => vec![(
+            format!("{}/{protocol}", parts[0]),
+            host.map(|host| binding(host.to_string())),
+        )],
+    }
+}
+
+/// First and last port of `80` or `8000-8010`.
+fn port_range(ports: &str) -> Option<(u16, u16)> {
+    match ports.split_once('-') {
+        Some((first, last)) => Some((first.parse().ok()?, last.parse().ok()?)),
+        None => ports.parse().ok().map(|port| (port, port)),
+    }
+}
+
+/// Reads sizes such as `512m` or `1g` into bytes.
+fn parse_bytes(size: &str) -> Option<i64> {
+    let size = size.trim().to_lowercase();
+    let size = size.strip_suffix('b').unwrap_or(&size);
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => size.split_at(at),
+        None => (size, ""),
+    };
+    let multiplier = match unit {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<i64>().ok().map(|number| number * multiplier)
+}
+
+/// Splits a command the way a shell would, quotes included.
+fn split_command(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// Name and tag of `image`, leaving digests and registry ports alone.
+fn split_image(image: &str) -> (&str, Option<&str>) {
+    if image.contains('@') {
+        return (image, None);
+    }
+    match image.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+        _ => (image, Some("latest")),
+    }
+}
+
+/// Percent-encodes a query value.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        os::unix::net::UnixListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::objects::structs::ComposeSpec;
+
+    /// Request received by a [`MockEngine`].
+    #[derive(Debug, Clone)]
+    struct Request {
+        method: String,
+        path: String,
+        body: Value,
+    }
+
+    /// Engine API on a unix socket in a temporary directory, answering each
+    /// request with the raw HTTP reply `answer` gives for it.
+    struct MockEngine {
+        _dir: TempDir,
+        socket: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl MockEngine {
+        fn start(mut answer: impl FnMut(&Request) -> String + Send + 'static) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let socket = dir.path().join("docker.sock");
+            let listener = UnixListener::bind(&socket).unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let received = requests.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(mut stream) = stream else {
+                        return;
+                    };
+                    let request = read_request(&stream);
+                    let reply = answer(&request);
+                    received.lock().unwrap().push(request);
+                    stream.write_all(reply.as_bytes()).ok();
+                }
+            });
+            Self {
+                _dir: dir,
+                socket: socket.to_string_lossy().into(),
+                requests,
+            }
+        }
+
+        fn engine(&self) -> Engine {
+            Engine::new(Some(self.socket.clone()))
+        }
+
+        /// `METHOD path` of the requests received so far.
+        fn calls(&self) -> Vec<String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|request| format!("{} {}", request.method, request.path))
+                .collect()
+        }
+
+        fn body_of(&self, path: &str) -> Value {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|request| request.path == path)
+                .map(|request| request.body.clone())
+                .unwrap_or_default()
+        }
+    }
+
+    fn read_request(stream: &UnixStream) -> Request {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut words = request_line.split_whitespace();
+        let method = words.next().unwrap_or_default().to_string();
+        let path = words.next().unwrap_or_default().to_string();
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).unwrap() == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some(("Content-Length", value)) = header.split_once(':') {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        Request {
+            method,
+            path,
+            body: serde_json::from_slice(&body).unwrap_or_default(),
+        }
+    }
+
+    fn fixed(status: u16, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn chunked(status: u16, chunks: &[&str]) -> String {
+        let mut reply = format!(
+            "HTTP/1.1 {status} OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+        for chunk in chunks {
+            reply.push_str(&format!("{:x}\r\n{chunk}\r\n", chunk.len()));
+        }
+        reply.push_str("0\r\n\r\n");
+        reply
+    }
+
+    fn stack() -> StackConfig {
+        let web = Container {
+            container_name: "shop-web".into(),
+            image: "nginx:1.27".into(),
+            restart: "always".into(),
+            ports: Some(vec!["8080:80".into()]),
+            ..Default::default()
+        };
+        let worker = Container {
+            container_name: "shop-worker".into(),
+            image: "registry:5000/worker".into(),
+            restart: "on-failure:3".into(),
+            pull_policy: Some("never".into()),
+            command: Some(r#"sh -c "echo 'hello world'""#.into()),
+            ..Default::default()
+        };
+        StackConfig {
+            stack_name: "shop".into(),
+            filename: "shop.yaml".into(),
+            home_directory: "/srv/shop".into(),
+            compose_spec: ComposeSpec {
+                services: HashMap::from([("web".into(), web), ("worker".into(), worker)]),
+            },
+            ..Default::default()
+        }
+    }
+
+    fn single(image: &str) -> StackConfig {
+        let container = Container {
+            container_name: "app".into(),
+            image: image.into(),
+            restart: "always".into(),
+            ..Default::default()
+        };
+        StackConfig {
+            stack_name: "app".into(),
+            compose_spec: ComposeSpec {
+                services: HashMap::from([("app".into(), container)]),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn containers_are_listed_and_inspected() {
+        let mock = MockEngine::start(|request| {
+            match request.path.as_str() {
+            path if path.starts_with("/containers/json?") => fixed(
+                200,
+                &json!([
+                    { "Id": "a1", "Names": ["/shop-web"], "Labels": { SERVICE_LABEL: "web" } },
+                    { "Id": "b2", "Names": ["/shop-worker"], "Labels": { SERVICE_LABEL: "worker" } },
+                ])
+                .to_string(),
+            ),
+            "/containers/a1/json" => chunked(
+                200,
+                &[
+                    r#"{"State":{"Status":"running","#,
+                    r#""Health":{"Status":"healthy"}}}"#,
+                ],
+            ),
+            "/containers/b2/json" => fixed(200, r#"{"State":{"Status":"exited"}}"#),
+            _ => fixed(404, r#"{"message":"not found"}"#),
+        }
+        });
+        let engine = mock.engine();
+        let states = engine.containers(&stack()).unwrap();
+
+        let states: Vec<_> = states
+            .iter()
+            .map(|state| {
+                (
+                    state.name.as_str(),
+                    state.service.as_str(),
+                    state.state.as_str(),
+                    state.health.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            states,
+            [
+                ("shop-web", "web", "running", "healthy"),
+                ("shop-worker", "worker", "exited", ""),
+            ]
+        );
+        let calls = mock.calls();
+        assert!(calls[0].starts_with("GET /containers/json?all=true&filters="));
+        assert!(calls[0].contains(&encode(&format!("{PROJECT_LABEL}=shop"))));
+        assert_eq!(engine.health(&stack()), Some(false));
+    }
+
+    #[test]
+    fn containers_report_engine_errors() {
+        let mock = MockEngine::start(|_| fixed(500, r#"{"message":"engine is on fire"}"#));
+        let err = mock.engine().containers(&stack()).unwrap_err();
+        assert_eq!(err, "engine is on fire");
+    }
+
+    #[test]
+    fn pull_streams_progress() {
+        let mock = MockEngine::start(|request| match request.path.as_str() {
+            "/images/create?fromImage=nginx&tag=1.27" => chunked(
+                200,
+                &[
+                    "{\"status\":\"Pulling from library/nginx\",\"id\":\"1.27\"}\n{\"status\":\"Downlo",
+                    "ading\",\"progressDetail\":{\"current\":1,\"total\":2},\"id\":\"abc\"}\n",
+                    "{\"status\":\"Pull complete\",\"id\":\"abc\"}\n",
+                    "{\"status\":\"Status: Downloaded newer image for nginx:1.27\"}",
+                ],
+            ),
+            _ => fixed(404, r#"{"message":"not found"}"#),
+        });
+        let outcome = mock.engine().pull(&stack());
+
+        assert!(outcome.success(), "{outcome:?}");
+        assert_eq!(
+            outcome.stdout,
+            [
+                "nginx:1.27 1.27: Pulling from library/nginx",
+                "nginx:1.27 abc: Pull complete",
+                "nginx:1.27: Status: Downloaded newer image for nginx:1.27",
+            ]
+            .join("\n")
+        );
+        // the worker's image is never pulled
+        assert_eq!(
+            mock.calls(),
+            ["POST /images/create?fromImage=nginx&tag=1.27"]
+        );
+    }
+
+    #[test]
+    fn pull_reports_failures() {
+        let mock = MockEngine::start(|request| match request.path.as_str() {
+            "/images/create?fromImage=missing&tag=1" => chunked(
+                200,
+                &["{\"error\":\"manifest unknown: manifest unknown\"}\n"],
+            ),
+            "/images/create?fromImage=private&tag=1" => {
+                fixed(401, r#"{"message":"authentication required"}"#)
+            }
+            _ => fixed(404, r#"{"message":"not found"}"#),
+        });
+        let engine = mock.engine();
+
+        let outcome = engine.pull(&single("missing:1"));
+        assert_eq!(outcome.error, Some(CommandErrorKind::ImageNotFound));
+        assert!(outcome.stderr.contains("manifest unknown"));
+        let outcome = engine.pull(&single("private:1"));
+        assert_eq!(outcome.error, Some(CommandErrorKind::RegistryAuth));
+    }
+
+    #[test]
+    fn pull_keeps_registry_ports_and_digests() {
+        let mock = MockEngine::start(|_| chunked(200, &["{\"status\":\"Pull complete\"}\n"]));
+        let engine = mock.engine();
+        assert!(engine.pull(&single("registry:5000/team/app")).success());
+        assert!(engine.pull(&single("app@sha256:0123abcd")).success());
+        assert_eq!(
+            mock.calls(),
+            [
+                "POST /images/create?fromImage=registry%3A5000%2Fteam%2Fapp&tag=latest",
+                "POST /images/create?fromImage=app%40sha256%3A0123abcd",
+            ]
+        );
+    }
+
+    #[test]
+    fn up_creates_network_and_containers() {
+        let mut created_web = false;
+        let mock = MockEngine::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/networks/shop_default") => {
+                    fixed(404, r#"{"message":"network shop_default not found"}"#)
+                }
+                ("POST", "/networks/create") => fixed(201, r#"{"Id":"n1"}"#),
+                // created from an older configuration
+                ("GET", "/containers/shop-web/json") => fixed(
+                    200,
+                    &json!({
+                        "Image": "sha256:0ld",
+                        "Config": { "Labels": { CONFIG_HASH_LABEL: "0ld" } },
+                        "State": { "Running": true },
+                    })
+                    .to_string(),
+                ),
+                ("GET", "/containers/shop-worker/json") => {
+                    fixed(404, r#"{"message":"No such container"}"#)
+                }
+                ("DELETE", "/containers/shop-web?force=true") => fixed(204, ""),
+                ("GET", path) if path.starts_with("/containers/json?") => fixed(
+                    200,
+                    &json!([
+                        { "Id": "a1", "Names": ["/shop-web"], "Labels": { SERVICE_LABEL: "web" } },
+                        { "Id": "b2", "Names": ["/shop-worker"], "Labels": { SERVICE_LABEL: "worker" } },
+                        { "Id": "c3", "Names": ["/shop-cache"], "Labels": { SERVICE_LABEL: "cache" } },
+                    ])
+                    .to_string(),
+                ),
+                ("DELETE", "/containers/c3?force=true") => fixed(204, ""),
+                // the image is missing until pulled
+                ("POST", "/containers/create?name=shop-web") if !created_web => {
+                    created_web = true;
+                    fixed(404, r#"{"message":"No such image: nginx:1.27"}"#)
+                }
+                ("POST", path) if path.starts_with("/containers/create?") => {
+                    fixed(201, r#"{"Id":"c1"}"#)
+                }
+                ("POST", "/images/create?fromImage=nginx&tag=1.27") => {
+                    chunked(200, &["{\"status\":\"Pull complete\",\"id\":\"abc\"}\n"])
+                }
+                ("POST", path) if path.ends_with("/start") => fixed(204, ""),
+                _ => fixed(500, r#"{"message":"unexpected request"}"#),
+            }
+        });
+        let outcome = mock.engine().up(&stack());
+
+        assert!(outcome.success(), "{outcome:?}");
+        assert_eq!(
+            outcome.stdout,
+            [
+                "Network shop_default created",
+                "Container shop-web removed",
+                "nginx:1.27 abc: Pull complete",
+                "Container shop-web created",
+                "Container shop-web started",
+                "Container shop-worker created",
+                "Container shop-worker started",
+                "Container shop-cache removed",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            mock.calls(),
+            [
+                "GET /networks/shop_default",
+                "POST /networks/create",
+                "GET /containers/shop-web/json",
+                "DELETE /containers/shop-web?force=true",
+                "POST /containers/create?name=shop-web",
+                "POST /images/create?fromImage=nginx&tag=1.27",
+                "POST /containers/create?name=shop-web",
+                "POST /containers/shop-web/start",
+                "GET /containers/shop-worker/json",
+                "POST /containers/create?name=shop-worker",
+                "POST /containers/shop-worker/start",
+                &format!(
+                    "GET /containers/json?all=true&filters={}",
+                    encode(&json!({ "label": [format!("{PROJECT_LABEL}=shop")] }).to_string())
+                ),
+                "DELETE /containers/c3?force=true",
+            ]
+        );
+        assert_eq!(
+            mock.body_of("/networks/create"),
+            json!({ "Name": "shop_default", "Labels": { PROJECT_LABEL: "shop" } })
+        );
+        let worker = mock.body_of("/containers/create?name=shop-worker");
+        assert_eq!(worker["Cmd"], json!(["sh", "-c", "echo 'hello world'"]));
+        assert_eq!(
+            worker["HostConfig"]["RestartPolicy"],
+            json!({ "Name": "on-failure", "MaximumRetryCount": 3 })
+        );
+    }
+
+    /// Engine holding the container of [`single`] as created from `hash` and
+    /// image `image_id`.
+    fn holding_app(hash: String, image_id: &'static str, running: bool) -> MockEngine {
+        MockEngine::start(
+            move |request| match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/networks/app_default") => fixed(200, r#"{"Id":"n1"}"#),
+                ("GET", "/containers/app/json") => fixed(
+                    200,
+                    &json!({
+                        "Image": "sha256:1",
+                        "Config": { "Labels": { CONFIG_HASH_LABEL: hash } },
+                        "State": { "Running": running },
+                    })
+                    .to_string(),
+                ),
+                ("GET", "/images/nginx:1.27/json") => {
+                    fixed(200, &json!({ "Id": image_id }).to_string())
+                }
+                ("DELETE", "/containers/app?force=true") => fixed(204, ""),
+                ("POST", "/containers/create?name=app") => fixed(201, r#"{"Id":"c1"}"#),
+                ("POST", "/containers/app/start") => fixed(204, ""),
+                ("GET", path) if path.starts_with("/containers/json?") => fixed(
+                    200,
+                    &json!([{ "Id": "a1", "Names": ["/app"], "Labels": { SERVICE_LABEL: "app" } }])
+                        .to_string(),
+                ),
+                _ => fixed(500, r#"{"message":"unexpected request"}"#),
+            },
+        )
+    }
+
+    fn app_hash(stack: &StackConfig) -> String {
+        let container = &stack.compose_spec.services["app"];
+        container_body(stack, "app", container)["Labels"][CONFIG_HASH_LABEL]
+            .as_str()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn up_leaves_unchanged_containers_running() {
+        let stack = single("nginx:1.27");
+        let mock = holding_app(app_hash(&stack), "sha256:1", true);
+        let outcome = mock.engine().up(&stack);
+
+        assert!(outcome.success(), "{outcome:?}");
+        assert_eq!(outcome.stdout, "Container app is up to date");
+        assert_eq!(
+            mock.calls(),
+            [
+                "GET /networks/app_default",
+                "GET /containers/app/json",
+                "GET /images/nginx:1.27/json",
+                &format!(
+                    "GET /containers/json?all=true&filters={}",
+                    encode(&json!({ "label": [format!("{PROJECT_LABEL}=app")] }).to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn up_starts_unchanged_stopped_containers() {
+        let stack = single("nginx:1.27");
+        let mock = holding_app(app_hash(&stack), "sha256:1", false);
+        let outcome = mock.engine().up(&stack);
+
+        assert!(outcome.success(), "{outcome:?}");
+        assert_eq!(outcome.stdout, "Container app started");
+        assert!(!mock.calls().iter().any(|call| call.starts_with("DELETE")));
+    }
+
+    #[test]
+    fn up_recreates_containers_of_another_configuration_or_image() {
+        let stack = single("nginx:1.27");
+        let mut changed = stack.clone();
+        changed
+            .compose_spec
+            .services
+            .get_mut("app")
+            .unwrap()
+            .environment = Some(vec!["KEY=value".into()]);
+        assert_ne!(app_hash(&stack), app_hash(&changed));
+
+        for mock in [
+            holding_app(app_hash(&stack), "sha256:1", true),
+            // the tag was pulled again
+            holding_app(app_hash(&changed), "sha256:2", true),
+        ] {
+            let outcome = mock.engine().up(&changed);
+            assert!(outcome.success(), "{outcome:?}");
+            assert_eq!(
+                outcome.stdout,
+                [
+                    "Container app removed",
+                    "Container app created",
+                    "Container app started",
+                ]
+                .join("\n")
+            );
+        }
+    }
+
+    #[test]
+    fn config_hash_follows_the_configuration() {
+        let stack = single("nginx:1.27");
+        assert_eq!(app_hash(&stack), app_hash(&stack.clone()));
+        assert_eq!(app_hash(&stack).len(), 64);
+        assert_ne!(app_hash(&stack), app_hash(&single("nginx:1.28")));
+    }
+
+    #[test]
+    fn up_stops_at_the_first_failure() {
+        let mock =
+            MockEngine::start(
+                |request| match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/networks/shop_default") => fixed(200, r#"{"Id":"n1"}"#),
+                    ("GET", _) => fixed(404, r#"{"message":"No such container"}"#),
+                    ("POST", "/containers/create?name=shop-web") => fixed(201, r#"{"Id":"c1"}"#),
+                    ("POST", "/containers/shop-web/start") => fixed(
+                        500,
+                        r#"{"message":"Bind for 0.0.0.0:8080 failed: port is already allocated"}"#,
+                    ),
+                    _ => fixed(500, r#"{"message":"unexpected request"}"#),
+                },
+            );
+        let outcome = mock.engine().up(&stack());
+
+        assert_eq!(outcome.error, Some(CommandErrorKind::PortConflict));
+        assert!(!mock.calls().iter().any(|call| call.contains("shop-worker")));
+    }
+
+    #[test]
+    fn unreachable_engine_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::new(Some(
+            dir.path().join("docker.sock").to_string_lossy().into(),
+        ));
+        let outcome = engine.up(&stack());
+        assert_eq!(outcome.error, Some(CommandErrorKind::DaemonUnavailable));
+        assert!(engine.containers(&stack()).is_err());
+    }
+
+    #[test]
+    fn events_are_streamed_until_the_end() {
+        let event = |kind: &str, action: &str, attributes: Value| {
+            json!({ "Type": kind, "Action": action, "Actor": { "Attributes": attributes } })
+                .to_string()
+        };
+        let start = event(
+            "container",
+            "start",
+            json!({ PROJECT_LABEL: "shop", "name": "shop-web" }),
+        );
+        let network = event(
+            "network",
+            "connect",
+            json!({ PROJECT_LABEL: "shop", "name": "shop_default" }),
+        );
+        let die = event(
+            "container",
+            "die",
+            json!({ PROJECT_LABEL: "shop", "name": "shop-worker" }),
+        );
+        let (die_head, die_tail) = die.split_at(20);
+        let lines = [
+            format!("{start}\n{network}\n"),
+            die_head.to_string(),
+            format!("{die_tail}\n"),
+        ];
+        let mock = MockEngine::start(move |request| {
+            if request.path.starts_with("/events?") {
+                chunked(200, &lines.iter().map(String::as_str).collect::<Vec<_>>())
+            } else {
+                fixed(404, r#"{"message":"not found"}"#)
+            }
+        });
+        let mut events = Vec::new();
+        let result = mock.engine().watch_events(&mut |event| {
+            events.push((event.stack, event.container, event.action));
+        });
+
+        assert_eq!(result, Err("Event stream ended".to_string()));
+        assert_eq!(
+            events,
+            [
+                ("shop".into(), "shop-web".into(), "start".into()),
+                ("shop".into(), "shop-worker".into(), "die".into()),
+            ]
+        );
+        assert!(mock.calls()[0].contains(&encode(PROJECT_LABEL)));
+    }
+
+    #[test]
+    fn events_report_refused_streams() {
+        let mock = MockEngine::start(|_| fixed(500, r#"{"message":"nope"}"#));
+        let result = mock.engine().watch_events(&mut |_| {});
+        assert_eq!(
+            result,
+            Err("Event stream answered with status 500".to_string())
+        );
+    }
+
+    #[test]
+    fn container_body_follows_compose() {
+        let container = Container {
+            container_name: "shop-web".into(),
+            image: "nginx:1.27".into(),
+            restart: "unless-stopped".into(),
+            user: Some("1000:1000".into()),
+            tty: Some(true),
+            ports: Some(vec!["8080:80".into(), "127.0.0.1:8443:443".into()]),
+            volumes: Some(vec![
+                "./html:/usr/share/nginx/html:ro".into(),
+                "/var/log/shop:/var/log/nginx".into(),
+                "/cache".into(),
+            ]),
+            environment: Some(vec!["KEY=value".into()]),
+            mem_limit: Some("512m".into()),
+            mem_reservation: Some("1g".into()),
+            privileged: Some(true),
+            ..Default::default()
+        };
+        let body = container_body(&stack(), "web", &container);
+
+        assert_eq!(body["Image"], "nginx:1.27");
+        assert_eq!(body["Cmd"], Value::Null);
+        assert_eq!(body["Env"], json!(["KEY=value"]));
+        assert_eq!(body["User"], "1000:1000");
+        assert_eq!(body["Tty"], true);
+        assert_eq!(body["OpenStdin"], false);
+        assert_eq!(body["Labels"][PROJECT_LABEL], "shop");
+        assert_eq!(body["Labels"][SERVICE_LABEL], "web");
+        assert!(body["Labels"][CONFIG_HASH_LABEL].is_string());
+        assert_eq!(body["ExposedPorts"], json!({ "80/tcp": {}, "443/tcp": {} }));
+        assert_eq!(body["Volumes"], json!({ "/cache": {} }));
+        let host_config = &body["HostConfig"];
+        assert_eq!(
+            host_config["PortBindings"],
+            json!({
+                "80/tcp": [{ "HostIp": "", "HostPort": "8080" }],
+                "443/tcp": [{ "HostIp": "127.0.0.1", "HostPort": "8443" }],
+            })
+        );
+        assert_eq!(
+            host_config["Binds"],
+            json!([
+                "/srv/shop/html:/usr/share/nginx/html:ro",
+                "/var/log/shop:/var/log/nginx",
+            ])
+        );
+        assert_eq!(
+            host_config["RestartPolicy"],
+            json!({ "Name": "unless-stopped", "MaximumRetryCount": 0 })
+        );
+        assert_eq!(host_config["Memory"], 512 << 20);
+        assert_eq!(host_config["MemoryReservation"], 1 << 30);
+        assert_eq!(host_config["Privileged"], true);
+        assert_eq!(
+            body["NetworkingConfig"]["EndpointsConfig"],
+            json!({ "shop_default": { "Aliases": ["web"] } })
+        );
+    }
+
+    #[test]
+    fn ports_are_parsed() {
+        let binding = |ip: &str, port: &str| Some(json!({ "HostIp": ip, "HostPort": port }));
+        assert_eq!(parse_port("80"), [("80/tcp".into(), None)]);
+        assert_eq!(
+            parse_port("8080:80"),
+            [("80/tcp".into(), binding("", "8080"))]
+        );
+        assert_eq!(
+            parse_port("127.0.0.1:5353:53/udp"),
+            [("53/udp".into(), binding("127.0.0.1", "5353"))]
+        );
+        assert_eq!(
+            parse_port("[::1]:8080:80"),
+            [("80/tcp".into(), binding("::1", "8080"))]
+        );
+        assert_eq!(
+            parse_port("[2001:db8::1]:8443:443/tcp"),
+            [("443/tcp".into(), binding("2001:db8::1", "8443"))]
+        );
+    }
+
+    #[test]
+    fn port_ranges_map_port_by_port() {
+        let binding = |ip: &str, port: &str| Some(json!({ "HostIp": ip, "HostPort": port }));
+        assert_eq!(
+            parse_port("8000-8001:80-81"),
+            [
+                ("80/tcp".into(), binding("", "8000")),
+                ("81/tcp".into(), binding("", "8001")),
+            ]
+        );
+        assert_eq!(
+            parse_port("0.0.0.0:9000-9001:9000-9001/udp"),
+            [
+                ("9000/udp".into(), binding("0.0.0.0", "9000")),
+                ("9001/udp".into(), binding("0.0.0.0", "9001")),
+            ]
+        );
+        assert_eq!(
+            parse_port("3000-3001"),
+            [("3000/tcp".into(), None), ("3001/tcp".into(), None)]
+        );
+        // the engine picks a free port out of the host range
+        assert_eq!(
+            parse_port("8000-8005:80"),
+            [("80/tcp".into(), binding("", "8000-8005"))]
+        );
+    }
+
+    #[test]
+    fn sizes_are_parsed() {
+        assert_eq!(parse_bytes("512m"), Some(512 << 20));
+        assert_eq!(parse_bytes("1g"), Some(1 << 30));
+        assert_eq!(parse_bytes("1GB"), Some(1 << 30));
+        assert_eq!(parse_bytes("64k"), Some(64 << 10));
+        assert_eq!(parse_bytes("1048576"), Some(1 << 20));
+        assert_eq!(parse_bytes("1.5g"), None);
+        assert_eq!(parse_bytes("10x"), None);
+    }
+
+    #[test]
+    fn commands_are_split_like_a_shell() {
+        assert_eq!(
+            split_command("nginx -g 'daemon off;'"),
+            ["nginx", "-g", "daemon off;"]
+        );
+        assert_eq!(
+            split_command(r#"sh -c "echo 'hello world'""#),
+            ["sh", "-c", "echo 'hello world'"]
+        );
+        assert_eq!(split_command("  run   --flag  "), ["run", "--flag"]);
+        assert_eq!(split_command("--name='a b'c"), ["--name=a bc"]);
+        assert_eq!(split_command("echo ''"), ["echo", ""]);
+        assert!(split_command("").is_empty());
+    }
+
+    #[test]
+    fn images_are_split_into_name_and_tag() {
+        assert_eq!(split_image("nginx"), ("nginx", Some("latest")));
+        assert_eq!(split_image("nginx:1.27"), ("nginx", Some("1.27")));
+        assert_eq!(
+            split_image("registry:5000/app"),
+            ("registry:5000/app", Some("latest"))
+        );
+        assert_eq!(
+            split_image("registry:5000/team/app:2.0"),
+            ("registry:5000/team/app", Some("2.0"))
+        );
+        assert_eq!(
+            split_image("app@sha256:0123abcd"),
+            ("app@sha256:0123abcd", None)
+        );
+        assert_eq!(
+            split_image("registry:5000/app@sha256:0123abcd"),
+            ("registry:5000/app@sha256:0123abcd", None)
+        );
+    }
+}
@@ -0,0 +1,215 @@
+pub mod compose;
+pub mod engine;
+
+use std::{sync::OnceLock, thread, time::Duration};
+
+use log::{error, info, warn};
+use serde_json::Value;
+
+use crate::{
+    objects::structs::{CommandOutcome, RuntimeConfig, RuntimeKind, StackConfig},
+    utils::{
+        logging::LogContext,
+        runtime::{compose::ComposeCli, engine::Engine},
+    },
+};
+
+static RUNTIME: OnceLock<Box<dyn Runtime>> = OnceLock::new();
+
+/// Label compose puts on every container of a project, which both backends
+/// use to find the containers of a stack.
+pub const PROJECT_LABEL: &str = "com.docker.compose.project";
+/// Label naming the service a container runs.
+pub const SERVICE_LABEL: &str = "com.docker.compose.service";
+
+/// Something that runs the stacks of the node.
+pub trait Runtime: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Fetches the images of `stack`.
+    fn pull(&self, stack: &StackConfig) -> CommandOutcome;
+
+    /// Creates or recreates the containers of `stack` and starts them,
+    /// removing those of services it no longer has.
+    fn up(&self, stack: &StackConfig) -> CommandOutcome;
+
+    /// Stops and removes the containers of `stack`.
+    fn down(&self, stack: &StackConfig) -> CommandOutcome;
+
+    /// Containers of `stack`, stopped ones included.
+    fn containers(&self, stack: &StackConfig) -> Result<Vec<ContainerState>, String>;
+
+    /// `None` while a container of `stack` is still starting, otherwise
+    /// whether all of them run and none is unhealthy.
+    fn health(&self, stack: &StackConfig) -> Option<bool> {
+        let containers = match self.containers(stack) {
+            Ok(containers) => containers,
+            Err(e) => {
+                error!("Unable to list containers of {}: {e}", stack.stack_name);
+                return Some(false);
+            }
+        };
+        if containers.is_empty() {
+            return Some(false);
+        }
+        let mut healthy = true;
+        for container in &containers {
+            match container.health.as_str() {
+                "starting" => return None,
+                "unhealthy" => healthy = false,
+                _ if container.state != "running" => healthy = false,
+                _ => {}
+            }
+        }
+        Some(healthy)
+    }
+
+    /// Hands `on_event` the container events of the stacks until the stream
+    /// ends or fails. Returns `Ok` right away when the runtime has no events
+    /// to give.
+    fn watch_events(&self, on_event: &mut dyn FnMut(RuntimeEvent)) -> Result<(), String>;
+}
+
+/// A container of a stack as the runtime sees it.
+#[derive(Debug, Clone)]
+pub struct ContainerState {
+    pub name: String,
+    pub service: String,
+    /// `running`, `exited`, `paused`...
+    pub state: String,
+    /// `healthy`, `unhealthy` or `starting`, empty without a health check.
+    pub health: String,
+}
+
+/// Something that happened to a container of a stack.
+#[derive(Debug, Clone)]
+pub struct RuntimeEvent {
+    pub stack: String,
+    pub container: String,
+    pub action: String,
+}
+
+impl RuntimeEvent {
+    /// Reads an event as printed by `docker events --format '{{json .}}'` or
+    /// streamed by `GET /events`, which share their shape.
+    pub fn parse(line: &str) -> Option<Self> {
+        let event: Value = serde_json::from_str(line).ok()?;
+        if event["Type"].as_str() != Some("container") {
+            return None;
+        }
+        let attributes = &event["Actor"]["Attributes"];
+        Some(Self {
+            stack: attributes[PROJECT_LABEL].as_str()?.into(),
+            container: attributes["name"].as_str().unwrap_or_default().into(),
+            action: event["Action"].as_str().unwrap_or_default().into(),
+        })
+    }
+
+    /// Reads an event as printed by `podman events --format json`, naming
+    /// actions the way Docker does.
+    pub fn parse_podman(line: &str) -> Option<Self> {
+        let event: Value = serde_json::from_str(line).ok()?;
+        if event["Type"].as_str() != Some("container") {
+            return None;
+        }
+        let action = match event["Status"].as_str().unwrap_or_default() {
+            "died" => "die".into(),
+            "health_status" => format!(
+                "health_status: {}",
+                event["HealthStatus"].as_str().unwrap_or_default()
+            ),
+            status => status.into(),
+        };
+        Some(Self {
+            stack: event["Attributes"][PROJECT_LABEL].as_str()?.into(),
+            container: event["Name"].as_str().unwrap_or_default().into(),
+            action,
+        })
+    }
+}
+
+/// Picks the runtime of the node, looking for a compose CLI when none is
+/// configured. The first call wins, later ones only log.
+pub fn init_runtime(config: &RuntimeConfig) {
+    let runtime: Box<dyn Runtime> = match config.kind {
+        RuntimeKind::Auto => match ComposeCli::detect() {
+            Some(cli) => Box::new(cli),
+            None => {
+                error!("No compose CLI found, trying docker compose anyway");
+                Box::new(ComposeCli::docker())
+            }
+        },
+        RuntimeKind::DockerCompose => configured(ComposeCli::docker()),
+        RuntimeKind::DockerComposeV1 => configured(ComposeCli::docker_compose()),
+        RuntimeKind::Podman => configured(ComposeCli::podman()),
+        RuntimeKind::PodmanCompose => configured(ComposeCli::podman_compose()),
+        RuntimeKind::Nerdctl => configured(ComposeCli::nerdctl()),
+        RuntimeKind::Engine => Box::new(Engine::new(config.socket.clone())),
+    };
+    let name = runtime.name();
+    match RUNTIME.set(runtime) {
+        Ok(()) => info!("Running stacks with {name}"),
+        Err(_) => warn!("Runtime already chosen, ignoring {name}"),
+    }
+}
+
+/// Keeps the CLI set in `node.toml` even when it doesn't answer yet, it may
+/// be installed later.
+fn configured(cli: ComposeCli) -> Box<dyn Runtime> {
+    if !cli.available() {
+        warn!("{} is not available on this node", cli.name());
+    }
+    Box::new(cli)
+}
+
+/// Runtime of the node, the `docker compose` CLI unless [`init_runtime`]
+/// picked another one.
+pub fn runtime() -> &'static dyn Runtime {
+    RUNTIME
+        .get_or_init(|| Box::new(ComposeCli::docker()))
+        .as_ref()
+}
+
+/// Runs `f`, which waits on the runtime, on tokio's blocking threads so the
+/// async tasks beside it keep going. The log context comes along.
+pub async fn blocking<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> R {
+    let context = LogContext::current();
+    tokio::task::spawn_blocking(move || context.enter(f))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+pub fn compose_file_path(stack: &StackConfig) -> String {
+    format!("{}/{}", stack.home_directory, stack.filename)
+}
+
+/// Logs the container events of the stacks from a thread of its own, coming
+/// back after a pause whenever the stream breaks.
+pub fn watch_events() {
+    thread::spawn(|| {
+        loop {
+            let result = runtime().watch_events(&mut |event| {
+                LogContext::current()
+                    .stack(&event.stack)
+                    .enter(|| match event.action.as_str() {
+                        "die" | "oom" | "kill" | "health_status: unhealthy" => warn!(
+                            "Container {} of stack {}: {}",
+                            event.container, event.stack, event.action
+                        ),
+                        _ => info!(
+                            "Container {} of stack {}: {}",
+                            event.container, event.stack, event.action
+                        ),
+                    });
+            });
+            match result {
+                Ok(()) => {
+                    info!("{} reports no container events", runtime().name());
+                    return;
+                }
+                Err(e) => error!("Container event stream failed: {e}"),
+            }
+            thread::sleep(Duration::from_secs(30));
+        }
+    });
+}
@@ -26,6 +26,16 @@ pub fn load_secrets(mode: &str) -> Result<Vec<String>, ConfigError> {
                 .map_err(|_| ConfigError::MissingField("POSTGRES_PORT".into()))?;
             vec![pg_user, pg_pass, pg_host, pg_port, pg_db]
         }
+        "storage" => {
+            let backend: String =
+                std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "postgres".into());
+            vec![backend]
+        }
+        "sqlite" => {
+            let sqlite_db: String = std::env::var("SQLITE_DATABASE")
+                .map_err(|_| ConfigError::MissingField("SQLITE_DATABASE".into()))?;
+            vec![sqlite_db]
+        }
         "agent" => {
             let hikari_server: String = std::env::var("HIKARI_SERVER_DOMAIN")
                 .map_err(|_| ConfigError::MissingField("HIKARI_SERVER_DOMAIN".into()))?;